@@ -0,0 +1,577 @@
+use ab_glyph::{Font, FontArc, FontVec, OutlineCurve};
+use eframe::egui::{self, FontTweak};
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref};
+use qmk_via_api::keycodes::Keycode;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use svg2pdf::usvg::{self, TreeParsing};
+
+use crate::keyboard::Keyboard;
+use crate::keyboard_info::Encoder;
+use crate::keycode_labels::{self, KeycodeKind, KeycodeLabel, LabelOptions};
+use crate::macros::Macro;
+use crate::overlay_window::Overlay;
+use crate::vial::VialFeatures;
+
+/// Size of a key unit in the exported images, in pixels
+const KEY_SIZE: f32 = 60.0;
+
+/// Space around each layer, in key units
+const PAGE_MARGIN: f32 = 0.4;
+
+/// Height of the layer title above the keys, in key units
+const TITLE_HEIGHT: f32 = 0.6;
+
+/// Height of a line of the macro legend, in key units
+const MACRO_LINE_HEIGHT: f32 = 0.35;
+
+const TITLE_COLOR: egui::Color32 = egui::Color32::from_rgb(40, 40, 40);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheatSheetFormat {
+    Svg,
+    Png,
+    Pdf,
+}
+
+impl CheatSheetFormat {
+    pub fn from_path(path: &str) -> Result<Self, String> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("svg") => Ok(CheatSheetFormat::Svg),
+            Some("png") => Ok(CheatSheetFormat::Png),
+            Some("pdf") => Ok(CheatSheetFormat::Pdf),
+            _ => Err(format!("'{path}' is not an .svg, .png or .pdf file")),
+        }
+    }
+}
+
+/// A layer drawn as the body of an SVG document.
+struct Page {
+    width: f32,
+    height: f32,
+    body: String,
+}
+
+impl Page {
+    fn to_svg(&self) -> String {
+        format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+             viewBox=\"0 0 {w} {h}\">\n{}</svg>\n",
+            self.body,
+            w = self.width,
+            h = self.height
+        )
+    }
+}
+
+/// Turns the glyphs of egui galleys into SVG paths, so the exported files don't depend on
+/// installed fonts.
+struct GlyphOutliner {
+    fonts: Vec<(FontArc, FontTweak)>,
+}
+
+impl GlyphOutliner {
+    /// Loads the fonts of the proportional family, in the order egui falls back through them.
+    fn new(definitions: &egui::FontDefinitions) -> Self {
+        let fonts = definitions
+            .families
+            .get(&egui::FontFamily::Proportional)
+            .into_iter()
+            .flatten()
+            .filter_map(|name| {
+                let data = definitions.font_data.get(name)?;
+                let font = FontVec::try_from_vec_and_index(data.font.to_vec(), data.index).ok()?;
+                Some((FontArc::new(font), data.tweak))
+            })
+            .collect();
+        GlyphOutliner { fonts }
+    }
+
+    /// Returns the SVG path data of a glyph placed at `origin`, matching the position and size
+    /// of the glyph as rasterized by egui.
+    fn glyph_path(
+        &self,
+        glyph: &egui::epaint::text::Glyph,
+        size: f32,
+        origin: egui::Pos2,
+    ) -> Option<String> {
+        if glyph.uv_rect.is_nothing() {
+            return None;
+        }
+        let (font, tweak) = self
+            .fonts
+            .iter()
+            .find(|(font, _)| font.glyph_id(glyph.chr).0 != 0)?;
+        let outline = font.outline(font.glyph_id(glyph.chr))?;
+
+        // Same scale as egui, which rounds it to whole pixels
+        let height = font.height_unscaled();
+        let scale_in_pixels = (size * height / font.units_per_em()? * tweak.scale).round();
+        let factor = scale_in_pixels / height;
+
+        // The glyph offset is the top left of the pixel bounds of the outline, whose bounds
+        // have the top in `min.y`
+        let bounds = outline.bounds;
+        let offset = origin + glyph.pos.to_vec2() + glyph.uv_rect.offset
+            - egui::vec2(
+                (bounds.min.x * factor).floor(),
+                (-bounds.min.y * factor).floor(),
+            );
+        let point = |p: ab_glyph::Point| (offset.x + p.x * factor, offset.y - p.y * factor);
+
+        let mut path = String::new();
+        let mut last = None;
+        for curve in &outline.curves {
+            let (start, end) = match curve {
+                OutlineCurve::Line(a, b) => (*a, *b),
+                OutlineCurve::Quad(a, _, b) => (*a, *b),
+                OutlineCurve::Cubic(a, _, _, b) => (*a, *b),
+            };
+            if last != Some(start) {
+                let (x, y) = point(start);
+                let _ = write!(path, "M{x:.2} {y:.2}");
+            }
+            match curve {
+                OutlineCurve::Line(_, b) => {
+                    let (x, y) = point(*b);
+                    let _ = write!(path, "L{x:.2} {y:.2}");
+                }
+                OutlineCurve::Quad(_, c, b) => {
+                    let ((cx, cy), (x, y)) = (point(*c), point(*b));
+                    let _ = write!(path, "Q{cx:.2} {cy:.2} {x:.2} {y:.2}");
+                }
+                OutlineCurve::Cubic(_, c1, c2, b) => {
+                    let ((c1x, c1y), (c2x, c2y), (x, y)) = (point(*c1), point(*c2), point(*b));
+                    let _ = write!(path, "C{c1x:.2} {c1y:.2} {c2x:.2} {c2y:.2} {x:.2} {y:.2}");
+                }
+            }
+            last = Some(end);
+        }
+        Some(path)
+    }
+}
+
+/// Formats a color as SVG fill or stroke attributes.
+fn paint(attribute: &str, color: egui::Color32) -> String {
+    let [r, g, b, a] = color.to_srgba_unmultiplied();
+    format!(
+        "{attribute}=\"rgb({r},{g},{b})\" {attribute}-opacity=\"{:.3}\"",
+        a as f32 / 255.0
+    )
+}
+
+/// Draws the layers of a keyboard the same way as the overlay, without a window.
+struct Renderer<'a> {
+    ctx: &'a egui::Context,
+    outliner: &'a GlyphOutliner,
+    keyboard: &'a Keyboard,
+    macros: Vec<Macro>,
+    vial_features: Option<VialFeatures>,
+    label_options: LabelOptions,
+}
+
+impl Renderer<'_> {
+    fn galley(
+        &self,
+        body: &mut String,
+        pos: egui::Pos2,
+        galley: &Arc<egui::Galley>,
+        color: egui::Color32,
+    ) {
+        let Some(section) = galley.job.sections.first() else {
+            return;
+        };
+        let size = section.format.font_id.size;
+        let path: String = galley
+            .rows
+            .iter()
+            .flat_map(|row| {
+                row.row.glyphs.iter().filter_map(move |glyph| {
+                    self.outliner
+                        .glyph_path(glyph, size, pos + row.pos.to_vec2())
+                })
+            })
+            .collect();
+        if !path.is_empty() {
+            let _ = writeln!(body, "<path d=\"{path}\" {}/>", paint("fill", color));
+        }
+    }
+
+    fn label(
+        &self,
+        body: &mut String,
+        label: KeycodeLabel,
+        rect: egui::Rect,
+        font_size: f32,
+        color: egui::Color32,
+    ) {
+        let font = egui::FontId::proportional(font_size * KEY_SIZE);
+        for (pos, galley) in Overlay::layout_key_label(self.ctx, KEY_SIZE, label, rect, font, color)
+        {
+            self.galley(body, pos, &galley, color);
+        }
+    }
+
+    /// Returns the keycode shown for a key on the layer, falling through transparent keys like
+    /// the firmware does, and whether it comes from a lower layer.
+    fn resolve(&self, layer: usize, get_keycode: impl Fn(usize) -> u16) -> (u8, u16, bool) {
+        (0..=layer)
+            .rev()
+            .map(|l| (l as u8, get_keycode(l), l != layer))
+            .find(|(_, keycode, _)| *keycode != Keycode::KC_TRANSPARENT as u16)
+            .unwrap_or((layer as u8, Keycode::KC_TRANSPARENT as u16, false))
+    }
+
+    fn keycode_label(&self, keycode: u16) -> KeycodeLabel {
+        let mut label = keycode_labels::get_keycode_label(keycode, self.label_options);
+        Macro::annotate_label(&self.macros, keycode, &mut label, self.label_options);
+        if let Some(features) = &self.vial_features {
+            features.annotate_label(keycode, &mut label, self.label_options);
+        }
+        label
+    }
+
+    fn draw_layer(&self, layer: usize) -> Page {
+        let (width, height) = self.keyboard.layout.get_dimensions();
+        let origin = egui::vec2(PAGE_MARGIN, PAGE_MARGIN + TITLE_HEIGHT) * KEY_SIZE;
+        let mut body = String::new();
+
+        let title = self.ctx.fonts(|fonts| {
+            fonts.layout(
+                format!("{} – Layer {layer}", self.keyboard.layout.name),
+                egui::FontId::proportional(0.3 * KEY_SIZE),
+                TITLE_COLOR,
+                f32::INFINITY,
+            )
+        });
+        let title_pos = egui::pos2(PAGE_MARGIN * KEY_SIZE, PAGE_MARGIN * KEY_SIZE);
+        self.galley(&mut body, title_pos, &title, TITLE_COLOR);
+
+        for key in &self.keyboard.layout.keys {
+            let (row, col) = (key.row as usize, key.col as usize);
+            let (resolved_layer, keycode, is_background) =
+                self.resolve(layer, |l| self.keyboard.get_keycode(l, row, col));
+            let label = self.keycode_label(keycode);
+            let first_layer_kind = keycode_labels::get_keycode_label(
+                self.keyboard.get_keycode(0, row, col),
+                self.label_options,
+            )
+            .kind;
+            let (fill, stroke, thickness, font_color) = Overlay::get_keycode_color(
+                KEY_SIZE,
+                label.layer_ref.unwrap_or(resolved_layer),
+                first_layer_kind,
+                is_background,
+                false,
+            );
+
+            let rect = egui::Rect::from_min_size(
+                egui::pos2(key.x, key.y) * KEY_SIZE + origin,
+                egui::vec2(key.w, key.h) * KEY_SIZE,
+            )
+            .shrink(0.06 * KEY_SIZE);
+            // The overlay draws the border outside of the key
+            let outer = rect.expand(0.5 * thickness);
+            let _ = writeln!(
+                body,
+                "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" rx=\"{:.2}\" {} {} stroke-width=\"{thickness:.2}\"/>",
+                outer.min.x,
+                outer.min.y,
+                outer.width(),
+                outer.height(),
+                0.1 * KEY_SIZE + 0.5 * thickness,
+                paint("fill", fill),
+                paint("stroke", stroke),
+            );
+            self.label(&mut body, label, rect, 0.25, font_color);
+        }
+
+        for encoder in &self.keyboard.layout.encoders {
+            self.draw_encoder(&mut body, encoder, layer, origin);
+        }
+
+        Page {
+            width: (width + 2.0 * PAGE_MARGIN) * KEY_SIZE,
+            height: (height + TITLE_HEIGHT + 2.0 * PAGE_MARGIN) * KEY_SIZE,
+            body,
+        }
+    }
+
+    /// Lists the full expansion of the macros bound to keys, which only fit as an abbreviation
+    /// on the keys themselves.
+    fn draw_macro_legend(&self) -> Option<Page> {
+        let layout = &self.keyboard.layout;
+        let mut indices = BTreeSet::new();
+        for layer in 0..self.keyboard.get_num_layers() {
+            let keys = layout.keys.iter().map(|key| {
+                self.keyboard
+                    .get_keycode(layer, key.row as usize, key.col as usize)
+            });
+            let encoders = layout.encoders.iter().flat_map(|encoder| {
+                [false, true].map(|clockwise| {
+                    self.keyboard
+                        .get_encoder_keycode(layer, encoder.index as usize, clockwise)
+                })
+            });
+            indices.extend(keys.chain(encoders).filter_map(Macro::get_index));
+        }
+        let lines: Vec<String> = indices
+            .into_iter()
+            .filter_map(|index| {
+                let macro_ = self.macros.get(index)?;
+                (!macro_.actions.is_empty())
+                    .then(|| format!("MACRO({index})   {}", macro_.describe(self.label_options)))
+            })
+            .collect();
+        if lines.is_empty() {
+            return None;
+        }
+
+        let width = layout.get_dimensions().0.max(4.0);
+        let mut body = String::new();
+        let title = self.ctx.fonts(|fonts| {
+            fonts.layout(
+                format!("{} – Macros", layout.name),
+                egui::FontId::proportional(0.3 * KEY_SIZE),
+                TITLE_COLOR,
+                f32::INFINITY,
+            )
+        });
+        let mut pos = egui::pos2(PAGE_MARGIN, PAGE_MARGIN) * KEY_SIZE;
+        self.galley(&mut body, pos, &title, TITLE_COLOR);
+        pos.y += TITLE_HEIGHT * KEY_SIZE;
+        for line in lines {
+            // Long macros wrap at the width of the keyboard
+            let galley = self.ctx.fonts(|fonts| {
+                fonts.layout(
+                    line,
+                    egui::FontId::proportional(0.2 * KEY_SIZE),
+                    TITLE_COLOR,
+                    width * KEY_SIZE,
+                )
+            });
+            self.galley(&mut body, pos, &galley, TITLE_COLOR);
+            pos.y += galley.rect.height().max(MACRO_LINE_HEIGHT * KEY_SIZE);
+        }
+
+        Some(Page {
+            width: (width + 2.0 * PAGE_MARGIN) * KEY_SIZE,
+            height: pos.y + PAGE_MARGIN * KEY_SIZE,
+            body,
+        })
+    }
+
+    /// Draws an encoder as a knob with both directions, like the overlay.
+    fn draw_encoder(&self, body: &mut String, encoder: &Encoder, layer: usize, origin: egui::Vec2) {
+        let rect = egui::Rect::from_min_size(
+            egui::pos2(encoder.x, encoder.y) * KEY_SIZE + origin,
+            egui::vec2(encoder.w, encoder.h) * KEY_SIZE,
+        )
+        .shrink(0.06 * KEY_SIZE);
+        let radius = 0.5 * rect.width().min(rect.height());
+        let index = encoder.index as usize;
+
+        let directions = [false, true].map(|clockwise| {
+            self.resolve(layer, |l| {
+                self.keyboard.get_encoder_keycode(l, index, clockwise)
+            })
+        });
+        let (knob_layer, _, is_background) = directions
+            .iter()
+            .max_by_key(|(layer, _, _)| *layer)
+            .expect("Encoders have two directions");
+        let (fill, stroke, thickness, font_color) = Overlay::get_keycode_color(
+            KEY_SIZE,
+            *knob_layer,
+            KeycodeKind::Basic,
+            *is_background,
+            false,
+        );
+        let center = rect.center();
+        let _ = writeln!(
+            body,
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" {} {} stroke-width=\"{thickness:.2}\"/>",
+            center.x,
+            center.y,
+            radius,
+            paint("fill", fill),
+            paint("stroke", stroke),
+        );
+
+        for ((_, keycode, _), clockwise) in directions.into_iter().zip([false, true]) {
+            let side = if clockwise { 1.0 } else { -1.0 };
+            let center = center + egui::vec2(side * 0.45 * radius, 0.0);
+            let label_rect = egui::Rect::from_center_size(center, egui::vec2(radius, radius));
+            self.label(
+                body,
+                self.keycode_label(keycode),
+                label_rect,
+                0.2,
+                font_color,
+            );
+
+            let arrow = if clockwise {
+                egui_phosphor::regular::ARROW_CLOCKWISE
+            } else {
+                egui_phosphor::regular::ARROW_COUNTER_CLOCKWISE
+            };
+            let galley = self.ctx.fonts(|fonts| {
+                fonts.layout(
+                    arrow.to_string(),
+                    egui::FontId::proportional(0.18 * KEY_SIZE),
+                    font_color,
+                    f32::INFINITY,
+                )
+            });
+            let arrow_center = center - egui::vec2(0.0, 0.55 * radius);
+            self.galley(
+                body,
+                arrow_center - galley.rect.center().to_vec2(),
+                &galley,
+                font_color,
+            );
+        }
+    }
+}
+
+/// Draws every layer of the keyboard, followed by the macro legend if any macros are bound to
+/// keys. A headless egui context is used for the label layout.
+fn render_pages(keyboard: &Keyboard, label_options: LabelOptions) -> Vec<Page> {
+    let mut definitions = egui::FontDefinitions::default();
+    egui_phosphor::add_to_fonts(&mut definitions, egui_phosphor::Variant::Regular);
+    let outliner = GlyphOutliner::new(&definitions);
+    let ctx = egui::Context::default();
+    ctx.set_fonts(definitions);
+
+    let mut pages = Vec::new();
+    let _ = ctx.run(egui::RawInput::default(), |ctx| {
+        let renderer = Renderer {
+            ctx,
+            outliner: &outliner,
+            keyboard,
+            macros: keyboard.get_macros(),
+            vial_features: keyboard.get_vial_features(),
+            label_options,
+        };
+        pages = (0..keyboard.get_num_layers())
+            .map(|layer| renderer.draw_layer(layer))
+            .collect();
+        if !pages.is_empty() {
+            pages.extend(renderer.draw_macro_legend());
+        }
+    });
+    pages
+}
+
+/// Stacks the layers below each other in a single SVG document.
+fn stack_pages(pages: &[Page]) -> Page {
+    let width = pages.iter().map(|page| page.width).fold(0.0, f32::max);
+    let mut body = String::new();
+    let mut y = 0.0;
+    for page in pages {
+        let _ = writeln!(
+            body,
+            "<g transform=\"translate(0 {y:.2})\">\n{}</g>",
+            page.body
+        );
+        y += page.height;
+    }
+    Page {
+        width,
+        height: y,
+        body,
+    }
+}
+
+fn parse_svg(svg: &str) -> Result<usvg::Tree, String> {
+    let mut tree = usvg::Tree::from_str(svg, &usvg::Options::default())
+        .map_err(|e| format!("Failed to create the cheat sheet: {e}"))?;
+    // The labels are paths already, so converting text isn't needed
+    tree.calculate_abs_transforms();
+    tree.calculate_bounding_boxes();
+    Ok(tree)
+}
+
+fn write_png(page: &Page, path: &str) -> Result<(), String> {
+    let tree = parse_svg(&page.to_svg())?;
+    let mut pixmap =
+        resvg::tiny_skia::Pixmap::new(page.width.ceil() as u32, page.height.ceil() as u32)
+            .ok_or("The keyboard has no keys to draw")?;
+    pixmap.fill(resvg::tiny_skia::Color::WHITE);
+    resvg::render(
+        &tree,
+        resvg::tiny_skia::Transform::default(),
+        &mut pixmap.as_mut(),
+    );
+    pixmap
+        .save_png(path)
+        .map_err(|e| format!("Failed to write '{path}': {e}"))
+}
+
+/// Writes a PDF with a page per layer and the macro legend.
+fn write_pdf(pages: &[Page], path: &str) -> Result<(), String> {
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let mut next_id = 3;
+
+    let mut pdf = Pdf::new();
+    let mut page_ids = Vec::new();
+    for page in pages {
+        let tree = parse_svg(&page.to_svg())?;
+        let page_id = Ref::new(next_id);
+        let content_id = Ref::new(next_id + 1);
+        let svg_id = Ref::new(next_id + 2);
+        let svg_name = Name(b"S1");
+        page_ids.push(page_id);
+
+        // The SVG is converted to a form XObject covering the unit square
+        let svg_options = svg2pdf::Options::default();
+        next_id = svg2pdf::convert_tree_into(&tree, svg_options, &mut pdf, svg_id).get();
+
+        let mut pdf_page = pdf.page(page_id);
+        pdf_page.media_box(Rect::new(0.0, 0.0, page.width, page.height));
+        pdf_page.parent(page_tree_id);
+        pdf_page.contents(content_id);
+        pdf_page.resources().x_objects().pair(svg_name, svg_id);
+        pdf_page.finish();
+
+        let mut content = Content::new();
+        content
+            .transform([page.width, 0.0, 0.0, page.height, 0.0, 0.0])
+            .x_object(svg_name);
+        pdf.stream(content_id, &content.finish());
+    }
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+
+    std::fs::write(path, pdf.finish()).map_err(|e| format!("Failed to write '{path}': {e}"))
+}
+
+/// Draws every layer of the keyboard and the macro legend and writes them to an SVG or PNG
+/// image, with the layers below each other, or a PDF with a page each. The format is taken from
+/// the extension.
+pub fn export_cheat_sheet(
+    keyboard: &Keyboard,
+    path: &str,
+    label_options: LabelOptions,
+) -> Result<(), String> {
+    let format = CheatSheetFormat::from_path(path)?;
+    let pages = render_pages(keyboard, label_options);
+    if pages.is_empty() {
+        return Err("The keymap hasn't been read from the device yet".to_string());
+    }
+    match format {
+        CheatSheetFormat::Svg => std::fs::write(path, stack_pages(&pages).to_svg())
+            .map_err(|e| format!("Failed to write '{path}': {e}")),
+        CheatSheetFormat::Png => write_png(&stack_pages(&pages), path),
+        CheatSheetFormat::Pdf => write_pdf(&pages, path),
+    }
+}
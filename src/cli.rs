@@ -0,0 +1,721 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use qmk_via_api::keycodes::Keycode;
+use serde::Serialize;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::diagnostics::{CheckStatus, Diagnostics};
+use crate::keyboard::{Keyboard, KeyboardEvent};
+use crate::keycode_labels::{self, LabelOptions};
+use crate::qmk_keymap::ROW_BREAK_DISTANCE;
+use crate::settings::{
+    HostLayout, KeyboardSettings, LightingDisplay, ModifierLabels, OverlayMode, Settings,
+    WindowPosition,
+};
+use crate::transport::{self, DeviceId};
+
+/// Live on-screen overlay of the active QMK keyboard layers.
+///
+/// Without a command the overlay is shown, using the settings file and the given options.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub settings: SettingsArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show the overlay
+    Overlay(SettingsArgs),
+    /// List the connected VIA devices with the ID to pass to --device
+    ListDevices,
+    /// Print all layers of the keymap
+    DumpKeymap {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    /// Draw all layers to a cheat sheet
+    Render {
+        /// SVG, PNG or PDF file to write, further keyboards get their index appended
+        output: String,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+    /// Check the firmware integration of the keyboards
+    Diagnose(SettingsArgs),
+    /// Print layer changes and key presses as they happen, until interrupted
+    Watch {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        #[command(flatten)]
+        settings: SettingsArgs,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    /// JSON, or JSON Lines for streamed events
+    Json,
+}
+
+/// Options overriding the settings file. The keyboard options apply to the keyboard selected
+/// with `--keyboard`, which may be left out if only one is configured.
+#[derive(Args)]
+pub struct SettingsArgs {
+    /// Settings file to start from
+    #[arg(long, value_name = "PATH", default_value = crate::SETTINGS_FILE)]
+    pub settings: String,
+    /// Keyboard the keyboard options apply to, counted from 1. Required if several keyboards
+    /// are configured.
+    #[arg(long, value_name = "NUMBER")]
+    keyboard: Option<usize>,
+    /// Keyboard info JSON or VIA definition
+    #[arg(long, value_name = "PATH")]
+    info: Option<String>,
+    /// Layout of the keyboard info JSON
+    #[arg(long)]
+    layout: Option<String>,
+    /// Device as printed by list-devices, e.g. 4653:0001:ff60
+    #[arg(long, value_parser = parse_setting::<DeviceId>)]
+    device: Option<DeviceId>,
+    /// Simulated keyboard JSON used instead of the device
+    #[arg(long, value_name = "PATH")]
+    simulation: Option<String>,
+    /// Recorded session played back instead of connecting to the device
+    #[arg(long, value_name = "PATH")]
+    replay: Option<String>,
+    /// Factor the recorded session is played back faster with, e.g. 0.5 for half speed
+    #[arg(long, value_name = "FACTOR", value_parser = parse_replay_speed)]
+    replay_speed: Option<f64>,
+    /// QMK keymap.json shown offline instead of the keymap of the device
+    #[arg(long, value_name = "PATH")]
+    keymap: Option<String>,
+    /// How several keyboards are shown: combined or separate
+    #[arg(long, value_parser = parse_setting::<OverlayMode>)]
+    overlay_mode: Option<OverlayMode>,
+    /// Key unit size in pixels
+    #[arg(long)]
+    size: Option<i32>,
+    /// Alignment on the screen: top-left, top-right, bottom-left, bottom-right, top or bottom
+    #[arg(long, value_parser = parse_setting::<WindowPosition>)]
+    position: Option<WindowPosition>,
+    /// Display duration in milliseconds after returning to the base layer
+    #[arg(long, value_name = "MS")]
+    timeout: Option<u64>,
+    /// Distance from the screen edge in pixels
+    #[arg(long)]
+    margin: Option<u32>,
+    /// Keymap refresh interval in seconds, 0 disables it
+    #[arg(long, value_name = "SECONDS")]
+    keymap_refresh_interval: Option<u64>,
+    /// Lighting display: off, tint, indicator or key-borders
+    #[arg(long, value_parser = parse_setting::<LightingDisplay>)]
+    lighting_display: Option<LightingDisplay>,
+    /// Modifiers in key labels: glyphs or text
+    #[arg(long, value_parser = parse_setting::<ModifierLabels>)]
+    modifier_labels: Option<ModifierLabels>,
+    /// Keyboard layout of the host: us, uk, german, french, spanish, swedish, dvorak or colemak
+    #[arg(long, value_parser = parse_setting::<HostLayout>)]
+    host_layout: Option<HostLayout>,
+    /// Track the layer state on the host from key presses
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    layer_emulation: Option<bool>,
+    /// Tapping term of the layer emulation in milliseconds
+    #[arg(long, value_name = "MS")]
+    tapping_term: Option<u64>,
+    /// Lower, upper and adjust layer of the tri layer feature, e.g. 1,2,3. Empty disables it.
+    #[arg(long, value_name = "LAYERS")]
+    tri_layer: Option<String>,
+    /// Key press polling interval in milliseconds, 0 disables it
+    #[arg(long, value_name = "MS")]
+    matrix_poll_interval: Option<u64>,
+    /// Count key presses for the heatmap
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    record_statistics: Option<bool>,
+    /// Record the layer state and key press events to a JSON Lines file. Further keyboards get
+    /// their index appended to the file name.
+    #[arg(long, value_name = "PATH")]
+    record: Option<String>,
+    /// Write the resulting settings to the settings file
+    #[arg(long)]
+    save_settings: bool,
+}
+
+/// Parses a setting as written in the settings file, e.g. `Top Left`, or in the command line
+/// spelling `top-left`.
+fn parse_setting<T: FromStr>(value: &str) -> Result<T, String> {
+    if let Ok(parsed) = value.parse() {
+        return Ok(parsed);
+    }
+    let words: Vec<String> = value
+        .split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+                .unwrap_or_default()
+        })
+        .collect();
+    words
+        .join(" ")
+        .parse()
+        .map_err(|_| format!("unknown value '{value}'"))
+}
+
+fn parse_replay_speed(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|speed: &f64| *speed > 0.0 && speed.is_finite())
+        .ok_or(format!("'{value}' is not a positive number"))
+}
+
+impl SettingsArgs {
+    fn configures_keyboard(&self) -> bool {
+        self.info.is_some()
+            || self.device.is_some()
+            || self.simulation.is_some()
+            || self.replay.is_some()
+            || self.keymap.is_some()
+    }
+
+    fn has_keyboard_options(&self) -> bool {
+        self.configures_keyboard() || self.layout.is_some() || self.replay_speed.is_some()
+    }
+
+    /// Returns the index of the keyboard the keyboard options apply to.
+    fn selected_keyboard(&self, settings: &Settings) -> Result<usize, String> {
+        let count = settings.keyboards.len();
+        match self.keyboard {
+            Some(number) if (1..=count).contains(&number) => Ok(number - 1),
+            Some(number) => Err(format!(
+                "There is no keyboard {number}, {count} keyboards are configured"
+            )),
+            None if count > 1 => Err(format!(
+                "{count} keyboards are configured, select the one the keyboard options apply \
+                 to with --keyboard"
+            )),
+            None => Ok(0),
+        }
+    }
+
+    /// Loads the settings file and applies the options. Returns `None` if there is neither a
+    /// settings file nor a keyboard given on the command line.
+    pub fn load_settings(&self) -> Result<Option<Settings>, String> {
+        let loaded = Settings::load_from_file(&self.settings);
+        if loaded.is_none() && !self.configures_keyboard() {
+            return Ok(None);
+        }
+        let mut settings = loaded.unwrap_or_default();
+        self.apply(&mut settings)?;
+        settings.confirmed = true;
+        if self.save_settings {
+            settings
+                .save_to_file(&self.settings)
+                .map_err(|e| format!("Failed to save settings: {e}"))?;
+        }
+        Ok(Some(settings))
+    }
+
+    fn apply(&self, settings: &mut Settings) -> Result<(), String> {
+        if settings.keyboards.is_empty() {
+            settings.keyboards.push(KeyboardSettings::default());
+        }
+        if self.keyboard.is_some() || self.has_keyboard_options() {
+            let index = self.selected_keyboard(settings)?;
+            let keyboard = &mut settings.keyboards[index];
+            if let Some(info) = &self.info {
+                keyboard.keyboard_config_path = info.clone();
+            }
+            if let Some(layout) = &self.layout {
+                keyboard.layout_name = layout.clone();
+            }
+            if let Some(device) = &self.device {
+                keyboard.device = Some(device.clone());
+            }
+            if let Some(simulation) = &self.simulation {
+                keyboard.simulation_path = simulation.clone();
+            }
+            if let Some(replay) = &self.replay {
+                keyboard.replay_path = replay.clone();
+            }
+            if let Some(speed) = self.replay_speed {
+                keyboard.replay_speed = speed;
+            }
+            if let Some(keymap) = &self.keymap {
+                keyboard.keymap_path = keymap.clone();
+            }
+        }
+        if let Some(overlay_mode) = self.overlay_mode {
+            settings.overlay_mode = overlay_mode;
+        }
+        if let Some(size) = self.size {
+            settings.size = size;
+        }
+        if let Some(position) = self.position {
+            settings.position = position;
+        }
+        if let Some(timeout) = self.timeout {
+            settings.timeout = timeout;
+        }
+        if let Some(margin) = self.margin {
+            settings.margin = margin;
+        }
+        if let Some(interval) = self.keymap_refresh_interval {
+            settings.keymap_refresh_interval = interval;
+        }
+        if let Some(lighting_display) = self.lighting_display {
+            settings.lighting_display = lighting_display;
+        }
+        if let Some(modifier_labels) = self.modifier_labels {
+            settings.modifier_labels = modifier_labels;
+        }
+        if let Some(host_layout) = self.host_layout {
+            settings.host_layout = host_layout;
+        }
+        if let Some(layer_emulation) = self.layer_emulation {
+            settings.layer_emulation = layer_emulation;
+        }
+        if let Some(tapping_term) = self.tapping_term {
+            settings.tapping_term = tapping_term;
+        }
+        if let Some(tri_layer) = &self.tri_layer {
+            settings.tri_layer = if tri_layer.is_empty() {
+                None
+            } else {
+                Some(
+                    Settings::parse_tri_layer(tri_layer)
+                        .ok_or(format!("Invalid tri layer '{tri_layer}'"))?,
+                )
+            };
+        }
+        if let Some(interval) = self.matrix_poll_interval {
+            settings.matrix_poll_interval = interval;
+        }
+        if let Some(record_statistics) = self.record_statistics {
+            settings.record_statistics = record_statistics;
+        }
+        if let Some(record) = &self.record {
+            settings.record_path = record.clone();
+        }
+        Ok(())
+    }
+}
+
+/// Attaches to the console of the shell the application was started from. Windows does not
+/// give it one, as it is built as a GUI application, so the output of commands would be lost.
+#[cfg(target_os = "windows")]
+pub fn attach_console() {
+    use windows_sys::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    // Fails if started without a console, e.g. from the explorer, where output is not shown
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+pub fn attach_console() {}
+
+pub fn list_devices() -> Result<(), String> {
+    let devices = transport::list_devices();
+    if devices.is_empty() {
+        return Err("No VIA devices found".to_string());
+    }
+    for device in devices {
+        println!("{}\t{device}", device.id);
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct KeycodeDump {
+    keycode: String,
+    label: String,
+}
+
+impl KeycodeDump {
+    fn new(keycode: u16, label_options: LabelOptions) -> Self {
+        KeycodeDump {
+            keycode: keycode_labels::format_keycode(keycode),
+            label: keycode_labels::get_keycode_name(keycode, label_options),
+        }
+    }
+
+    /// Returns the label, or the `keymap.c` placeholder for keys without one.
+    fn text(&self, keycode: u16) -> String {
+        if !self.label.is_empty() {
+            self.label.replace('\n', " ")
+        } else if keycode == Keycode::KC_TRANSPARENT as u16 {
+            "_______".to_string()
+        } else {
+            "XXXXXXX".to_string()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct KeyDump {
+    row: u8,
+    col: u8,
+    #[serde(flatten)]
+    keycode: KeycodeDump,
+}
+
+#[derive(Serialize)]
+struct EncoderDump {
+    index: u8,
+    ccw: KeycodeDump,
+    cw: KeycodeDump,
+}
+
+#[derive(Serialize)]
+struct LayerDump {
+    keys: Vec<KeyDump>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    encoders: Vec<EncoderDump>,
+}
+
+#[derive(Serialize)]
+struct KeyboardDump {
+    layout: String,
+    layers: Vec<LayerDump>,
+}
+
+fn dump_keyboard(keyboard: &Keyboard, label_options: LabelOptions) -> KeyboardDump {
+    let layout = &keyboard.layout;
+    let layers = (0..keyboard.get_num_layers())
+        .map(|layer| LayerDump {
+            keys: layout
+                .keys
+                .iter()
+                .map(|key| KeyDump {
+                    row: key.row,
+                    col: key.col,
+                    keycode: KeycodeDump::new(
+                        keyboard.get_keycode(layer, key.row as usize, key.col as usize),
+                        label_options,
+                    ),
+                })
+                .collect(),
+            encoders: layout
+                .encoders
+                .iter()
+                .map(|encoder| {
+                    let index = encoder.index as usize;
+                    EncoderDump {
+                        index: encoder.index,
+                        ccw: KeycodeDump::new(
+                            keyboard.get_encoder_keycode(layer, index, false),
+                            label_options,
+                        ),
+                        cw: KeycodeDump::new(
+                            keyboard.get_encoder_keycode(layer, index, true),
+                            label_options,
+                        ),
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+    KeyboardDump {
+        layout: layout.name.clone(),
+        layers,
+    }
+}
+
+/// Writes a layer as text with a line per row of keys, padding the labels to the same width.
+fn write_layer_text(
+    text: &mut String,
+    keyboard: &Keyboard,
+    layer: usize,
+    label_options: LabelOptions,
+) {
+    let labels: Vec<String> = keyboard
+        .layout
+        .keys
+        .iter()
+        .map(|key| {
+            let keycode = keyboard.get_keycode(layer, key.row as usize, key.col as usize);
+            KeycodeDump::new(keycode, label_options).text(keycode)
+        })
+        .collect();
+    let width = labels
+        .iter()
+        .map(|label| label.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    let mut line_y = None;
+    for (key, label) in keyboard.layout.keys.iter().zip(&labels) {
+        if line_y.is_none_or(|line_y| key.y - line_y >= ROW_BREAK_DISTANCE) {
+            if line_y.is_some() {
+                text.push('\n');
+            }
+            line_y = Some(key.y);
+        }
+        text.push_str("  ");
+        let _ = write!(text, "{label:width$}");
+    }
+    text.push('\n');
+
+    for encoder in &keyboard.layout.encoders {
+        let index = encoder.index as usize;
+        let [ccw, cw] = [false, true].map(|clockwise| {
+            let keycode = keyboard.get_encoder_keycode(layer, index, clockwise);
+            KeycodeDump::new(keycode, label_options).text(keycode)
+        });
+        let _ = writeln!(text, "  Encoder {index}: {ccw} / {cw}");
+    }
+}
+
+/// Prints all layers of the keyboards, as text laid out like the keyboard or as JSON listing
+/// the keycodes and labels by matrix position.
+pub fn dump_keymap(
+    keyboards: &[Keyboard],
+    format: OutputFormat,
+    label_options: LabelOptions,
+) -> Result<(), String> {
+    if format == OutputFormat::Json {
+        let dump: Vec<KeyboardDump> = keyboards
+            .iter()
+            .map(|keyboard| dump_keyboard(keyboard, label_options))
+            .collect();
+        let json = serde_json::to_string_pretty(&dump)
+            .map_err(|e| format!("Failed to serialize keymap: {e}"))?;
+        println!("{json}");
+        return Ok(());
+    }
+
+    let mut text = String::new();
+    for (index, keyboard) in keyboards.iter().enumerate() {
+        if keyboards.len() > 1 {
+            let _ = writeln!(text, "Keyboard {}: {}\n", index + 1, keyboard.layout.name);
+        }
+        for layer in 0..keyboard.get_num_layers() {
+            let _ = writeln!(text, "Layer {layer}");
+            write_layer_text(&mut text, keyboard, layer, label_options);
+            text.push('\n');
+        }
+    }
+    print!("{text}");
+    Ok(())
+}
+
+fn format_layers(state: u32) -> String {
+    let layers: Vec<String> = (0..32)
+        .filter(|layer| state & (1 << layer) != 0)
+        .map(|layer| layer.to_string())
+        .collect();
+    if layers.is_empty() {
+        "-".to_string()
+    } else {
+        layers.join(",")
+    }
+}
+
+/// Runs the diagnostics for the keyboards one after another, printing each check once it is
+/// done.
+pub fn diagnose(settings: &Settings) -> Result<(), String> {
+    let mut failed = false;
+    for (index, keyboard) in settings.keyboards.iter().enumerate() {
+        if settings.keyboards.len() > 1 {
+            println!("Keyboard {}", index + 1);
+        }
+        let diagnostics = Diagnostics::start(keyboard.clone());
+        let mut printed = 0;
+        let mut prompted = false;
+        loop {
+            let report = diagnostics.report();
+            for check in report.checks[printed..]
+                .iter()
+                .take_while(|check| check.status != CheckStatus::Running)
+            {
+                let status = match check.status {
+                    CheckStatus::Running | CheckStatus::Passed => "[ OK ]",
+                    CheckStatus::Warning => "[WARN]",
+                    CheckStatus::Failed => "[FAIL]",
+                };
+                println!("{status} {}: {}", check.name, check.details);
+                printed += 1;
+            }
+            if let (false, Some(prompt), Some(listen_until)) =
+                (prompted, &report.prompt, report.listen_until)
+            {
+                let seconds = listen_until
+                    .saturating_duration_since(std::time::Instant::now())
+                    .as_secs()
+                    + 1;
+                println!("{prompt} within {seconds} s...");
+                prompted = true;
+            }
+            if report.finished && printed == report.checks.len() {
+                failed |= report.has_failures();
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    if failed {
+        return Err("The diagnostics found problems".to_string());
+    }
+    Ok(())
+}
+
+fn event_text(event: KeyboardEvent, label_options: LabelOptions) -> String {
+    match event {
+        KeyboardEvent::Connected => "connected".to_string(),
+        KeyboardEvent::Disconnected => "disconnected".to_string(),
+        KeyboardEvent::LayerState {
+            default_layer_state,
+            layer_state,
+        } => format!(
+            "layer default={} active={}",
+            format_layers(default_layer_state),
+            format_layers(layer_state)
+        ),
+        KeyboardEvent::Key {
+            row,
+            col,
+            pressed,
+            layer,
+            keycode,
+        } => {
+            let dump = KeycodeDump::new(keycode, label_options);
+            format!(
+                "{} row={row} col={col} layer={layer} {} {}",
+                if pressed { "press" } else { "release" },
+                dump.keycode,
+                dump.text(keycode)
+            )
+        }
+    }
+}
+
+fn event_json(
+    index: usize,
+    event: KeyboardEvent,
+    label_options: LabelOptions,
+) -> serde_json::Value {
+    match event {
+        KeyboardEvent::Connected => serde_json::json!({ "keyboard": index, "event": "connected" }),
+        KeyboardEvent::Disconnected => {
+            serde_json::json!({ "keyboard": index, "event": "disconnected" })
+        }
+        KeyboardEvent::LayerState {
+            default_layer_state,
+            layer_state,
+        } => serde_json::json!({
+            "keyboard": index,
+            "event": "layer",
+            "default_layer_state": default_layer_state,
+            "layer_state": layer_state,
+        }),
+        KeyboardEvent::Key {
+            row,
+            col,
+            pressed,
+            layer,
+            keycode,
+        } => {
+            let dump = KeycodeDump::new(keycode, label_options);
+            serde_json::json!({
+                "keyboard": index,
+                "event": if pressed { "press" } else { "release" },
+                "row": row,
+                "col": col,
+                "layer": layer,
+                "keycode": dump.keycode,
+                "label": dump.label,
+            })
+        }
+    }
+}
+
+/// Prints the events of the keyboards as they arrive, a line per event. With several keyboards
+/// the lines are prefixed by the keyboard index.
+pub fn watch(keyboards: &[Keyboard], format: OutputFormat, label_options: LabelOptions) {
+    let (sender, events) = mpsc::channel();
+    for (index, keyboard) in keyboards.iter().enumerate() {
+        let receiver = keyboard.subscribe();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for event in receiver {
+                if sender.send((index, event)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    for (index, event) in events {
+        match format {
+            OutputFormat::Json => println!("{}", event_json(index, event, label_options)),
+            OutputFormat::Text if keyboards.len() > 1 => {
+                println!("[{index}] {}", event_text(event, label_options))
+            }
+            OutputFormat::Text => println!("{}", event_text(event, label_options)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_args(args: &[&str]) -> SettingsArgs {
+        let cli = Cli::try_parse_from(["qmk-layout-helper"].iter().chain(args)).unwrap();
+        cli.settings
+    }
+
+    fn two_keyboards() -> Settings {
+        let mut settings = Settings::default();
+        settings.keyboards.push(KeyboardSettings::default());
+        settings
+    }
+
+    #[test]
+    fn applies_keyboard_options_to_the_only_keyboard() {
+        let mut settings = Settings::default();
+        settings_args(&["--info", "board.json"])
+            .apply(&mut settings)
+            .unwrap();
+        assert_eq!(settings.keyboards[0].keyboard_config_path, "board.json");
+    }
+
+    #[test]
+    fn applies_keyboard_options_to_the_selected_keyboard() {
+        let mut settings = two_keyboards();
+        settings_args(&["--keyboard", "2", "--layout", "LAYOUT_split"])
+            .apply(&mut settings)
+            .unwrap();
+        assert_eq!(settings.keyboards[0].layout_name, "LAYOUT");
+        assert_eq!(settings.keyboards[1].layout_name, "LAYOUT_split");
+    }
+
+    #[test]
+    fn rejects_keyboard_options_without_selection_of_several_keyboards() {
+        let mut settings = two_keyboards();
+        assert!(settings_args(&["--info", "board.json"])
+            .apply(&mut settings)
+            .is_err());
+        assert!(settings_args(&["--keyboard", "3", "--info", "board.json"])
+            .apply(&mut settings)
+            .is_err());
+        // Options of all keyboards need no selection
+        settings_args(&["--size", "40"])
+            .apply(&mut settings)
+            .unwrap();
+        assert_eq!(settings.size, 40);
+    }
+}
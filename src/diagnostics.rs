@@ -0,0 +1,538 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::keyboard_info::{KeyboardInfo, KeyboardLayout};
+use crate::protocol::{self, capabilities, FirmwareInfo, Message};
+use crate::settings::KeyboardSettings;
+use crate::transport::{self, Transport};
+use crate::vial;
+
+/// Time the user gets to press keys and change layers.
+const LISTEN_DURATION: Duration = Duration::from_secs(30);
+const FRAME_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Number of offending positions listed in the details of a check.
+const MAX_LISTED_POSITIONS: usize = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheckStatus {
+    Running,
+    Passed,
+    Warning,
+    Failed,
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub details: String,
+}
+
+/// Progress of the diagnostics, updated by the thread running them.
+#[derive(Clone, Default)]
+pub struct DiagnosticsReport {
+    pub checks: Vec<Check>,
+    /// Asks the user to use the keyboard while the frames sent by the firmware are checked
+    pub prompt: Option<String>,
+    /// End of the time the frames are recorded
+    pub listen_until: Option<Instant>,
+    pub finished: bool,
+}
+
+impl DiagnosticsReport {
+    pub fn has_failures(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| check.status == CheckStatus::Failed)
+    }
+}
+
+/// Checks of the firmware integration of a keyboard, run in the background. Dropping it stops
+/// the checks.
+pub struct Diagnostics {
+    report: Arc<Mutex<DiagnosticsReport>>,
+    cancelled: Arc<Mutex<bool>>,
+}
+
+impl Diagnostics {
+    pub fn start(settings: KeyboardSettings) -> Self {
+        let report = Arc::new(Mutex::new(DiagnosticsReport::default()));
+        let cancelled = Arc::new(Mutex::new(false));
+        let runner = Runner {
+            report: report.clone(),
+            cancelled: cancelled.clone(),
+        };
+        thread::spawn(move || {
+            runner.run(&settings);
+            let mut report = runner.report.lock().unwrap();
+            report.prompt = None;
+            report.listen_until = None;
+            report.finished = true;
+        });
+        Diagnostics { report, cancelled }
+    }
+
+    pub fn report(&self) -> DiagnosticsReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+impl Drop for Diagnostics {
+    fn drop(&mut self) {
+        *self.cancelled.lock().unwrap() = true;
+    }
+}
+
+/// Messages of one kind received while listening.
+#[derive(Default)]
+struct FrameStats {
+    received: usize,
+    malformed: usize,
+    first_error: Option<String>,
+}
+
+impl FrameStats {
+    fn summary(&self) -> String {
+        format!("{} received", self.received)
+    }
+}
+
+fn format_positions(positions: &[(u8, u8)]) -> String {
+    let mut listed: Vec<String> = positions
+        .iter()
+        .take(MAX_LISTED_POSITIONS)
+        .map(|(row, col)| format!("row {row}, column {col}"))
+        .collect();
+    if positions.len() > MAX_LISTED_POSITIONS {
+        listed.push(format!("{} more", positions.len() - MAX_LISTED_POSITIONS));
+    }
+    listed.join("; ")
+}
+
+struct Runner {
+    report: Arc<Mutex<DiagnosticsReport>>,
+    cancelled: Arc<Mutex<bool>>,
+}
+
+impl Runner {
+    fn begin(&self, name: &'static str) -> usize {
+        let mut report = self.report.lock().unwrap();
+        report.checks.push(Check {
+            name,
+            status: CheckStatus::Running,
+            details: String::new(),
+        });
+        report.checks.len() - 1
+    }
+
+    fn update(&self, index: usize, status: CheckStatus, details: impl Into<String>) {
+        let mut report = self.report.lock().unwrap();
+        report.checks[index].status = status;
+        report.checks[index].details = details.into();
+    }
+
+    fn check(&self, name: &'static str, status: CheckStatus, details: impl Into<String>) {
+        let index = self.begin(name);
+        self.update(index, status, details);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        *self.cancelled.lock().unwrap()
+    }
+
+    fn run(&self, settings: &KeyboardSettings) {
+        let index = self.begin("Keyboard definition");
+        let keyboard_info = match KeyboardInfo::load(settings) {
+            Ok(keyboard_info) => keyboard_info,
+            Err(e) => return self.update(index, CheckStatus::Failed, e),
+        };
+        let layout = match keyboard_info.get_layout(&settings.layout_name) {
+            Ok(layout) => layout,
+            Err(e) => return self.update(index, CheckStatus::Failed, e),
+        };
+        self.update(
+            index,
+            CheckStatus::Passed,
+            format!(
+                "{} with a {}×{} matrix",
+                layout.name, keyboard_info.rows, keyboard_info.cols
+            ),
+        );
+        self.check_layout_positions(&keyboard_info, &layout);
+
+        let index = self.begin("Connection");
+        // The protocol version is checked below to report it instead of failing to connect
+        let device_id = transport::device_id(settings, &keyboard_info);
+        let transport = match transport::open(settings, &device_id, false) {
+            Ok(transport) => transport,
+            Err(e) => return self.update(index, CheckStatus::Failed, e),
+        };
+        self.update(index, CheckStatus::Passed, "Connected to the keyboard");
+
+        let index = self.begin("VIA protocol");
+        match transport.get_protocol_version() {
+            Ok(version) => {
+                let vial_version = transport
+                    .is_vial()
+                    .then(|| vial::get_protocol_version(transport.as_ref()).ok())
+                    .flatten();
+                let details = match vial_version {
+                    Some(vial_version) => format!("Version {version}, Vial version {vial_version}"),
+                    None => format!("Version {version}"),
+                };
+                if let Err(e) = transport::check_protocol_version(version, vial_version) {
+                    return self.update(
+                        index,
+                        CheckStatus::Failed,
+                        format!("{e} Update the firmware to a recent QMK version."),
+                    );
+                }
+                self.update(index, CheckStatus::Passed, details)
+            }
+            Err(e) => {
+                return self.update(
+                    index,
+                    CheckStatus::Failed,
+                    format!("{e}. Check that VIA_ENABLE is set in rules.mk."),
+                )
+            }
+        }
+
+        let firmware_info = self.check_handshake(transport.as_ref());
+        self.check_matrix_size(transport.as_ref(), &keyboard_info, &layout);
+        self.check_frames(transport.as_ref(), &firmware_info, &keyboard_info, &layout);
+    }
+
+    fn check_layout_positions(&self, keyboard_info: &KeyboardInfo, layout: &KeyboardLayout) {
+        let outside: Vec<(u8, u8)> = layout
+            .matrix_order
+            .iter()
+            .filter(|(row, col)| {
+                *row as usize >= keyboard_info.rows || *col as usize >= keyboard_info.cols
+            })
+            .copied()
+            .collect();
+        if outside.is_empty() {
+            self.check(
+                "Layout keys inside the matrix",
+                CheckStatus::Passed,
+                format!("All {} keys", layout.matrix_order.len()),
+            );
+        } else {
+            self.check(
+                "Layout keys inside the matrix",
+                CheckStatus::Failed,
+                format!(
+                    "Outside the {}×{} matrix: {}",
+                    keyboard_info.rows,
+                    keyboard_info.cols,
+                    format_positions(&outside)
+                ),
+            );
+        }
+    }
+
+    fn check_handshake(&self, transport: &dyn Transport) -> FirmwareInfo {
+        let firmware_info = protocol::handshake(transport);
+        if firmware_info.version == 0 {
+            self.check(
+                "Handshake",
+                CheckStatus::Passed,
+                "Not answered, layer state and key event messages are expected",
+            );
+            return firmware_info;
+        }
+
+        let announced: Vec<&str> = [
+            (capabilities::LAYER_STATE, "layer state"),
+            (capabilities::KEY_EVENTS, "key events"),
+            (capabilities::STATUS, "status"),
+        ]
+        .into_iter()
+        .filter(|(capability, _)| firmware_info.supports(*capability))
+        .map(|(_, name)| name)
+        .collect();
+        let status = if firmware_info.supports(capabilities::LAYER_STATE) {
+            CheckStatus::Passed
+        } else {
+            CheckStatus::Warning
+        };
+        self.check(
+            "Handshake",
+            status,
+            format!(
+                "Version {}, announces {}",
+                firmware_info.version,
+                if announced.is_empty() {
+                    "no messages".to_string()
+                } else {
+                    announced.join(", ")
+                }
+            ),
+        );
+        firmware_info
+    }
+
+    /// The keymap is read as a buffer laid out by the matrix size of the keyboard info, while
+    /// single keycodes are looked up by the firmware using its own matrix size. If the sizes
+    /// differ, the keycodes end up at different positions. Keycodes beyond the matrix of the
+    /// keyboard info reveal a larger firmware matrix.
+    fn check_matrix_size(
+        &self,
+        transport: &dyn Transport,
+        keyboard_info: &KeyboardInfo,
+        layout: &KeyboardLayout,
+    ) {
+        let (rows, cols) = (keyboard_info.rows, keyboard_info.cols);
+        let index = self.begin("Matrix size");
+        let result = (|| -> Result<(usize, Vec<(u8, u8)>), String> {
+            let buffer = transport.read_raw_matrix(0, rows, cols)?;
+            let mut mismatches = 0;
+            for (row, col) in &layout.matrix_order {
+                let (row, col) = (*row as usize, *col as usize);
+                if row >= rows || col >= cols {
+                    continue;
+                }
+                if transport.read_keycode(0, row as u8, col as u8)? != buffer[row * cols + col] {
+                    mismatches += 1;
+                }
+            }
+            let mut beyond = Vec::new();
+            for col in 0..cols {
+                if transport.read_keycode(0, rows as u8, col as u8)? != 0 {
+                    beyond.push((rows as u8, col as u8));
+                }
+            }
+            for row in 0..rows {
+                if transport.read_keycode(0, row as u8, cols as u8)? != 0 {
+                    beyond.push((row as u8, cols as u8));
+                }
+            }
+            Ok((mismatches, beyond))
+        })();
+
+        match result {
+            Ok((0, beyond)) if beyond.is_empty() => self.update(
+                index,
+                CheckStatus::Passed,
+                format!("The keymap of the firmware matches the {rows}×{cols} matrix"),
+            ),
+            Ok((0, beyond)) => self.update(
+                index,
+                CheckStatus::Failed,
+                format!(
+                    "The firmware has keys outside the {rows}×{cols} matrix of the keyboard info: {}",
+                    format_positions(&beyond)
+                ),
+            ),
+            Ok((mismatches, _)) => self.update(
+                index,
+                CheckStatus::Failed,
+                format!(
+                    "{mismatches} of {} keys are at a different position in the keymap. MATRIX_ROWS and MATRIX_COLS of the firmware don't match the {rows}×{cols} matrix of the keyboard info.",
+                    layout.matrix_order.len()
+                ),
+            ),
+            Err(e) => self.update(index, CheckStatus::Warning, format!("Not checked: {e}")),
+        }
+    }
+
+    /// Records the frames sent by the firmware while the user presses keys and changes layers.
+    /// Frames are decoded regardless of the handshake, to tell apart missing and unannounced
+    /// messages.
+    fn check_frames(
+        &self,
+        transport: &dyn Transport,
+        firmware_info: &FirmwareInfo,
+        keyboard_info: &KeyboardInfo,
+        layout: &KeyboardLayout,
+    ) {
+        let any_message = FirmwareInfo {
+            version: protocol::HOST_PROTOCOL_VERSION,
+            capabilities: u16::MAX,
+        };
+        let announced =
+            |capability| firmware_info.version == 0 || firmware_info.supports(capability);
+        let expects_key_events = announced(capabilities::KEY_EVENTS);
+        let layout_positions: HashSet<(u8, u8)> = layout.matrix_order.iter().copied().collect();
+
+        let layer_index = self.begin("Layer state messages (0xFF)");
+        let key_index = self.begin("Key event messages (0xF1)");
+        let mut layer_stats = FrameStats::default();
+        let mut key_stats = FrameStats::default();
+        let mut highest_layer = 0;
+        let mut empty_default_layer_state = false;
+        let (mut presses, mut releases) = (0, 0);
+        let mut outside_matrix = Vec::new();
+        let mut outside_layout = Vec::new();
+
+        let deadline = Instant::now() + LISTEN_DURATION;
+        {
+            let mut report = self.report.lock().unwrap();
+            report.prompt =
+                Some("Press a few keys and hold or switch to another layer".to_string());
+            report.listen_until = Some(deadline);
+        }
+        while !self.is_cancelled() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let layers_done = highest_layer > 0;
+            let keys_done = !expects_key_events || (presses > 0 && releases > 0);
+            if remaining.is_zero() || (layers_done && keys_done) {
+                break;
+            }
+            self.update(layer_index, CheckStatus::Running, layer_stats.summary());
+            self.update(key_index, CheckStatus::Running, key_stats.summary());
+
+            let frame = match transport.read_frame(FRAME_POLL_INTERVAL) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.update(layer_index, CheckStatus::Failed, e.clone());
+                    self.update(key_index, CheckStatus::Failed, e);
+                    return;
+                }
+            };
+            let stats = match frame.first() {
+                Some(&protocol::LAYER_STATE_MESSAGE_ID) => &mut layer_stats,
+                Some(&protocol::KEY_EVENT_MESSAGE_ID) => &mut key_stats,
+                _ => continue,
+            };
+            stats.received += 1;
+            match protocol::decode(&frame, &any_message) {
+                Ok(Message::LayerState {
+                    default_layer_state,
+                    layer_state,
+                }) => {
+                    highest_layer = highest_layer.max(layer_state.checked_ilog2().unwrap_or(0));
+                    empty_default_layer_state |= default_layer_state == 0;
+                }
+                Ok(Message::KeyEvent { row, col, pressed }) => {
+                    if pressed {
+                        presses += 1;
+                    } else {
+                        releases += 1;
+                    }
+                    let position = (row, col);
+                    if row as usize >= keyboard_info.rows || col as usize >= keyboard_info.cols {
+                        if !outside_matrix.contains(&position) {
+                            outside_matrix.push(position);
+                        }
+                    } else if !layout_positions.contains(&position)
+                        && !outside_layout.contains(&position)
+                    {
+                        outside_layout.push(position);
+                    }
+                }
+                Ok(Message::Status(_)) => {}
+                Err(e) => {
+                    stats.malformed += 1;
+                    stats.first_error.get_or_insert_with(|| e.to_string());
+                }
+            }
+        }
+
+        let (status, details) = if layer_stats.malformed > 0 {
+            (
+                CheckStatus::Failed,
+                format!(
+                    "{} of {} malformed ({}). Compare layer_state_set_user with the README.",
+                    layer_stats.malformed,
+                    layer_stats.received,
+                    layer_stats.first_error.unwrap_or_default()
+                ),
+            )
+        } else if layer_stats.received == 0 {
+            (
+                CheckStatus::Failed,
+                "None received. Check that layer_state_set_user from the README is in the keymap and RAW_ENABLE is set, or use layer emulation.".to_string(),
+            )
+        } else if !announced(capabilities::LAYER_STATE) {
+            (
+                CheckStatus::Warning,
+                format!(
+                    "{}, but not announced in the handshake, so they are ignored",
+                    layer_stats.summary()
+                ),
+            )
+        } else if highest_layer == 0 {
+            (
+                CheckStatus::Warning,
+                format!("{}, but only for the base layer. The overlay is only shown while another layer is active.", layer_stats.summary()),
+            )
+        } else if empty_default_layer_state {
+            (
+                CheckStatus::Warning,
+                format!("{}, but the default layer state is empty. Check that default_layer_state is copied.", layer_stats.summary()),
+            )
+        } else {
+            (
+                CheckStatus::Passed,
+                format!("{}, up to layer {highest_layer}", layer_stats.summary()),
+            )
+        };
+        self.update(layer_index, status, details);
+
+        let (status, details) = if key_stats.malformed > 0 {
+            (
+                CheckStatus::Failed,
+                format!(
+                    "{} of {} malformed ({}). Compare process_record_user with the README.",
+                    key_stats.malformed,
+                    key_stats.received,
+                    key_stats.first_error.unwrap_or_default()
+                ),
+            )
+        } else if key_stats.received == 0 && !expects_key_events {
+            (
+                CheckStatus::Passed,
+                "Not announced by the firmware, pressed keys are only shown with key press polling"
+                    .to_string(),
+            )
+        } else if key_stats.received == 0 {
+            (
+                CheckStatus::Warning,
+                "None received. Add process_record_user from the README to highlight pressed keys, or use key press polling.".to_string(),
+            )
+        } else if !outside_matrix.is_empty() {
+            (
+                CheckStatus::Failed,
+                format!(
+                    "Keys outside the {}×{} matrix: {}",
+                    keyboard_info.rows,
+                    keyboard_info.cols,
+                    format_positions(&outside_matrix)
+                ),
+            )
+        } else if !expects_key_events {
+            (
+                CheckStatus::Warning,
+                format!(
+                    "{}, but not announced in the handshake, so they are ignored",
+                    key_stats.summary()
+                ),
+            )
+        } else if !outside_layout.is_empty() {
+            (
+                CheckStatus::Warning,
+                format!(
+                    "Keys missing from layout {}: {}. Check the selected layout.",
+                    layout.name,
+                    format_positions(&outside_layout)
+                ),
+            )
+        } else if presses == 0 || releases == 0 {
+            (
+                CheckStatus::Warning,
+                format!("{presses} presses and {releases} releases received, the pressed state isn't sent correctly"),
+            )
+        } else {
+            (
+                CheckStatus::Passed,
+                format!("{presses} presses and {releases} releases received"),
+            )
+        };
+        self.update(key_index, status, details);
+    }
+}
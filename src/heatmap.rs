@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::keycode_labels;
+
+/// File the press counts of all keyboards are kept in across sessions.
+const STATISTICS_FILE: &str = "statistics.json";
+
+/// Interval in which changed press counts are written to disk.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Returns the statistics file placed next to the given settings file.
+pub fn statistics_path(settings_path: &str) -> String {
+    Path::new(settings_path)
+        .with_file_name(STATISTICS_FILE)
+        .display()
+        .to_string()
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct PressCount {
+    pub layer: u8,
+    pub row: u8,
+    pub col: u8,
+    pub count: u64,
+}
+
+/// Number of presses per matrix position and the layer the key was effective on when pressed.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<PressCount>", into = "Vec<PressCount>")]
+pub struct PressStatistics {
+    counts: HashMap<(u8, u8, u8), u64>,
+    total: u64,
+}
+
+impl From<Vec<PressCount>> for PressStatistics {
+    fn from(entries: Vec<PressCount>) -> Self {
+        let mut statistics = PressStatistics::default();
+        for entry in entries {
+            *statistics
+                .counts
+                .entry((entry.layer, entry.row, entry.col))
+                .or_default() += entry.count;
+            statistics.total += entry.count;
+        }
+        statistics
+    }
+}
+
+impl From<PressStatistics> for Vec<PressCount> {
+    fn from(statistics: PressStatistics) -> Self {
+        statistics.entries()
+    }
+}
+
+impl PressStatistics {
+    pub fn record(&mut self, layer: u8, row: u8, col: u8) {
+        *self.counts.entry((layer, row, col)).or_default() += 1;
+        self.total += 1;
+    }
+
+    pub fn get(&self, layer: u8, row: u8, col: u8) -> u64 {
+        self.counts.get(&(layer, row, col)).copied().unwrap_or(0)
+    }
+
+    /// Returns the highest count of a key on the layer.
+    pub fn max_on_layer(&self, layer: u8) -> u64 {
+        self.counts
+            .iter()
+            .filter(|((key_layer, _, _), _)| *key_layer == layer)
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the counts ordered by layer, row and column.
+    pub fn entries(&self) -> Vec<PressCount> {
+        let mut entries: Vec<PressCount> = self
+            .counts
+            .iter()
+            .map(|(&(layer, row, col), &count)| PressCount {
+                layer,
+                row,
+                col,
+                count,
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry.layer, entry.row, entry.col));
+        entries
+    }
+
+    /// Writes the counts together with the keycodes of the keys as CSV if the path ends in
+    /// `.csv`, as JSON otherwise.
+    pub fn export(
+        &self,
+        path: &str,
+        get_keycode: impl Fn(u8, u8, u8) -> u16,
+    ) -> Result<(), String> {
+        let keycode_name = |entry: &PressCount| {
+            keycode_labels::format_keycode(get_keycode(entry.layer, entry.row, entry.col))
+        };
+        let is_csv = Path::new(path).extension().is_some_and(|ext| ext == "csv");
+        let contents = if is_csv {
+            let mut csv = String::from("layer,row,col,keycode,count\n");
+            for entry in self.entries() {
+                let _ = writeln!(
+                    csv,
+                    "{},{},{},\"{}\",{}",
+                    entry.layer,
+                    entry.row,
+                    entry.col,
+                    keycode_name(&entry).replace('"', "\"\""),
+                    entry.count
+                );
+            }
+            csv
+        } else {
+            let entries: Vec<serde_json::Value> = self
+                .entries()
+                .iter()
+                .map(|entry| {
+                    serde_json::json!({
+                        "layer": entry.layer,
+                        "row": entry.row,
+                        "col": entry.col,
+                        "keycode": keycode_name(entry),
+                        "count": entry.count,
+                    })
+                })
+                .collect();
+            serde_json::to_string_pretty(&entries)
+                .map_err(|e| format!("Failed to serialize statistics: {e}"))?
+        };
+        std::fs::write(path, contents)
+            .map_err(|e| format!("Failed to write statistics '{path}': {e}"))
+    }
+}
+
+/// Press statistics of all keyboards ever recorded, keyed by the keyboard they belong to.
+pub struct StatisticsStore {
+    path: String,
+    keyboards: BTreeMap<String, Arc<Mutex<PressStatistics>>>,
+    /// Total number of presses written last, locked while writing
+    saved_total: Mutex<u64>,
+}
+
+impl StatisticsStore {
+    /// Loads the statistics file, starting empty if there is none yet.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let keyboards: BTreeMap<String, PressStatistics> = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| format!("Failed to parse statistics '{path}': {e}"))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Failed to read statistics '{path}': {e}")),
+        };
+        let mut store = StatisticsStore {
+            path: path.to_string(),
+            keyboards: keyboards
+                .into_iter()
+                .map(|(id, statistics)| (id, Arc::new(Mutex::new(statistics))))
+                .collect(),
+            saved_total: Mutex::new(0),
+        };
+        *store.saved_total.get_mut().unwrap() = store.total();
+        Ok(store)
+    }
+
+    /// Returns the statistics of a keyboard, shared with the store for saving.
+    pub fn get(&mut self, id: &str) -> Arc<Mutex<PressStatistics>> {
+        self.keyboards.entry(id.to_string()).or_default().clone()
+    }
+
+    fn total(&self) -> u64 {
+        self.keyboards
+            .values()
+            .map(|statistics| statistics.lock().unwrap().total)
+            .sum()
+    }
+
+    /// Writes the statistics to disk if they changed since they were written last.
+    pub fn save(&self) -> Result<(), String> {
+        let mut saved_total = self.saved_total.lock().unwrap();
+        let total = self.total();
+        if total == *saved_total {
+            return Ok(());
+        }
+        let keyboards: BTreeMap<&String, PressStatistics> = self
+            .keyboards
+            .iter()
+            .map(|(id, statistics)| (id, statistics.lock().unwrap().clone()))
+            .collect();
+        let contents = serde_json::to_string(&keyboards)
+            .map_err(|e| format!("Failed to serialize statistics: {e}"))?;
+        std::fs::write(&self.path, contents)
+            .map_err(|e| format!("Failed to write statistics '{}': {e}", self.path))?;
+        *saved_total = total;
+        Ok(())
+    }
+
+    /// Writes the statistics to disk in the background whenever they changed. The last changes
+    /// have to be saved with [`Self::save`] before exiting.
+    pub fn save_periodically(self: Arc<Self>) {
+        thread::spawn(move || loop {
+            thread::sleep(SAVE_INTERVAL);
+            if let Err(e) = self.save() {
+                eprintln!("{e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_statistics_next_to_the_settings() {
+        assert_eq!(statistics_path("settings.ini"), STATISTICS_FILE);
+        assert_eq!(
+            statistics_path("config/work.ini"),
+            Path::new("config")
+                .join(STATISTICS_FILE)
+                .display()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn saves_the_presses_since_the_last_save() {
+        let path = std::env::temp_dir().join(format!("statistics-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut store = StatisticsStore::load(path).unwrap();
+        let statistics = store.get("keyboard");
+        // Nothing was pressed, so there is nothing to write
+        store.save().unwrap();
+        assert!(!Path::new(path).exists());
+
+        statistics.lock().unwrap().record(1, 2, 3);
+        store.save().unwrap();
+        let mut reloaded = StatisticsStore::load(path).unwrap();
+        assert_eq!(reloaded.get("keyboard").lock().unwrap().get(1, 2, 3), 1);
+
+        statistics.lock().unwrap().record(1, 2, 3);
+        store.save().unwrap();
+        let mut reloaded = StatisticsStore::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(reloaded.get("keyboard").lock().unwrap().get(1, 2, 3), 2);
+    }
+}
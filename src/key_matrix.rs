@@ -1,5 +1,9 @@
+use qmk_via_api::keycodes::Keycode;
+
 pub struct KeyMatrix {
     pub keycodes: Vec<Vec<Vec<u16>>>,
+    /// Counter-clockwise and clockwise keycodes indexed by `[layer][encoder]`.
+    pub encoders: Vec<Vec<[u16; 2]>>,
     pub pressed: Vec<Vec<bool>>,
 }
 
@@ -7,10 +11,42 @@ impl KeyMatrix {
     pub fn new(keycodes: Vec<Vec<Vec<u16>>>, rows: usize, cols: usize) -> Self {
         KeyMatrix {
             keycodes,
+            encoders: Vec::new(),
             pressed: vec![vec![false; cols]; rows],
         }
     }
 
+    pub fn update_encoders(&mut self, encoders: Vec<Vec<[u16; 2]>>) {
+        self.encoders = encoders;
+    }
+
+    /// Replaces the cached keycodes with the given ones, touching only keys that changed.
+    /// Returns the number of changed keys.
+    pub fn update_keycodes(&mut self, keycodes: Vec<Vec<Vec<u16>>>) -> usize {
+        let same_shape = self.keycodes.len() == keycodes.len()
+            && self.keycodes.iter().zip(&keycodes).all(|(a, b)| {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.len() == b.len())
+            });
+        if !same_shape {
+            let changed = keycodes.iter().flatten().map(Vec::len).sum();
+            self.keycodes = keycodes;
+            return changed;
+        }
+
+        let mut changed = 0;
+        for (cached_layer, layer) in self.keycodes.iter_mut().zip(keycodes) {
+            for (cached_row, row) in cached_layer.iter_mut().zip(layer) {
+                for (cached_keycode, keycode) in cached_row.iter_mut().zip(row) {
+                    if *cached_keycode != keycode {
+                        *cached_keycode = keycode;
+                        changed += 1;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
     pub fn get_num_layers(&self) -> usize {
         self.keycodes.len()
     }
@@ -24,6 +60,33 @@ impl KeyMatrix {
             .unwrap_or(0)
     }
 
+    /// Returns the keycode a key resolves to with the given layers active, which is the keycode on
+    /// the highest active layer that is not transparent.
+    pub fn get_active_keycode(
+        &self,
+        layer_state: u32,
+        default_layer_state: u32,
+        row: usize,
+        col: usize,
+    ) -> u16 {
+        let active_layers = layer_state | default_layer_state;
+        (0..self.get_num_layers().min(32))
+            .rev()
+            .filter(|layer| active_layers & (1 << layer) != 0)
+            .map(|layer| self.get_keycode(layer, row, col))
+            .find(|&keycode| keycode != Keycode::KC_TRANSPARENT as u16)
+            .unwrap_or_else(|| self.get_keycode(0, row, col))
+    }
+
+    /// Returns the keycode of an encoder on the given layer, `clockwise` selecting the direction.
+    pub fn get_encoder_keycode(&self, layer: usize, index: usize, clockwise: bool) -> u16 {
+        self.encoders
+            .get(layer)
+            .and_then(|encoders| encoders.get(index))
+            .map(|keycodes| keycodes[clockwise as usize])
+            .unwrap_or(0)
+    }
+
     pub fn is_pressed(&self, row: usize, col: usize) -> bool {
         self.pressed
             .get(row)
@@ -39,4 +102,10 @@ impl KeyMatrix {
             }
         }
     }
+
+    pub fn clear_pressed(&mut self) {
+        for row in self.pressed.iter_mut() {
+            row.fill(false);
+        }
+    }
 }
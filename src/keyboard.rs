@@ -1,167 +1,692 @@
-use qmk_via_api::api::{self};
 use qmk_via_api::keycodes::Keycode;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::heatmap::PressStatistics;
 use crate::key_matrix::KeyMatrix;
 use crate::keyboard_info::{KeyboardInfo, KeyboardLayout};
+use crate::layer_emulation::LayerEmulator;
+use crate::lighting::LightingState;
+use crate::macros::{Macro, MacroFormat};
+use crate::matrix_polling::{PressSource, SwitchMatrixPoller};
+use crate::protocol::{self, capabilities, FirmwareInfo, Message};
+use crate::qmk_keymap::QmkKeymap;
+use crate::session::{RecordedEvent, SessionRecorder};
+use crate::settings::{KeyboardSettings, LightingDisplay, Settings};
+use crate::status::KeyboardStatus;
+use crate::transport::{Connector, Transport};
+use crate::vial::{self, VialFeatures};
 
-pub struct Keyboard {
-    pub layout: KeyboardLayout,
-    pub time_to_hide_overlay: Arc<Mutex<Option<Instant>>>,
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const FRAME_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LIGHTING_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings used by the thread communicating with the device.
+struct Options {
+    rows: usize,
+    cols: usize,
+    encoders: usize,
+    timeout: u64,
+    keymap_refresh_interval: Option<Duration>,
+    read_lighting: bool,
+    /// Tapping term and tri layer used to track the layer state on the host
+    layer_emulation: Option<(Duration, Option<[u8; 3]>)>,
+    matrix_poll_interval: Option<Duration>,
+    /// The keymap is shown without a device, the layers are switched by hand
+    offline: bool,
+}
+
+/// Change of the keyboard state, as received from the device or emulated on the host.
+#[derive(Clone, Copy, Debug)]
+pub enum KeyboardEvent {
+    Connected,
+    Disconnected,
+    LayerState {
+        default_layer_state: u32,
+        layer_state: u32,
+    },
+    Key {
+        row: u8,
+        col: u8,
+        pressed: bool,
+        /// Layer the key was effective on when the event arrived, and its keycode there
+        layer: u8,
+        keycode: u16,
+    },
+}
+
+/// State shared between the overlay and the thread communicating with the device.
+#[derive(Clone)]
+struct SharedState {
+    time_to_hide_overlay: Arc<Mutex<Option<Instant>>>,
     matrix: Arc<Mutex<KeyMatrix>>,
     layer_state: Arc<Mutex<u32>>,
     default_layer_state: Arc<Mutex<u32>>,
+    connected: Arc<Mutex<bool>>,
+    keymap_refresh_requested: Arc<Mutex<bool>>,
+    lighting: Arc<Mutex<Option<LightingState>>>,
+    macros: Arc<Mutex<Vec<Macro>>>,
+    vial_features: Arc<Mutex<Option<VialFeatures>>>,
+    press_source: Arc<Mutex<PressSource>>,
+    status: Arc<Mutex<Option<KeyboardStatus>>>,
+    subscribers: Arc<Mutex<Vec<Sender<KeyboardEvent>>>>,
+    /// Press counts recorded for the heatmap, if enabled
+    statistics: Option<Arc<Mutex<PressStatistics>>>,
+    recorder: Arc<Mutex<Option<SessionRecorder>>>,
+}
+
+impl SharedState {
+    /// Passes an event to all subscribers, forgetting the ones that went away, and records it
+    /// if a recording is running.
+    fn notify(&self, event: KeyboardEvent) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event).is_ok());
+        if let Some(recorder) = self.recorder.lock().unwrap().as_mut() {
+            recorder.record(event.into());
+        }
+    }
+
+    fn get_effective_layer(&self, get_keycode: impl Fn(&KeyMatrix, usize) -> u16) -> (u8, bool) {
+        let layer_state = *self.layer_state.lock().unwrap();
+        let default_layer_state = *self.default_layer_state.lock().unwrap();
+        let matrix = self.matrix.lock().unwrap();
+        let num_layers = matrix.get_num_layers().min(32);
+
+        // Track if there is any active momentary layer above the effective layer
+        // (i.e, key should be shown as background key)
+        let mut active_layer_above = false;
+
+        for i in (1..num_layers).rev() {
+            let layer_mask = 1u32 << (i as u32);
+            let is_active_default_layer = (default_layer_state & layer_mask) != 0;
+            let is_active_momentary_layer = (layer_state & layer_mask) != 0;
+            if (is_active_momentary_layer || is_active_default_layer)
+                && get_keycode(&matrix, i) != Keycode::KC_TRANSPARENT as u16
+            {
+                return (i as u8, is_active_default_layer && active_layer_above);
+            }
+            active_layer_above |= is_active_momentary_layer;
+        }
+
+        (0, active_layer_above)
+    }
+}
+
+pub struct Keyboard {
+    pub layout: KeyboardLayout,
+    state: SharedState,
+    offline: bool,
 }
 
 impl Keyboard {
     pub fn new(
         keyboard_info: KeyboardInfo,
-        layout_name: String,
-        timeout: u64,
+        keyboard_settings: &KeyboardSettings,
+        settings: &Settings,
+        connector: Connector,
+        statistics: Option<Arc<Mutex<PressStatistics>>>,
     ) -> Result<Self, String> {
-        let layout = keyboard_info
-            .get_layout(&layout_name)
-            .map_err(|_| "Failed to get layout".to_string())?;
-
-        let api = Self::try_get_api(keyboard_info.vid, keyboard_info.pid)?;
+        let layout = keyboard_info.get_layout(&keyboard_settings.layout_name)?;
 
-        let layers = api
-            .get_layer_count()
-            .map_err(|_| "Failed to get layer count".to_string())? as usize;
-        let keycodes =
-            Self::get_keycodes_from_device(&api, layers, keyboard_info.rows, keyboard_info.cols);
-
-        let layer_state = Arc::new(Mutex::new(0));
-        let default_layer_state = Arc::new(Mutex::new(0));
-        let time_to_hide_overlay = Arc::new(Mutex::new(Some(Instant::now())));
-        let matrix = Arc::new(Mutex::new(KeyMatrix::new(
-            keycodes,
-            keyboard_info.rows,
-            keyboard_info.cols,
-        )));
-
-        let keyboard = Keyboard {
-            layout,
-            matrix: Arc::clone(&matrix),
-            time_to_hide_overlay: Arc::clone(&time_to_hide_overlay),
-            layer_state: Arc::clone(&layer_state),
-            default_layer_state: Arc::clone(&default_layer_state),
+        let options = Options {
+            rows: keyboard_info.rows,
+            cols: keyboard_info.cols,
+            encoders: keyboard_info.encoders,
+            timeout: settings.timeout,
+            keymap_refresh_interval: settings.get_keymap_refresh_interval(),
+            read_lighting: settings.lighting_display != LightingDisplay::Off,
+            layer_emulation: settings.layer_emulation.then(|| {
+                (
+                    Duration::from_millis(settings.tapping_term),
+                    settings.tri_layer,
+                )
+            }),
+            matrix_poll_interval: settings.get_matrix_poll_interval(),
+            offline: keyboard_settings.is_offline(),
         };
+        let offline = options.offline;
 
-        let layer_state_clone = Arc::clone(&keyboard.layer_state);
-        let default_layer_state_clone = Arc::clone(&keyboard.default_layer_state);
-        let time_to_hide_clone = Arc::clone(&keyboard.time_to_hide_overlay);
-        let matrix_clone = Arc::clone(&matrix);
+        // Nothing is known about the keymap until the device is connected. The overlay stays
+        // visible in the meantime to indicate the missing connection.
+        let state = SharedState {
+            time_to_hide_overlay: Arc::new(Mutex::new(None)),
+            matrix: Arc::new(Mutex::new(KeyMatrix::new(
+                Vec::new(),
+                options.rows,
+                options.cols,
+            ))),
+            layer_state: Arc::new(Mutex::new(0)),
+            default_layer_state: Arc::new(Mutex::new(0)),
+            connected: Arc::new(Mutex::new(false)),
+            keymap_refresh_requested: Arc::new(Mutex::new(false)),
+            lighting: Arc::new(Mutex::new(None)),
+            macros: Arc::new(Mutex::new(Vec::new())),
+            vial_features: Arc::new(Mutex::new(None)),
+            press_source: Arc::new(Mutex::new(PressSource::None)),
+            status: Arc::new(Mutex::new(None)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            statistics,
+            recorder: Arc::new(Mutex::new(None)),
+        };
 
+        let thread_state = state.clone();
         thread::spawn(move || loop {
-            if let Ok(response) = api.hid_read() {
-                if response[0] == 0xff {
-                    let size = response[1] as usize;
-
-                    let mut default_bytes = [0u8; 4];
-                    default_bytes[..size].copy_from_slice(&response[2..2 + size]);
-                    let default_layer_state = u32::from_le_bytes(default_bytes);
-
-                    let mut layer_bytes = [0u8; 4];
-                    layer_bytes[..size].copy_from_slice(&response[2 + size..2 + 2 * size]);
-                    let layer_state = u32::from_le_bytes(layer_bytes);
-
-                    if layer_state > 1 {
-                        *time_to_hide_clone.lock().unwrap() = None;
-                    } else {
-                        let time_to_hide = Instant::now() + Duration::from_millis(timeout);
-                        *time_to_hide_clone.lock().unwrap() = Some(time_to_hide);
-                    }
+            let transport = match connector() {
+                Ok(transport) => transport,
+                Err(_) => {
+                    thread::sleep(RECONNECT_INTERVAL);
+                    continue;
+                }
+            };
+
+            // Reconnecting always re-reads the keymap, since it might have been changed by
+            // another host in the meantime
+            if Self::refresh_keymap(transport.as_ref(), &thread_state, &options).is_err() {
+                thread::sleep(RECONNECT_INTERVAL);
+                continue;
+            }
+            let firmware_info = protocol::handshake(transport.as_ref());
+            *thread_state.connected.lock().unwrap() = true;
+            thread_state.notify(KeyboardEvent::Connected);
+            // Offline keymaps are meant to be looked at, so they stay visible
+            if !options.offline {
+                Self::hide_overlay_after(&thread_state, options.timeout);
+            }
+
+            let _ =
+                Self::process_frames(transport.as_ref(), &firmware_info, &thread_state, &options);
+
+            // The device is gone. Drop the stale layer and key state and keep the overlay
+            // visible until the device is back.
+            *thread_state.connected.lock().unwrap() = false;
+            *thread_state.layer_state.lock().unwrap() = 0;
+            *thread_state.default_layer_state.lock().unwrap() = 0;
+            thread_state.matrix.lock().unwrap().clear_pressed();
+            *thread_state.lighting.lock().unwrap() = None;
+            *thread_state.press_source.lock().unwrap() = PressSource::None;
+            *thread_state.status.lock().unwrap() = None;
+            *thread_state.time_to_hide_overlay.lock().unwrap() = None;
+            thread_state.notify(KeyboardEvent::Disconnected);
+        });
+
+        Ok(Keyboard {
+            layout,
+            state,
+            offline,
+        })
+    }
+
+    /// Handles frames from the firmware until the connection fails. Keymap refreshes are done
+    /// in between frames, so that the device is only ever accessed from a single thread.
+    fn process_frames(
+        transport: &dyn Transport,
+        firmware_info: &FirmwareInfo,
+        state: &SharedState,
+        options: &Options,
+    ) -> Result<(), String> {
+        let keymap_refresh_interval = options.keymap_refresh_interval;
+        let mut next_refresh = keymap_refresh_interval.map(|interval| Instant::now() + interval);
+        let mut next_lighting_poll = options.read_lighting.then(Instant::now);
+        let mut emulator = options
+            .layer_emulation
+            .map(|(tapping_term, tri_layer)| LayerEmulator::new(tapping_term, tri_layer));
+        if let Some(emulator) = &emulator {
+            Self::set_layer_state(
+                state,
+                emulator.default_layer_state(),
+                emulator.layer_state(),
+                options.timeout,
+            );
+        }
+
+        // Key events announced by the firmware are preferred over polling the switch matrix.
+        // Firmware from before the handshake might send them as well, which is only known once
+        // the first one arrives.
+        let announces_key_events =
+            firmware_info.version > 0 && firmware_info.supports(capabilities::KEY_EVENTS);
+        let mut poller = options
+            .matrix_poll_interval
+            .filter(|_| !announces_key_events)
+            .map(|_| SwitchMatrixPoller::new(options.rows, options.cols));
+        let mut next_matrix_poll = poller.is_some().then(Instant::now);
+        *state.press_source.lock().unwrap() = if announces_key_events {
+            PressSource::FirmwareEvents
+        } else if poller.is_some() {
+            PressSource::MatrixPolling
+        } else {
+            PressSource::None
+        };
 
-                    *layer_state_clone.lock().unwrap() = layer_state;
-                    *default_layer_state_clone.lock().unwrap() = default_layer_state;
-                } else if response[0] == 0xF1 {
-                    let row = response[1] as usize;
-                    let col = response[2] as usize;
-                    let pressed = response[3];
-                    if let Ok(mut mat) = matrix_clone.lock() {
-                        mat.set_pressed(row, col, pressed != 0);
+        loop {
+            // Held layer tap keys have to be noticed once the tapping term is exceeded
+            let deadline = emulator
+                .as_ref()
+                .and_then(LayerEmulator::next_deadline)
+                .into_iter()
+                .chain(next_matrix_poll)
+                .min();
+            let poll_interval = deadline.map_or(FRAME_POLL_INTERVAL, |deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(FRAME_POLL_INTERVAL)
+            });
+            if let Some(response) = transport.read_frame(poll_interval)? {
+                let is_key_event = Self::handle_frame(
+                    state,
+                    firmware_info,
+                    &response,
+                    emulator.as_mut(),
+                    options.timeout,
+                );
+                if is_key_event && poller.take().is_some() {
+                    next_matrix_poll = None;
+                    *state.press_source.lock().unwrap() = PressSource::FirmwareEvents;
+                }
+            }
+
+            if let (Some(matrix_poller), Some(interval)) =
+                (&mut poller, options.matrix_poll_interval)
+            {
+                if next_matrix_poll.is_some_and(|time| Instant::now() >= time) {
+                    match transport.read_switch_matrix(options.rows, options.cols) {
+                        Ok(Some(switch_matrix)) => {
+                            for (row, col, pressed) in matrix_poller.update(&switch_matrix) {
+                                Self::handle_key_event(
+                                    state,
+                                    row,
+                                    col,
+                                    pressed,
+                                    emulator.as_mut(),
+                                    options.timeout,
+                                );
+                            }
+                            next_matrix_poll = Some(Instant::now() + interval);
+                        }
+                        // Firmware without the query won't gain it, so polling is given up
+                        Ok(None) => {
+                            poller = None;
+                            next_matrix_poll = None;
+                            *state.press_source.lock().unwrap() = PressSource::None;
+                        }
+                        // A timed out or garbled read is retried on the next poll
+                        Err(_) => next_matrix_poll = Some(Instant::now() + interval),
                     }
                 }
             }
+            if let Some(emulator) = &mut emulator {
+                if emulator.tick(Instant::now()) {
+                    Self::set_layer_state(
+                        state,
+                        emulator.default_layer_state(),
+                        emulator.layer_state(),
+                        options.timeout,
+                    );
+                }
+            }
+
+            let refresh_requested =
+                std::mem::take(&mut *state.keymap_refresh_requested.lock().unwrap())
+                    || transport.keymap_changed();
+            let refresh_due = next_refresh.is_some_and(|time| Instant::now() >= time);
+            if refresh_requested || refresh_due {
+                Self::refresh_keymap(transport, state, options)?;
+                next_refresh = keymap_refresh_interval.map(|interval| Instant::now() + interval);
+            }
+
+            // The lighting is changed by keys without any message from the firmware, so it has
+            // to be polled. Firmware without lighting simply leaves the state empty.
+            if next_lighting_poll.is_some_and(|time| Instant::now() >= time) {
+                *state.lighting.lock().unwrap() = LightingState::query(transport).ok();
+                next_lighting_poll = Some(Instant::now() + LIGHTING_POLL_INTERVAL);
+            }
+        }
+    }
+
+    fn refresh_keymap(
+        transport: &dyn Transport,
+        state: &SharedState,
+        options: &Options,
+    ) -> Result<(), String> {
+        let layers = transport.get_layer_count()? as usize;
+        let keycodes =
+            Self::get_keycodes_from_device(transport, layers, options.rows, options.cols)?;
+        let is_vial = transport.is_vial();
+        // Encoder mapping is optional in the firmware, keyboards without it have no knobs to show
+        let encoders = Self::get_encoders_from_device(transport, is_vial, layers, options.encoders)
+            .unwrap_or_default();
+        let macro_format = if is_vial {
+            MacroFormat::Vial
+        } else {
+            MacroFormat::Via
+        };
+        let macros = Self::get_macros_from_device(transport, macro_format).unwrap_or_default();
+        let vial_features = is_vial
+            .then(|| VialFeatures::read(transport).ok())
+            .flatten();
+
+        let mut matrix = state.matrix.lock().unwrap();
+        matrix.update_keycodes(keycodes);
+        matrix.update_encoders(encoders);
+        if let Some(recorder) = state.recorder.lock().unwrap().as_mut() {
+            recorder.record_keymap(&matrix.keycodes, &matrix.encoders);
+        }
+        *state.macros.lock().unwrap() = macros;
+        *state.vial_features.lock().unwrap() = vial_features;
+        Ok(())
+    }
+
+    fn get_macros_from_device(
+        transport: &dyn Transport,
+        format: MacroFormat,
+    ) -> Result<Vec<Macro>, String> {
+        let count = transport.get_macro_count()?;
+        let buffer = transport.read_macro_buffer(count)?;
+        Ok(Macro::parse_buffer(&buffer, count as usize, format))
+    }
+
+    fn handle_frame(
+        state: &SharedState,
+        firmware_info: &FirmwareInfo,
+        response: &[u8],
+        emulator: Option<&mut LayerEmulator>,
+        timeout: u64,
+    ) -> bool {
+        // Malformed frames and responses to commands are not of interest here
+        let Ok(message) = protocol::decode(response, firmware_info) else {
+            return false;
+        };
+
+        match message {
+            // The emulated layer state takes precedence over the one reported by the firmware
+            Message::LayerState { .. } if emulator.is_some() => false,
+            Message::LayerState {
+                default_layer_state,
+                layer_state,
+            } => {
+                Self::set_layer_state(state, default_layer_state, layer_state, timeout);
+                false
+            }
+            Message::KeyEvent { row, col, pressed } => {
+                Self::handle_key_event(state, row, col, pressed, emulator, timeout);
+                true
+            }
+            Message::Status(status) => {
+                *state.status.lock().unwrap() = Some(status);
+                false
+            }
+        }
+    }
+
+    fn handle_key_event(
+        state: &SharedState,
+        row: u8,
+        col: u8,
+        pressed: bool,
+        emulator: Option<&mut LayerEmulator>,
+        timeout: u64,
+    ) {
+        // The layer is taken before the press changes it, e.g. when pressing a layer key
+        let (layer, _) = state.get_effective_layer(|matrix, layer| {
+            matrix.get_keycode(layer, row as usize, col as usize)
+        });
+        if let (Some(statistics), true) = (&state.statistics, pressed) {
+            statistics.lock().unwrap().record(layer, row, col);
+        }
+        let keycode =
+            state
+                .matrix
+                .lock()
+                .unwrap()
+                .get_keycode(layer as usize, row as usize, col as usize);
+        if let Ok(mut mat) = state.matrix.lock() {
+            mat.set_pressed(row as usize, col as usize, pressed);
+        }
+        state.notify(KeyboardEvent::Key {
+            row,
+            col,
+            pressed,
+            layer,
+            keycode,
         });
+        let Some(emulator) = emulator else {
+            return;
+        };
+
+        let previous = (emulator.default_layer_state(), emulator.layer_state());
+        let now = Instant::now();
+        if pressed {
+            let keycode = state.matrix.lock().unwrap().get_active_keycode(
+                emulator.layer_state(),
+                emulator.default_layer_state(),
+                row as usize,
+                col as usize,
+            );
+            emulator.press(row, col, keycode, now);
+        } else {
+            emulator.release(row, col, now);
+        }
+        if previous == (emulator.default_layer_state(), emulator.layer_state()) {
+            return;
+        }
+        Self::set_layer_state(
+            state,
+            emulator.default_layer_state(),
+            emulator.layer_state(),
+            timeout,
+        );
+    }
 
-        Ok(keyboard)
+    fn set_layer_state(
+        state: &SharedState,
+        default_layer_state: u32,
+        layer_state: u32,
+        timeout: u64,
+    ) {
+        if layer_state > 1 {
+            *state.time_to_hide_overlay.lock().unwrap() = None;
+        } else {
+            Self::hide_overlay_after(state, timeout);
+        }
+
+        *state.layer_state.lock().unwrap() = layer_state;
+        *state.default_layer_state.lock().unwrap() = default_layer_state;
+        state.notify(KeyboardEvent::LayerState {
+            default_layer_state,
+            layer_state,
+        });
+    }
+
+    fn hide_overlay_after(state: &SharedState, timeout: u64) {
+        let time_to_hide = Instant::now() + Duration::from_millis(timeout);
+        *state.time_to_hide_overlay.lock().unwrap() = Some(time_to_hide);
     }
 
     fn get_keycodes_from_device(
-        api: &api::KeyboardApi,
+        transport: &dyn Transport,
         layers: usize,
         rows: usize,
         cols: usize,
-    ) -> Vec<Vec<Vec<u16>>> {
+    ) -> Result<Vec<Vec<Vec<u16>>>, String> {
         let mut keycodes = vec![vec![vec![0; cols]; rows]; layers];
-        let matrix_info = api::MatrixInfo {
-            rows: rows as u8,
-            cols: cols as u8,
-        };
 
-        for layer in 0..layers {
-            if let Ok(raw_matrix) = api.read_raw_matrix(matrix_info, layer as u8) {
-                for (i, keycode) in raw_matrix.iter().enumerate() {
-                    let row = i / cols;
-                    let col = i % cols;
-                    keycodes[layer][row][col] = *keycode;
-                }
+        for (layer, layer_keycodes) in keycodes.iter_mut().enumerate() {
+            let raw_matrix = transport.read_raw_matrix(layer as u8, rows, cols)?;
+            for (i, keycode) in raw_matrix.iter().enumerate().take(rows * cols) {
+                let row = i / cols;
+                let col = i % cols;
+                layer_keycodes[row][col] = *keycode;
             }
         }
 
-        keycodes
+        Ok(keycodes)
+    }
+
+    fn get_encoders_from_device(
+        transport: &dyn Transport,
+        is_vial: bool,
+        layers: usize,
+        encoders: usize,
+    ) -> Result<Vec<Vec<[u16; 2]>>, String> {
+        let read_encoder = |layer: usize, index: usize| {
+            if is_vial {
+                vial::read_encoder(transport, layer as u8, index as u8)
+            } else {
+                transport.read_encoder(layer as u8, index as u8)
+            }
+        };
+        (0..layers)
+            .map(|layer| {
+                (0..encoders)
+                    .map(|index| read_encoder(layer, index))
+                    .collect()
+            })
+            .collect()
     }
 
     pub fn get_effective_key_layer(&self, row: usize, col: usize) -> (u8, bool) {
-        let layer_state = *self.layer_state.lock().unwrap();
-        let default_layer_state = *self.default_layer_state.lock().unwrap();
-        let matrix = self.matrix.lock().unwrap();
-        let num_layers = matrix.get_num_layers().min(32);
+        self.state
+            .get_effective_layer(|matrix, layer| matrix.get_keycode(layer, row, col))
+    }
 
-        // Track if there is any active momentary layer above the effective layer
-        // (i.e, key should be shown as background key)
-        let mut active_layer_above = false;
+    /// Returns the layer an encoder turn in the given direction resolves to, see
+    /// [`Self::get_effective_key_layer`].
+    pub fn get_effective_encoder_layer(&self, index: usize, clockwise: bool) -> (u8, bool) {
+        self.state.get_effective_layer(|matrix, layer| {
+            matrix.get_encoder_keycode(layer, index, clockwise)
+        })
+    }
 
-        for i in (1..num_layers).rev() {
-            let layer_mask = 1u32 << (i as u32);
-            let is_active_default_layer = (default_layer_state & layer_mask) != 0;
-            let is_active_momentary_layer = (layer_state & layer_mask) != 0;
-            if is_active_momentary_layer || is_active_default_layer {
-                if matrix.get_keycode(i, row, col) != Keycode::KC_TRANSPARENT as u16 {
-                    return (i as u8, is_active_default_layer && active_layer_above);
-                }
-            }
-            active_layer_above |= is_active_momentary_layer;
-        }
+    pub fn get_keycode(&self, layer: usize, row: usize, col: usize) -> u16 {
+        self.state
+            .matrix
+            .lock()
+            .unwrap()
+            .get_keycode(layer, row, col)
+    }
 
-        (0, active_layer_above)
+    /// Returns the number of layers read from the device, zero until the keymap was read.
+    pub fn get_num_layers(&self) -> usize {
+        self.state.matrix.lock().unwrap().get_num_layers()
     }
 
-    pub fn get_keycode(&self, layer: usize, row: usize, col: usize) -> u16 {
-        self.matrix.lock().unwrap().get_keycode(layer, row, col)
+    pub fn get_encoder_keycode(&self, layer: usize, index: usize, clockwise: bool) -> u16 {
+        self.state
+            .matrix
+            .lock()
+            .unwrap()
+            .get_encoder_keycode(layer, index, clockwise)
+    }
+
+    pub fn get_macros(&self) -> Vec<Macro> {
+        self.state.macros.lock().unwrap().clone()
+    }
+
+    pub fn get_vial_features(&self) -> Option<VialFeatures> {
+        self.state.vial_features.lock().unwrap().clone()
     }
 
     pub fn is_key_pressed(&self, row: usize, col: usize) -> bool {
-        self.matrix.lock().unwrap().is_pressed(row, col)
+        self.state.matrix.lock().unwrap().is_pressed(row, col)
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Shows the next or previous layer of an offline keymap, wrapping around at the ends.
+    pub fn cycle_layer(&self, forward: bool) {
+        if !self.offline {
+            return;
+        }
+        let num_layers = self
+            .state
+            .matrix
+            .lock()
+            .unwrap()
+            .get_num_layers()
+            .clamp(1, 32) as u32;
+        let mut layer_state = self.state.layer_state.lock().unwrap();
+        let current = layer_state.checked_ilog2().unwrap_or(0);
+        let next = if forward {
+            (current + 1) % num_layers
+        } else {
+            (current + num_layers - 1) % num_layers
+        };
+        *layer_state = if next == 0 { 0 } else { 1 << next };
+    }
+
+    /// Returns the keymap read from the device as a QMK keymap for the selected layout.
+    pub fn export_keymap(&self) -> Result<QmkKeymap, String> {
+        let matrix = self.state.matrix.lock().unwrap();
+        if matrix.get_num_layers() == 0 {
+            return Err("The keymap hasn't been read from the device yet".to_string());
+        }
+        Ok(QmkKeymap::from_matrix(
+            &self.layout,
+            &matrix,
+            &self.state.macros.lock().unwrap(),
+        ))
+    }
+
+    /// Re-reads the keymap from the device as soon as possible.
+    pub fn request_keymap_refresh(&self) {
+        *self.state.keymap_refresh_requested.lock().unwrap() = true;
     }
 
-    pub fn try_get_api(vid: u16, pid: u16) -> Result<api::KeyboardApi, String> {
-        let api = api::KeyboardApi::new(vid, pid, 0xff60)
-            .map_err(|e| format!("Failed to connect to device ({vid:04x}:{pid:04x}): {e}"))?;
+    pub fn is_connected(&self) -> bool {
+        *self.state.connected.lock().unwrap()
+    }
 
-        let protocol_version = api
-            .get_protocol_version()
-            .map_err(|e| format!("Failed to get protocol version: {e}"))?;
-        if protocol_version < 12 {
-            return Err(format!(
-                "Unsupported protocol version: {}. Minimum required version is 12.",
-                protocol_version
-            ));
+    pub fn get_lighting(&self) -> Option<LightingState> {
+        *self.state.lighting.lock().unwrap()
+    }
+
+    /// Returns the modifier and lock state, if reported by the firmware.
+    pub fn get_status(&self) -> Option<KeyboardStatus> {
+        *self.state.status.lock().unwrap()
+    }
+
+    pub fn get_press_source(&self) -> PressSource {
+        *self.state.press_source.lock().unwrap()
+    }
+
+    /// Returns the press counts recorded for the heatmap, if enabled.
+    pub fn get_press_statistics(&self) -> Option<PressStatistics> {
+        self.state
+            .statistics
+            .as_ref()
+            .map(|statistics| statistics.lock().unwrap().clone())
+    }
+
+    /// Records the keymap and all further layer state and key events to a JSON Lines file, to
+    /// be played back later without the device.
+    pub fn start_recording(&self, path: &str) -> Result<(), String> {
+        let mut recorder = SessionRecorder::create(path)?;
+        // Events only cover changes, so the recording starts with the current state
+        let matrix = self.state.matrix.lock().unwrap();
+        recorder.record_keymap(&matrix.keycodes, &matrix.encoders);
+        if self.is_connected() {
+            recorder.record(RecordedEvent::Layer {
+                default_layer_state: *self.state.default_layer_state.lock().unwrap(),
+                layer_state: *self.state.layer_state.lock().unwrap(),
+            });
         }
-        Ok(api)
+        *self.state.recorder.lock().unwrap() = Some(recorder);
+        Ok(())
+    }
+
+    /// Returns a channel receiving all further events of the keyboard.
+    pub fn subscribe(&self) -> Receiver<KeyboardEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.state.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn get_time_to_hide_overlay(&self) -> Option<Instant> {
+        *self.state.time_to_hide_overlay.lock().unwrap()
     }
 }
@@ -1,9 +1,13 @@
 use qmk_via_api::api;
 use serde_json::Value;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
 use std::num::ParseIntError;
 
+use crate::settings::KeyboardSettings;
+use crate::vial;
+
 #[derive(Debug, Clone)]
 pub struct Key {
     pub row: api::Row,
@@ -14,18 +18,92 @@ pub struct Key {
     pub h: f32,
 }
 
+/// Rotary encoder drawn as a knob. Layout entries with an `encoder` index become encoders,
+/// their optional matrix position is the push switch of the knob.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    pub index: u8,
+    pub matrix: Option<(api::Row, api::Column)>,
+    pub x: f32,
+    pub y: f32,
+    pub w: f32,
+    pub h: f32,
+}
+
 #[derive(Clone)]
 pub struct KeyboardLayout {
     pub name: String,
     pub keys: Vec<Key>,
+    pub encoders: Vec<Encoder>,
+    /// Matrix positions in the order of the arguments of the layout macro, as used by keymaps.
+    pub matrix_order: Vec<(api::Row, api::Column)>,
+    /// Whether the name is a layout macro of the firmware. Layouts of VIA definitions have none,
+    /// their keys are in the order of the definition.
+    pub has_macro: bool,
+}
+
+/// Keys, encoders and macro argument order collected from a layout definition.
+type LayoutEntries = (Vec<Key>, Vec<Encoder>, Vec<(api::Row, api::Column)>);
+
+/// Position of each raw KLE legend for every legend alignment `a`, as in kle-serial. Legends are
+/// stored without the empty positions, so the raw index depends on the alignment.
+const KLE_LABEL_MAP: [[i8; 12]; 8] = [
+    [0, 6, 2, 8, 9, 11, 3, 5, 1, 4, 7, 10],
+    [1, 7, -1, -1, 9, 11, 4, -1, -1, -1, -1, 10],
+    [3, -1, 5, -1, 9, 11, -1, -1, 4, -1, -1, 10],
+    [4, -1, -1, -1, 9, 11, -1, -1, -1, -1, -1, 10],
+    [0, 6, 2, 8, 10, -1, 3, 5, 1, 4, 7, -1],
+    [1, 7, -1, -1, 10, -1, 4, -1, -1, -1, -1, -1],
+    [3, -1, 5, -1, 10, -1, -1, -1, 4, -1, -1, -1],
+    [4, -1, -1, -1, 10, -1, -1, -1, -1, -1, -1, -1],
+];
+
+/// Default legend alignment of KLE keys
+const KLE_DEFAULT_ALIGN: usize = 4;
+
+/// Places the raw legends of a KLE key on their positions for the alignment.
+fn reorder_kle_legends(raw: &str, align: usize) -> [&str; 12] {
+    let mut legends = [""; 12];
+    let map = &KLE_LABEL_MAP[align.min(KLE_LABEL_MAP.len() - 1)];
+    for (legend, &position) in raw.split('\n').zip(map) {
+        if let Ok(position) = usize::try_from(position) {
+            legends[position] = legend;
+        }
+    }
+    legends
 }
 
 impl KeyboardLayout {
     pub fn get_dimensions(&self) -> (f32, f32) {
-        let max_x = self.keys.iter().map(|k| k.x + k.w).fold(0.0, f32::max);
-        let max_y = self.keys.iter().map(|k| k.y + k.h).fold(0.0, f32::max);
+        let bounds = self
+            .keys
+            .iter()
+            .map(|k| (k.x + k.w, k.y + k.h))
+            .chain(self.encoders.iter().map(|e| (e.x + e.w, e.y + e.h)));
+        let max_x = bounds.clone().map(|(x, _)| x).fold(0.0, f32::max);
+        let max_y = bounds.map(|(_, y)| y).fold(0.0, f32::max);
         (max_x, max_y)
     }
+
+    /// Adds knobs for encoders without a position in the layout in a column right of the keys.
+    fn place_missing_encoders(&mut self, encoder_count: usize) {
+        let x = self.get_dimensions().0 + 0.25;
+        let mut y = 0.0;
+        for index in 0..encoder_count as u8 {
+            if self.encoders.iter().any(|e| e.index == index) {
+                continue;
+            }
+            self.encoders.push(Encoder {
+                index,
+                matrix: None,
+                x,
+                y,
+                w: 1.0,
+                h: 1.0,
+            });
+            y += 1.0;
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -34,44 +112,81 @@ pub struct KeyboardInfo {
     pub pid: u16,
     pub rows: usize,
     pub cols: usize,
+    pub encoders: usize,
     pub layouts: Vec<KeyboardLayout>,
 }
 
 impl KeyboardInfo {
-    fn collect_layout_keys(layout: &Value) -> Result<Vec<Key>, Box<dyn std::error::Error>> {
+    fn collect_layout_keys(layout: &Value) -> Result<LayoutEntries, Box<dyn std::error::Error>> {
         let layout = layout["layout"].as_array().expect("No layout array found.");
 
         let mut keys = Vec::new();
+        let mut encoders = Vec::new();
+        let mut matrix_order = Vec::new();
         for key in layout {
-            let matrix: Vec<usize> = key["matrix"]
-                .as_array()
-                .expect("Unable to find 'matrix' array in key definition.")
-                .iter()
-                .map(|v| v.as_u64().expect("Unable to parse 'matrix' value.") as usize)
-                .collect();
+            let encoder = key["encoder"].as_u64();
+            let matrix: Option<Vec<usize>> = key["matrix"].as_array().map(|matrix| {
+                matrix
+                    .iter()
+                    .map(|v| v.as_u64().expect("Unable to parse 'matrix' value.") as usize)
+                    .collect()
+            });
+            if matrix.is_none() && encoder.is_none() {
+                panic!("Unable to find 'matrix' array in key definition.");
+            }
+            let matrix = matrix.map(|matrix| (matrix[0] as api::Row, matrix[1] as api::Column));
+            matrix_order.extend(matrix);
 
             let x = key["x"].as_f64().unwrap_or(0.0) as f32;
             let y = key["y"].as_f64().unwrap_or(0.0) as f32;
             let w = key["w"].as_f64().unwrap_or(1.0) as f32;
             let h = key["h"].as_f64().unwrap_or(1.0) as f32;
 
-            keys.push(Key {
-                row: matrix[0] as api::Row,
-                col: matrix[1] as api::Layer,
-                x,
-                y,
-                w,
-                h,
-            });
+            if let Some(index) = encoder {
+                encoders.push(Encoder {
+                    index: index as u8,
+                    matrix,
+                    x,
+                    y,
+                    w,
+                    h,
+                });
+            } else if let Some((row, col)) = matrix {
+                keys.push(Key {
+                    row,
+                    col,
+                    x,
+                    y,
+                    w,
+                    h,
+                });
+            }
         }
 
-        Ok(keys)
+        Ok((keys, encoders, matrix_order))
+    }
+
+    /// Counts the rotary encoders of both halves, as well as any referenced by a layout.
+    fn count_encoders(json: &Value, layouts: &[KeyboardLayout]) -> usize {
+        let rotary_count = |encoder: &Value| encoder["rotary"].as_array().map_or(0, Vec::len);
+        let declared =
+            rotary_count(&json["encoder"]) + rotary_count(&json["split"]["encoder"]["right"]);
+        let referenced = layouts
+            .iter()
+            .flat_map(|layout| &layout.encoders)
+            .map(|encoder| encoder.index as usize + 1)
+            .max()
+            .unwrap_or(0);
+        declared.max(referenced)
     }
 
     pub fn new(json_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let file = File::open(json_path).expect("Failed to open keyboard info JSON.");
         let reader = BufReader::new(file);
         let json: Value = serde_json::from_reader(reader)?;
+        if Self::is_via_definition(&json) {
+            return Self::from_via_definition(&json);
+        }
 
         let mut layouts = Vec::new();
         let raw_layouts = json["layouts"]
@@ -79,14 +194,22 @@ impl KeyboardInfo {
             .expect("No layouts found in keyboard info JSON.");
         for layout_name in raw_layouts.keys() {
             let raw_layout = &raw_layouts[layout_name];
-            let keys = Self::collect_layout_keys(raw_layout)?;
+            let (keys, encoders, matrix_order) = Self::collect_layout_keys(raw_layout)?;
             let layout = KeyboardLayout {
                 name: layout_name.clone(),
                 keys,
+                encoders,
+                matrix_order,
+                has_macro: true,
             };
             layouts.push(layout);
         }
 
+        let encoders = Self::count_encoders(&json, &layouts);
+        for layout in layouts.iter_mut() {
+            layout.place_missing_encoders(encoders);
+        }
+
         let is_split_keyboard = json
             .get("split")
             .unwrap_or_default()
@@ -134,10 +257,244 @@ impl KeyboardInfo {
             pid,
             rows,
             cols,
+            encoders,
+            layouts,
+        })
+    }
+
+    /// Parses a keyboard definition in the VIA format, as used by VIA and stored by Vial
+    /// firmware. The matrix position of each key is given as "row,col" in its top left legend.
+    pub fn from_via_definition(json: &Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let matrix = json
+            .get("matrix")
+            .ok_or("Unable to find 'matrix' in keyboard definition.")?;
+        let rows = matrix["rows"]
+            .as_u64()
+            .ok_or("Unable to find 'rows' in 'matrix'.")? as usize;
+        let cols = matrix["cols"]
+            .as_u64()
+            .ok_or("Unable to find 'cols' in 'matrix'.")? as usize;
+        let keymap = &json["layouts"]["keymap"];
+
+        // The default layout uses the first choice of every layout option. Every other choice
+        // is offered as a layout of its own, differing from the default in that option only.
+        let mut layouts = Vec::new();
+        let (keys, encoders, matrix_order) = Self::collect_kle_keys(keymap, &[])?;
+        layouts.push(KeyboardLayout {
+            name: "LAYOUT".to_string(),
+            keys,
+            encoders,
+            matrix_order,
+            has_macro: false,
+        });
+        let options = json["layouts"]["labels"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for (option, label) in options.iter().enumerate() {
+            // Options are either a name toggled on and off, or a name followed by the choices
+            let (name, choices) = match label {
+                Value::String(name) => (name.as_str(), vec!["On"]),
+                Value::Array(values) => (
+                    values.first().and_then(Value::as_str).unwrap_or_default(),
+                    values.iter().skip(2).filter_map(Value::as_str).collect(),
+                ),
+                _ => continue,
+            };
+            for (i, choice) in choices.into_iter().enumerate() {
+                let mut selected = vec![0; option + 1];
+                selected[option] = i as u8 + 1;
+                let (keys, encoders, matrix_order) = Self::collect_kle_keys(keymap, &selected)?;
+                layouts.push(KeyboardLayout {
+                    name: format!("LAYOUT ({name}: {choice})"),
+                    keys,
+                    encoders,
+                    matrix_order,
+                    has_macro: false,
+                });
+            }
+        }
+        let encoder_count = layouts[0].encoders.len();
+
+        // The IDs are optional in definitions read from the device itself
+        let id = |name: &str| json[name].as_str().and_then(|id| Self::hex_to_u16(id).ok());
+        Ok(KeyboardInfo {
+            vid: id("vendorId").unwrap_or(0),
+            pid: id("productId").unwrap_or(0),
+            rows,
+            cols,
+            encoders: encoder_count,
             layouts,
         })
     }
 
+    fn is_via_definition(json: &Value) -> bool {
+        json["matrix"]["rows"].is_u64() && json["layouts"]["keymap"].is_array()
+    }
+
+    /// Parses a keymap in the KLE format as used by VIA. Keys belonging to a layout option are
+    /// marked with "option,choice" in the bottom right legend and only included for the choice
+    /// given in `selected`, defaulting to the first one. Other choices are drawn next to the
+    /// keyboard in the definition and moved to the place of the first one when selected.
+    /// Encoders have an "e" in the center left legend and their index in the top left legend.
+    fn collect_kle_keys(
+        keymap: &Value,
+        selected: &[u8],
+    ) -> Result<LayoutEntries, Box<dyn std::error::Error>> {
+        let rows = keymap
+            .as_array()
+            .ok_or("Unable to find 'keymap' in 'layouts'.")?;
+
+        // Legends and position of every key, decals excluded
+        let mut entries: Vec<([&str; 12], f32, f32, f32, f32)> = Vec::new();
+        let (mut cluster_x, mut cluster_y) = (0.0, 0.0);
+        let (mut x, mut y) = (0.0, 0.0);
+        let mut align = KLE_DEFAULT_ALIGN;
+        for row in rows {
+            // The first entry may be an object with keyboard metadata instead of a row
+            let Some(items) = row.as_array() else {
+                continue;
+            };
+            let (mut w, mut h, mut decal) = (1.0, 1.0, false);
+            for item in items {
+                if let Some(props) = item.as_object() {
+                    let prop = |name: &str| props.get(name).and_then(Value::as_f64);
+                    if prop("rx").is_some() || prop("ry").is_some() {
+                        cluster_x = prop("rx").unwrap_or(cluster_x as f64) as f32;
+                        cluster_y = prop("ry").unwrap_or(cluster_y as f64) as f32;
+                        x = cluster_x;
+                        y = cluster_y;
+                    }
+                    x += prop("x").unwrap_or(0.0) as f32;
+                    y += prop("y").unwrap_or(0.0) as f32;
+                    w = prop("w").unwrap_or(w as f64) as f32;
+                    h = prop("h").unwrap_or(h as f64) as f32;
+                    decal = props.get("d").and_then(Value::as_bool).unwrap_or(false);
+                    // Unlike the size, the alignment applies to all following keys
+                    align = prop("a").map_or(align, |a| a as usize);
+                    continue;
+                }
+
+                if !decal {
+                    let legends = reorder_kle_legends(item.as_str().unwrap_or_default(), align);
+                    entries.push((legends, x, y, w, h));
+                }
+                x += w;
+                (w, h, decal) = (1.0, 1.0, false);
+            }
+            x = cluster_x;
+            y += 1.0;
+        }
+
+        let pair = |legend: &str| {
+            let (a, b) = legend.split_once(',')?;
+            Some((a.trim().parse::<u8>().ok()?, b.trim().parse::<u8>().ok()?))
+        };
+        // Top left corner of the keys of every choice
+        let mut origins: HashMap<(u8, u8), (f32, f32)> = HashMap::new();
+        for (legends, x, y, _, _) in &entries {
+            if let Some(choice) = pair(legends[8]) {
+                let origin = origins.entry(choice).or_insert((*x, *y));
+                *origin = (origin.0.min(*x), origin.1.min(*y));
+            }
+        }
+
+        let mut keys = Vec::new();
+        let mut encoders: Vec<Encoder> = Vec::new();
+        for (legends, x, y, w, h) in entries {
+            let (mut x, mut y) = (x, y);
+            if let Some((option, choice)) = pair(legends[8]) {
+                if selected.get(option as usize).copied().unwrap_or(0) != choice {
+                    continue;
+                }
+                let first = origins.get(&(option, 0));
+                if let (Some(first), Some(own)) = (first, origins.get(&(option, choice))) {
+                    x += first.0 - own.0;
+                    y += first.1 - own.1;
+                }
+            }
+
+            if legends[4].trim() == "e" {
+                let index = pair(legends[0])
+                    .ok_or("Invalid encoder legend in keyboard definition.")?
+                    .0;
+                match encoders.iter_mut().find(|encoder| encoder.index == index) {
+                    // Both directions are separate keys, the knob covers them both
+                    Some(encoder) => {
+                        let (right, bottom) = (
+                            (encoder.x + encoder.w).max(x + w),
+                            (encoder.y + encoder.h).max(y + h),
+                        );
+                        encoder.x = encoder.x.min(x);
+                        encoder.y = encoder.y.min(y);
+                        encoder.w = right - encoder.x;
+                        encoder.h = bottom - encoder.y;
+                    }
+                    None => encoders.push(Encoder {
+                        index,
+                        matrix: None,
+                        x,
+                        y,
+                        w,
+                        h,
+                    }),
+                }
+            } else if let Some((row, col)) = pair(legends[0]) {
+                keys.push(Key {
+                    row,
+                    col,
+                    x,
+                    y,
+                    w,
+                    h,
+                });
+            }
+        }
+
+        // Unselected layout options may leave a gap at the top or left
+        let min_x = keys
+            .iter()
+            .map(|k| k.x)
+            .chain(encoders.iter().map(|e| e.x))
+            .fold(f32::MAX, f32::min);
+        let min_y = keys
+            .iter()
+            .map(|k| k.y)
+            .chain(encoders.iter().map(|e| e.y))
+            .fold(f32::MAX, f32::min);
+        if min_x < f32::MAX {
+            for key in keys.iter_mut() {
+                key.x -= min_x;
+                key.y -= min_y;
+            }
+            for encoder in encoders.iter_mut() {
+                encoder.x -= min_x;
+                encoder.y -= min_y;
+            }
+        }
+
+        // VIA keymaps have no layout macro, the keys are listed in the order of the definition
+        let matrix_order = keys.iter().map(|key| (key.row, key.col)).collect();
+        Ok((keys, encoders, matrix_order))
+    }
+
+    /// Loads the info JSON of a keyboard, or reads the definition from the device if there is
+    /// none, as done for Vial keyboards.
+    pub fn load(settings: &KeyboardSettings) -> Result<Self, String> {
+        if !settings.keyboard_config_path.is_empty() {
+            return KeyboardInfo::new(&settings.keyboard_config_path).map_err(|e| {
+                format!(
+                    "Failed to parse keyboard info '{}': {e}",
+                    settings.keyboard_config_path
+                )
+            });
+        }
+        match &settings.device {
+            Some(device_id) => vial::read_keyboard_info(device_id),
+            None => Err("Neither a keyboard info JSON nor a device is selected".to_string()),
+        }
+    }
+
     pub fn get_layout_names(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut layout_names = Vec::new();
         for layout in &self.layouts {
@@ -160,3 +517,122 @@ impl KeyboardInfo {
         u16::from_str_radix(cleaned_hex, 16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// VIA definition in the layout of a macro pad with a knob, a split backspace and a choice
+    /// of bottom rows, written the way VIA definitions place their options and encoders.
+    const VIA_DEFINITION: &str = r##"{
+        "name": "Test Pad",
+        "vendorId": "0x4B42",
+        "productId": "0x6061",
+        "matrix": {"rows": 3, "cols": 4},
+        "layouts": {
+            "labels": ["Split Backspace", ["Bottom Row", "ANSI", "Tsangan"]],
+            "keymap": [
+                [{"c": "#777777"}, "0,0\n\n\n\n\n\n\n\n\ne", "0,1\n\n\n\n\n\n\n\n\ne",
+                 {"x": 0.5, "c": "#cccccc"}, "0,0", "0,1", {"w": 2}, "0,2\n\n\n0,0",
+                 {"x": 0.5}, "0,2\n\n\n0,1", "0,3\n\n\n0,1"],
+                [{"x": 2.5}, "1,0", "1,1", "1,2", "1,3"],
+                [{"x": 2.5, "w": 1.5}, "2,0\n\n\n1,0", {"w": 2.5}, "2,1\n\n\n1,0",
+                 {"x": 0.5, "w": 4}, "2,1\n\n\n1,1"]
+            ]
+        }
+    }"##;
+
+    fn load_layout(name: &str) -> KeyboardLayout {
+        let json: Value = serde_json::from_str(VIA_DEFINITION).unwrap();
+        KeyboardInfo::from_via_definition(&json)
+            .unwrap()
+            .get_layout(name)
+            .unwrap()
+    }
+
+    fn positions(layout: &KeyboardLayout) -> Vec<(u8, u8)> {
+        layout.keys.iter().map(|key| (key.row, key.col)).collect()
+    }
+
+    #[test]
+    fn reorders_legends_by_alignment() {
+        let legends = reorder_kle_legends("0,1\n\n\n1,2\n\n\n\n\n\ne", KLE_DEFAULT_ALIGN);
+        assert_eq!(legends[0], "0,1");
+        assert_eq!(legends[8], "1,2");
+        assert_eq!(legends[4], "e");
+
+        let legends = reorder_kle_legends("0,1\n\n\n1,2", 0);
+        assert_eq!(legends[0], "0,1");
+        assert_eq!(legends[8], "1,2");
+    }
+
+    #[test]
+    fn default_layout_uses_first_choices() {
+        let json: Value = serde_json::from_str(VIA_DEFINITION).unwrap();
+        let info = KeyboardInfo::from_via_definition(&json).unwrap();
+        assert_eq!(
+            info.get_layout_names().unwrap(),
+            [
+                "LAYOUT",
+                "LAYOUT (Split Backspace: On)",
+                "LAYOUT (Bottom Row: Tsangan)"
+            ]
+        );
+        assert_eq!(info.encoders, 1);
+
+        let layout = load_layout("LAYOUT");
+        assert_eq!(
+            positions(&layout),
+            [
+                (0, 0),
+                (0, 1),
+                (0, 2),
+                (1, 0),
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (2, 0),
+                (2, 1)
+            ]
+        );
+        let backspace = &layout.keys[2];
+        assert_eq!(backspace.w, 2.0);
+        assert_eq!(layout.keys[8].w, 2.5);
+    }
+
+    #[test]
+    fn encoders_are_not_keys() {
+        let layout = load_layout("LAYOUT");
+        assert_eq!(layout.encoders.len(), 1);
+        let encoder = &layout.encoders[0];
+        assert_eq!(encoder.index, 0);
+        assert_eq!(
+            (encoder.x, encoder.y, encoder.w, encoder.h),
+            (0.0, 0.0, 2.0, 1.0)
+        );
+        // The knob is left of the keys instead of on top of them
+        assert!(layout.keys.iter().all(|key| key.x >= 2.5));
+    }
+
+    #[test]
+    fn selecting_a_choice_replaces_the_keys_of_the_others() {
+        let layout = load_layout("LAYOUT (Split Backspace: On)");
+        let top_row: Vec<_> = layout.keys.iter().filter(|key| key.row == 0).collect();
+        assert_eq!(top_row.len(), 4);
+        assert!(top_row.iter().all(|key| key.w == 1.0));
+        // The bottom row keeps the first choice
+        assert!(positions(&layout).contains(&(2, 0)));
+
+        let layout = load_layout("LAYOUT (Bottom Row: Tsangan)");
+        let bottom_row: Vec<_> = layout.keys.iter().filter(|key| key.row == 2).collect();
+        assert_eq!(bottom_row.len(), 1);
+        assert_eq!((bottom_row[0].col, bottom_row[0].w), (1, 4.0));
+        // Chosen keys are moved to the place of the keys they replace
+        assert_eq!(bottom_row[0].x, 2.5);
+        assert_eq!(
+            layout.keys.iter().filter(|key| key.row == 0).count(),
+            3,
+            "the split backspace is not part of this layout"
+        );
+    }
+}
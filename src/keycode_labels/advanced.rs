@@ -1,92 +1,52 @@
-use crate::keycode_labels::basic::get_basic_keycode_label;
-use crate::keycode_labels::constants::*;
-use crate::keycode_labels::keycode_label::{KeycodeKind, KeycodeLabel};
+use qmk_via_api::keycodes::Keycode;
 
-pub fn get_advanced_keycode_label(keycode_bytes: u16) -> Option<KeycodeLabel> {
+use crate::keycode_labels::constants::*;
+use crate::keycode_labels::host_layout::get_host_keycode_label;
+use crate::keycode_labels::keycode_label::{KeycodeKind, KeycodeLabel, LabelOptions};
+use crate::keycode_labels::modifiers::{describe_mods, with_mods};
+
+pub fn get_advanced_keycode_label(
+    keycode_bytes: u16,
+    options: LabelOptions,
+) -> Option<KeycodeLabel> {
+    if let Some(label) = get_space_cadet_label(keycode_bytes, options) {
+        return Some(label);
+    }
     match keycode_bytes {
         input_bytes if QK_MODS.contains(&input_bytes) => {
-            let keycode = input_bytes & 0xff;
-            let keycode_str = get_basic_keycode_label(keycode)
-                .and_then(|k| k.long)
-                .unwrap_or_else(|| format!("0x{:02X}", keycode));
-
-            let input_modifiers = input_bytes & 0x1f00;
-
-            // Try to find exact matches first
-            if let Some((name, _)) = MODIFIER_KEY_TO_VALUE
-                .iter()
-                .find(|(_, v)| *v == input_modifiers)
-            {
-                return Some(KeycodeLabel {
-                    long: Some(format!("{}({})", name, keycode_str)),
-                    kind: KeycodeKind::Modifier,
-                    ..Default::default()
-                });
-            }
+            let mod_value = (input_bytes >> 8) & 0x1F;
+            let tap = get_tap_label(input_bytes & 0xFF, options);
+            // Keys with the shifted character above the unshifted one use the unshifted one
+            let key_label = |label: Option<String>| {
+                label.map(|label| label.rsplit('\n').next().unwrap_or_default().to_string())
+            };
+            let long = key_label(tap.long).unwrap_or_default();
+            let short = key_label(tap.short).unwrap_or_else(|| long.clone());
 
-            // Left and right side modifiers are mutually exclusive. Therefore a single boolean
-            // is used to indicate which side to use.
-            let is_right_side_mods = (input_modifiers & QK_RMODS_MIN) != 0;
-            let enabled: Vec<&str> = MODIFIER_KEY_TO_VALUE
-                .iter()
-                .filter(|(_, modifiers)| {
-                    if is_right_side_mods {
-                        *modifiers >= QK_RMODS_MIN
-                    } else {
-                        *modifiers < QK_RMODS_MIN
-                    }
-                })
-                .filter_map(|(modifiers_name, modifiers)| {
-                    if (input_modifiers & *modifiers) == *modifiers {
-                        Some(*modifiers_name)
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            if !enabled.is_empty() {
-                // Build nested parentheses style, e.g. LCTL(LALT(A))
-                let mut nested_mods = String::new();
-                for (i, part) in enabled.iter().enumerate() {
-                    if i > 0 {
-                        nested_mods.push('(');
-                    }
-                    nested_mods.push_str(part);
-                }
-                if !nested_mods.is_empty() {
-                    nested_mods.push('(');
-                }
-                nested_mods.push_str(&keycode_str);
-                for _ in 0..enabled.len() {
-                    nested_mods.push(')');
-                }
-
-                return Some(KeycodeLabel {
-                    long: Some(nested_mods),
-                    kind: KeycodeKind::Modifier,
-                    ..Default::default()
-                });
-            }
-
-            None
+            Some(KeycodeLabel {
+                long: Some(with_mods(
+                    &describe_mods(mod_value, false, options),
+                    &long,
+                    options,
+                )),
+                short: Some(with_mods(
+                    &describe_mods(mod_value, true, options),
+                    &short,
+                    options,
+                )),
+                kind: KeycodeKind::Modifier,
+                ..Default::default()
+            })
         }
         input_bytes if QK_MOD_TAP.contains(&input_bytes) => {
             let remainder = input_bytes & !(QK_MOD_TAP.start);
 
             let mod_value = (remainder >> 8) & 0x1F;
-            let mod_str = mod_value_to_string(mod_value);
-
-            let keycode = (remainder & 0xFF) as u8;
-            let keycode_str = get_basic_keycode_label(keycode as u16)
-                .and_then(|k| k.long)
-                .unwrap_or_else(|| format!("0x{:02X}", keycode));
-
-            Some(KeycodeLabel {
-                long: Some(format!("MT({},{})", mod_str, keycode_str)),
-                kind: KeycodeKind::Modifier,
-                ..Default::default()
-            })
+            let keycode = remainder & 0xFF;
+            Some(get_tap_hold_label(
+                get_tap_label(keycode, options),
+                describe_mods(mod_value, true, options),
+            ))
         }
         input_bytes if QK_LAYER_MOD.contains(&input_bytes) => {
             let remainder = input_bytes & !(QK_LAYER_MOD.start);
@@ -96,22 +56,23 @@ pub fn get_advanced_keycode_label(keycode_bytes: u16) -> Option<KeycodeLabel> {
             let layer = remainder >> shift;
 
             let mod_value = remainder & mask;
-            let mod_str = mod_value_to_string(mod_value);
 
+            // Nothing happens on tap, the layer is shown as legend and the modifiers below it
             Some(KeycodeLabel {
-                long: Some(format!("LM({},{})", layer, mod_str)),
+                long: Some(format!("LM({})", layer)),
+                short: Some(format!("L{}", layer)),
                 kind: KeycodeKind::Modifier,
                 layer_ref: Some(layer as u8),
+                hold: Some(describe_mods(mod_value, true, options)),
                 ..Default::default()
             })
         }
         input_bytes if QK_ONE_SHOT_MOD.contains(&input_bytes) => {
             let remainder = input_bytes & !(QK_ONE_SHOT_MOD.start);
 
-            let mod_str = mod_value_to_string(remainder);
-
             Some(KeycodeLabel {
-                long: Some(format!("OSM({})", mod_str)),
+                long: Some(format!("OSM {}", describe_mods(remainder, false, options))),
+                short: Some(describe_mods(remainder, true, options)),
                 kind: KeycodeKind::Modifier,
                 ..Default::default()
             })
@@ -121,52 +82,63 @@ pub fn get_advanced_keycode_label(keycode_bytes: u16) -> Option<KeycodeLabel> {
 
             let layer = remainder >> 8;
 
-            let keycode = (remainder & 0xFF) as u8;
-            let keycode_str = get_basic_keycode_label(keycode as u16)
-                .and_then(|k| k.long)
-                .unwrap_or_else(|| format!("0x{:02X}", keycode));
-
+            let keycode = remainder & 0xFF;
             Some(KeycodeLabel {
-                long: Some(format!("LT({},{})", layer, keycode_str)),
-                kind: KeycodeKind::Modifier,
                 layer_ref: Some(layer as u8),
-                ..Default::default()
+                ..get_tap_hold_label(get_tap_label(keycode, options), format!("L{layer}"))
             })
         }
+        // The top of the range holds the swap hands keys without a tap keycode
+        input_bytes if QK_SWAP_HANDS.contains(&input_bytes) && input_bytes & 0xFF < 0xF0 => {
+            let keycode = input_bytes & 0xFF;
+            Some(get_tap_hold_label(
+                get_tap_label(keycode, options),
+                "Swap".to_string(),
+            ))
+        }
         _ => None,
     }
 }
 
-fn mod_value_to_string(mod_mask: u16) -> String {
-    let mut mods = Vec::new();
-    if mod_mask & MOD_LCTL != 0 {
-        mods.push("MOD_LCTL");
-    }
-    if mod_mask & MOD_LSFT != 0 {
-        mods.push("MOD_LSFT");
-    }
-    if mod_mask & MOD_LALT != 0 {
-        mods.push("MOD_LALT");
-    }
-    if mod_mask & MOD_LGUI != 0 {
-        mods.push("MOD_LGUI");
-    }
-    if mod_mask & MOD_RCTL != 0 {
-        mods.push("MOD_RCTL");
-    }
-    if mod_mask & MOD_RSFT != 0 {
-        mods.push("MOD_RSFT");
-    }
-    if mod_mask & MOD_RALT != 0 {
-        mods.push("MOD_RALT");
-    }
-    if mod_mask & MOD_RGUI != 0 {
-        mods.push("MOD_RGUI");
-    }
+/// Returns the label of the basic keycode sent when a dual-role key is tapped.
+fn get_tap_label(keycode: u16, options: LabelOptions) -> KeycodeLabel {
+    get_host_keycode_label(keycode, options.host_layout).unwrap_or_else(|| KeycodeLabel {
+        long: Some(format!("0x{:02X}", keycode)),
+        ..Default::default()
+    })
+}
 
-    if mods.is_empty() {
-        "None".to_string()
-    } else {
-        mods.join(" | ")
+fn get_tap_hold_label(tap: KeycodeLabel, hold: String) -> KeycodeLabel {
+    KeycodeLabel {
+        kind: KeycodeKind::Modifier,
+        hold: Some(hold),
+        // AltGr applies to the tap only, which happens too quickly to hold it
+        altgr: None,
+        ..tap
     }
 }
+
+/// Space cadet keys send a parenthesis or Enter when tapped and act as modifier when held.
+fn get_space_cadet_label(keycode_bytes: u16, options: LabelOptions) -> Option<KeycodeLabel> {
+    let (tap, mods) = match Keycode::try_from(keycode_bytes).ok()? {
+        Keycode::QK_SPACE_CADET_LEFT_CTRL_PARENTHESIS_OPEN => ("(", MOD_LCTL),
+        Keycode::QK_SPACE_CADET_RIGHT_CTRL_PARENTHESIS_CLOSE => (")", MOD_RIGHT | MOD_LCTL),
+        Keycode::QK_SPACE_CADET_LEFT_SHIFT_PARENTHESIS_OPEN => ("(", MOD_LSFT),
+        Keycode::QK_SPACE_CADET_RIGHT_SHIFT_PARENTHESIS_CLOSE => (")", MOD_RIGHT | MOD_LSFT),
+        Keycode::QK_SPACE_CADET_LEFT_ALT_PARENTHESIS_OPEN => ("(", MOD_LALT),
+        Keycode::QK_SPACE_CADET_RIGHT_ALT_PARENTHESIS_CLOSE => (")", MOD_RIGHT | MOD_LALT),
+        Keycode::QK_SPACE_CADET_RIGHT_SHIFT_ENTER => {
+            let enter = get_tap_label(Keycode::KC_ENTER as u16, options);
+            return Some(get_tap_hold_label(
+                enter,
+                describe_mods(MOD_RIGHT | MOD_LSFT, true, options),
+            ));
+        }
+        _ => return None,
+    };
+    let tap = KeycodeLabel {
+        long: Some(tap.to_string()),
+        ..Default::default()
+    };
+    Some(get_tap_hold_label(tap, describe_mods(mods, true, options)))
+}
@@ -2776,34 +2776,14 @@ pub fn get_basic_keycode_label(keycode_bytes: u16) -> Option<KeycodeLabel> {
             short: Some("VelKey".to_string()),
             ..Default::default()
         }),
-        Keycode::QK_SPACE_CADET_LEFT_CTRL_PARENTHESIS_OPEN => Some(KeycodeLabel {
-            long: Some("LC (".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_RIGHT_CTRL_PARENTHESIS_CLOSE => Some(KeycodeLabel {
-            long: Some("RC )".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_LEFT_SHIFT_PARENTHESIS_OPEN => Some(KeycodeLabel {
-            long: Some("LS (".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_RIGHT_SHIFT_PARENTHESIS_CLOSE => Some(KeycodeLabel {
-            long: Some("RS )".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_LEFT_ALT_PARENTHESIS_OPEN => Some(KeycodeLabel {
-            long: Some("LA (".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_RIGHT_ALT_PARENTHESIS_CLOSE => Some(KeycodeLabel {
-            long: Some("RA )".to_string()),
-            ..Default::default()
-        }),
-        Keycode::QK_SPACE_CADET_RIGHT_SHIFT_ENTER => Some(KeycodeLabel {
-            long: Some("SftEnt".to_string()),
-            ..Default::default()
-        }),
+        // Dual-role keys with a tap and hold legend, see `get_advanced_keycode_label`
+        Keycode::QK_SPACE_CADET_LEFT_CTRL_PARENTHESIS_OPEN
+        | Keycode::QK_SPACE_CADET_RIGHT_CTRL_PARENTHESIS_CLOSE
+        | Keycode::QK_SPACE_CADET_LEFT_SHIFT_PARENTHESIS_OPEN
+        | Keycode::QK_SPACE_CADET_RIGHT_SHIFT_PARENTHESIS_CLOSE
+        | Keycode::QK_SPACE_CADET_LEFT_ALT_PARENTHESIS_OPEN
+        | Keycode::QK_SPACE_CADET_RIGHT_ALT_PARENTHESIS_CLOSE
+        | Keycode::QK_SPACE_CADET_RIGHT_SHIFT_ENTER => None,
         Keycode::QK_OUTPUT_AUTO => Some(KeycodeLabel {
             long: Some("Out Auto".to_string()),
             short: Some("OutAuto".to_string()),
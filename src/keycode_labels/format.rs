@@ -0,0 +1,99 @@
+use qmk_via_api::keycodes::Keycode;
+
+use crate::keycode_labels::constants::*;
+
+/// Modifier wrappers of `QK_MODS` keycodes, applied from the outside in.
+const MODIFIER_FUNCTIONS: &[(u16, &str, &str)] = &[
+    (QK_LCTL, "LCTL", "RCTL"),
+    (QK_LSFT, "LSFT", "RSFT"),
+    (QK_LALT, "LALT", "RALT"),
+    (QK_LGUI, "LGUI", "RGUI"),
+];
+
+/// Keycode functions taking a layer or an index, with the range they map to.
+const NUMBERED_FUNCTIONS: &[(&str, std::ops::Range<u16>)] = &[
+    ("TO", QK_TO),
+    ("MO", QK_MOMENTARY),
+    ("DF", QK_DEF_LAYER),
+    ("TG", QK_TOGGLE_LAYER),
+    ("OSL", QK_ONE_SHOT_LAYER),
+    ("TT", QK_LAYER_TAP_TOGGLE),
+    ("TD", QK_TAP_DANCE),
+];
+
+/// Formats the 5-bit modifiers of `MT()`, `LM()` and `OSM()`, where the highest bit selects the
+/// right hand side for all of them.
+fn mod_value_to_string(mod_mask: u16) -> String {
+    let is_right = mod_mask & MOD_RIGHT != 0;
+    let mods: Vec<&str> = [
+        (MOD_LCTL, "MOD_LCTL", "MOD_RCTL"),
+        (MOD_LSFT, "MOD_LSFT", "MOD_RSFT"),
+        (MOD_LALT, "MOD_LALT", "MOD_RALT"),
+        (MOD_LGUI, "MOD_LGUI", "MOD_RGUI"),
+    ]
+    .into_iter()
+    .filter(|(bit, _, _)| mod_mask & bit != 0)
+    .map(|(_, left, right)| if is_right { right } else { left })
+    .collect();
+
+    if mods.is_empty() {
+        "None".to_string()
+    } else {
+        mods.join(" | ")
+    }
+}
+
+/// Formats 5-bit modifiers for use in C, where modifiers without a name are written as a number.
+fn format_mods(mods: u16) -> String {
+    if mods & 0x0F == 0 {
+        format!("0x{mods:02X}")
+    } else {
+        mod_value_to_string(mods)
+    }
+}
+
+/// Formats a keycode as a QMK expression using the canonical keycode names, e.g. `KC_SPACE`,
+/// `LCTL(KC_C)` or `LT(1, KC_ENTER)`. Keycodes without a name are written as hex numbers.
+/// The result is valid in `keymap.json` as well as `keymap.c` and accepted by
+/// [`super::parse_keycode`].
+pub fn format_keycode(keycode: u16) -> String {
+    if let Ok(named) = Keycode::try_from(keycode) {
+        return named.as_ref().to_string();
+    }
+    if let Some((name, range)) = NUMBERED_FUNCTIONS
+        .iter()
+        .find(|(_, r)| r.contains(&keycode))
+    {
+        return format!("{name}({})", keycode - range.start);
+    }
+
+    match keycode {
+        k if QK_MODS.contains(&k) && k & 0x0F00 != 0 => {
+            let is_right = k & QK_RMODS_MIN != 0;
+            let mut expression = format_keycode(k & 0xFF);
+            for (bit, left, right) in MODIFIER_FUNCTIONS.iter().rev() {
+                if k & bit != 0 {
+                    let name = if is_right { right } else { left };
+                    expression = format!("{name}({expression})");
+                }
+            }
+            expression
+        }
+        k if QK_MOD_TAP.contains(&k) => {
+            let mods = (k >> 8) & 0x1F;
+            format!("MT({}, {})", format_mods(mods), format_keycode(k & 0xFF))
+        }
+        k if QK_LAYER_TAP.contains(&k) => {
+            let layer = (k >> 8) & 0x0F;
+            format!("LT({layer}, {})", format_keycode(k & 0xFF))
+        }
+        k if QK_LAYER_MOD.contains(&k) => {
+            let remainder = k - QK_LAYER_MOD.start;
+            format!("LM({}, {})", remainder >> 5, format_mods(remainder & 0x1F))
+        }
+        k if QK_ONE_SHOT_MOD.contains(&k) => {
+            format!("OSM({})", format_mods(k - QK_ONE_SHOT_MOD.start))
+        }
+        _ => format!("0x{keycode:04X}"),
+    }
+}
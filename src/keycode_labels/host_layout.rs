@@ -0,0 +1,264 @@
+use qmk_via_api::keycodes::Keycode;
+
+use crate::keycode_labels::basic::get_basic_keycode_label;
+use crate::keycode_labels::keycode_label::KeycodeLabel;
+use crate::settings::HostLayout;
+
+/// Characters typed by a key on the host, unshifted, shifted and with AltGr. Keys missing from a
+/// table type the same as on a US host, empty strings mark characters the key doesn't have.
+type HostKeys = &'static [(Keycode, &'static str, &'static str, &'static str)];
+
+const UK: HostKeys = &[
+    (Keycode::KC_GRAVE, "`", "¬", "¦"),
+    (Keycode::KC_2, "2", "\"", ""),
+    (Keycode::KC_3, "3", "£", ""),
+    (Keycode::KC_4, "4", "$", "€"),
+    (Keycode::KC_QUOTE, "'", "@", ""),
+    (Keycode::KC_BACKSLASH, "#", "~", ""),
+    (Keycode::KC_NONUS_HASH, "#", "~", ""),
+    (Keycode::KC_NONUS_BACKSLASH, "\\", "|", ""),
+];
+
+const GERMAN: HostKeys = &[
+    (Keycode::KC_GRAVE, "^", "°", ""),
+    (Keycode::KC_2, "2", "\"", "²"),
+    (Keycode::KC_3, "3", "§", "³"),
+    (Keycode::KC_6, "6", "&", ""),
+    (Keycode::KC_7, "7", "/", "{"),
+    (Keycode::KC_8, "8", "(", "["),
+    (Keycode::KC_9, "9", ")", "]"),
+    (Keycode::KC_0, "0", "=", "}"),
+    (Keycode::KC_MINUS, "ß", "?", "\\"),
+    (Keycode::KC_EQUAL, "´", "`", ""),
+    (Keycode::KC_Q, "q", "Q", "@"),
+    (Keycode::KC_E, "e", "E", "€"),
+    (Keycode::KC_Y, "z", "Z", ""),
+    (Keycode::KC_LEFT_BRACKET, "ü", "Ü", ""),
+    (Keycode::KC_RIGHT_BRACKET, "+", "*", "~"),
+    (Keycode::KC_BACKSLASH, "#", "'", ""),
+    (Keycode::KC_NONUS_HASH, "#", "'", ""),
+    (Keycode::KC_SEMICOLON, "ö", "Ö", ""),
+    (Keycode::KC_QUOTE, "ä", "Ä", ""),
+    (Keycode::KC_NONUS_BACKSLASH, "<", ">", "|"),
+    (Keycode::KC_Z, "y", "Y", ""),
+    (Keycode::KC_M, "m", "M", "µ"),
+    (Keycode::KC_COMMA, ",", ";", ""),
+    (Keycode::KC_DOT, ".", ":", ""),
+    (Keycode::KC_SLASH, "-", "_", ""),
+];
+
+const FRENCH: HostKeys = &[
+    (Keycode::KC_GRAVE, "²", "", ""),
+    (Keycode::KC_1, "&", "1", ""),
+    (Keycode::KC_2, "é", "2", "~"),
+    (Keycode::KC_3, "\"", "3", "#"),
+    (Keycode::KC_4, "'", "4", "{"),
+    (Keycode::KC_5, "(", "5", "["),
+    (Keycode::KC_6, "-", "6", "|"),
+    (Keycode::KC_7, "è", "7", "`"),
+    (Keycode::KC_8, "_", "8", "\\"),
+    (Keycode::KC_9, "ç", "9", "^"),
+    (Keycode::KC_0, "à", "0", "@"),
+    (Keycode::KC_MINUS, ")", "°", "]"),
+    (Keycode::KC_EQUAL, "=", "+", "}"),
+    (Keycode::KC_Q, "a", "A", ""),
+    (Keycode::KC_W, "z", "Z", ""),
+    (Keycode::KC_E, "e", "E", "€"),
+    (Keycode::KC_LEFT_BRACKET, "^", "¨", ""),
+    (Keycode::KC_RIGHT_BRACKET, "$", "£", "¤"),
+    (Keycode::KC_BACKSLASH, "*", "µ", ""),
+    (Keycode::KC_NONUS_HASH, "*", "µ", ""),
+    (Keycode::KC_A, "q", "Q", ""),
+    (Keycode::KC_SEMICOLON, "m", "M", ""),
+    (Keycode::KC_QUOTE, "ù", "%", ""),
+    (Keycode::KC_NONUS_BACKSLASH, "<", ">", ""),
+    (Keycode::KC_Z, "w", "W", ""),
+    (Keycode::KC_M, ",", "?", ""),
+    (Keycode::KC_COMMA, ";", ".", ""),
+    (Keycode::KC_DOT, ":", "/", ""),
+    (Keycode::KC_SLASH, "!", "§", ""),
+];
+
+const SPANISH: HostKeys = &[
+    (Keycode::KC_GRAVE, "º", "ª", "\\"),
+    (Keycode::KC_1, "1", "!", "|"),
+    (Keycode::KC_2, "2", "\"", "@"),
+    (Keycode::KC_3, "3", "·", "#"),
+    (Keycode::KC_4, "4", "$", "~"),
+    (Keycode::KC_5, "5", "%", "€"),
+    (Keycode::KC_6, "6", "&", "¬"),
+    (Keycode::KC_7, "7", "/", ""),
+    (Keycode::KC_8, "8", "(", ""),
+    (Keycode::KC_9, "9", ")", ""),
+    (Keycode::KC_0, "0", "=", ""),
+    (Keycode::KC_MINUS, "'", "?", ""),
+    (Keycode::KC_EQUAL, "¡", "¿", ""),
+    (Keycode::KC_E, "e", "E", "€"),
+    (Keycode::KC_LEFT_BRACKET, "`", "^", "["),
+    (Keycode::KC_RIGHT_BRACKET, "+", "*", "]"),
+    (Keycode::KC_BACKSLASH, "ç", "Ç", "}"),
+    (Keycode::KC_NONUS_HASH, "ç", "Ç", "}"),
+    (Keycode::KC_SEMICOLON, "ñ", "Ñ", ""),
+    (Keycode::KC_QUOTE, "´", "¨", "{"),
+    (Keycode::KC_NONUS_BACKSLASH, "<", ">", ""),
+    (Keycode::KC_COMMA, ",", ";", ""),
+    (Keycode::KC_DOT, ".", ":", ""),
+    (Keycode::KC_SLASH, "-", "_", ""),
+];
+
+const SWEDISH: HostKeys = &[
+    (Keycode::KC_GRAVE, "§", "½", ""),
+    (Keycode::KC_2, "2", "\"", "@"),
+    (Keycode::KC_3, "3", "#", "£"),
+    (Keycode::KC_4, "4", "¤", "$"),
+    (Keycode::KC_5, "5", "%", "€"),
+    (Keycode::KC_6, "6", "&", ""),
+    (Keycode::KC_7, "7", "/", "{"),
+    (Keycode::KC_8, "8", "(", "["),
+    (Keycode::KC_9, "9", ")", "]"),
+    (Keycode::KC_0, "0", "=", "}"),
+    (Keycode::KC_MINUS, "+", "?", "\\"),
+    (Keycode::KC_EQUAL, "´", "`", ""),
+    (Keycode::KC_E, "e", "E", "€"),
+    (Keycode::KC_LEFT_BRACKET, "å", "Å", ""),
+    (Keycode::KC_RIGHT_BRACKET, "¨", "^", "~"),
+    (Keycode::KC_BACKSLASH, "'", "*", ""),
+    (Keycode::KC_NONUS_HASH, "'", "*", ""),
+    (Keycode::KC_SEMICOLON, "ö", "Ö", ""),
+    (Keycode::KC_QUOTE, "ä", "Ä", ""),
+    (Keycode::KC_NONUS_BACKSLASH, "<", ">", "|"),
+    (Keycode::KC_M, "m", "M", "µ"),
+    (Keycode::KC_COMMA, ",", ";", ""),
+    (Keycode::KC_DOT, ".", ":", ""),
+    (Keycode::KC_SLASH, "-", "_", ""),
+];
+
+const DVORAK: HostKeys = &[
+    (Keycode::KC_MINUS, "[", "{", ""),
+    (Keycode::KC_EQUAL, "]", "}", ""),
+    (Keycode::KC_Q, "'", "\"", ""),
+    (Keycode::KC_W, ",", "<", ""),
+    (Keycode::KC_E, ".", ">", ""),
+    (Keycode::KC_R, "p", "P", ""),
+    (Keycode::KC_T, "y", "Y", ""),
+    (Keycode::KC_Y, "f", "F", ""),
+    (Keycode::KC_U, "g", "G", ""),
+    (Keycode::KC_I, "c", "C", ""),
+    (Keycode::KC_O, "r", "R", ""),
+    (Keycode::KC_P, "l", "L", ""),
+    (Keycode::KC_LEFT_BRACKET, "/", "?", ""),
+    (Keycode::KC_RIGHT_BRACKET, "=", "+", ""),
+    (Keycode::KC_S, "o", "O", ""),
+    (Keycode::KC_D, "e", "E", ""),
+    (Keycode::KC_F, "u", "U", ""),
+    (Keycode::KC_G, "i", "I", ""),
+    (Keycode::KC_H, "d", "D", ""),
+    (Keycode::KC_J, "h", "H", ""),
+    (Keycode::KC_K, "t", "T", ""),
+    (Keycode::KC_L, "n", "N", ""),
+    (Keycode::KC_SEMICOLON, "s", "S", ""),
+    (Keycode::KC_QUOTE, "-", "_", ""),
+    (Keycode::KC_Z, ";", ":", ""),
+    (Keycode::KC_X, "q", "Q", ""),
+    (Keycode::KC_C, "j", "J", ""),
+    (Keycode::KC_V, "k", "K", ""),
+    (Keycode::KC_B, "x", "X", ""),
+    (Keycode::KC_N, "b", "B", ""),
+    (Keycode::KC_COMMA, "w", "W", ""),
+    (Keycode::KC_DOT, "v", "V", ""),
+    (Keycode::KC_SLASH, "z", "Z", ""),
+];
+
+const COLEMAK: HostKeys = &[
+    (Keycode::KC_E, "f", "F", ""),
+    (Keycode::KC_R, "p", "P", ""),
+    (Keycode::KC_T, "g", "G", ""),
+    (Keycode::KC_Y, "j", "J", ""),
+    (Keycode::KC_U, "l", "L", ""),
+    (Keycode::KC_I, "u", "U", ""),
+    (Keycode::KC_O, "y", "Y", ""),
+    (Keycode::KC_P, ";", ":", ""),
+    (Keycode::KC_S, "r", "R", ""),
+    (Keycode::KC_D, "s", "S", ""),
+    (Keycode::KC_F, "t", "T", ""),
+    (Keycode::KC_G, "d", "D", ""),
+    (Keycode::KC_J, "n", "N", ""),
+    (Keycode::KC_K, "e", "E", ""),
+    (Keycode::KC_L, "i", "I", ""),
+    (Keycode::KC_SEMICOLON, "o", "O", ""),
+    (Keycode::KC_N, "k", "K", ""),
+];
+
+fn get_host_keys(layout: HostLayout) -> HostKeys {
+    match layout {
+        HostLayout::Us => &[],
+        HostLayout::Uk => UK,
+        HostLayout::German => GERMAN,
+        HostLayout::French => FRENCH,
+        HostLayout::Spanish => SPANISH,
+        HostLayout::Swedish => SWEDISH,
+        HostLayout::Dvorak => DVORAK,
+        HostLayout::Colemak => COLEMAK,
+    }
+}
+
+/// Returns the label of a basic keycode with the characters it types on the host layout.
+pub(super) fn get_host_keycode_label(
+    keycode_bytes: u16,
+    layout: HostLayout,
+) -> Option<KeycodeLabel> {
+    let Some((_, base, shifted, altgr)) = get_host_keys(layout)
+        .iter()
+        .find(|(keycode, ..)| keycode.clone() as u16 == keycode_bytes)
+    else {
+        return get_basic_keycode_label(keycode_bytes);
+    };
+
+    // Letters show the capital only, other keys the shifted character above the unshifted one
+    let long = if shifted.is_empty() {
+        base.to_string()
+    } else if base.chars().all(char::is_alphabetic) && base.to_uppercase() == *shifted {
+        shifted.to_string()
+    } else {
+        format!("{shifted}\n{base}")
+    };
+    Some(KeycodeLabel {
+        long: Some(long),
+        altgr: (!altgr.is_empty()).then(|| altgr.to_string()),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode_labels::{get_keycode_label, LabelOptions};
+
+    fn options(host_layout: HostLayout) -> LabelOptions {
+        LabelOptions {
+            host_layout,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn labels_keys_for_the_host_layout_of_the_caller() {
+        let keycode = Keycode::KC_Y as u16;
+        let us = get_keycode_label(keycode, options(HostLayout::Us));
+        let german = get_keycode_label(keycode, options(HostLayout::German));
+        assert_eq!(us.long.as_deref(), Some("Y"));
+        assert_eq!(german.long.as_deref(), Some("Z"));
+
+        let at = get_keycode_label(Keycode::KC_Q as u16, options(HostLayout::German));
+        assert_eq!(at.altgr.as_deref(), Some("@"));
+    }
+
+    #[test]
+    fn labels_the_tap_of_dual_role_keys_for_the_host_layout() {
+        // LT(1, KC_Y)
+        let keycode = 0x4100 | Keycode::KC_Y as u16;
+        let german = get_keycode_label(keycode, options(HostLayout::German));
+        assert_eq!(german.long.as_deref(), Some("Z"));
+        assert_eq!(german.hold.as_deref(), Some("L1"));
+    }
+}
@@ -1,6 +1,27 @@
 use crate::keycode_labels::advanced::get_advanced_keycode_label;
-use crate::keycode_labels::basic::get_basic_keycode_label;
+use crate::keycode_labels::host_layout::get_host_keycode_label;
 use crate::keycode_labels::layer::get_layer_keycode_label;
+use crate::settings::{HostLayout, ModifierLabels};
+
+/// How keycodes are written in labels, chosen by the caller so that e.g. the overlay and the
+/// terminal output can differ.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LabelOptions {
+    pub modifier_labels: ModifierLabels,
+    /// Layout of the host deciding the characters shown for basic keycodes
+    pub host_layout: HostLayout,
+}
+
+/// Modifiers are written as text, which keeps output meant for the terminal readable, and
+/// characters as typed on a US host.
+impl Default for LabelOptions {
+    fn default() -> Self {
+        LabelOptions {
+            modifier_labels: ModifierLabels::Text,
+            host_layout: HostLayout::Us,
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum KeycodeKind {
@@ -15,6 +36,11 @@ pub struct KeycodeLabel {
     pub symbol: Option<String>,
     pub kind: KeycodeKind,
     pub layer_ref: Option<u8>,
+    /// Function of a dual-role key when held, e.g. the modifier of a mod-tap. The other fields
+    /// then describe the key sent when tapped.
+    pub hold: Option<String>,
+    /// Character typed together with AltGr on the host layout, drawn small in the corner.
+    pub altgr: Option<String>,
 }
 
 impl Default for KeycodeLabel {
@@ -25,17 +51,56 @@ impl Default for KeycodeLabel {
             symbol: None,
             kind: KeycodeKind::Basic,
             layer_ref: None,
+            hold: None,
+            altgr: None,
+        }
+    }
+}
+
+impl KeycodeLabel {
+    /// Reduces legends showing the shifted and unshifted character, e.g. `!` above `1`, to the
+    /// shifted one.
+    pub fn apply_shift(&mut self) {
+        if self.kind != KeycodeKind::Basic {
+            return;
+        }
+        if let Some((shifted, _)) = self.long.as_deref().and_then(|long| long.split_once('\n')) {
+            self.long = Some(shifted.to_string());
+        }
+    }
+
+    /// Replaces the legend with the character typed together with AltGr, if the key has one.
+    pub fn apply_altgr(&mut self) {
+        if let Some(altgr) = self.altgr.take() {
+            self.long = Some(altgr);
+            self.short = None;
         }
     }
 }
 
-pub fn get_keycode_label(bytes: u16) -> KeycodeLabel {
-    get_basic_keycode_label(bytes)
+pub fn get_keycode_label(bytes: u16, options: LabelOptions) -> KeycodeLabel {
+    get_host_keycode_label(bytes, options.host_layout)
         .or_else(|| get_layer_keycode_label(bytes))
-        .or_else(|| get_advanced_keycode_label(bytes))
+        .or_else(|| get_advanced_keycode_label(bytes, options))
         .unwrap_or_else(|| get_hex_keycode_label(bytes))
 }
 
+/// Returns the most compact text describing a keycode, e.g. for listing it in a sequence.
+/// Dual-role keys are written as tap and hold function, e.g. `Space/L2`.
+pub fn get_keycode_name(bytes: u16, options: LabelOptions) -> String {
+    let label = get_keycode_label(bytes, options);
+    let name = label
+        .short
+        .or(label.long)
+        .or(label.symbol)
+        .unwrap_or_else(|| format!("0x{bytes:04X}"));
+    match label.hold {
+        Some(hold) if name.is_empty() => hold,
+        Some(hold) => format!("{name}/{hold}"),
+        None => name,
+    }
+}
+
 fn get_hex_keycode_label(keycode_bytes: u16) -> KeycodeLabel {
     KeycodeLabel {
         long: Some(format!("0x{:04X}", keycode_bytes)),
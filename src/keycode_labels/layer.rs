@@ -31,6 +31,10 @@ pub fn get_layer_keycode_label(keycode_bytes: u16) -> Option<KeycodeLabel> {
             let n = b - QK_KB.start;
             (Some(format!("CUSTOM({})", n)), None)
         }
+        b if QK_TAP_DANCE.contains(&b) => {
+            let n = b - QK_TAP_DANCE.start;
+            (Some(format!("TD({})", n)), None)
+        }
         b if QK_MACRO.contains(&b) => {
             let n = b - QK_MACRO.start;
             (Some(format!("MACRO({})", n)), None)
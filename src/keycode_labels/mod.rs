@@ -1,14 +1,24 @@
 mod advanced;
 mod basic;
-mod constants;
+pub mod constants;
+mod format;
+mod host_layout;
 mod keycode_label;
 mod layer;
+mod modifiers;
+mod parse;
 
 #[allow(unused_imports)]
 pub use advanced::get_advanced_keycode_label;
 #[allow(unused_imports)]
 pub use basic::get_basic_keycode_label;
 #[allow(unused_imports)]
-pub use keycode_label::{get_keycode_label, KeycodeKind, KeycodeLabel};
+pub use format::format_keycode;
+#[allow(unused_imports)]
+pub use keycode_label::{
+    get_keycode_label, get_keycode_name, KeycodeKind, KeycodeLabel, LabelOptions,
+};
 #[allow(unused_imports)]
 pub use layer::get_layer_keycode_label;
+#[allow(unused_imports)]
+pub use parse::parse_keycode;
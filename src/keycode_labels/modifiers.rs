@@ -0,0 +1,116 @@
+use crate::keycode_labels::constants::*;
+use crate::keycode_labels::keycode_label::LabelOptions;
+use crate::settings::ModifierLabels;
+
+#[cfg(target_os = "macos")]
+const SUPER_GLYPH: &str = egui_phosphor::regular::COMMAND;
+#[cfg(not(target_os = "macos"))]
+const SUPER_GLYPH: &str = egui_phosphor::regular::DIAMONDS_FOUR;
+
+/// Glyph, text and abbreviated text of each modifier. The glyphs are taken from the icon font,
+/// as the default fonts don't have all of ⌃ ⌥ ⇧ ⌘ ❖.
+const MODIFIERS: &[(u16, &str, &str, &str)] = &[
+    (MOD_LCTL, egui_phosphor::regular::CONTROL, "Ctrl", "Ctl"),
+    (MOD_LALT, egui_phosphor::regular::OPTION, "Alt", "Alt"),
+    (
+        MOD_LSFT,
+        egui_phosphor::regular::ARROW_FAT_UP,
+        "Shift",
+        "Sft",
+    ),
+    (MOD_LGUI, SUPER_GLYPH, "Super", "Sup"),
+];
+
+fn use_glyphs(options: LabelOptions) -> bool {
+    options.modifier_labels == ModifierLabels::Glyphs
+}
+
+/// Describes 5-bit modifiers, e.g. `⌃⇧` or `Ctrl+Shift`, with right hand modifiers marked by an
+/// `R`. `compact` abbreviates the text. An empty mask is written as `None` either way.
+pub(super) fn describe_mods(mod_mask: u16, compact: bool, options: LabelOptions) -> String {
+    let marker = if mod_mask & MOD_RIGHT != 0 { "R" } else { "" };
+    let mods = mod_mask & 0x0F;
+    if mods == 0 {
+        return "None".to_string();
+    }
+    if use_glyphs(options) {
+        let glyphs: String = MODIFIERS
+            .iter()
+            .filter(|(bit, ..)| mods & bit != 0)
+            .map(|(_, glyph, ..)| *glyph)
+            .collect();
+        return format!("{marker}{glyphs}");
+    }
+
+    if mods == MOD_LCTL | MOD_LSFT | MOD_LALT | MOD_LGUI {
+        return format!("{marker}Hyper");
+    }
+    if mods == MOD_LCTL | MOD_LSFT | MOD_LALT {
+        return format!("{marker}Meh");
+    }
+    let names: Vec<String> = MODIFIERS
+        .iter()
+        .filter(|(bit, ..)| mods & bit != 0)
+        .map(|(_, _, text, short)| format!("{marker}{}", if compact { short } else { text }))
+        .collect();
+    names.join("+")
+}
+
+/// Joins modifiers with the key they are applied to, e.g. `⌃⇧A` or `Ctrl+Shift+A`.
+pub(super) fn with_mods(mods: &str, key: &str, options: LabelOptions) -> String {
+    if use_glyphs(options) {
+        format!("{mods}{key}")
+    } else {
+        format!("{mods}+{key}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode_labels::get_keycode_label;
+    use crate::settings::HostLayout;
+
+    const GLYPHS: LabelOptions = LabelOptions {
+        modifier_labels: ModifierLabels::Glyphs,
+        host_layout: HostLayout::Us,
+    };
+    const TEXT: LabelOptions = LabelOptions {
+        modifier_labels: ModifierLabels::Text,
+        host_layout: HostLayout::Us,
+    };
+
+    #[test]
+    fn describes_no_modifiers_alike_in_both_modes() {
+        for compact in [false, true] {
+            assert_eq!(describe_mods(0, compact, GLYPHS), "None");
+            assert_eq!(describe_mods(0, compact, TEXT), "None");
+            assert_eq!(describe_mods(MOD_RIGHT, compact, GLYPHS), "None");
+        }
+    }
+
+    #[test]
+    fn describes_modifiers_as_chosen_by_the_caller() {
+        let mods = MOD_RIGHT | MOD_LCTL | MOD_LSFT;
+        assert_eq!(describe_mods(mods, false, TEXT), "RCtrl+RShift");
+        assert_eq!(describe_mods(mods, true, TEXT), "RCtl+RSft");
+        assert_eq!(
+            describe_mods(mods, false, GLYPHS),
+            format!(
+                "R{}{}",
+                egui_phosphor::regular::CONTROL,
+                egui_phosphor::regular::ARROW_FAT_UP
+            )
+        );
+
+        // C(KC_A), labelled in both modes one after another without shared state
+        let keycode = QK_LCTL | 0x04;
+        let text = get_keycode_label(keycode, TEXT);
+        let glyphs = get_keycode_label(keycode, GLYPHS);
+        assert_eq!(text.long.as_deref(), Some("Ctrl+A"));
+        assert_eq!(
+            glyphs.long,
+            Some(format!("{}A", egui_phosphor::regular::CONTROL))
+        );
+    }
+}
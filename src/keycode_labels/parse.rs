@@ -0,0 +1,639 @@
+use qmk_via_api::keycodes::Keycode;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::keycode_labels::basic::get_basic_keycode_label;
+use crate::keycode_labels::constants::*;
+
+/// Short aliases used by QMK Configurator and most keymaps, mapped to the full keycode names.
+const KEYCODE_ALIASES: &[(&str, &str)] = &[
+    ("XXXXXXX", "KC_NO"),
+    ("_______", "KC_TRANSPARENT"),
+    ("KC_TRNS", "KC_TRANSPARENT"),
+    ("KC_ENT", "KC_ENTER"),
+    ("KC_ESC", "KC_ESCAPE"),
+    ("KC_BSPC", "KC_BACKSPACE"),
+    ("KC_SPC", "KC_SPACE"),
+    ("KC_MINS", "KC_MINUS"),
+    ("KC_EQL", "KC_EQUAL"),
+    ("KC_LBRC", "KC_LEFT_BRACKET"),
+    ("KC_RBRC", "KC_RIGHT_BRACKET"),
+    ("KC_BSLS", "KC_BACKSLASH"),
+    ("KC_NUHS", "KC_NONUS_HASH"),
+    ("KC_SCLN", "KC_SEMICOLON"),
+    ("KC_QUOT", "KC_QUOTE"),
+    ("KC_GRV", "KC_GRAVE"),
+    ("KC_COMM", "KC_COMMA"),
+    ("KC_SLSH", "KC_SLASH"),
+    ("KC_CAPS", "KC_CAPS_LOCK"),
+    ("KC_PSCR", "KC_PRINT_SCREEN"),
+    ("KC_SCRL", "KC_SCROLL_LOCK"),
+    ("KC_BRMD", "KC_SCROLL_LOCK"),
+    ("KC_PAUS", "KC_PAUSE"),
+    ("KC_BRK", "KC_PAUSE"),
+    ("KC_BRMU", "KC_PAUSE"),
+    ("KC_INS", "KC_INSERT"),
+    ("KC_PGUP", "KC_PAGE_UP"),
+    ("KC_DEL", "KC_DELETE"),
+    ("KC_PGDN", "KC_PAGE_DOWN"),
+    ("KC_RGHT", "KC_RIGHT"),
+    ("KC_NUM", "KC_NUM_LOCK"),
+    ("KC_PSLS", "KC_KP_SLASH"),
+    ("KC_PAST", "KC_KP_ASTERISK"),
+    ("KC_PMNS", "KC_KP_MINUS"),
+    ("KC_PPLS", "KC_KP_PLUS"),
+    ("KC_PENT", "KC_KP_ENTER"),
+    ("KC_P1", "KC_KP_1"),
+    ("KC_P2", "KC_KP_2"),
+    ("KC_P3", "KC_KP_3"),
+    ("KC_P4", "KC_KP_4"),
+    ("KC_P5", "KC_KP_5"),
+    ("KC_P6", "KC_KP_6"),
+    ("KC_P7", "KC_KP_7"),
+    ("KC_P8", "KC_KP_8"),
+    ("KC_P9", "KC_KP_9"),
+    ("KC_P0", "KC_KP_0"),
+    ("KC_PDOT", "KC_KP_DOT"),
+    ("KC_NUBS", "KC_NONUS_BACKSLASH"),
+    ("KC_APP", "KC_APPLICATION"),
+    ("KC_PEQL", "KC_KP_EQUAL"),
+    ("KC_EXEC", "KC_EXECUTE"),
+    ("KC_SLCT", "KC_SELECT"),
+    ("KC_AGIN", "KC_AGAIN"),
+    ("KC_PSTE", "KC_PASTE"),
+    ("KC_LCAP", "KC_LOCKING_CAPS_LOCK"),
+    ("KC_LNUM", "KC_LOCKING_NUM_LOCK"),
+    ("KC_LSCR", "KC_LOCKING_SCROLL_LOCK"),
+    ("KC_PCMM", "KC_KP_COMMA"),
+    ("KC_INT1", "KC_INTERNATIONAL_1"),
+    ("KC_INT2", "KC_INTERNATIONAL_2"),
+    ("KC_INT3", "KC_INTERNATIONAL_3"),
+    ("KC_INT4", "KC_INTERNATIONAL_4"),
+    ("KC_INT5", "KC_INTERNATIONAL_5"),
+    ("KC_INT6", "KC_INTERNATIONAL_6"),
+    ("KC_INT7", "KC_INTERNATIONAL_7"),
+    ("KC_INT8", "KC_INTERNATIONAL_8"),
+    ("KC_INT9", "KC_INTERNATIONAL_9"),
+    ("KC_LNG1", "KC_LANGUAGE_1"),
+    ("KC_LNG2", "KC_LANGUAGE_2"),
+    ("KC_LNG3", "KC_LANGUAGE_3"),
+    ("KC_LNG4", "KC_LANGUAGE_4"),
+    ("KC_LNG5", "KC_LANGUAGE_5"),
+    ("KC_LNG6", "KC_LANGUAGE_6"),
+    ("KC_LNG7", "KC_LANGUAGE_7"),
+    ("KC_LNG8", "KC_LANGUAGE_8"),
+    ("KC_LNG9", "KC_LANGUAGE_9"),
+    ("KC_ERAS", "KC_ALTERNATE_ERASE"),
+    ("KC_SYRQ", "KC_SYSTEM_REQUEST"),
+    ("KC_CNCL", "KC_CANCEL"),
+    ("KC_CLR", "KC_CLEAR"),
+    ("KC_PRIR", "KC_PRIOR"),
+    ("KC_RETN", "KC_RETURN"),
+    ("KC_SEPR", "KC_SEPARATOR"),
+    ("KC_CLAG", "KC_CLEAR_AGAIN"),
+    ("KC_CRSL", "KC_CRSEL"),
+    ("KC_EXSL", "KC_EXSEL"),
+    ("KC_PWR", "KC_SYSTEM_POWER"),
+    ("KC_SLEP", "KC_SYSTEM_SLEEP"),
+    ("KC_WAKE", "KC_SYSTEM_WAKE"),
+    ("KC_MUTE", "KC_AUDIO_MUTE"),
+    ("KC_VOLU", "KC_AUDIO_VOL_UP"),
+    ("KC_VOLD", "KC_AUDIO_VOL_DOWN"),
+    ("KC_MNXT", "KC_MEDIA_NEXT_TRACK"),
+    ("KC_MPRV", "KC_MEDIA_PREV_TRACK"),
+    ("KC_MSTP", "KC_MEDIA_STOP"),
+    ("KC_MPLY", "KC_MEDIA_PLAY_PAUSE"),
+    ("KC_MSEL", "KC_MEDIA_SELECT"),
+    ("KC_EJCT", "KC_MEDIA_EJECT"),
+    ("KC_CALC", "KC_CALCULATOR"),
+    ("KC_MYCM", "KC_MY_COMPUTER"),
+    ("KC_WSCH", "KC_WWW_SEARCH"),
+    ("KC_WHOM", "KC_WWW_HOME"),
+    ("KC_WBAK", "KC_WWW_BACK"),
+    ("KC_WFWD", "KC_WWW_FORWARD"),
+    ("KC_WSTP", "KC_WWW_STOP"),
+    ("KC_WREF", "KC_WWW_REFRESH"),
+    ("KC_WFAV", "KC_WWW_FAVORITES"),
+    ("KC_MFFD", "KC_MEDIA_FAST_FORWARD"),
+    ("KC_MRWD", "KC_MEDIA_REWIND"),
+    ("KC_BRIU", "KC_BRIGHTNESS_UP"),
+    ("KC_BRID", "KC_BRIGHTNESS_DOWN"),
+    ("KC_CPNL", "KC_CONTROL_PANEL"),
+    ("KC_ASST", "KC_ASSISTANT"),
+    ("KC_MCTL", "KC_MISSION_CONTROL"),
+    ("KC_LPAD", "KC_LAUNCHPAD"),
+    ("KC_LCTL", "KC_LEFT_CTRL"),
+    ("KC_LSFT", "KC_LEFT_SHIFT"),
+    ("KC_LALT", "KC_LEFT_ALT"),
+    ("KC_LOPT", "KC_LEFT_ALT"),
+    ("KC_LGUI", "KC_LEFT_GUI"),
+    ("KC_LCMD", "KC_LEFT_GUI"),
+    ("KC_LWIN", "KC_LEFT_GUI"),
+    ("KC_RCTL", "KC_RIGHT_CTRL"),
+    ("KC_RSFT", "KC_RIGHT_SHIFT"),
+    ("KC_RALT", "KC_RIGHT_ALT"),
+    ("KC_ROPT", "KC_RIGHT_ALT"),
+    ("KC_ALGR", "KC_RIGHT_ALT"),
+    ("KC_RGUI", "KC_RIGHT_GUI"),
+    ("KC_RCMD", "KC_RIGHT_GUI"),
+    ("KC_RWIN", "KC_RIGHT_GUI"),
+    ("KC_MS_U", "QK_MOUSE_CURSOR_UP"),
+    ("KC_MS_D", "QK_MOUSE_CURSOR_DOWN"),
+    ("KC_MS_L", "QK_MOUSE_CURSOR_LEFT"),
+    ("KC_MS_R", "QK_MOUSE_CURSOR_RIGHT"),
+    ("KC_BTN1", "QK_MOUSE_BUTTON_1"),
+    ("KC_BTN2", "QK_MOUSE_BUTTON_2"),
+    ("KC_BTN3", "QK_MOUSE_BUTTON_3"),
+    ("KC_BTN4", "QK_MOUSE_BUTTON_4"),
+    ("KC_BTN5", "QK_MOUSE_BUTTON_5"),
+    ("KC_WH_U", "QK_MOUSE_WHEEL_UP"),
+    ("KC_WH_D", "QK_MOUSE_WHEEL_DOWN"),
+    ("KC_WH_L", "QK_MOUSE_WHEEL_LEFT"),
+    ("KC_WH_R", "QK_MOUSE_WHEEL_RIGHT"),
+    ("KC_ACL0", "QK_MOUSE_ACCELERATION_0"),
+    ("KC_ACL1", "QK_MOUSE_ACCELERATION_1"),
+    ("KC_ACL2", "QK_MOUSE_ACCELERATION_2"),
+    ("MS_UP", "QK_MOUSE_CURSOR_UP"),
+    ("MS_DOWN", "QK_MOUSE_CURSOR_DOWN"),
+    ("MS_LEFT", "QK_MOUSE_CURSOR_LEFT"),
+    ("MS_RGHT", "QK_MOUSE_CURSOR_RIGHT"),
+    ("MS_BTN1", "QK_MOUSE_BUTTON_1"),
+    ("MS_BTN2", "QK_MOUSE_BUTTON_2"),
+    ("MS_BTN3", "QK_MOUSE_BUTTON_3"),
+    ("MS_BTN4", "QK_MOUSE_BUTTON_4"),
+    ("MS_BTN5", "QK_MOUSE_BUTTON_5"),
+    ("MS_WHLU", "QK_MOUSE_WHEEL_UP"),
+    ("MS_WHLD", "QK_MOUSE_WHEEL_DOWN"),
+    ("MS_WHLL", "QK_MOUSE_WHEEL_LEFT"),
+    ("MS_WHLR", "QK_MOUSE_WHEEL_RIGHT"),
+    ("QK_BOOT", "QK_BOOTLOADER"),
+    ("QK_RBT", "QK_REBOOT"),
+    ("DB_TOGG", "QK_DEBUG_TOGGLE"),
+    ("EE_CLR", "QK_CLEAR_EEPROM"),
+    ("QK_GESC", "QK_GRAVE_ESCAPE"),
+    ("SC_LCPO", "QK_SPACE_CADET_LEFT_CTRL_PARENTHESIS_OPEN"),
+    ("SC_RCPC", "QK_SPACE_CADET_RIGHT_CTRL_PARENTHESIS_CLOSE"),
+    ("SC_LSPO", "QK_SPACE_CADET_LEFT_SHIFT_PARENTHESIS_OPEN"),
+    ("SC_RSPC", "QK_SPACE_CADET_RIGHT_SHIFT_PARENTHESIS_CLOSE"),
+    ("SC_LAPO", "QK_SPACE_CADET_LEFT_ALT_PARENTHESIS_OPEN"),
+    ("SC_RAPC", "QK_SPACE_CADET_RIGHT_ALT_PARENTHESIS_CLOSE"),
+    ("SC_SENT", "QK_SPACE_CADET_RIGHT_SHIFT_ENTER"),
+    ("SH_TOGG", "QK_SWAP_HANDS_TOGGLE"),
+    ("SH_TT", "QK_SWAP_HANDS_TAP_TOGGLE"),
+    ("SH_MON", "QK_SWAP_HANDS_MOMENTARY_ON"),
+    ("SH_MOFF", "QK_SWAP_HANDS_MOMENTARY_OFF"),
+    ("SH_OFF", "QK_SWAP_HANDS_OFF"),
+    ("SH_ON", "QK_SWAP_HANDS_ON"),
+    ("SH_OS", "QK_SWAP_HANDS_ONE_SHOT"),
+    ("NK_ON", "QK_MAGIC_NKRO_ON"),
+    ("NK_OFF", "QK_MAGIC_NKRO_OFF"),
+    ("NK_TOGG", "QK_MAGIC_TOGGLE_NKRO"),
+    ("CW_TOGG", "QK_CAPS_WORD_TOGGLE"),
+    ("AC_ON", "QK_AUTOCORRECT_ON"),
+    ("AC_OFF", "QK_AUTOCORRECT_OFF"),
+    ("AC_TOGG", "QK_AUTOCORRECT_TOGGLE"),
+    ("TL_LOWR", "QK_TRI_LAYER_LOWER"),
+    ("TL_UPPR", "QK_TRI_LAYER_UPPER"),
+    ("QK_REP", "QK_REPEAT_KEY"),
+    ("QK_AREP", "QK_ALT_REPEAT_KEY"),
+    ("QK_LEAD", "QK_LEADER"),
+    ("CM_ON", "QK_COMBO_ON"),
+    ("CM_OFF", "QK_COMBO_OFF"),
+    ("CM_TOGG", "QK_COMBO_TOGGLE"),
+    ("OS_ON", "QK_ONE_SHOT_ON"),
+    ("OS_OFF", "QK_ONE_SHOT_OFF"),
+    ("OS_TOGG", "QK_ONE_SHOT_TOGGLE"),
+    ("KO_TOGG", "QK_KEY_OVERRIDE_TOGGLE"),
+    ("KO_ON", "QK_KEY_OVERRIDE_ON"),
+    ("KO_OFF", "QK_KEY_OVERRIDE_OFF"),
+    ("DM_REC1", "QK_DYNAMIC_MACRO_RECORD_START_1"),
+    ("DM_REC2", "QK_DYNAMIC_MACRO_RECORD_START_2"),
+    ("DM_RSTP", "QK_DYNAMIC_MACRO_RECORD_STOP"),
+    ("DM_PLY1", "QK_DYNAMIC_MACRO_PLAY_1"),
+    ("DM_PLY2", "QK_DYNAMIC_MACRO_PLAY_2"),
+    ("AS_DOWN", "QK_AUTO_SHIFT_DOWN"),
+    ("AS_UP", "QK_AUTO_SHIFT_UP"),
+    ("AS_RPT", "QK_AUTO_SHIFT_REPORT"),
+    ("AS_ON", "QK_AUTO_SHIFT_ON"),
+    ("AS_OFF", "QK_AUTO_SHIFT_OFF"),
+    ("AS_TOGG", "QK_AUTO_SHIFT_TOGGLE"),
+    ("BL_ON", "QK_BACKLIGHT_ON"),
+    ("BL_OFF", "QK_BACKLIGHT_OFF"),
+    ("BL_TOGG", "QK_BACKLIGHT_TOGGLE"),
+    ("BL_DOWN", "QK_BACKLIGHT_DOWN"),
+    ("BL_UP", "QK_BACKLIGHT_UP"),
+    ("BL_STEP", "QK_BACKLIGHT_STEP"),
+    ("BL_BRTG", "QK_BACKLIGHT_TOGGLE_BREATHING"),
+    ("RGB_TOG", "QK_UNDERGLOW_TOGGLE"),
+    ("RGB_MOD", "QK_UNDERGLOW_MODE_NEXT"),
+    ("RGB_RMOD", "QK_UNDERGLOW_MODE_PREVIOUS"),
+    ("RGB_HUI", "QK_UNDERGLOW_HUE_UP"),
+    ("RGB_HUD", "QK_UNDERGLOW_HUE_DOWN"),
+    ("RGB_SAI", "QK_UNDERGLOW_SATURATION_UP"),
+    ("RGB_SAD", "QK_UNDERGLOW_SATURATION_DOWN"),
+    ("RGB_VAI", "QK_UNDERGLOW_VALUE_UP"),
+    ("RGB_VAD", "QK_UNDERGLOW_VALUE_DOWN"),
+    ("RGB_SPI", "QK_UNDERGLOW_SPEED_UP"),
+    ("RGB_SPD", "QK_UNDERGLOW_SPEED_DOWN"),
+    ("UG_TOGG", "QK_UNDERGLOW_TOGGLE"),
+    ("UG_NEXT", "QK_UNDERGLOW_MODE_NEXT"),
+    ("UG_PREV", "QK_UNDERGLOW_MODE_PREVIOUS"),
+    ("UG_HUEU", "QK_UNDERGLOW_HUE_UP"),
+    ("UG_HUED", "QK_UNDERGLOW_HUE_DOWN"),
+    ("UG_SATU", "QK_UNDERGLOW_SATURATION_UP"),
+    ("UG_SATD", "QK_UNDERGLOW_SATURATION_DOWN"),
+    ("UG_VALU", "QK_UNDERGLOW_VALUE_UP"),
+    ("UG_VALD", "QK_UNDERGLOW_VALUE_DOWN"),
+    ("UG_SPDU", "QK_UNDERGLOW_SPEED_UP"),
+    ("UG_SPDD", "QK_UNDERGLOW_SPEED_DOWN"),
+    ("RM_ON", "QK_RGB_MATRIX_ON"),
+    ("RM_OFF", "QK_RGB_MATRIX_OFF"),
+    ("RM_TOGG", "QK_RGB_MATRIX_TOGGLE"),
+    ("RM_NEXT", "QK_RGB_MATRIX_MODE_NEXT"),
+    ("RM_PREV", "QK_RGB_MATRIX_MODE_PREVIOUS"),
+    ("RM_HUEU", "QK_RGB_MATRIX_HUE_UP"),
+    ("RM_HUED", "QK_RGB_MATRIX_HUE_DOWN"),
+    ("RM_SATU", "QK_RGB_MATRIX_SATURATION_UP"),
+    ("RM_SATD", "QK_RGB_MATRIX_SATURATION_DOWN"),
+    ("RM_VALU", "QK_RGB_MATRIX_VALUE_UP"),
+    ("RM_VALD", "QK_RGB_MATRIX_VALUE_DOWN"),
+    ("RM_SPDU", "QK_RGB_MATRIX_SPEED_UP"),
+    ("RM_SPDD", "QK_RGB_MATRIX_SPEED_DOWN"),
+    ("RGB_M_P", "RGB_MODE_PLAIN"),
+    ("RGB_M_B", "RGB_MODE_BREATHE"),
+    ("RGB_M_R", "RGB_MODE_RAINBOW"),
+    ("RGB_M_SW", "RGB_MODE_SWIRL"),
+    ("RGB_M_SN", "RGB_MODE_SNAKE"),
+    ("RGB_M_K", "RGB_MODE_KNIGHT"),
+    ("RGB_M_X", "RGB_MODE_XMAS"),
+    ("RGB_M_G", "RGB_MODE_GRADIENT"),
+    ("RGB_M_T", "RGB_MODE_RGBTEST"),
+    ("RGB_M_TW", "RGB_MODE_TWINKLE"),
+];
+
+/// Shifted keycodes, which are `LSFT()` applied to the unshifted key.
+const SHIFTED_ALIASES: &[(&str, &str)] = &[
+    ("KC_TILD", "KC_GRAVE"),
+    ("KC_TILDE", "KC_GRAVE"),
+    ("KC_EXLM", "KC_1"),
+    ("KC_AT", "KC_2"),
+    ("KC_HASH", "KC_3"),
+    ("KC_DLR", "KC_4"),
+    ("KC_PERC", "KC_5"),
+    ("KC_CIRC", "KC_6"),
+    ("KC_AMPR", "KC_7"),
+    ("KC_ASTR", "KC_8"),
+    ("KC_LPRN", "KC_9"),
+    ("KC_RPRN", "KC_0"),
+    ("KC_UNDS", "KC_MINUS"),
+    ("KC_PLUS", "KC_EQUAL"),
+    ("KC_LCBR", "KC_LEFT_BRACKET"),
+    ("KC_RCBR", "KC_RIGHT_BRACKET"),
+    ("KC_PIPE", "KC_BACKSLASH"),
+    ("KC_COLN", "KC_SEMICOLON"),
+    ("KC_DQUO", "KC_QUOTE"),
+    ("KC_DQT", "KC_QUOTE"),
+    ("KC_LABK", "KC_COMMA"),
+    ("KC_LT", "KC_COMMA"),
+    ("KC_RABK", "KC_DOT"),
+    ("KC_GT", "KC_DOT"),
+    ("KC_QUES", "KC_SLASH"),
+];
+
+/// Shorthands for `MT()` missing from [`MODIFIER_KEY_TO_VALUE`], whose names get a `_T`
+/// suffix otherwise, e.g. `LCTL_T(kc)`.
+const MOD_TAP_ALIASES: &[(&str, u16)] = &[
+    ("CTL_T", MOD_LCTL),
+    ("SFT_T", MOD_LSFT),
+    ("ALT_T", MOD_LALT),
+    ("OPT_T", MOD_LALT),
+    ("LOPT_T", MOD_LALT),
+    ("ROPT_T", MOD_RIGHT | MOD_LALT),
+    ("GUI_T", MOD_LGUI),
+    ("CMD_T", MOD_LGUI),
+    ("WIN_T", MOD_LGUI),
+    ("LCS_T", MOD_LCTL | MOD_LSFT),
+    ("C_S_T", MOD_LCTL | MOD_LSFT),
+    ("RCA_T", MOD_RIGHT | MOD_LCTL | MOD_LALT),
+    ("RCAG_T", MOD_RIGHT | MOD_LCTL | MOD_LALT | MOD_LGUI),
+    ("ALL_T", MOD_LCTL | MOD_LSFT | MOD_LALT | MOD_LGUI),
+];
+
+/// Looks up the 5-bit modifiers of a modifier name like `LCTL`, `RALT` or `MEH`, as used with
+/// the `MOD_` and `OS_` prefixes and the `_T` suffix.
+fn named_mods(name: &str) -> Option<u16> {
+    MODIFIER_KEY_TO_VALUE
+        .iter()
+        // The single letter names are only used for the modifier functions like C(kc)
+        .find(|(n, _)| n.len() > 1 && *n == name)
+        .map(|(_, mods)| mods >> 8)
+}
+
+/// Keycode functions taking a layer, with the range they map to.
+const LAYER_FUNCTIONS: &[(&str, std::ops::Range<u16>)] = &[
+    ("MO", QK_MOMENTARY),
+    ("TG", QK_TOGGLE_LAYER),
+    ("TO", QK_TO),
+    ("TT", QK_LAYER_TAP_TOGGLE),
+    ("OSL", QK_ONE_SHOT_LAYER),
+    ("DF", QK_DEF_LAYER),
+];
+
+/// Keycode functions taking an index, with the range they map to.
+const INDEX_FUNCTIONS: &[(&str, std::ops::Range<u16>)] =
+    &[("TD", QK_TAP_DANCE), ("MACRO", QK_MACRO), ("CUSTOM", QK_KB)];
+
+/// Full keycode names, e.g. `KC_SPACE`, mapped to their values.
+fn keycode_names() -> &'static HashMap<String, u16> {
+    static NAMES: OnceLock<HashMap<String, u16>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        (0..=u16::MAX)
+            .filter_map(|value| {
+                let keycode = Keycode::try_from(value).ok()?;
+                Some((keycode.as_ref().to_string(), value))
+            })
+            .collect()
+    })
+}
+
+/// Legends of the basic keycodes, e.g. `Space`, so that `LT(1,Space)` can be written. Legends
+/// shared by several keycodes, like `Enter` for `KC_ENTER` and `KC_KP_ENTER`, are left out, as
+/// it's unclear which one is meant.
+fn basic_legends() -> &'static HashMap<String, u16> {
+    static LEGENDS: OnceLock<HashMap<String, u16>> = OnceLock::new();
+    LEGENDS.get_or_init(|| {
+        let mut legends: HashMap<String, Option<u16>> = HashMap::new();
+        for value in 0..=0xFF {
+            if let Some(legend) = get_basic_keycode_label(value).and_then(|label| label.long) {
+                legends
+                    .entry(legend)
+                    .and_modify(|keycode| *keycode = None)
+                    .or_insert(Some(value));
+            }
+        }
+        legends
+            .into_iter()
+            .filter_map(|(legend, keycode)| Some((legend, keycode?)))
+            .collect()
+    })
+}
+
+fn parse_name(name: &str) -> Option<u16> {
+    let names = keycode_names();
+    if let Some(value) = names.get(name) {
+        return Some(*value);
+    }
+    if let Some((_, full_name)) = KEYCODE_ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return names.get(*full_name).copied();
+    }
+    if let Some((_, unshifted)) = SHIFTED_ALIASES.iter().find(|(alias, _)| *alias == name) {
+        return names.get(*unshifted).map(|value| QK_LSFT | value);
+    }
+    if let Some(mods) = name.strip_prefix("OS_").and_then(named_mods) {
+        return Some(QK_ONE_SHOT_MOD.start | mods);
+    }
+    basic_legends().get(name).copied()
+}
+
+/// Parses 5-bit modifiers like `MOD_LCTL | MOD_LSFT`. Left and right hand modifiers can't be
+/// combined, any right hand modifier makes all of them right hand ones.
+fn parse_mods(expression: &str) -> Result<u16, String> {
+    expression
+        .split('|')
+        .map(|name| {
+            let name = name.trim();
+            if name == "None" {
+                return Ok(0);
+            }
+            name.strip_prefix("MOD_")
+                .and_then(named_mods)
+                .or_else(|| parse_number(name).ok().filter(|mods| *mods <= 0x1F))
+                .ok_or_else(|| format!("Unknown modifier '{name}'"))
+        })
+        .try_fold(0, |mods, parsed| Ok(mods | parsed?))
+}
+
+/// Parses the tap keycode of a mod tap or layer tap, which is limited to basic keycodes.
+fn parse_tap_keycode(expression: &str) -> Result<u16, String> {
+    let keycode = parse_keycode(expression)?;
+    if keycode > 0xFF {
+        return Err(format!("'{expression}' is not a basic keycode"));
+    }
+    Ok(keycode)
+}
+
+fn parse_number(text: &str) -> Result<u16, String> {
+    let text = text.trim();
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid number '{text}'"))
+}
+
+/// Splits `NAME(...)` into the name and the text between the parentheses.
+fn split_call(expression: &str) -> Option<(&str, &str)> {
+    let (name, rest) = expression.split_once('(')?;
+    let inner = rest.strip_suffix(')')?;
+    Some((name.trim(), inner))
+}
+
+/// Splits the arguments of a keycode function at top-level commas. The last argument takes the
+/// rest of the text, so that legends like `,` can be used as keycodes.
+fn split_args<'a>(name: &str, inner: &'a str, count: usize) -> Result<Vec<&'a str>, String> {
+    let mut args = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        if args.len() + 1 == count {
+            break;
+        }
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                args.push(inner[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    args.push(inner[start..].trim());
+    if args.len() != count || args.iter().any(|arg| arg.is_empty()) {
+        return Err(format!("{name}() takes {count} argument(s)"));
+    }
+    Ok(args)
+}
+
+/// Parses a QMK keycode expression like `KC_A`, `LCTL(KC_C)`, `MT(MOD_LCTL|MOD_LSFT, KC_ESC)`,
+/// `LT(1,KC_SPC)` or `OSM(MOD_HYPR)`, as written by [`super::format_keycode`]. The labels of
+/// layer keycodes like `MO(1)` parse back to the keycode as well. Labels of other keycodes are
+/// meant for display only and depend on the label settings, so they are not accepted.
+pub fn parse_keycode(expression: &str) -> Result<u16, String> {
+    let expression = expression.trim();
+    let Some((name, inner)) = split_call(expression) else {
+        return parse_name(expression)
+            .or_else(|| parse_number(expression).ok())
+            .ok_or_else(|| format!("Unknown keycode '{expression}'"));
+    };
+
+    if let Some((_, range)) = LAYER_FUNCTIONS.iter().find(|(n, _)| *n == name) {
+        let args = split_args(name, inner, 1)?;
+        let layer = parse_number(args[0])?;
+        if layer >= range.end - range.start {
+            return Err(format!("Layer {layer} is out of range for {name}()"));
+        }
+        return Ok(range.start + layer);
+    }
+    if let Some((_, range)) = INDEX_FUNCTIONS.iter().find(|(n, _)| *n == name) {
+        let args = split_args(name, inner, 1)?;
+        let index = parse_number(args[0])?;
+        if index >= range.end - range.start {
+            return Err(format!("Index {index} is out of range for {name}()"));
+        }
+        return Ok(range.start + index);
+    }
+    match name {
+        "LT" => {
+            let args = split_args(name, inner, 2)?;
+            let layer = parse_number(args[0])?;
+            if layer > 0x0F {
+                return Err(format!("Layer {layer} is out of range for LT()"));
+            }
+            return Ok(QK_LAYER_TAP.start | layer << 8 | parse_tap_keycode(args[1])?);
+        }
+        "MT" => {
+            let args = split_args(name, inner, 2)?;
+            let mods = parse_mods(args[0])?;
+            return Ok(QK_MOD_TAP.start | mods << 8 | parse_tap_keycode(args[1])?);
+        }
+        "LM" => {
+            let args = split_args(name, inner, 2)?;
+            let layer = parse_number(args[0])?;
+            if layer > 0x0F {
+                return Err(format!("Layer {layer} is out of range for LM()"));
+            }
+            return Ok(QK_LAYER_MOD.start | layer << 5 | parse_mods(args[1])?);
+        }
+        "OSM" => {
+            let args = split_args(name, inner, 1)?;
+            return Ok(QK_ONE_SHOT_MOD.start | parse_mods(args[0])?);
+        }
+        _ => {}
+    }
+    let mod_tap = MOD_TAP_ALIASES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, mods)| *mods)
+        .or_else(|| name.strip_suffix("_T").and_then(named_mods));
+    if let Some(mods) = mod_tap {
+        let args = split_args(name, inner, 1)?;
+        return Ok(QK_MOD_TAP.start | mods << 8 | parse_tap_keycode(args[0])?);
+    }
+    if let Some((_, mods)) = MODIFIER_KEY_TO_VALUE.iter().find(|(n, _)| *n == name) {
+        let args = split_args(name, inner, 1)?;
+        let keycode = parse_keycode(args[0])?;
+        if keycode >= QK_MODS.end {
+            return Err(format!("Modifiers can't be applied to '{}'", args[0]));
+        }
+        // Left and right modifiers can't be mixed, the right flag applies to all of them
+        return Ok(mods | keycode);
+    }
+    Err(format!("Unknown keycode function '{name}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode_labels::{format_keycode, get_layer_keycode_label};
+
+    #[test]
+    fn parses_expressions() {
+        assert_eq!(parse_keycode("KC_A"), Ok(0x04));
+        assert_eq!(parse_keycode("KC_SPC"), Ok(0x2C));
+        assert_eq!(parse_keycode("LCTL(KC_C)"), Ok(QK_LCTL | 0x06));
+        assert_eq!(parse_keycode("KC_EXLM"), Ok(QK_LSFT | 0x1E));
+        assert_eq!(
+            parse_keycode("MT(MOD_LCTL|MOD_LSFT, KC_ESC)"),
+            Ok(QK_MOD_TAP.start | (MOD_LCTL | MOD_LSFT) << 8 | 0x29)
+        );
+        assert_eq!(
+            parse_keycode("LT(2, KC_SPC)"),
+            Ok(QK_LAYER_TAP.start | 2 << 8 | 0x2C)
+        );
+        assert_eq!(
+            parse_keycode("OSM(MOD_HYPR)"),
+            Ok(QK_ONE_SHOT_MOD.start | 0x0F)
+        );
+        assert_eq!(parse_keycode("TO(3)"), Ok(QK_TO.start + 3));
+        assert_eq!(parse_keycode("LT(1,Space)"), parse_keycode("LT(1,KC_SPC)"));
+    }
+
+    #[test]
+    fn right_hand_modifiers_use_the_right_flag() {
+        let right_ctrl = MOD_RIGHT | MOD_LCTL;
+        assert_eq!(parse_mods("MOD_RCTL"), Ok(right_ctrl));
+        assert_eq!(
+            parse_keycode("RCTL_T(KC_A)"),
+            Ok(QK_MOD_TAP.start | right_ctrl << 8 | 0x04)
+        );
+        assert_eq!(
+            parse_keycode("OS_RALT"),
+            Ok(QK_ONE_SHOT_MOD.start | MOD_RIGHT | MOD_LALT)
+        );
+        assert_eq!(
+            parse_keycode("MT(MOD_MEH, KC_B)"),
+            Ok(QK_MOD_TAP.start | (MOD_LCTL | MOD_LSFT | MOD_LALT) << 8 | 0x05)
+        );
+        assert_eq!(parse_keycode("ALL_T(KC_C)"), parse_keycode("HYPR_T(KC_C)"));
+        assert_eq!(parse_keycode("RALT(KC_E)"), Ok(QK_RALT | 0x08));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(parse_keycode("KC_NOT_A_KEY").is_err());
+        assert!(parse_keycode("MO(32)").is_err());
+        assert!(parse_keycode("LT(1)").is_err());
+        assert!(parse_keycode("LT(1, LCTL(KC_A))").is_err());
+        assert!(parse_keycode("MT(MOD_FOO, KC_A)").is_err());
+        assert!(parse_keycode("FOO(KC_A)").is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_legends() {
+        assert!(parse_keycode("Enter").is_err());
+        assert_eq!(parse_keycode("KC_ENTER"), Ok(0x28));
+        assert_eq!(parse_keycode("KC_KP_ENTER"), Ok(0x58));
+    }
+
+    #[test]
+    fn formatted_keycodes_parse_back() {
+        for keycode in 0..=u16::MAX {
+            let expression = format_keycode(keycode);
+            assert_eq!(
+                parse_keycode(&expression),
+                Ok(keycode),
+                "'{expression}' does not parse back to 0x{keycode:04X}"
+            );
+        }
+    }
+
+    #[test]
+    fn layer_labels_parse_back() {
+        let ranges = [
+            QK_TO,
+            QK_MOMENTARY,
+            QK_DEF_LAYER,
+            QK_TOGGLE_LAYER,
+            QK_ONE_SHOT_LAYER,
+            QK_LAYER_TAP_TOGGLE,
+            QK_TAP_DANCE,
+            QK_MACRO,
+            QK_KB,
+        ];
+        for keycode in ranges.into_iter().flatten() {
+            let label = get_layer_keycode_label(keycode).unwrap().long.unwrap();
+            assert_eq!(parse_keycode(&label), Ok(keycode), "label '{label}'");
+        }
+    }
+}
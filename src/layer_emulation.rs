@@ -0,0 +1,447 @@
+use qmk_via_api::keycodes::Keycode;
+use std::time::{Duration, Instant};
+
+use crate::keycode_labels::constants::*;
+
+/// Number of taps on a `TT(n)` key toggling its layer, QMK's `TAPPING_TOGGLE`.
+const TAPPING_TOGGLE: u8 = 5;
+/// Number of layers fitting into the layer state.
+pub const LAYER_COUNT: u8 = 32;
+
+/// Layer action of a key, resolved when it is pressed like QMK does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LayerAction {
+    Momentary(u8),
+    LayerTap(u8),
+    Toggle(u8),
+    To(u8),
+    TapToggle(u8),
+    OneShot(u8),
+    Default(u8),
+    /// Any key without a layer action. Pressing it consumes a pending one-shot layer.
+    Other,
+}
+
+impl LayerAction {
+    fn from_keycode(keycode: u16, tri_layer: Option<[u8; 3]>) -> Self {
+        let low_layer = |range: std::ops::Range<u16>| (keycode - range.start) as u8 & 0x1F;
+        match keycode {
+            k if QK_MOMENTARY.contains(&k) => LayerAction::Momentary(low_layer(QK_MOMENTARY)),
+            k if QK_LAYER_TAP.contains(&k) => LayerAction::LayerTap(((k >> 8) & 0x0F) as u8),
+            k if QK_LAYER_MOD.contains(&k) => LayerAction::Momentary(((k >> 5) & 0x0F) as u8),
+            k if QK_TOGGLE_LAYER.contains(&k) => LayerAction::Toggle(low_layer(QK_TOGGLE_LAYER)),
+            k if QK_TO.contains(&k) => LayerAction::To(low_layer(QK_TO)),
+            k if QK_LAYER_TAP_TOGGLE.contains(&k) => {
+                LayerAction::TapToggle(low_layer(QK_LAYER_TAP_TOGGLE))
+            }
+            k if QK_ONE_SHOT_LAYER.contains(&k) => {
+                LayerAction::OneShot(low_layer(QK_ONE_SHOT_LAYER))
+            }
+            k if QK_DEF_LAYER.contains(&k) => LayerAction::Default(low_layer(QK_DEF_LAYER)),
+            k if k == Keycode::QK_TRI_LAYER_LOWER as u16 => match tri_layer {
+                Some([lower, _, _]) => LayerAction::Momentary(lower),
+                None => LayerAction::Other,
+            },
+            k if k == Keycode::QK_TRI_LAYER_UPPER as u16 => match tri_layer {
+                Some([_, upper, _]) => LayerAction::Momentary(upper),
+                None => LayerAction::Other,
+            },
+            _ => LayerAction::Other,
+        }
+    }
+}
+
+struct HeldKey {
+    row: u8,
+    col: u8,
+    action: LayerAction,
+    pressed_at: Instant,
+    /// Another key was pressed while this one was held
+    interrupted: bool,
+    /// The hold of a layer tap key exceeded the tapping term and its layer is active
+    holding: bool,
+    /// Consecutive taps on a tap toggle key before this press, counted like QMK
+    tap_count: u8,
+}
+
+/// Emulates the layer handling of QMK on the host for firmware not reporting its layer state.
+/// Fed with the press and release events of the keys, it keeps track of the layer state the
+/// firmware would have.
+pub struct LayerEmulator {
+    tapping_term: Duration,
+    /// Lower, upper and adjust layer. The adjust layer is active while lower and upper are.
+    tri_layer: Option<[u8; 3]>,
+    layer_state: u32,
+    default_layer_state: u32,
+    held_keys: Vec<HeldKey>,
+    /// One-shot layer waiting for the next key, and the key it was consumed by
+    one_shot_layer: Option<(u8, Option<(u8, u8)>)>,
+    /// Layer, count and release time of the last consecutive taps on a tap toggle key
+    tap_toggle: Option<(u8, u8, Instant)>,
+}
+
+impl LayerEmulator {
+    /// Creates the emulator. A tri layer with a layer outside of the layer state is ignored.
+    pub fn new(tapping_term: Duration, tri_layer: Option<[u8; 3]>) -> Self {
+        LayerEmulator {
+            tapping_term,
+            tri_layer: tri_layer.filter(|layers| layers.iter().all(|layer| *layer < LAYER_COUNT)),
+            layer_state: 0,
+            default_layer_state: 1,
+            held_keys: Vec::new(),
+            one_shot_layer: None,
+            tap_toggle: None,
+        }
+    }
+
+    pub fn layer_state(&self) -> u32 {
+        self.layer_state
+    }
+
+    pub fn default_layer_state(&self) -> u32 {
+        self.default_layer_state
+    }
+
+    /// Handles a key press. `keycode` is the keycode of the key on the currently active
+    /// layers.
+    pub fn press(&mut self, row: u8, col: u8, keycode: u16, now: Instant) {
+        let action = LayerAction::from_keycode(keycode, self.tri_layer);
+        for held in self.held_keys.iter_mut() {
+            held.interrupted = true;
+        }
+        if let Some((_, consumer @ None)) = &mut self.one_shot_layer {
+            if !matches!(action, LayerAction::OneShot(_)) {
+                *consumer = Some((row, col));
+            }
+        }
+
+        let tap_count = match (action, self.tap_toggle) {
+            (LayerAction::TapToggle(layer), Some((tap_layer, taps, last_tap)))
+                if tap_layer == layer && now.duration_since(last_tap) < self.tapping_term =>
+            {
+                taps.saturating_add(1)
+            }
+            _ => 0,
+        };
+
+        match action {
+            LayerAction::Momentary(layer) => self.layer_on(layer),
+            LayerAction::Toggle(layer) => self.layer_invert(layer),
+            LayerAction::To(layer) => self.layer_state = 1 << layer,
+            // Like QMK, the layer is inverted on press and release until the taps toggle it
+            LayerAction::TapToggle(layer) if tap_count < TAPPING_TOGGLE => self.layer_invert(layer),
+            LayerAction::TapToggle(_) => {}
+            LayerAction::OneShot(layer) => self.layer_on(layer),
+            LayerAction::Default(layer) => self.default_layer_state = 1 << layer,
+            LayerAction::LayerTap(_) | LayerAction::Other => {}
+        }
+        self.held_keys.push(HeldKey {
+            row,
+            col,
+            action,
+            pressed_at: now,
+            interrupted: false,
+            holding: false,
+            tap_count,
+        });
+        self.update_tri_layer();
+    }
+
+    pub fn release(&mut self, row: u8, col: u8, now: Instant) {
+        let Some(index) = self
+            .held_keys
+            .iter()
+            .position(|held| held.row == row && held.col == col)
+        else {
+            return;
+        };
+        let held = self.held_keys.remove(index);
+        let is_tap = now.duration_since(held.pressed_at) < self.tapping_term;
+
+        match held.action {
+            LayerAction::Momentary(layer) => self.layer_off(layer),
+            LayerAction::LayerTap(layer) if held.holding => self.layer_off(layer),
+            LayerAction::TapToggle(layer) => {
+                // A hold counts no taps, so it always restores the layer on release
+                let is_tap = is_tap && !held.interrupted;
+                let taps = if is_tap { held.tap_count.max(1) } else { 0 };
+                if taps <= TAPPING_TOGGLE {
+                    self.layer_invert(layer);
+                }
+                self.tap_toggle = is_tap.then_some((layer, taps, now));
+            }
+            LayerAction::OneShot(layer) => {
+                // Tapped one-shot layers stay active for the next key, held ones act like MO
+                if held.interrupted || !is_tap {
+                    self.layer_off(layer);
+                } else {
+                    self.one_shot_layer = Some((layer, None));
+                }
+            }
+            _ => {}
+        }
+
+        if let Some((layer, Some(consumer))) = self.one_shot_layer {
+            if consumer == (row, col) {
+                self.one_shot_layer = None;
+                self.layer_off(layer);
+            }
+        }
+        self.update_tri_layer();
+    }
+
+    /// Activates the layers of layer tap keys held for longer than the tapping term. Returns
+    /// whether the layer state changed.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        let mut activated = Vec::new();
+        for held in self.held_keys.iter_mut() {
+            if let LayerAction::LayerTap(layer) = held.action {
+                if !held.holding && now.duration_since(held.pressed_at) >= self.tapping_term {
+                    held.holding = true;
+                    activated.push(layer);
+                }
+            }
+        }
+        for layer in &activated {
+            self.layer_on(*layer);
+        }
+        self.update_tri_layer();
+        !activated.is_empty()
+    }
+
+    /// Returns the time at which the next layer tap key exceeds the tapping term.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held_keys
+            .iter()
+            .filter(|held| matches!(held.action, LayerAction::LayerTap(_)) && !held.holding)
+            .map(|held| held.pressed_at + self.tapping_term)
+            .min()
+    }
+
+    fn layer_on(&mut self, layer: u8) {
+        self.layer_state |= 1 << layer;
+    }
+
+    fn layer_off(&mut self, layer: u8) {
+        self.layer_state &= !(1 << layer);
+    }
+
+    fn layer_invert(&mut self, layer: u8) {
+        self.layer_state ^= 1 << layer;
+    }
+
+    fn update_tri_layer(&mut self) {
+        let Some([lower, upper, adjust]) = self.tri_layer else {
+            return;
+        };
+        let mask = (1 << lower) | (1 << upper);
+        if self.layer_state & mask == mask {
+            self.layer_on(adjust);
+        } else {
+            self.layer_off(adjust);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAPPING_TERM: Duration = Duration::from_millis(200);
+
+    fn momentary(layer: u8) -> u16 {
+        QK_MOMENTARY.start + layer as u16
+    }
+
+    fn layer_tap(layer: u8, keycode: Keycode) -> u16 {
+        QK_LAYER_TAP.start | (layer as u16) << 8 | keycode as u16
+    }
+
+    /// Taps the key at the given time, returning the time after the release.
+    fn tap(emulator: &mut LayerEmulator, keycode: u16, now: Instant) -> Instant {
+        emulator.press(0, 0, keycode, now);
+        let now = now + TAPPING_TERM / 4;
+        emulator.release(0, 0, now);
+        now + TAPPING_TERM / 4
+    }
+
+    #[test]
+    fn activates_momentary_layers_while_held() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        emulator.press(0, 0, momentary(2), now);
+        assert_eq!(emulator.layer_state(), 0b100);
+        emulator.press(0, 1, QK_LAYER_MOD.start | 1 << 5, now);
+        assert_eq!(emulator.layer_state(), 0b110);
+        emulator.release(0, 0, now);
+        emulator.release(0, 1, now);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn activates_layer_taps_only_when_held_past_the_tapping_term() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        let keycode = layer_tap(1, Keycode::KC_A);
+
+        emulator.press(0, 0, keycode, now);
+        assert_eq!(emulator.next_deadline(), Some(now + TAPPING_TERM));
+        assert!(!emulator.tick(now + TAPPING_TERM / 2));
+        emulator.release(0, 0, now + TAPPING_TERM / 2);
+        assert_eq!(emulator.layer_state(), 0);
+        assert_eq!(emulator.next_deadline(), None);
+
+        emulator.press(0, 0, keycode, now);
+        assert!(emulator.tick(now + TAPPING_TERM));
+        assert_eq!(emulator.layer_state(), 0b10);
+        assert_eq!(emulator.next_deadline(), None);
+        assert!(!emulator.tick(now + 2 * TAPPING_TERM));
+        emulator.release(0, 0, now + 2 * TAPPING_TERM);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn toggles_and_switches_layers() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        let now = tap(&mut emulator, QK_TOGGLE_LAYER.start + 1, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+        let now = tap(&mut emulator, QK_TOGGLE_LAYER.start + 2, now);
+        assert_eq!(emulator.layer_state(), 0b110);
+        let now = tap(&mut emulator, QK_TOGGLE_LAYER.start + 1, now);
+        assert_eq!(emulator.layer_state(), 0b100);
+
+        let now = tap(&mut emulator, QK_TOGGLE_LAYER.start + 1, now);
+        tap(&mut emulator, QK_TO.start + 3, now);
+        assert_eq!(emulator.layer_state(), 0b1000);
+    }
+
+    #[test]
+    fn sets_the_default_layer() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        assert_eq!(emulator.default_layer_state(), 0b1);
+        tap(&mut emulator, QK_DEF_LAYER.start + 2, now);
+        assert_eq!(emulator.default_layer_state(), 0b100);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn keeps_tapped_one_shot_layers_for_the_next_key() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        let now = tap(&mut emulator, QK_ONE_SHOT_LAYER.start + 1, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+
+        emulator.press(0, 1, Keycode::KC_A as u16, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+        emulator.release(0, 1, now);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn held_one_shot_layers_act_like_momentary_layers() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        emulator.press(0, 0, QK_ONE_SHOT_LAYER.start + 1, now);
+        emulator.press(0, 1, Keycode::KC_A as u16, now);
+        emulator.release(0, 1, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+        emulator.release(0, 0, now);
+        assert_eq!(emulator.layer_state(), 0);
+
+        emulator.press(0, 0, QK_ONE_SHOT_LAYER.start + 1, now);
+        emulator.release(0, 0, now + TAPPING_TERM);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn tap_toggle_inverts_the_layer_after_tapping_toggle_taps() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        let keycode = QK_LAYER_TAP_TOGGLE.start + 1;
+
+        emulator.press(0, 0, keycode, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+        emulator.release(0, 0, now + TAPPING_TERM);
+        assert_eq!(emulator.layer_state(), 0);
+
+        let mut now = now + 2 * TAPPING_TERM;
+        for _ in 1..TAPPING_TOGGLE {
+            now = tap(&mut emulator, keycode, now);
+            assert_eq!(emulator.layer_state(), 0);
+        }
+        now = tap(&mut emulator, keycode, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+
+        // Like in QMK, a single tap or hold of the toggled layer brings it back on release
+        now += 2 * TAPPING_TERM;
+        emulator.press(0, 0, keycode, now);
+        assert_eq!(emulator.layer_state(), 0);
+        now += TAPPING_TERM / 4;
+        emulator.release(0, 0, now);
+        assert_eq!(emulator.layer_state(), 0b10);
+        now += 2 * TAPPING_TERM;
+        emulator.press(0, 0, keycode, now);
+        emulator.release(0, 0, now + TAPPING_TERM);
+        assert_eq!(emulator.layer_state(), 0b10);
+
+        now += 2 * TAPPING_TERM;
+        for _ in 0..TAPPING_TOGGLE {
+            now = tap(&mut emulator, keycode, now);
+        }
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn activates_the_adjust_layer_while_lower_and_upper_are_held() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, Some([1, 2, 3]));
+        emulator.press(0, 0, momentary(1), now);
+        assert_eq!(emulator.layer_state(), 0b0010);
+        emulator.press(0, 1, momentary(2), now);
+        assert_eq!(emulator.layer_state(), 0b1110);
+        emulator.release(0, 0, now);
+        assert_eq!(emulator.layer_state(), 0b0100);
+        emulator.release(0, 1, now);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn tri_layer_keys_use_the_configured_layers() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, Some([4, 5, 6]));
+        emulator.press(0, 0, Keycode::QK_TRI_LAYER_LOWER as u16, now);
+        emulator.press(0, 1, Keycode::QK_TRI_LAYER_UPPER as u16, now);
+        assert_eq!(emulator.layer_state(), 0b111_0000);
+        emulator.release(0, 1, now);
+        assert_eq!(emulator.layer_state(), 0b001_0000);
+
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, None);
+        emulator.press(0, 0, Keycode::QK_TRI_LAYER_LOWER as u16, now);
+        assert_eq!(emulator.layer_state(), 0);
+    }
+
+    #[test]
+    fn activates_the_adjust_layer_once_a_layer_tap_is_held() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, Some([1, 2, 3]));
+        emulator.press(0, 0, momentary(1), now);
+        emulator.press(0, 1, layer_tap(2, Keycode::KC_SPACE), now);
+        assert_eq!(emulator.layer_state(), 0b0010);
+        assert!(!emulator.tick(now + TAPPING_TERM / 2));
+        assert!(emulator.tick(now + TAPPING_TERM));
+        assert_eq!(emulator.layer_state(), 0b1110);
+        emulator.release(0, 1, now + TAPPING_TERM);
+        assert_eq!(emulator.layer_state(), 0b0010);
+    }
+
+    #[test]
+    fn ignores_tri_layers_outside_of_the_layer_state() {
+        let now = Instant::now();
+        let mut emulator = LayerEmulator::new(TAPPING_TERM, Some([1, 2, 40]));
+        emulator.press(0, 0, momentary(1), now);
+        emulator.press(0, 1, momentary(2), now);
+        assert_eq!(emulator.layer_state(), 0b0110);
+    }
+}
@@ -0,0 +1,133 @@
+use qmk_via_api::api_commands::{ViaChannelId, ViaCommandId, ViaQmkRgbMatrixValue};
+use qmk_via_api::keycodes::Keycode;
+
+use crate::keycode_labels::KeycodeLabel;
+use crate::transport::Transport;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LightingSource {
+    RgbMatrix,
+    Underglow,
+}
+
+impl LightingSource {
+    fn channel(&self) -> ViaChannelId {
+        match self {
+            LightingSource::RgbMatrix => ViaChannelId::IdQmkRgbMatrixChannel,
+            LightingSource::Underglow => ViaChannelId::IdQmkRgblightChannel,
+        }
+    }
+}
+
+/// Lighting values as reported by the VIA custom value channels. All values are in the range
+/// used by QMK, i.e. 0 to 255.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LightingState {
+    pub source: LightingSource,
+    pub brightness: u8,
+    pub effect: u8,
+    pub speed: u8,
+    pub hue: u8,
+    pub saturation: u8,
+}
+
+// The value ids are the same for the RGB matrix and the RGB light (underglow) channel.
+const BRIGHTNESS: u8 = ViaQmkRgbMatrixValue::IdQmkRgbMatrixBrightness as u8;
+const EFFECT: u8 = ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffect as u8;
+const EFFECT_SPEED: u8 = ViaQmkRgbMatrixValue::IdQmkRgbMatrixEffectSpeed as u8;
+const COLOR: u8 = ViaQmkRgbMatrixValue::IdQmkRgbMatrixColor as u8;
+
+impl LightingState {
+    /// Reads the lighting state of the RGB matrix, falling back to the underglow if the firmware
+    /// has no RGB matrix.
+    pub fn query(transport: &dyn Transport) -> Result<Self, String> {
+        Self::query_source(transport, LightingSource::RgbMatrix)
+            .or_else(|_| Self::query_source(transport, LightingSource::Underglow))
+    }
+
+    fn query_source(transport: &dyn Transport, source: LightingSource) -> Result<Self, String> {
+        let get_value = |value_id: u8| {
+            let channel = source.channel() as u8;
+            transport
+                .command(ViaCommandId::CustomMenuGetValue as u8, &[channel, value_id])
+                .map(|response| (response[3], response[4]))
+        };
+
+        let (brightness, _) = get_value(BRIGHTNESS)?;
+        let (effect, _) = get_value(EFFECT)?;
+        let (speed, _) = get_value(EFFECT_SPEED)?;
+        let (hue, saturation) = get_value(COLOR)?;
+        Ok(LightingState {
+            source,
+            brightness,
+            effect,
+            speed,
+            hue,
+            saturation,
+        })
+    }
+
+    pub fn effect_description(&self) -> String {
+        format!("Effect {} · {}%", self.effect, percent(self.brightness))
+    }
+
+    /// Appends the current value to the labels of keys changing the lighting state, e.g. the
+    /// brightness to the label of a brightness up key.
+    pub fn annotate_label(&self, keycode: u16, label: &mut KeycodeLabel) {
+        let Some(value) = self.get_changed_value(keycode) else {
+            return;
+        };
+        label.long = label.long.take().map(|long| format!("{long} {value}"));
+        label.short = label.short.take().map(|short| format!("{short} {value}"));
+    }
+
+    fn get_changed_value(&self, keycode: u16) -> Option<String> {
+        use Keycode::*;
+
+        let keycode = Keycode::try_from(keycode).ok()?;
+        let is_matching_source = match keycode {
+            QK_RGB_MATRIX_MODE_NEXT
+            | QK_RGB_MATRIX_MODE_PREVIOUS
+            | QK_RGB_MATRIX_HUE_UP
+            | QK_RGB_MATRIX_HUE_DOWN
+            | QK_RGB_MATRIX_SATURATION_UP
+            | QK_RGB_MATRIX_SATURATION_DOWN
+            | QK_RGB_MATRIX_VALUE_UP
+            | QK_RGB_MATRIX_VALUE_DOWN
+            | QK_RGB_MATRIX_SPEED_UP
+            | QK_RGB_MATRIX_SPEED_DOWN => self.source == LightingSource::RgbMatrix,
+            _ => self.source == LightingSource::Underglow,
+        };
+        if !is_matching_source {
+            return None;
+        }
+
+        match keycode {
+            QK_RGB_MATRIX_MODE_NEXT
+            | QK_RGB_MATRIX_MODE_PREVIOUS
+            | QK_UNDERGLOW_MODE_NEXT
+            | QK_UNDERGLOW_MODE_PREVIOUS => Some(format!("#{}", self.effect)),
+            QK_RGB_MATRIX_HUE_UP
+            | QK_RGB_MATRIX_HUE_DOWN
+            | QK_UNDERGLOW_HUE_UP
+            | QK_UNDERGLOW_HUE_DOWN => Some(format!("{}°", self.hue as u32 * 360 / 256)),
+            QK_RGB_MATRIX_SATURATION_UP
+            | QK_RGB_MATRIX_SATURATION_DOWN
+            | QK_UNDERGLOW_SATURATION_UP
+            | QK_UNDERGLOW_SATURATION_DOWN => Some(format!("{}%", percent(self.saturation))),
+            QK_RGB_MATRIX_VALUE_UP
+            | QK_RGB_MATRIX_VALUE_DOWN
+            | QK_UNDERGLOW_VALUE_UP
+            | QK_UNDERGLOW_VALUE_DOWN => Some(format!("{}%", percent(self.brightness))),
+            QK_RGB_MATRIX_SPEED_UP
+            | QK_RGB_MATRIX_SPEED_DOWN
+            | QK_UNDERGLOW_SPEED_UP
+            | QK_UNDERGLOW_SPEED_DOWN => Some(format!("{}%", percent(self.speed))),
+            _ => None,
+        }
+    }
+}
+
+fn percent(value: u8) -> u32 {
+    (value as u32 * 100 + 127) / 255
+}
@@ -0,0 +1,235 @@
+use qmk_via_api::keycodes::Keycode;
+
+use crate::keycode_labels::constants::QK_MACRO;
+use crate::keycode_labels::{self, KeycodeLabel, LabelOptions};
+
+// Escape sequences of the QMK send string format used by the dynamic macro buffer
+const SS_QMK_PREFIX: u8 = 1;
+const SS_TAP_CODE: u8 = 1;
+const SS_DOWN_CODE: u8 = 2;
+const SS_UP_CODE: u8 = 3;
+const SS_DELAY_CODE: u8 = 4;
+// Taps, presses and releases of 16 bit keycodes, written by newer configurators
+const SS_TAP_CODE_16: u8 = 5;
+const SS_DOWN_CODE_16: u8 = 6;
+const SS_UP_CODE_16: u8 = 7;
+
+/// Vial stores delays as two bytes instead of decimal digits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacroFormat {
+    Via,
+    Vial,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MacroAction {
+    Text(String),
+    Tap(u16),
+    Down(u16),
+    Up(u16),
+    Delay(u32),
+}
+
+/// A dynamic macro as stored in the VIA macro buffer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Macro {
+    pub actions: Vec<MacroAction>,
+}
+
+/// A step of a macro as shown to the user, with modifier presses folded into chords.
+enum Step {
+    Text(String),
+    Chord(Vec<u16>, u16),
+    Down(u16),
+    Up(u16),
+    Delay(u32),
+}
+
+impl Macro {
+    /// Splits the NUL separated macro buffer into `count` macros.
+    pub fn parse_buffer(buffer: &[u8], count: usize, format: MacroFormat) -> Vec<Macro> {
+        let mut macros = Vec::with_capacity(count);
+        let mut rest = buffer;
+        while macros.len() < count {
+            let (parsed, remaining) = Self::parse(rest, format);
+            macros.push(parsed);
+            rest = remaining;
+        }
+        macros
+    }
+
+    /// Parses a single macro and returns it together with the bytes following its terminator.
+    fn parse(bytes: &[u8], format: MacroFormat) -> (Macro, &[u8]) {
+        let mut actions = Vec::new();
+        let mut text = String::new();
+        let mut i = 0;
+        let flush_text = |actions: &mut Vec<MacroAction>, text: &mut String| {
+            if !text.is_empty() {
+                actions.push(MacroAction::Text(std::mem::take(text)));
+            }
+        };
+
+        while i < bytes.len() && bytes[i] != 0 {
+            if bytes[i] != SS_QMK_PREFIX {
+                text.push(bytes[i] as char);
+                i += 1;
+                continue;
+            }
+
+            flush_text(&mut actions, &mut text);
+            let code = bytes.get(i + 1).copied().unwrap_or(0);
+            let byte = |offset: usize| bytes.get(i + offset).copied().unwrap_or(0) as u16;
+            let (action, length) = match code {
+                SS_TAP_CODE => (MacroAction::Tap(byte(2)), 3),
+                SS_DOWN_CODE => (MacroAction::Down(byte(2)), 3),
+                SS_UP_CODE => (MacroAction::Up(byte(2)), 3),
+                SS_TAP_CODE_16 => (MacroAction::Tap(Self::keycode_16(byte(2), byte(3))), 4),
+                SS_DOWN_CODE_16 => (MacroAction::Down(Self::keycode_16(byte(2), byte(3))), 4),
+                SS_UP_CODE_16 => (MacroAction::Up(Self::keycode_16(byte(2), byte(3))), 4),
+                SS_DELAY_CODE if format == MacroFormat::Vial => {
+                    // Both bytes are offset by one to avoid zero bytes
+                    let delay = byte(2).saturating_sub(1) + byte(3).saturating_sub(1) * 255;
+                    (MacroAction::Delay(delay as u32), 4)
+                }
+                SS_DELAY_CODE => {
+                    // The delay is written as decimal digits terminated by '|'
+                    let digits: String = bytes[(i + 2).min(bytes.len())..]
+                        .iter()
+                        .take_while(|b| b.is_ascii_digit())
+                        .map(|b| *b as char)
+                        .collect();
+                    (
+                        MacroAction::Delay(digits.parse().unwrap_or(0)),
+                        3 + digits.len(),
+                    )
+                }
+                _ => {
+                    i += 2;
+                    continue;
+                }
+            };
+            actions.push(action);
+            i += length;
+        }
+        flush_text(&mut actions, &mut text);
+
+        let rest = bytes.get(i + 1..).unwrap_or_default();
+        (Macro { actions }, rest)
+    }
+
+    /// Zero bytes can't be part of the buffer, so a low byte of zero is stored in the high byte.
+    fn keycode_16(low: u16, high: u16) -> u16 {
+        let keycode = low | (high << 8);
+        if keycode > 0xFF00 {
+            (keycode & 0xFF) << 8
+        } else {
+            keycode
+        }
+    }
+
+    fn is_modifier(keycode: u16) -> bool {
+        (Keycode::KC_LEFT_CTRL as u16..=Keycode::KC_RIGHT_GUI as u16).contains(&keycode)
+    }
+
+    /// Folds the presses and releases of modifiers around taps into chords.
+    fn steps(&self) -> Vec<Step> {
+        let mut steps = Vec::new();
+        let mut held_modifiers: Vec<u16> = Vec::new();
+        let mut chord_pending = false;
+        for action in &self.actions {
+            match *action {
+                MacroAction::Text(ref text) => steps.push(Step::Text(text.clone())),
+                MacroAction::Down(keycode) if Self::is_modifier(keycode) => {
+                    held_modifiers.push(keycode);
+                    chord_pending = true;
+                }
+                MacroAction::Up(keycode) if held_modifiers.contains(&keycode) => {
+                    // Modifiers released without any tap in between are a chord on their own
+                    if chord_pending {
+                        let (last, rest) = held_modifiers.split_last().expect("Modifier is held");
+                        steps.push(Step::Chord(rest.to_vec(), *last));
+                        chord_pending = false;
+                    }
+                    held_modifiers.retain(|held| *held != keycode);
+                }
+                MacroAction::Tap(keycode) => {
+                    steps.push(Step::Chord(held_modifiers.clone(), keycode));
+                    chord_pending = false;
+                }
+                MacroAction::Down(keycode) => steps.push(Step::Down(keycode)),
+                MacroAction::Up(keycode) => steps.push(Step::Up(keycode)),
+                MacroAction::Delay(ms) => steps.push(Step::Delay(ms)),
+            }
+        }
+        steps
+    }
+
+    fn step_description(step: &Step, options: LabelOptions) -> String {
+        match step {
+            Step::Text(text) => format!("\"{text}\""),
+            Step::Chord(modifiers, keycode) => modifiers
+                .iter()
+                .chain(std::iter::once(keycode))
+                .map(|keycode| keycode_labels::get_keycode_name(*keycode, options))
+                .collect::<Vec<_>>()
+                .join("+"),
+            Step::Down(keycode) => {
+                format!("{}↓", keycode_labels::get_keycode_name(*keycode, options))
+            }
+            Step::Up(keycode) => {
+                format!("{}↑", keycode_labels::get_keycode_name(*keycode, options))
+            }
+            Step::Delay(ms) => format!("{ms} ms"),
+        }
+    }
+
+    /// Short form for key legends. Text is shown without quotes and delays are left out.
+    pub fn abbreviation(&self, options: LabelOptions) -> String {
+        self.steps()
+            .iter()
+            .filter_map(|step| match step {
+                Step::Text(text) => Some(text.trim().to_string()),
+                Step::Delay(_) => None,
+                step => Some(Self::step_description(step, options)),
+            })
+            .filter(|description| !description.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Returns the macro index of a `MACRO(n)` keycode.
+    pub fn get_index(keycode: u16) -> Option<usize> {
+        QK_MACRO
+            .contains(&keycode)
+            .then(|| (keycode - QK_MACRO.start) as usize)
+    }
+
+    /// Annotates the label of a `MACRO(n)` keycode with the abbreviation of the macro.
+    pub fn annotate_label(
+        macros: &[Macro],
+        keycode: u16,
+        label: &mut KeycodeLabel,
+        options: LabelOptions,
+    ) {
+        let Some(macro_) = Self::get_index(keycode).and_then(|index| macros.get(index)) else {
+            return;
+        };
+        let abbreviation = macro_.abbreviation(options);
+        if abbreviation.is_empty() {
+            return;
+        }
+        label.long = Some(abbreviation);
+        label.short = None;
+        label.symbol = None;
+    }
+
+    /// Full expansion of the macro, e.g. `Ctrl+C 100 ms "text"`.
+    pub fn describe(&self, options: LabelOptions) -> String {
+        let steps: Vec<String> = self
+            .steps()
+            .iter()
+            .map(|step| Self::step_description(step, options))
+            .collect();
+        steps.join(" ")
+    }
+}
@@ -1,27 +1,57 @@
 #![cfg_attr(target_os = "windows", windows_subsystem = "windows")]
+mod cheat_sheet;
+mod cli;
+mod diagnostics;
+mod heatmap;
 mod key_matrix;
 mod keyboard;
 mod keyboard_info;
 mod keycode_labels;
+mod layer_emulation;
+mod lighting;
+mod macros;
+mod matrix_polling;
 mod overlay_window;
+mod protocol;
+mod qmk_keymap;
+mod session;
 mod settings;
 mod settings_window;
+mod status;
+mod transport;
 mod tray;
+mod vial;
 
+use clap::Parser;
+use cli::{Cli, Command, SettingsArgs};
 use eframe::egui::{self, IconData};
 use keyboard::Keyboard;
 use keyboard_info::KeyboardInfo;
+use keycode_labels::LabelOptions;
 use overlay_window::Overlay;
-use settings::Settings;
+use qmk_keymap::QmkKeymap;
+use settings::{KeyboardSettings, ModifierLabels, Settings};
 use settings_window::SettingsApp;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use transport::{Connector, SimulatedTransport, Transport};
 
 const SETTINGS_FILE: &str = "settings.ini";
 
-fn run_overlay_app(keyboard: Keyboard, settings: &Settings) -> Result<(), eframe::Error> {
-    let _tray_icon = tray::create_tray_icon();
+/// Time to wait for the keymap to be read by commands working with the keymap
+const KEYMAP_TIMEOUT: Duration = Duration::from_secs(10);
 
+fn run_overlay_app(keyboards: Vec<Keyboard>, settings: &Settings) -> Result<(), eframe::Error> {
+    let offline = keyboards.iter().any(Keyboard::is_offline);
+    let statistics = keyboards
+        .iter()
+        .any(|keyboard| keyboard.get_press_statistics().is_some());
+    let tray = tray::create_tray_icon(offline, statistics);
+    // Returning on close lets the statistics be saved before exiting
     let options = eframe::NativeOptions {
+        run_and_return: true,
         viewport: egui::ViewportBuilder::default()
             .with_decorations(false)
             .with_taskbar(false)
@@ -39,17 +69,12 @@ fn run_overlay_app(keyboard: Keyboard, settings: &Settings) -> Result<(), eframe
             egui_phosphor::add_to_fonts(&mut fonts, egui_phosphor::Variant::Regular);
             cc.egui_ctx.set_fonts(fonts);
 
-            Ok(Box::new(Overlay::new(
-                keyboard,
-                settings.size,
-                settings.margin,
-                settings.position,
-            )))
+            Ok(Box::new(Overlay::new(keyboards, tray, settings)))
         }),
     )
 }
 
-fn show_settings_window() -> Option<Settings> {
+fn show_settings_window(settings_path: &str) -> Option<Settings> {
     let shared = Arc::new(Mutex::new(Settings::default()));
     let icon = {
         let image = image::load_from_memory(include_bytes!("../resources/icon.ico"))
@@ -57,8 +82,8 @@ fn show_settings_window() -> Option<Settings> {
             .into_rgba8();
         let (width, height) = image.dimensions();
         IconData {
-            width: width,
-            height: height,
+            width,
+            height,
             rgba: image.into_raw(),
         }
     };
@@ -66,7 +91,7 @@ fn show_settings_window() -> Option<Settings> {
         run_and_return: true,
         viewport: egui::ViewportBuilder::default()
             .with_decorations(true)
-            .with_inner_size([480.0, 360.0])
+            .with_inner_size([480.0, 820.0])
             .with_resizable(false)
             .with_maximize_button(false)
             .with_icon(icon),
@@ -90,43 +115,269 @@ fn show_settings_window() -> Option<Settings> {
         return None;
     }
     if settings.save_settings {
-        if let Err(e) = settings.save_to_file(SETTINGS_FILE) {
+        if let Err(e) = settings.save_to_file(settings_path) {
             eprintln!("Failed to save settings: {e}");
         }
     }
     Some(settings)
 }
 
-fn try_to_launch_overlay(settings: &Settings) -> bool {
-    let keyboard_info = match KeyboardInfo::new(&settings.keyboard_config_path) {
-        Ok(info) => info,
-        Err(_) => return false,
-    };
+/// Serves a keymap.json instead of connecting to the device. The layout macro named in the
+/// keymap is used if the keyboard info has it.
+fn create_offline_connector(
+    settings: &mut KeyboardSettings,
+    keyboard_info: &KeyboardInfo,
+) -> Result<Connector, String> {
+    let keymap = QmkKeymap::load(&settings.keymap_path)?;
+    if keyboard_info.get_layout(&keymap.layout).is_ok() {
+        settings.layout_name = keymap.layout.clone();
+    }
+    let layout = keyboard_info.get_layout(&settings.layout_name)?;
+    let resolved = keymap.resolve(&layout, keyboard_info.rows, keyboard_info.cols);
+    for error in &resolved.errors {
+        eprintln!("{}: {error}", settings.keymap_path);
+    }
+    Ok(Box::new(move || -> Result<Box<dyn Transport>, String> {
+        Ok(Box::new(SimulatedTransport::from_keymap(
+            resolved.keycodes.clone(),
+            resolved.encoders.clone(),
+        )))
+    }))
+}
 
-    let keyboard = match Keyboard::new(
-        keyboard_info.clone(),
-        settings.layout_name.clone(),
-        settings.timeout,
-    ) {
-        Ok(kb) => kb,
-        Err(_) => return false,
+/// Sets up the keyboards of the settings, along with the store of their press statistics if
+/// they are recorded. The statistics are kept next to the settings file.
+fn create_keyboards(
+    settings: &Settings,
+    settings_path: &str,
+) -> Result<(Vec<Keyboard>, Option<Arc<heatmap::StatisticsStore>>), String> {
+    let mut statistics_store = if settings.record_statistics {
+        match heatmap::StatisticsStore::load(&heatmap::statistics_path(settings_path)) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                eprintln!("{e}, not recording key press statistics");
+                None
+            }
+        }
+    } else {
+        None
     };
 
-    let _ = run_overlay_app(keyboard, settings);
+    let mut keyboards = Vec::new();
+    for (index, keyboard_settings) in settings.keyboards.iter().enumerate() {
+        let keyboard_info = KeyboardInfo::load(keyboard_settings)?;
+
+        let mut keyboard_settings = keyboard_settings.clone();
+        let connector = if keyboard_settings.is_offline() {
+            create_offline_connector(&mut keyboard_settings, &keyboard_info)?
+        } else {
+            transport::create_connector(&keyboard_settings, &keyboard_info)
+        };
+
+        // Offline keymaps are never pressed and replays were counted when recorded, the
+        // statistics belong to the device.
+        let statistics = statistics_store
+            .as_mut()
+            .filter(|_| !keyboard_settings.is_offline() && keyboard_settings.replay_path.is_empty())
+            .map(|store| store.get(&statistics_id(&keyboard_settings, &keyboard_info)));
+
+        keyboards.push(Keyboard::new(
+            keyboard_info.clone(),
+            &keyboard_settings,
+            settings,
+            connector,
+            statistics,
+        )?);
+
+        if !settings.record_path.is_empty() {
+            keyboards[index].start_recording(&indexed_path(&settings.record_path, index))?;
+        }
+    }
+
+    let statistics_store = statistics_store.map(Arc::new);
+    if let Some(store) = &statistics_store {
+        store.clone().save_periodically();
+    }
+
+    if keyboards.is_empty() {
+        return Err("No keyboard configured".to_string());
+    }
+    Ok((keyboards, statistics_store))
+}
 
-    true
+/// Returns the key the press statistics of a keyboard are stored under.
+fn statistics_id(settings: &KeyboardSettings, keyboard_info: &KeyboardInfo) -> String {
+    if !settings.simulation_path.is_empty() {
+        return format!("simulation:{}", settings.simulation_path);
+    }
+    settings
+        .device
+        .clone()
+        .unwrap_or_else(|| transport::DeviceId::new(keyboard_info.vid, keyboard_info.pid))
+        .to_string()
 }
 
-fn main() -> Result<(), eframe::Error> {
-    if let Some(settings) = Settings::load_from_file(SETTINGS_FILE) {
-        if try_to_launch_overlay(&settings) {
-            return Ok(());
+fn try_to_launch_overlay(settings: &Settings, settings_path: &str) -> Result<(), String> {
+    let (keyboards, statistics_store) = create_keyboards(settings, settings_path)?;
+    let _ = run_overlay_app(keyboards, settings);
+    // The presses since the last periodic save would be lost otherwise
+    if let Some(store) = statistics_store {
+        if let Err(e) = store.save() {
+            eprintln!("{e}");
         }
     }
+    Ok(())
+}
+
+/// Loads the settings for commands running without the settings window.
+fn require_settings(args: &SettingsArgs) -> Result<Settings, String> {
+    args.load_settings()?.ok_or(format!(
+        "No keyboard configured, pass --info or --device or create {} using the settings window",
+        args.settings
+    ))
+}
+
+/// Sets up the keyboards of the settings without showing the overlay.
+fn load_keyboards(settings: &Settings, settings_path: &str) -> Result<Vec<Keyboard>, String> {
+    create_keyboards(settings, settings_path).map(|(keyboards, _)| keyboards)
+}
 
-    if let Some(settings) = show_settings_window() {
-        let _ = try_to_launch_overlay(&settings);
+/// Labels printed to the terminal write modifiers as text, which the glyphs of the icon font
+/// can't be shown in.
+fn terminal_label_options(settings: &Settings) -> LabelOptions {
+    LabelOptions {
+        modifier_labels: ModifierLabels::Text,
+        host_layout: settings.host_layout,
     }
+}
 
+/// Waits until the keymaps have been read from the devices.
+fn wait_for_keymaps(keyboards: &[Keyboard]) -> Result<(), String> {
+    let deadline = Instant::now() + KEYMAP_TIMEOUT;
+    while keyboards
+        .iter()
+        .any(|keyboard| keyboard.get_num_layers() == 0)
+    {
+        if Instant::now() > deadline {
+            return Err("Timed out reading the keymap".to_string());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
     Ok(())
 }
+
+/// Returns the file of the keyboard with the given index, the further keyboards getting their
+/// index appended to the file name.
+fn indexed_path(path: &str, index: usize) -> String {
+    if index == 0 {
+        return path.to_string();
+    }
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!("{stem}-{index}.{}", extension.to_string_lossy()),
+        None => format!("{stem}-{index}"),
+    };
+    path.with_file_name(file_name).display().to_string()
+}
+
+/// Draws all layers of the keyboards to `path`, see [`indexed_path`].
+fn export_cheat_sheets(
+    keyboards: &[Keyboard],
+    path: &str,
+    label_options: LabelOptions,
+) -> Result<(), String> {
+    for (index, keyboard) in keyboards.iter().enumerate() {
+        cheat_sheet::export_cheat_sheet(keyboard, &indexed_path(path, index), label_options)?;
+    }
+    Ok(())
+}
+
+fn show_overlay(args: &SettingsArgs) -> Result<(), String> {
+    if let Some(settings) = args.load_settings()? {
+        // The settings window lets the failing settings be corrected
+        match try_to_launch_overlay(&settings, &args.settings) {
+            Ok(()) => return Ok(()),
+            Err(e) => eprintln!("{e}"),
+        }
+    }
+
+    match show_settings_window(&args.settings) {
+        Some(settings) => try_to_launch_overlay(&settings, &args.settings),
+        None => Ok(()),
+    }
+}
+
+fn run_command(cli: Cli) -> Result<(), String> {
+    match cli.command {
+        None => show_overlay(&cli.settings),
+        Some(Command::Overlay(args)) => show_overlay(&args),
+        Some(Command::ListDevices) => cli::list_devices(),
+        Some(Command::DumpKeymap {
+            format,
+            settings: args,
+        }) => {
+            let settings = require_settings(&args)?;
+            let keyboards = load_keyboards(&settings, &args.settings)?;
+            wait_for_keymaps(&keyboards)?;
+            cli::dump_keymap(&keyboards, format, terminal_label_options(&settings))
+        }
+        Some(Command::Render {
+            output,
+            settings: args,
+        }) => {
+            let settings = require_settings(&args)?;
+            let keyboards = load_keyboards(&settings, &args.settings)?;
+            wait_for_keymaps(&keyboards)?;
+            let label_options = LabelOptions {
+                modifier_labels: settings.modifier_labels,
+                host_layout: settings.host_layout,
+            };
+            export_cheat_sheets(&keyboards, &output, label_options)
+        }
+        Some(Command::Diagnose(args)) => cli::diagnose(&require_settings(&args)?),
+        Some(Command::Watch {
+            format,
+            settings: args,
+        }) => {
+            let settings = require_settings(&args)?;
+            let keyboards = load_keyboards(&settings, &args.settings)?;
+            cli::watch(&keyboards, format, terminal_label_options(&settings));
+            Ok(())
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::try_parse().unwrap_or_else(|e| {
+        cli::attach_console();
+        e.exit()
+    });
+    if !matches!(cli.command, None | Some(Command::Overlay(_))) {
+        cli::attach_console();
+    }
+    if let Err(e) = run_command(cli) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_the_index_to_the_file_name() {
+        assert_eq!(indexed_path("keymap.svg", 0), "keymap.svg");
+        assert_eq!(indexed_path("keymap.svg", 1), "keymap-1.svg");
+        assert_eq!(
+            indexed_path("sessions/today.jsonl", 2),
+            Path::new("sessions")
+                .join("today-2.jsonl")
+                .display()
+                .to_string()
+        );
+        assert_eq!(indexed_path("recording", 1), "recording-1");
+        assert_eq!(indexed_path("archive.tar.gz", 1), "archive.tar-1.gz");
+    }
+}
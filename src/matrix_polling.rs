@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// Number of consecutive polls a switch has to keep its new state for the change to be taken.
+const DEBOUNCE_POLLS: u8 = 2;
+
+/// Where the pressed state of the keys comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PressSource {
+    /// Neither source is available, no keys are highlighted
+    None,
+    /// Key events sent by the firmware from `process_record_user`
+    FirmwareEvents,
+    /// The switch matrix state polled using VIA
+    MatrixPolling,
+}
+
+impl fmt::Display for PressSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                PressSource::None => "None",
+                PressSource::FirmwareEvents => "Firmware events",
+                PressSource::MatrixPolling => "Matrix polling",
+            }
+        )
+    }
+}
+
+/// Turns polled switch matrix states into debounced key press and release events.
+pub struct SwitchMatrixPoller {
+    pressed: Vec<Vec<bool>>,
+    /// Number of consecutive polls each switch differed from its debounced state
+    changed_polls: Vec<Vec<u8>>,
+}
+
+impl SwitchMatrixPoller {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        SwitchMatrixPoller {
+            pressed: vec![vec![false; cols]; rows],
+            changed_polls: vec![vec![0; cols]; rows],
+        }
+    }
+
+    /// Takes the next polled state and returns the keys whose debounced state changed as
+    /// `(row, col, pressed)`.
+    pub fn update(&mut self, state: &[Vec<bool>]) -> Vec<(u8, u8, bool)> {
+        let mut events = Vec::new();
+        for (row, (pressed_row, changed_row)) in self
+            .pressed
+            .iter_mut()
+            .zip(self.changed_polls.iter_mut())
+            .enumerate()
+        {
+            let state_row = state.get(row).map(Vec::as_slice).unwrap_or_default();
+            for (col, (pressed, changed_polls)) in pressed_row
+                .iter_mut()
+                .zip(changed_row.iter_mut())
+                .enumerate()
+            {
+                let is_pressed = state_row.get(col).copied().unwrap_or(false);
+                if is_pressed == *pressed {
+                    *changed_polls = 0;
+                    continue;
+                }
+                *changed_polls += 1;
+                if *changed_polls >= DEBOUNCE_POLLS {
+                    *pressed = is_pressed;
+                    *changed_polls = 0;
+                    events.push((row as u8, col as u8, is_pressed));
+                }
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debounces_presses_and_releases() {
+        let mut poller = SwitchMatrixPoller::new(1, 2);
+        let released = [vec![false, false]];
+        let pressed = [vec![false, true]];
+
+        // A switch bouncing for a single poll is ignored
+        assert_eq!(poller.update(&pressed), []);
+        assert_eq!(poller.update(&released), []);
+        assert_eq!(poller.update(&released), []);
+
+        assert_eq!(poller.update(&pressed), []);
+        assert_eq!(poller.update(&pressed), [(0, 1, true)]);
+        assert_eq!(poller.update(&pressed), []);
+
+        assert_eq!(poller.update(&released), []);
+        assert_eq!(poller.update(&pressed), []);
+        assert_eq!(poller.update(&released), []);
+        assert_eq!(poller.update(&released), [(0, 1, false)]);
+    }
+}
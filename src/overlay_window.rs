@@ -1,6 +1,13 @@
+use crate::cheat_sheet;
 use crate::keyboard::Keyboard;
-use crate::keycode_labels::{self, KeycodeKind, KeycodeLabel};
-use crate::settings::WindowPosition;
+use crate::keyboard_info::Encoder;
+use crate::keycode_labels::{self, KeycodeKind, KeycodeLabel, LabelOptions};
+use crate::lighting::LightingState;
+use crate::macros::Macro;
+use crate::settings::{LightingDisplay, OverlayMode, Settings, WindowPosition};
+use crate::status::KeyboardStatus;
+use crate::tray::{Tray, TrayCommand};
+use crate::vial::VialFeatures;
 
 use eframe::egui::{self, Align2, Window};
 use std::time::Instant;
@@ -10,45 +17,88 @@ struct LabelGalleys {
     text: Option<std::sync::Arc<egui::Galley>>,
 }
 
+/// Galleys of a key label with their top left positions.
+pub type PlacedGalleys = Vec<(egui::Pos2, std::sync::Arc<egui::Galley>)>;
+
+/// Gap between keyboards shown at the same time, in key units
+const KEYBOARD_GAP: f32 = 0.5;
+
+/// Height of the lighting indicator below a keyboard, in key units
+const LIGHTING_INDICATOR_HEIGHT: f32 = 0.4;
+
+/// Height of a line in the details below a keyboard, in key units
+const DETAILS_LINE_HEIGHT: f32 = 0.35;
+
+/// Height of the modifier and lock status strip below a keyboard, in key units
+const STATUS_STRIP_HEIGHT: f32 = 0.35;
+
+/// Size of the hold function legend of dual-role keys and the AltGr legend relative to the
+/// main legend
+const SUB_LEGEND_FONT_SCALE: f32 = 0.7;
+
+/// Border color of keys whose modifier, one-shot or lock is in effect
+const ACTIVE_STATUS_COLOR: egui::Color32 = egui::Color32::from_rgb(255, 190, 60);
+
+/// Fill color of the most pressed key of a layer in the heatmap
+const HEAT_COLOR: egui::Color32 = egui::Color32::from_rgb(230, 60, 30);
+
 pub struct Overlay {
-    keyboard: Keyboard,
+    keyboards: Vec<Keyboard>,
+    tray: Tray,
+    overlay_mode: OverlayMode,
+    was_connected: Option<bool>,
     size: f32,
     margin: u32,
     position: WindowPosition,
+    lighting_display: LightingDisplay,
+    show_details: bool,
+    show_heatmap: bool,
+    label_options: LabelOptions,
 }
 
 impl Overlay {
-    pub fn new(keyboard: Keyboard, size: i32, margin: u32, position: WindowPosition) -> Self {
+    pub fn new(keyboards: Vec<Keyboard>, tray: Tray, settings: &Settings) -> Self {
         Self {
-            keyboard,
-            margin,
-            position,
-            size: size as f32,
+            keyboards,
+            tray,
+            overlay_mode: settings.overlay_mode,
+            was_connected: None,
+            margin: settings.margin,
+            position: settings.position,
+            size: settings.size as f32,
+            lighting_display: settings.lighting_display,
+            show_details: false,
+            show_heatmap: false,
+            label_options: LabelOptions {
+                modifier_labels: settings.modifier_labels,
+                host_layout: settings.host_layout,
+            },
         }
     }
 
     fn generate_key_label_galleys(
-        &self,
-        ui: &egui::Ui,
+        ctx: &egui::Context,
+        size: f32,
         keycode_label: KeycodeLabel,
         rect: egui::Rect,
         font: egui::FontId,
         color: egui::Color32,
     ) -> LabelGalleys {
-        let create_galley =
-            |text: String, fid: egui::FontId| ui.painter().layout_no_wrap(text, fid, color);
+        let create_galley = |text: String, fid: egui::FontId| {
+            ctx.fonts(|fonts| fonts.layout(text, fid, color, f32::INFINITY))
+        };
         let fits_width =
             |galley: &std::sync::Arc<egui::Galley>, max: f32| galley.rect.width() <= max;
         let max_width = rect.width() * 0.85;
 
         if let Some(symbol) = keycode_label.symbol {
-            let symbol_font = egui::FontId::proportional(0.33 * self.size);
+            let symbol_font = egui::FontId::proportional(0.33 * size);
             let symbol_galley = create_galley(symbol, symbol_font);
 
             // Try to fit symbol + long label
             if let Some(long) = keycode_label.long {
                 let text_galley = create_galley(long, font.clone());
-                let gap = 0.06 * self.size;
+                let gap = 0.06 * size;
                 let total_width = symbol_galley.rect.width() + gap + text_galley.rect.width();
                 if total_width <= max_width {
                     return LabelGalleys {
@@ -61,7 +111,7 @@ impl Overlay {
             // Try to fit symbol + short label
             if let Some(short) = keycode_label.short {
                 let text_galley = create_galley(short, font.clone());
-                let gap = 0.06 * self.size;
+                let gap = 0.06 * size;
                 let total_width = symbol_galley.rect.width() + gap + text_galley.rect.width();
                 if total_width <= max_width {
                     return LabelGalleys {
@@ -89,7 +139,7 @@ impl Overlay {
         }
 
         // Try fitting short label or truncated long label
-        let mut truncated = if keycode_label.short.is_some() {
+        let truncated = if keycode_label.short.is_some() {
             let short_label = keycode_label.short.unwrap_or_default();
             let short_galley = create_galley(short_label.clone(), font.clone());
             if fits_width(&short_galley, max_width) {
@@ -103,24 +153,58 @@ impl Overlay {
             long_label
         };
 
-        while truncated.len() > 1 {
-            truncated.pop();
-            let truncated_with_ellipsis = format!("{}...", truncated);
-            let truncated_galley = create_galley(truncated_with_ellipsis, font.clone());
-            if fits_width(&truncated_galley, max_width) {
-                return LabelGalleys {
-                    symbol: None,
-                    text: Some(truncated_galley),
-                };
-            }
-        }
-
         LabelGalleys {
             symbol: None,
-            text: None,
+            text: Self::truncate_to_fit(ctx, truncated, font, color, max_width),
         }
     }
 
+    /// Shortens the text until it fits the width, marking the cut with an ellipsis.
+    fn truncate_to_fit(
+        ctx: &egui::Context,
+        mut text: String,
+        font: egui::FontId,
+        color: egui::Color32,
+        max_width: f32,
+    ) -> Option<std::sync::Arc<egui::Galley>> {
+        while text.len() > 1 {
+            text.pop();
+            let truncated_with_ellipsis = format!("{}...", text);
+            let truncated_galley = ctx.fonts(|fonts| {
+                fonts.layout(truncated_with_ellipsis, font.clone(), color, f32::INFINITY)
+            });
+            if truncated_galley.rect.width() <= max_width {
+                return Some(truncated_galley);
+            }
+        }
+        None
+    }
+
+    /// Lays out the hold function of a dual-role key at the bottom of the rect.
+    fn layout_hold_label(
+        ctx: &egui::Context,
+        size: f32,
+        hold: String,
+        rect: egui::Rect,
+        font: &egui::FontId,
+        color: egui::Color32,
+    ) -> Option<(egui::Pos2, std::sync::Arc<egui::Galley>)> {
+        let font = egui::FontId::proportional(font.size * SUB_LEGEND_FONT_SCALE);
+        let max_width = rect.width() * 0.85;
+        let galley =
+            ctx.fonts(|fonts| fonts.layout(hold.clone(), font.clone(), color, f32::INFINITY));
+        let galley = if galley.rect.width() <= max_width {
+            galley
+        } else {
+            Self::truncate_to_fit(ctx, hold, font, color, max_width)?
+        };
+        let pos = egui::pos2(
+            rect.center().x - galley.rect.width() * 0.5,
+            rect.max.y - 0.04 * size - galley.rect.height(),
+        );
+        Some((pos, galley))
+    }
+
     fn get_anchor_params(&self) -> (Align2, egui::Vec2) {
         match self.position {
             WindowPosition::TopLeft => (
@@ -148,7 +232,7 @@ impl Overlay {
     }
 
     pub fn get_keycode_color(
-        &self,
+        size: f32,
         layer: u8,
         kind: KeycodeKind,
         desaturate: bool,
@@ -180,7 +264,7 @@ impl Overlay {
             return (
                 background_color.lerp_to_gamma(egui::Color32::WHITE, 0.2),
                 background_color.lerp_to_gamma(egui::Color32::WHITE, 0.7),
-                0.03 * self.size,
+                0.03 * size,
                 egui::Color32::WHITE,
             );
         }
@@ -207,6 +291,548 @@ impl Overlay {
 
         (background_color, border_color, 1.0, font_color)
     }
+
+    fn get_lighting_color(lighting: &LightingState) -> egui::Color32 {
+        egui::ecolor::Hsva::new(
+            lighting.hue as f32 / 255.0,
+            lighting.saturation as f32 / 255.0,
+            1.0,
+            1.0,
+        )
+        .into()
+    }
+
+    fn get_lighting_indicator_height(&self) -> f32 {
+        if self.lighting_display == LightingDisplay::Indicator {
+            LIGHTING_INDICATOR_HEIGHT
+        } else {
+            0.0
+        }
+    }
+
+    /// Asks for a file and saves the keymap of the keyboard to it, as `keymap.c` if the chosen
+    /// file ends in `.c` and as `keymap.json` otherwise.
+    fn export_keymap(keyboard: &Keyboard) -> Result<(), String> {
+        let keymap = keyboard.export_keymap()?;
+        let Some(path) = rfd::FileDialog::new()
+            .set_title(format!("Export keymap ({})", keyboard.layout.name))
+            .set_file_name("keymap.json")
+            .add_filter("QMK keymap", &["json"])
+            .add_filter("QMK keymap source", &["c"])
+            .save_file()
+        else {
+            return Ok(());
+        };
+        keymap.save(&path.display().to_string(), &keyboard.layout)
+    }
+
+    /// Asks for a file and draws all layers of the keyboard to it as SVG, PNG or PDF.
+    fn export_cheat_sheet(keyboard: &Keyboard, label_options: LabelOptions) -> Result<(), String> {
+        let Some(path) = rfd::FileDialog::new()
+            .set_title(format!("Export cheat sheet ({})", keyboard.layout.name))
+            .set_file_name("cheat_sheet.pdf")
+            .add_filter("PDF", &["pdf"])
+            .add_filter("SVG image", &["svg"])
+            .add_filter("PNG image", &["png"])
+            .save_file()
+        else {
+            return Ok(());
+        };
+        cheat_sheet::export_cheat_sheet(keyboard, &path.display().to_string(), label_options)
+    }
+
+    /// Asks for a file and saves the key press statistics of the keyboard to it, as CSV if the
+    /// chosen file ends in `.csv` and as JSON otherwise.
+    fn export_statistics(keyboard: &Keyboard) -> Result<(), String> {
+        let statistics = keyboard
+            .get_press_statistics()
+            .ok_or("Key press statistics are not recorded")?;
+        let Some(path) = rfd::FileDialog::new()
+            .set_title(format!("Export statistics ({})", keyboard.layout.name))
+            .set_file_name("statistics.csv")
+            .add_filter("CSV", &["csv"])
+            .add_filter("JSON", &["json"])
+            .save_file()
+        else {
+            return Ok(());
+        };
+        statistics.export(&path.display().to_string(), |layer, row, col| {
+            keyboard.get_keycode(layer as usize, row as usize, col as usize)
+        })
+    }
+
+    /// Returns descriptions of the macros, tap dances, combos and key overrides that can be
+    /// triggered from the keys of the active layers.
+    fn get_details(&self, keyboard: &Keyboard) -> Vec<String> {
+        if !self.show_details {
+            return Vec::new();
+        }
+        let mut keycodes = Vec::new();
+        let mut layers = Vec::new();
+        for key in &keyboard.layout.keys {
+            let (row, col) = (key.row as usize, key.col as usize);
+            let (layer, _) = keyboard.get_effective_key_layer(row, col);
+            keycodes.push(keyboard.get_keycode(layer as usize, row, col));
+            layers.push(layer);
+        }
+        keycodes.sort_unstable();
+        keycodes.dedup();
+
+        let macros = keyboard.get_macros();
+        let mut details = vec![format!("Key presses: {}", keyboard.get_press_source())];
+        details.extend(
+            keycodes
+                .iter()
+                .filter_map(|keycode| Macro::get_index(*keycode))
+                .filter_map(|index| {
+                    let macro_ = macros.get(index).filter(|m| !m.actions.is_empty())?;
+                    Some(format!("M{index}: {}", macro_.describe(self.label_options)))
+                }),
+        );
+
+        if let Some(features) = keyboard.get_vial_features() {
+            details.extend(
+                keycodes
+                    .iter()
+                    .filter_map(|keycode| VialFeatures::get_tap_dance_index(*keycode))
+                    .filter_map(|index| {
+                        let tap_dance = features.tap_dances.get(index).filter(|t| !t.is_empty())?;
+                        Some(format!(
+                            "TD{index}: {}",
+                            tap_dance.describe(self.label_options)
+                        ))
+                    }),
+            );
+            details.extend(
+                features
+                    .combos
+                    .iter()
+                    .filter(|combo| !combo.is_empty())
+                    .filter(|combo| combo.get_keys().all(|key| keycodes.contains(&key)))
+                    .map(|combo| format!("Combo: {}", combo.describe(self.label_options))),
+            );
+            let top_layer = layers.into_iter().max().unwrap_or(0);
+            details.extend(
+                features
+                    .key_overrides
+                    .iter()
+                    .filter(|key_override| {
+                        key_override.is_enabled()
+                            && key_override.is_active_on_layer(top_layer)
+                            && keycodes.contains(&key_override.trigger)
+                    })
+                    .map(|key_override| {
+                        format!("Override: {}", key_override.describe(self.label_options))
+                    }),
+            );
+        }
+        details
+    }
+
+    /// The status strip is only shown for firmware reporting the status.
+    fn get_status_strip_height(keyboard: &Keyboard) -> f32 {
+        if keyboard.get_status().is_some() {
+            STATUS_STRIP_HEIGHT
+        } else {
+            0.0
+        }
+    }
+
+    fn get_keyboard_size(&self, keyboard: &Keyboard) -> egui::Vec2 {
+        let layout_size = keyboard.layout.get_dimensions();
+        let details_height = self.get_details(keyboard).len() as f32 * DETAILS_LINE_HEIGHT;
+        egui::vec2(
+            layout_size.0 * self.size,
+            (layout_size.1
+                + self.get_lighting_indicator_height()
+                + Self::get_status_strip_height(keyboard)
+                + details_height)
+                * self.size,
+        )
+    }
+
+    /// The heatmap keeps the overlay visible until it is hidden again.
+    fn is_keyboard_visible(&self, keyboard: &Keyboard) -> bool {
+        if self.show_heatmap {
+            return true;
+        }
+        match keyboard.get_time_to_hide_overlay() {
+            Some(time_to_hide) => Instant::now() < time_to_hide,
+            None => true,
+        }
+    }
+
+    /// Lays out the AltGr character of a key in the bottom right corner of the rect, like on the
+    /// keycaps of layouts with AltGr.
+    fn layout_altgr_label(
+        ctx: &egui::Context,
+        size: f32,
+        altgr: String,
+        rect: egui::Rect,
+        font: &egui::FontId,
+        color: egui::Color32,
+    ) -> (egui::Pos2, std::sync::Arc<egui::Galley>) {
+        let font = egui::FontId::proportional(font.size * SUB_LEGEND_FONT_SCALE);
+        let galley = ctx.fonts(|fonts| fonts.layout(altgr, font, color, f32::INFINITY));
+        let pos = egui::pos2(
+            rect.max.x - 0.08 * size - galley.rect.width(),
+            rect.max.y - 0.04 * size - galley.rect.height(),
+        );
+        (pos, galley)
+    }
+
+    /// Fits a key label into the rect, as drawn on the keys of the overlay, and returns the
+    /// galleys with their positions. The hold function of dual-role keys is drawn smaller at
+    /// the bottom, the tap legend centered in the space above it.
+    pub fn layout_key_label(
+        ctx: &egui::Context,
+        size: f32,
+        mut keycode_label: KeycodeLabel,
+        mut rect: egui::Rect,
+        font: egui::FontId,
+        font_color: egui::Color32,
+    ) -> PlacedGalleys {
+        let hold = keycode_label
+            .hold
+            .take()
+            .and_then(|hold| Self::layout_hold_label(ctx, size, hold, rect, &font, font_color));
+        if let Some((pos, _)) = &hold {
+            rect.max.y = pos.y;
+        }
+        let altgr = keycode_label
+            .altgr
+            .take()
+            .map(|altgr| Self::layout_altgr_label(ctx, size, altgr, rect, &font, font_color));
+
+        let mut placed: PlacedGalleys = hold.into_iter().chain(altgr).collect();
+        placed.extend(Self::place_key_label(
+            ctx,
+            size,
+            keycode_label,
+            rect,
+            font,
+            font_color,
+        ));
+        placed
+    }
+
+    fn place_key_label(
+        ctx: &egui::Context,
+        size: f32,
+        keycode_label: KeycodeLabel,
+        rect: egui::Rect,
+        font: egui::FontId,
+        font_color: egui::Color32,
+    ) -> PlacedGalleys {
+        match Self::generate_key_label_galleys(ctx, size, keycode_label, rect, font, font_color) {
+            LabelGalleys {
+                symbol: Some(symbol_galley),
+                text: Some(text_galley),
+            } => {
+                let gap = 0.06 * size;
+                let total_width = symbol_galley.rect.width() + gap + text_galley.rect.width();
+                let start_x = rect.center().x - total_width * 0.5;
+
+                let text_pos_x = start_x + gap + symbol_galley.rect.width();
+                let text_pos =
+                    egui::pos2(text_pos_x, rect.center().y - text_galley.rect.center().y);
+                let sym_pos = egui::pos2(start_x, rect.center().y - symbol_galley.rect.center().y);
+                vec![(sym_pos, symbol_galley), (text_pos, text_galley)]
+            }
+            LabelGalleys {
+                symbol: Some(symbol_galley),
+                text: None,
+            } => {
+                let sym_pos = rect.center() - symbol_galley.rect.center().to_vec2();
+                vec![(sym_pos, symbol_galley)]
+            }
+            LabelGalleys {
+                symbol: None,
+                text: Some(text_galley),
+            } => {
+                let label_pos = rect.center() - text_galley.rect.center().to_vec2();
+                vec![(label_pos, text_galley)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn draw_label(
+        &self,
+        ui: &egui::Ui,
+        keycode_label: KeycodeLabel,
+        rect: egui::Rect,
+        font: egui::FontId,
+        font_color: egui::Color32,
+    ) {
+        let placed =
+            Self::layout_key_label(ui.ctx(), self.size, keycode_label, rect, font, font_color);
+        for (pos, galley) in placed {
+            ui.painter().galley(pos, galley, font_color);
+        }
+    }
+
+    /// Draws a line of text on a dark background, as used below the keyboard.
+    fn draw_text_line(&self, ui: &egui::Ui, text: String, top_left: egui::Pos2, height: f32) {
+        let galley = ui.painter().layout_no_wrap(
+            text,
+            egui::FontId::proportional(0.22 * self.size),
+            egui::Color32::WHITE,
+        );
+        let background_rect = egui::Rect::from_min_size(
+            top_left,
+            egui::vec2(galley.rect.width() + 0.2 * self.size, height),
+        )
+        .shrink2(egui::vec2(0.0, 0.02 * self.size));
+        ui.painter().rect_filled(
+            background_rect,
+            0.05 * self.size,
+            egui::Color32::from_black_alpha(200),
+        );
+        let text_pos = egui::pos2(
+            background_rect.min.x + 0.1 * self.size,
+            background_rect.center().y - galley.rect.center().y,
+        );
+        ui.painter().galley(text_pos, galley, egui::Color32::WHITE);
+    }
+
+    fn draw_status_strip(&self, ui: &egui::Ui, status: &KeyboardStatus, top_left: egui::Pos2) {
+        let parts = status.describe();
+        let text = if parts.is_empty() {
+            "No modifiers".to_string()
+        } else {
+            parts.join("  ·  ")
+        };
+        self.draw_text_line(ui, text, top_left, STATUS_STRIP_HEIGHT * self.size);
+    }
+
+    /// Draws an encoder as a knob with the counter-clockwise legend on the left and the
+    /// clockwise legend on the right half.
+    fn draw_encoder(
+        &self,
+        ui: &egui::Ui,
+        keyboard: &Keyboard,
+        encoder: &Encoder,
+        origin: egui::Pos2,
+        lighting: Option<&LightingState>,
+        macros: &[Macro],
+    ) {
+        let rect = egui::Rect::from_min_size(
+            egui::pos2(encoder.x * self.size, encoder.y * self.size) + origin.to_vec2(),
+            egui::vec2(encoder.w * self.size, encoder.h * self.size),
+        )
+        .shrink(0.06 * self.size);
+        let radius = 0.5 * rect.width().min(rect.height());
+        let pressed = encoder
+            .matrix
+            .is_some_and(|(row, col)| keyboard.is_key_pressed(row as usize, col as usize));
+
+        let directions = [false, true].map(|clockwise| {
+            let index = encoder.index as usize;
+            let (layer, is_background) = keyboard.get_effective_encoder_layer(index, clockwise);
+            let bytes = keyboard.get_encoder_keycode(layer as usize, index, clockwise);
+            let mut keycode_label = keycode_labels::get_keycode_label(bytes, self.label_options);
+            if let Some(lighting) = lighting {
+                lighting.annotate_label(bytes, &mut keycode_label);
+            }
+            Macro::annotate_label(macros, bytes, &mut keycode_label, self.label_options);
+            (layer, is_background, keycode_label)
+        });
+
+        // The knob takes the color of the highest layer either direction resolves to
+        let (layer, is_background, _) = directions
+            .iter()
+            .max_by_key(|(layer, _, _)| *layer)
+            .expect("Encoders have two directions");
+        let (fill_color, stroke_color, border_thickness, font_color) = Self::get_keycode_color(
+            self.size,
+            *layer,
+            KeycodeKind::Basic,
+            *is_background || !keyboard.is_connected(),
+            pressed,
+        );
+        ui.painter().circle(
+            rect.center(),
+            radius,
+            fill_color,
+            egui::Stroke::new(border_thickness, stroke_color),
+        );
+
+        let arrow_font = egui::FontId::proportional(0.18 * self.size);
+        let font = egui::FontId::proportional(0.2 * self.size);
+        for ((_, _, keycode_label), clockwise) in directions.into_iter().zip([false, true]) {
+            let side = if clockwise { 1.0 } else { -1.0 };
+            let center = rect.center() + egui::vec2(side * 0.45 * radius, 0.0);
+            let label_rect = egui::Rect::from_center_size(center, egui::vec2(radius, radius));
+            self.draw_label(ui, keycode_label, label_rect, font.clone(), font_color);
+
+            let arrow = if clockwise {
+                egui_phosphor::regular::ARROW_CLOCKWISE
+            } else {
+                egui_phosphor::regular::ARROW_COUNTER_CLOCKWISE
+            };
+            ui.painter().text(
+                center - egui::vec2(0.0, 0.55 * radius),
+                Align2::CENTER_CENTER,
+                arrow,
+                arrow_font.clone(),
+                font_color,
+            );
+        }
+    }
+
+    fn draw_keyboard(&self, ui: &egui::Ui, keyboard: &Keyboard, origin: egui::Pos2) {
+        let connected = keyboard.is_connected();
+        let lighting = keyboard.get_lighting();
+        let macros = keyboard.get_macros();
+        let vial_features = keyboard.get_vial_features();
+        let status = keyboard.get_status();
+        let statistics = keyboard
+            .get_press_statistics()
+            .filter(|_| self.show_heatmap);
+        for key in &keyboard.layout.keys {
+            let (effective_layer, is_background_key) =
+                keyboard.get_effective_key_layer(key.row as usize, key.col as usize);
+
+            let bytes =
+                keyboard.get_keycode(effective_layer as usize, key.row as usize, key.col as usize);
+            let mut keycode_label = keycode_labels::get_keycode_label(bytes, self.label_options);
+            if let Some(lighting) = &lighting {
+                lighting.annotate_label(bytes, &mut keycode_label);
+            }
+            Macro::annotate_label(&macros, bytes, &mut keycode_label, self.label_options);
+            if let Some(features) = &vial_features {
+                features.annotate_label(bytes, &mut keycode_label, self.label_options);
+            }
+            if status.is_some_and(|status| status.is_altgr()) {
+                keycode_label.apply_altgr();
+            } else if status.is_some_and(|status| status.is_shifted()) {
+                keycode_label.apply_shift();
+            }
+
+            let first_layer_bytes = keyboard.get_keycode(0, key.row as usize, key.col as usize);
+            let first_layer_keycode_kind =
+                keycode_labels::get_keycode_label(first_layer_bytes, self.label_options).kind;
+
+            let pressed = keyboard.is_key_pressed(key.row as usize, key.col as usize);
+            let (mut fill_color, mut stroke_color, mut border_thickness, font_color) =
+                Self::get_keycode_color(
+                    self.size,
+                    keycode_label.layer_ref.unwrap_or(effective_layer),
+                    first_layer_keycode_kind,
+                    is_background_key || !connected,
+                    pressed,
+                );
+
+            if let (Some(lighting), false) = (&lighting, pressed) {
+                let lighting_color = Self::get_lighting_color(lighting);
+                match self.lighting_display {
+                    LightingDisplay::Tint => {
+                        let amount = 0.35 * lighting.brightness as f32 / 255.0;
+                        fill_color = fill_color.lerp_to_gamma(lighting_color, amount);
+                    }
+                    LightingDisplay::KeyBorders => {
+                        let amount = lighting.brightness as f32 / 255.0;
+                        stroke_color = stroke_color.lerp_to_gamma(lighting_color, amount);
+                        border_thickness = 0.03 * self.size;
+                    }
+                    LightingDisplay::Off | LightingDisplay::Indicator => {}
+                }
+            }
+            if let (Some(statistics), false) = (&statistics, pressed) {
+                let max = statistics.max_on_layer(effective_layer);
+                if max > 0 {
+                    let count = statistics.get(effective_layer, key.row, key.col);
+                    let amount = (count as f32 / max as f32).sqrt();
+                    fill_color = fill_color.lerp_to_gamma(HEAT_COLOR, amount);
+                }
+            }
+            if status.is_some_and(|status| status.is_keycode_active(bytes)) {
+                stroke_color = ACTIVE_STATUS_COLOR;
+                border_thickness = 0.04 * self.size;
+            }
+
+            // Draw key background
+            let rect = egui::Rect::from_min_size(
+                egui::pos2(key.x * self.size, key.y * self.size) + origin.to_vec2(),
+                egui::vec2(key.w * self.size, key.h * self.size),
+            )
+            .shrink(0.06 * self.size);
+            ui.painter().rect(
+                rect,
+                0.1 * self.size,
+                fill_color,
+                egui::Stroke::new(border_thickness, stroke_color),
+                egui::StrokeKind::Outside,
+            );
+
+            // Draw key label and optional symbol
+            let font = egui::FontId::proportional(0.25 * self.size);
+            self.draw_label(ui, keycode_label, rect, font, font_color);
+        }
+
+        for encoder in &keyboard.layout.encoders {
+            self.draw_encoder(ui, keyboard, encoder, origin, lighting.as_ref(), &macros);
+        }
+
+        if let (Some(lighting), LightingDisplay::Indicator) = (&lighting, self.lighting_display) {
+            let layout_height = keyboard.layout.get_dimensions().1 * self.size;
+            let center_y = origin.y + layout_height + 0.5 * LIGHTING_INDICATOR_HEIGHT * self.size;
+            let radius = 0.12 * self.size;
+            let dot_center = egui::pos2(origin.x + 0.1 * self.size + radius, center_y);
+            ui.painter().circle(
+                dot_center,
+                radius,
+                Self::get_lighting_color(lighting),
+                egui::Stroke::new(1.0, egui::Color32::from_black_alpha(200)),
+            );
+            let galley = ui.painter().layout_no_wrap(
+                lighting.effect_description(),
+                egui::FontId::proportional(0.25 * self.size),
+                egui::Color32::WHITE,
+            );
+            let text_pos = egui::pos2(
+                dot_center.x + radius + 0.1 * self.size,
+                center_y - galley.rect.center().y,
+            );
+            ui.painter().galley(text_pos, galley, egui::Color32::WHITE);
+        }
+
+        let status_top = origin.y
+            + (keyboard.layout.get_dimensions().1 + self.get_lighting_indicator_height())
+                * self.size;
+        if let Some(status) = &status {
+            self.draw_status_strip(ui, status, egui::pos2(origin.x, status_top));
+        }
+
+        let details_top = status_top + Self::get_status_strip_height(keyboard) * self.size;
+        let line_height = DETAILS_LINE_HEIGHT * self.size;
+        for (i, detail) in self.get_details(keyboard).into_iter().enumerate() {
+            let top_left = egui::pos2(origin.x, details_top + i as f32 * line_height);
+            self.draw_text_line(ui, detail, top_left, line_height);
+        }
+
+        if !connected {
+            let layout_rect = egui::Rect::from_min_size(origin, self.get_keyboard_size(keyboard));
+            let text = format!("{} Disconnected", egui_phosphor::regular::PLUGS);
+            let galley = ui.painter().layout_no_wrap(
+                text,
+                egui::FontId::proportional(0.5 * self.size),
+                egui::Color32::WHITE,
+            );
+            let background_rect = egui::Rect::from_center_size(
+                layout_rect.center(),
+                galley.rect.size() + egui::vec2(0.6, 0.3) * self.size,
+            );
+            ui.painter().rect_filled(
+                background_rect,
+                0.1 * self.size,
+                egui::Color32::from_black_alpha(200),
+            );
+            let text_pos = layout_rect.center() - galley.rect.center().to_vec2();
+            ui.painter().galley(text_pos, galley, egui::Color32::WHITE);
+        }
+    }
 }
 
 impl eframe::App for Overlay {
@@ -217,119 +843,130 @@ impl eframe::App for Overlay {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         ctx.send_viewport_cmd(egui::ViewportCommand::MousePassthrough(true));
 
-        let mut window_open = match self.keyboard.time_to_hide_overlay.lock().unwrap().as_ref() {
-            Some(time_to_hide) => Instant::now() < *time_to_hide,
-            None => true,
-        };
+        let connected = self
+            .keyboards
+            .iter()
+            .all(|keyboard| keyboard.is_connected());
+        if self.was_connected != Some(connected) {
+            self.tray.set_connected(connected);
+            self.was_connected = Some(connected);
+        }
 
-        let anchor_params = self.get_anchor_params();
-
-        Window::new("QMK Layout Helper")
-            .open(&mut window_open)
-            .auto_sized()
-            .anchor(anchor_params.0, anchor_params.1)
-            .frame(egui::Frame::NONE.fill(egui::Color32::TRANSPARENT))
-            .fade_out(true)
-            .title_bar(false)
-            .show(ctx, |ui| {
-                // Allow auto_sized window to shrink to fit content
-                let layout_size = self.keyboard.layout.get_dimensions();
-                ui.allocate_space(egui::vec2(
-                    layout_size.0 * self.size,
-                    layout_size.1 * self.size,
-                ));
-
-                let window_pos = ui.min_rect().min;
-
-                for key in &self.keyboard.layout.keys {
-                    let (effective_layer, is_background_key) = self
-                        .keyboard
-                        .get_effective_key_layer(key.row as usize, key.col as usize);
-
-                    let bytes = self.keyboard.get_keycode(
-                        effective_layer as usize,
-                        key.row as usize,
-                        key.col as usize,
-                    );
-                    let keycode_label = keycode_labels::get_keycode_label(bytes);
-
-                    let first_layer_bytes =
-                        self.keyboard
-                            .get_keycode(0, key.row as usize, key.col as usize);
-                    let first_layer_keycode_kind =
-                        keycode_labels::get_keycode_label(first_layer_bytes).kind;
-
-                    let (fill_color, stroke_color, border_thickness, font_color) = self
-                        .get_keycode_color(
-                            keycode_label.layer_ref.unwrap_or(effective_layer),
-                            first_layer_keycode_kind,
-                            is_background_key,
-                            self.keyboard
-                                .is_key_pressed(key.row as usize, key.col as usize),
-                        );
-
-                    // Draw key background
-                    let rect = egui::Rect::from_min_size(
-                        egui::pos2(key.x * self.size, key.y * self.size) + window_pos.to_vec2(),
-                        egui::vec2(key.w * self.size, key.h * self.size),
-                    )
-                    .shrink(0.06 * self.size);
-                    ui.painter().rect(
-                        rect,
-                        0.1 * self.size,
-                        fill_color,
-                        egui::Stroke::new(border_thickness, stroke_color),
-                        egui::StrokeKind::Outside,
-                    );
-
-                    // Draw key label and optional symbol
-                    let font = egui::FontId::proportional(0.25 * self.size);
-                    match self.generate_key_label_galleys(
-                        ui,
-                        keycode_label,
-                        rect,
-                        font.clone(),
-                        font_color,
-                    ) {
-                        LabelGalleys {
-                            symbol: Some(symbol_galley),
-                            text: Some(text_galley),
-                        } => {
-                            let gap = 0.06 * self.size;
-                            let total_width =
-                                symbol_galley.rect.width() + gap + text_galley.rect.width();
-                            let start_x = rect.center().x - total_width * 0.5;
-
-                            let text_pos_x = start_x + gap + symbol_galley.rect.width();
-                            let text_pos = egui::pos2(
-                                text_pos_x,
-                                rect.center().y - text_galley.rect.center().y,
-                            );
-                            let sym_pos = egui::pos2(
-                                start_x,
-                                rect.center().y - symbol_galley.rect.center().y,
-                            );
-                            ui.painter().galley(sym_pos, symbol_galley, font_color);
-                            ui.painter().galley(text_pos, text_galley, font_color);
+        while let Some(command) = self.tray.try_recv_command() {
+            match command {
+                TrayCommand::RefreshKeymap => {
+                    for keyboard in &self.keyboards {
+                        keyboard.request_keymap_refresh();
+                    }
+                }
+                TrayCommand::ExportKeymap => {
+                    for keyboard in &self.keyboards {
+                        if let Err(e) = Self::export_keymap(keyboard) {
+                            eprintln!("{e}");
                         }
-                        LabelGalleys {
-                            symbol: Some(symbol_galley),
-                            text: None,
-                        } => {
-                            let sym_pos = rect.center() - symbol_galley.rect.center().to_vec2();
-                            ui.painter().galley(sym_pos, symbol_galley, font_color);
+                    }
+                }
+                TrayCommand::ExportCheatSheet => {
+                    for keyboard in &self.keyboards {
+                        if let Err(e) = Self::export_cheat_sheet(keyboard, self.label_options) {
+                            eprintln!("{e}");
                         }
-                        LabelGalleys {
-                            symbol: None,
-                            text: Some(text_galley),
-                        } => {
-                            let label_pos = rect.center() - text_galley.rect.center().to_vec2();
-                            ui.painter().galley(label_pos, text_galley, font_color);
+                    }
+                }
+                TrayCommand::ToggleDetails => {
+                    self.show_details = !self.show_details;
+                }
+                TrayCommand::ToggleHeatmap => {
+                    self.show_heatmap = !self.show_heatmap;
+                }
+                TrayCommand::ExportStatistics => {
+                    for keyboard in &self.keyboards {
+                        if let Err(e) = Self::export_statistics(keyboard) {
+                            eprintln!("{e}");
                         }
-                        _ => {}
                     }
                 }
-            });
+                TrayCommand::NextLayer | TrayCommand::PreviousLayer => {
+                    for keyboard in &self.keyboards {
+                        keyboard.cycle_layer(command == TrayCommand::NextLayer);
+                    }
+                }
+                TrayCommand::Quit => {
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+            }
+        }
+
+        let (align, anchor_offset) = self.get_anchor_params();
+        let gap = KEYBOARD_GAP * self.size;
+
+        match self.overlay_mode {
+            OverlayMode::Combined => {
+                let mut window_open = self
+                    .keyboards
+                    .iter()
+                    .any(|keyboard| self.is_keyboard_visible(keyboard));
+                let sizes: Vec<egui::Vec2> = self
+                    .keyboards
+                    .iter()
+                    .map(|keyboard| self.get_keyboard_size(keyboard))
+                    .collect();
+                let total_size = egui::vec2(
+                    sizes.iter().map(|size| size.x).fold(0.0, f32::max),
+                    sizes.iter().map(|size| size.y).sum::<f32>()
+                        + gap * (sizes.len().saturating_sub(1)) as f32,
+                );
+
+                Window::new("QMK Layout Helper")
+                    .open(&mut window_open)
+                    .auto_sized()
+                    .anchor(align, anchor_offset)
+                    .frame(egui::Frame::NONE.fill(egui::Color32::TRANSPARENT))
+                    .fade_out(true)
+                    .title_bar(false)
+                    .show(ctx, |ui| {
+                        // Allow auto_sized window to shrink to fit content
+                        ui.allocate_space(total_size);
+
+                        // Keyboards are stacked vertically and aligned to the anchored side
+                        let mut origin = ui.min_rect().min;
+                        for (keyboard, size) in self.keyboards.iter().zip(&sizes) {
+                            let x_offset = (total_size.x - size.x) * align.x().to_factor();
+                            self.draw_keyboard(ui, keyboard, origin + egui::vec2(x_offset, 0.0));
+                            origin.y += size.y + gap;
+                        }
+                    });
+            }
+            OverlayMode::Separate => {
+                // Windows sharing the same anchor are stacked away from the screen edge
+                let mut stack_offset = 0.0;
+                for (i, keyboard) in self.keyboards.iter().enumerate() {
+                    let size = self.get_keyboard_size(keyboard);
+                    let mut window_open = self.is_keyboard_visible(keyboard);
+                    let y_offset = if align.y() == egui::Align::Max {
+                        -stack_offset
+                    } else {
+                        stack_offset
+                    };
+
+                    Window::new("QMK Layout Helper")
+                        .id(egui::Id::new(("keyboard_overlay", i)))
+                        .open(&mut window_open)
+                        .auto_sized()
+                        .anchor(align, anchor_offset + egui::vec2(0.0, y_offset))
+                        .frame(egui::Frame::NONE.fill(egui::Color32::TRANSPARENT))
+                        .fade_out(true)
+                        .title_bar(false)
+                        .show(ctx, |ui| {
+                            // Allow auto_sized window to shrink to fit content
+                            ui.allocate_space(size);
+                            self.draw_keyboard(ui, keyboard, ui.min_rect().min);
+                        });
+
+                    stack_offset += size.y + gap;
+                }
+            }
+        }
 
         ctx.request_repaint();
     }
@@ -0,0 +1,434 @@
+use qmk_via_api::api::RAW_EPSIZE;
+use std::fmt;
+
+use crate::status::KeyboardStatus;
+use crate::transport::Transport;
+
+/// Reports the default and momentary layer state. Sent from `layer_state_set_user`.
+pub const LAYER_STATE_MESSAGE_ID: u8 = 0xFF;
+/// Reports a key press or release. Sent from `process_record_user`.
+pub const KEY_EVENT_MESSAGE_ID: u8 = 0xF1;
+/// Reports the modifier, one-shot, Caps Word and lock LED state. Sent whenever it changes.
+pub const STATUS_MESSAGE_ID: u8 = 0xF2;
+/// Command sent by the host to query the firmware protocol version and capabilities.
+pub const HANDSHAKE_COMMAND_ID: u8 = 0xF0;
+
+/// Version of the message format implemented by the host.
+pub const HOST_PROTOCOL_VERSION: u8 = 1;
+
+pub mod capabilities {
+    pub const LAYER_STATE: u16 = 1 << 0;
+    pub const KEY_EVENTS: u16 = 1 << 1;
+    pub const STATUS: u16 = 1 << 2;
+}
+
+/// Flags of the status message.
+mod status_flags {
+    pub const CAPS_WORD: u8 = 1 << 0;
+    pub const ONESHOT_LAYER: u8 = 1 << 1;
+}
+
+/// Firmware side of the protocol as reported by the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    /// Protocol version of the firmware. Version 0 is firmware without handshake support.
+    pub version: u8,
+    pub capabilities: u16,
+}
+
+impl FirmwareInfo {
+    /// Firmware from before the handshake was introduced, which is assumed to send all messages
+    /// of the original README snippet.
+    pub const LEGACY: FirmwareInfo = FirmwareInfo {
+        version: 0,
+        capabilities: capabilities::LAYER_STATE | capabilities::KEY_EVENTS,
+    };
+
+    pub fn supports(&self, capability: u16) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Message {
+    LayerState {
+        default_layer_state: u32,
+        layer_state: u32,
+    },
+    KeyEvent {
+        row: u8,
+        col: u8,
+        pressed: bool,
+    },
+    Status(KeyboardStatus),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    UnknownMessage(u8),
+    /// The firmware did not announce support for the message in the handshake
+    UnexpectedMessage(u8),
+    Truncated {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidStateSize(u8),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty frame"),
+            DecodeError::UnknownMessage(id) => write!(f, "unknown message id 0x{id:02X}"),
+            DecodeError::UnexpectedMessage(id) => {
+                write!(f, "message id 0x{id:02X} was not announced by the firmware")
+            }
+            DecodeError::Truncated { expected, actual } => write!(
+                f,
+                "truncated frame: expected {expected} bytes, got {actual}"
+            ),
+            DecodeError::InvalidStateSize(size) => {
+                write!(f, "invalid layer state size {size}, expected 1 to 4 bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn ensure_len(frame: &[u8], expected: usize) -> Result<(), DecodeError> {
+    if frame.len() < expected {
+        return Err(DecodeError::Truncated {
+            expected,
+            actual: frame.len(),
+        });
+    }
+    Ok(())
+}
+
+fn read_state(bytes: &[u8]) -> u32 {
+    let mut state = [0u8; 4];
+    state[..bytes.len()].copy_from_slice(bytes);
+    u32::from_le_bytes(state)
+}
+
+/// Decodes a raw HID frame sent by the firmware. Only messages announced by the firmware in the
+/// handshake are accepted.
+pub fn decode(frame: &[u8], firmware: &FirmwareInfo) -> Result<Message, DecodeError> {
+    let id = *frame.first().ok_or(DecodeError::Empty)?;
+    let required_capability = match id {
+        LAYER_STATE_MESSAGE_ID => capabilities::LAYER_STATE,
+        KEY_EVENT_MESSAGE_ID => capabilities::KEY_EVENTS,
+        STATUS_MESSAGE_ID => capabilities::STATUS,
+        _ => return Err(DecodeError::UnknownMessage(id)),
+    };
+    if !firmware.supports(required_capability) {
+        return Err(DecodeError::UnexpectedMessage(id));
+    }
+
+    match id {
+        LAYER_STATE_MESSAGE_ID => {
+            ensure_len(frame, 2)?;
+            // The size of layer_state_t depends on the configured maximum number of layers
+            let size = frame[1];
+            if !(1..=4).contains(&size) {
+                return Err(DecodeError::InvalidStateSize(size));
+            }
+            let size = size as usize;
+            ensure_len(frame, 2 + 2 * size)?;
+            Ok(Message::LayerState {
+                default_layer_state: read_state(&frame[2..2 + size]),
+                layer_state: read_state(&frame[2 + size..2 + 2 * size]),
+            })
+        }
+        KEY_EVENT_MESSAGE_ID => {
+            ensure_len(frame, 4)?;
+            Ok(Message::KeyEvent {
+                row: frame[1],
+                col: frame[2],
+                pressed: frame[3] != 0,
+            })
+        }
+        STATUS_MESSAGE_ID => {
+            ensure_len(frame, 8)?;
+            let flags = frame[5];
+            Ok(Message::Status(KeyboardStatus {
+                mods: frame[1],
+                weak_mods: frame[2],
+                oneshot_mods: frame[3],
+                oneshot_locked_mods: frame[4],
+                caps_word: flags & status_flags::CAPS_WORD != 0,
+                oneshot_layer: (flags & status_flags::ONESHOT_LAYER != 0).then_some(frame[6]),
+                leds: frame[7],
+            }))
+        }
+        _ => Err(DecodeError::UnknownMessage(id)),
+    }
+}
+
+/// Whether a frame is a message sent by the firmware rather than a response that happens to
+/// start with a message id. Messages are padded with zeros and always have a default layer.
+pub fn is_message_frame(frame: &[u8]) -> bool {
+    let any = FirmwareInfo {
+        version: HOST_PROTOCOL_VERSION,
+        capabilities: u16::MAX,
+    };
+    let length = match decode(frame, &any) {
+        Ok(Message::LayerState {
+            default_layer_state: 0,
+            ..
+        })
+        | Err(_) => return false,
+        Ok(Message::LayerState { .. }) => 2 + 2 * frame[1] as usize,
+        Ok(Message::KeyEvent { .. }) => 4,
+        Ok(Message::Status(_)) => 8,
+    };
+    frame[length..].iter().all(|byte| *byte == 0)
+}
+
+/// Encodes a message the way the firmware sends it, using a 32-bit layer state.
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut frame = vec![0u8; RAW_EPSIZE];
+    match *message {
+        Message::LayerState {
+            default_layer_state,
+            layer_state,
+        } => {
+            let size = size_of::<u32>();
+            frame[0] = LAYER_STATE_MESSAGE_ID;
+            frame[1] = size as u8;
+            frame[2..2 + size].copy_from_slice(&default_layer_state.to_le_bytes());
+            frame[2 + size..2 + 2 * size].copy_from_slice(&layer_state.to_le_bytes());
+        }
+        Message::KeyEvent { row, col, pressed } => {
+            frame[0] = KEY_EVENT_MESSAGE_ID;
+            frame[1] = row;
+            frame[2] = col;
+            frame[3] = pressed as u8;
+        }
+        Message::Status(status) => {
+            let mut flags = 0;
+            if status.caps_word {
+                flags |= status_flags::CAPS_WORD;
+            }
+            if status.oneshot_layer.is_some() {
+                flags |= status_flags::ONESHOT_LAYER;
+            }
+            frame[0] = STATUS_MESSAGE_ID;
+            frame[1] = status.mods;
+            frame[2] = status.weak_mods;
+            frame[3] = status.oneshot_mods;
+            frame[4] = status.oneshot_locked_mods;
+            frame[5] = flags;
+            frame[6] = status.oneshot_layer.unwrap_or(0);
+            frame[7] = status.leds;
+        }
+    }
+    frame
+}
+
+/// Queries the protocol version and capabilities of the firmware. Firmware without handshake
+/// support leaves the command unhandled and is treated as legacy firmware.
+pub fn handshake(transport: &dyn Transport) -> FirmwareInfo {
+    // The leading zero keeps the echo of an unhandled command from looking like a valid layer
+    // state message.
+    match transport.command(HANDSHAKE_COMMAND_ID, &[0x00, HOST_PROTOCOL_VERSION]) {
+        Ok(response) if response.len() >= 6 => FirmwareInfo {
+            version: response[3],
+            capabilities: u16::from_le_bytes([response[4], response[5]]),
+        },
+        _ => FirmwareInfo::LEGACY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: FirmwareInfo = FirmwareInfo {
+        version: HOST_PROTOCOL_VERSION,
+        capabilities: capabilities::LAYER_STATE | capabilities::KEY_EVENTS | capabilities::STATUS,
+    };
+
+    fn layer_frame(size: u8, states: &[u8]) -> Vec<u8> {
+        let mut frame = vec![LAYER_STATE_MESSAGE_ID, size];
+        frame.extend_from_slice(states);
+        frame
+    }
+
+    #[test]
+    fn decodes_layer_states_of_every_size() {
+        assert_eq!(
+            decode(&layer_frame(1, &[0x01, 0x05]), &ALL),
+            Ok(Message::LayerState {
+                default_layer_state: 0x01,
+                layer_state: 0x05,
+            })
+        );
+        assert_eq!(
+            decode(&layer_frame(2, &[0x01, 0x00, 0x00, 0x81]), &ALL),
+            Ok(Message::LayerState {
+                default_layer_state: 0x0001,
+                layer_state: 0x8100,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_state_sizes() {
+        assert_eq!(
+            decode(&layer_frame(0, &[0; 8]), &ALL),
+            Err(DecodeError::InvalidStateSize(0))
+        );
+        assert_eq!(
+            decode(&layer_frame(5, &[0; 10]), &ALL),
+            Err(DecodeError::InvalidStateSize(5))
+        );
+        assert_eq!(
+            decode(&layer_frame(0xFF, &[0; 30]), &ALL),
+            Err(DecodeError::InvalidStateSize(0xFF))
+        );
+    }
+
+    #[test]
+    fn rejects_empty_and_truncated_frames() {
+        assert_eq!(decode(&[], &ALL), Err(DecodeError::Empty));
+        assert_eq!(
+            decode(&[LAYER_STATE_MESSAGE_ID], &ALL),
+            Err(DecodeError::Truncated {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert_eq!(
+            decode(&layer_frame(4, &[0; 7]), &ALL),
+            Err(DecodeError::Truncated {
+                expected: 10,
+                actual: 9
+            })
+        );
+        assert_eq!(
+            decode(&[KEY_EVENT_MESSAGE_ID, 1, 2], &ALL),
+            Err(DecodeError::Truncated {
+                expected: 4,
+                actual: 3
+            })
+        );
+        assert_eq!(
+            decode(&[STATUS_MESSAGE_ID, 0, 0, 0], &ALL),
+            Err(DecodeError::Truncated {
+                expected: 8,
+                actual: 4
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_and_unannounced_messages() {
+        assert_eq!(
+            decode(&[0x01, 0, 0, 0], &ALL),
+            Err(DecodeError::UnknownMessage(0x01))
+        );
+        assert_eq!(
+            decode(&[HANDSHAKE_COMMAND_ID, 0, 0, 0], &ALL),
+            Err(DecodeError::UnknownMessage(HANDSHAKE_COMMAND_ID))
+        );
+        let layer_state_only = FirmwareInfo {
+            version: HOST_PROTOCOL_VERSION,
+            capabilities: capabilities::LAYER_STATE,
+        };
+        assert_eq!(
+            decode(&[KEY_EVENT_MESSAGE_ID, 0, 0, 1], &layer_state_only),
+            Err(DecodeError::UnexpectedMessage(KEY_EVENT_MESSAGE_ID))
+        );
+        assert_eq!(
+            decode(
+                &encode(&Message::Status(KeyboardStatus::default())),
+                &FirmwareInfo::LEGACY
+            ),
+            Err(DecodeError::UnexpectedMessage(STATUS_MESSAGE_ID))
+        );
+    }
+
+    #[test]
+    fn decodes_encoded_messages() {
+        let messages = [
+            Message::LayerState {
+                default_layer_state: 1,
+                layer_state: 0x8000_0005,
+            },
+            Message::KeyEvent {
+                row: 3,
+                col: 14,
+                pressed: true,
+            },
+            Message::KeyEvent {
+                row: 0,
+                col: 0,
+                pressed: false,
+            },
+            Message::Status(KeyboardStatus {
+                mods: 0x03,
+                weak_mods: 0x02,
+                oneshot_mods: 0x10,
+                oneshot_locked_mods: 0x01,
+                oneshot_layer: Some(2),
+                caps_word: true,
+                leds: 0x02,
+            }),
+            Message::Status(KeyboardStatus::default()),
+        ];
+        for message in messages {
+            let frame = encode(&message);
+            assert_eq!(frame.len(), RAW_EPSIZE);
+            assert_eq!(decode(&frame, &ALL), Ok(message));
+        }
+    }
+
+    #[test]
+    fn tells_messages_from_responses() {
+        let key_event = Message::KeyEvent {
+            row: 1,
+            col: 2,
+            pressed: true,
+        };
+        assert!(is_message_frame(&encode(&key_event)));
+        assert!(is_message_frame(&layer_frame(1, &[0x01, 0x00])));
+        // Responses of Vial commands, e.g. a definition page, carry data after the message
+        let mut page = encode(&key_event);
+        page[20] = 0x5A;
+        assert!(!is_message_frame(&page));
+        assert!(!is_message_frame(&layer_frame(1, &[0x00, 0x00])));
+        assert!(!is_message_frame(&[0x06, 0, 0, 0]));
+    }
+
+    #[test]
+    fn never_panics_on_random_frames() {
+        // Xorshift keeps the frames reproducible without a dependency
+        let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        let ids = [
+            LAYER_STATE_MESSAGE_ID,
+            KEY_EVENT_MESSAGE_ID,
+            STATUS_MESSAGE_ID,
+        ];
+        for i in 0..100_000 {
+            let len = next() as usize % (RAW_EPSIZE + 1);
+            let mut frame: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Most frames start with a known id to get past the first check
+            if !frame.is_empty() && i % 4 != 0 {
+                frame[0] = ids[next() as usize % ids.len()];
+            }
+            for firmware in [ALL, FirmwareInfo::LEGACY] {
+                let _ = decode(&frame, &firmware);
+            }
+            let _ = is_message_frame(&frame);
+        }
+    }
+}
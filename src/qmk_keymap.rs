@@ -0,0 +1,375 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::key_matrix::KeyMatrix;
+use crate::keyboard_info::KeyboardLayout;
+use crate::keycode_labels;
+use crate::macros::{Macro, MacroAction};
+
+/// Vertical distance in key units after which an exported `keymap.c` starts a new line.
+pub const ROW_BREAK_DISTANCE: f32 = 0.5;
+
+#[derive(Deserialize, Serialize)]
+struct EncoderMapping {
+    ccw: String,
+    cw: String,
+}
+
+/// Keymap in the QMK `keymap.json` format, as exported by QMK Configurator or `qmk c2json`.
+/// Each layer lists the keycodes in the order of the layout macro arguments.
+#[derive(Deserialize, Serialize)]
+pub struct QmkKeymap {
+    #[serde(default)]
+    pub layout: String,
+    pub layers: Vec<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    encoders: Vec<Vec<EncoderMapping>>,
+    /// Macros in the format of `keymap.json`, bound to `QK_MACRO_n`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    macros: Vec<Vec<serde_json::Value>>,
+    /// Keycodes of all matrix positions indexed by `[layer][row][col]`, for layouts without a
+    /// layout macro. Only known for keymaps read from a device.
+    #[serde(skip)]
+    matrix: Vec<Vec<Vec<String>>>,
+}
+
+/// Keycodes of a keymap indexed by `[layer][row][col]` and encoder keycodes indexed by
+/// `[layer][encoder]`.
+pub struct ResolvedKeymap {
+    pub keycodes: Vec<Vec<Vec<u16>>>,
+    pub encoders: Vec<Vec<[u16; 2]>>,
+    /// Keycodes that couldn't be parsed and were replaced by `KC_NO`
+    pub errors: Vec<String>,
+}
+
+impl QmkKeymap {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open keymap '{path}': {e}"))?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Failed to parse keymap '{path}': {e}"))
+    }
+
+    /// Creates a keymap from keycodes and macros read from a device, using the canonical QMK
+    /// keycode names.
+    pub fn from_matrix(layout: &KeyboardLayout, matrix: &KeyMatrix, macros: &[Macro]) -> Self {
+        let layers = (0..matrix.get_num_layers())
+            .map(|layer| {
+                layout
+                    .matrix_order
+                    .iter()
+                    .map(|(row, col)| {
+                        let keycode = matrix.get_keycode(layer, *row as usize, *col as usize);
+                        keycode_labels::format_keycode(keycode)
+                    })
+                    .collect()
+            })
+            .collect();
+        let encoders = matrix
+            .encoders
+            .iter()
+            .map(|layer| {
+                layer
+                    .iter()
+                    .map(|[ccw, cw]| EncoderMapping {
+                        ccw: keycode_labels::format_keycode(*ccw),
+                        cw: keycode_labels::format_keycode(*cw),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let matrix = matrix
+            .keycodes
+            .iter()
+            .map(|layer| {
+                layer
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|k| keycode_labels::format_keycode(*k))
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Trailing empty macros are left out, as the firmware reserves more than are used
+        let used_macros = macros
+            .iter()
+            .rposition(|macro_| !macro_.actions.is_empty())
+            .map_or(0, |last| last + 1);
+
+        QmkKeymap {
+            layout: layout.name.clone(),
+            layers,
+            encoders,
+            macros: macros[..used_macros]
+                .iter()
+                .map(Self::macro_to_json)
+                .collect(),
+            matrix,
+        }
+    }
+
+    /// Writes the actions of a macro the way `keymap.json` expects them. Keycodes are written
+    /// without the `KC_` prefix, as QMK turns them into `X_` send string codes.
+    fn macro_to_json(macro_: &Macro) -> Vec<serde_json::Value> {
+        let keycode_action = |action: &str, keycode: u16| {
+            let name = keycode_labels::format_keycode(keycode);
+            let name = name.strip_prefix("KC_").unwrap_or(&name);
+            serde_json::json!({ "action": action, "keycodes": [name] })
+        };
+        macro_
+            .actions
+            .iter()
+            .map(|action| match action {
+                MacroAction::Text(text) => serde_json::Value::from(text.as_str()),
+                MacroAction::Tap(keycode) => keycode_action("tap", *keycode),
+                MacroAction::Down(keycode) => keycode_action("down", *keycode),
+                MacroAction::Up(keycode) => keycode_action("up", *keycode),
+                MacroAction::Delay(ms) => serde_json::json!({ "action": "delay", "duration": ms }),
+            })
+            .collect()
+    }
+
+    /// Writes the keymap as `keymap.c` if the path ends in `.c`, as `keymap.json` otherwise.
+    pub fn save(&self, path: &str, layout: &KeyboardLayout) -> Result<(), String> {
+        let is_c = Path::new(path).extension().is_some_and(|ext| ext == "c");
+        let contents = if is_c {
+            self.to_keymap_c(layout)?
+        } else {
+            serde_json::to_string_pretty(self)
+                .map_err(|e| format!("Failed to serialize keymap: {e}"))?
+        };
+        std::fs::write(path, contents).map_err(|e| format!("Failed to write keymap '{path}': {e}"))
+    }
+
+    /// Formats the keymap as the `keymaps` array of a QMK `keymap.c`, with a line per row of
+    /// keys in the layout. Layouts without a layout macro are written as plain matrix arrays.
+    pub fn to_keymap_c(&self, layout: &KeyboardLayout) -> Result<String, String> {
+        let mut c = String::from("#include QMK_KEYBOARD_H\n\n");
+        c.push_str("const uint16_t PROGMEM keymaps[][MATRIX_ROWS][MATRIX_COLS] = {\n");
+        if layout.has_macro {
+            self.write_layout_macros(&mut c, layout);
+        } else if !self.matrix.is_empty() {
+            self.write_matrix_arrays(&mut c);
+        } else {
+            return Err(format!(
+                "{} has no layout macro to write keymap.c with",
+                layout.name
+            ));
+        }
+        c.push_str("};\n");
+
+        if self.encoders.iter().any(|layer| !layer.is_empty()) {
+            c.push_str("\n#if defined(ENCODER_MAP_ENABLE)\n");
+            c.push_str("const uint16_t PROGMEM encoder_map[][NUM_ENCODERS][NUM_DIRECTIONS] = {\n");
+            for (layer, mappings) in self.encoders.iter().enumerate() {
+                let mappings: Vec<String> = mappings
+                    .iter()
+                    .map(|m| format!("ENCODER_CCW_CW({}, {})", m.ccw, m.cw))
+                    .collect();
+                let _ = writeln!(c, "    [{layer}] = {{ {} }},", mappings.join(", "));
+            }
+            c.push_str("};\n#endif\n");
+        }
+        Ok(c)
+    }
+
+    /// Writes each layer as a call of the layout macro.
+    fn write_layout_macros(&self, c: &mut String, layout: &KeyboardLayout) {
+        let key_y = |(row, col): &(u8, u8)| {
+            layout
+                .keys
+                .iter()
+                .find(|key| key.row == *row && key.col == *col)
+                .map(|key| key.y)
+                .or_else(|| {
+                    layout
+                        .encoders
+                        .iter()
+                        .find(|encoder| encoder.matrix == Some((*row, *col)))
+                        .map(|encoder| encoder.y)
+                })
+                .unwrap_or(0.0)
+        };
+
+        for (layer, names) in self.layers.iter().enumerate() {
+            let _ = write!(c, "    [{layer}] = {}(\n        ", self.layout);
+            let mut line_y = None;
+            for (i, (name, position)) in names.iter().zip(&layout.matrix_order).enumerate() {
+                let y = key_y(position);
+                if i > 0 {
+                    c.push(',');
+                    if line_y.is_some_and(|line_y| y - line_y >= ROW_BREAK_DISTANCE) {
+                        c.push_str("\n        ");
+                        line_y = Some(y);
+                    } else {
+                        c.push(' ');
+                    }
+                } else {
+                    line_y = Some(y);
+                }
+                c.push_str(name);
+            }
+            c.push_str("\n    ),\n");
+        }
+    }
+
+    /// Writes each layer as nested arrays with a line per matrix row, which needs no layout
+    /// macro and includes the keys of all layout options.
+    fn write_matrix_arrays(&self, c: &mut String) {
+        for (layer, rows) in self.matrix.iter().enumerate() {
+            let _ = writeln!(c, "    [{layer}] = {{");
+            for row in rows {
+                let _ = writeln!(c, "        {{ {} }},", row.join(", "));
+            }
+            c.push_str("    },\n");
+        }
+    }
+
+    /// Places the keycodes of each layer on the matrix positions of the layout.
+    pub fn resolve(&self, layout: &KeyboardLayout, rows: usize, cols: usize) -> ResolvedKeymap {
+        let mut errors = Vec::new();
+        let parse = |errors: &mut Vec<String>, layer: usize, name: &str| {
+            keycode_labels::parse_keycode(name).unwrap_or_else(|e| {
+                errors.push(format!("Layer {layer}: {e}"));
+                0
+            })
+        };
+
+        let mut keycodes = vec![vec![vec![0; cols]; rows]; self.layers.len()];
+        for (layer, (names, layer_keycodes)) in self.layers.iter().zip(&mut keycodes).enumerate() {
+            if names.len() != layout.matrix_order.len() {
+                errors.push(format!(
+                    "Layer {layer}: {} keycodes for {} keys of {}",
+                    names.len(),
+                    layout.matrix_order.len(),
+                    layout.name
+                ));
+            }
+            for (name, (row, col)) in names.iter().zip(&layout.matrix_order) {
+                let keycode = parse(&mut errors, layer, name);
+                if let Some(cell) = layer_keycodes
+                    .get_mut(*row as usize)
+                    .and_then(|r| r.get_mut(*col as usize))
+                {
+                    *cell = keycode;
+                }
+            }
+        }
+
+        let encoders = self
+            .encoders
+            .iter()
+            .enumerate()
+            .map(|(layer, mappings)| {
+                mappings
+                    .iter()
+                    .map(|mapping| {
+                        [
+                            parse(&mut errors, layer, &mapping.ccw),
+                            parse(&mut errors, layer, &mapping.cw),
+                        ]
+                    })
+                    .collect()
+            })
+            .collect();
+
+        ResolvedKeymap {
+            keycodes,
+            encoders,
+            errors,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard_info::Key;
+
+    fn layout(has_macro: bool) -> KeyboardLayout {
+        let key = |row, col, x| Key {
+            row,
+            col,
+            x,
+            y: 0.0,
+            w: 1.0,
+            h: 1.0,
+        };
+        KeyboardLayout {
+            name: if has_macro { "LAYOUT_2key" } else { "LAYOUT" }.to_string(),
+            // The layout macro takes the keys from right to left
+            keys: vec![key(0, 1, 0.0), key(0, 0, 1.0)],
+            encoders: Vec::new(),
+            matrix_order: vec![(0, 1), (0, 0)],
+            has_macro,
+        }
+    }
+
+    fn matrix() -> KeyMatrix {
+        // KC_A and KC_B, and KC_C on a position outside the layout
+        KeyMatrix::new(vec![vec![vec![0x04, 0x05], vec![0x06, 0x00]]], 2, 2)
+    }
+
+    #[test]
+    fn writes_layout_macro_arguments_in_macro_order() {
+        let layout = layout(true);
+        let c = QmkKeymap::from_matrix(&layout, &matrix(), &[])
+            .to_keymap_c(&layout)
+            .unwrap();
+        assert!(c.contains("[0] = LAYOUT_2key(\n        KC_B, KC_A\n    ),"));
+    }
+
+    #[test]
+    fn writes_matrix_arrays_without_layout_macro() {
+        let layout = layout(false);
+        let c = QmkKeymap::from_matrix(&layout, &matrix(), &[])
+            .to_keymap_c(&layout)
+            .unwrap();
+        assert!(!c.contains("LAYOUT"));
+        assert!(c.contains("[0] = {\n        { KC_A, KC_B },\n        { KC_C, KC_NO },\n    },"));
+    }
+
+    #[test]
+    fn exports_macros_to_keymap_json() {
+        let copy = Macro {
+            actions: vec![
+                MacroAction::Down(0xE0),
+                MacroAction::Tap(0x06),
+                MacroAction::Up(0xE0),
+                MacroAction::Delay(100),
+                MacroAction::Text("hello".to_string()),
+            ],
+        };
+        let macros = [copy, Macro::default(), Macro::default()];
+        let keymap = QmkKeymap::from_matrix(&layout(true), &matrix(), &macros);
+        let json: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&keymap).unwrap()).unwrap();
+        assert_eq!(
+            json["macros"],
+            serde_json::json!([[
+                {"action": "down", "keycodes": ["LEFT_CTRL"]},
+                {"action": "tap", "keycodes": ["C"]},
+                {"action": "up", "keycodes": ["LEFT_CTRL"]},
+                {"action": "delay", "duration": 100},
+                "hello"
+            ]])
+        );
+
+        let keymap = QmkKeymap::from_matrix(&layout(true), &matrix(), &[Macro::default()]);
+        assert!(!serde_json::to_string(&keymap).unwrap().contains("macros"));
+    }
+
+    #[test]
+    fn refuses_keymap_c_without_layout_macro_or_matrix() {
+        let keymap: QmkKeymap =
+            serde_json::from_str(r#"{"layout": "LAYOUT", "layers": [["KC_B", "KC_A"]]}"#).unwrap();
+        assert!(keymap.to_keymap_c(&layout(false)).is_err());
+        assert!(keymap.to_keymap_c(&layout(true)).is_ok());
+    }
+}
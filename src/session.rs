@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::time::Instant;
+
+use crate::keyboard::KeyboardEvent;
+
+/// Line of a recorded session, stamped with the milliseconds since the recording started.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedLine {
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// Keymap read from the device, recorded at the start and whenever it changed.
+    Keymap {
+        keymap: Vec<Vec<Vec<u16>>>,
+        #[serde(default)]
+        encoders: Vec<Vec<[u16; 2]>>,
+    },
+    Connected,
+    Disconnected,
+    Layer {
+        default_layer_state: u32,
+        layer_state: u32,
+    },
+    Press {
+        row: u8,
+        col: u8,
+    },
+    Release {
+        row: u8,
+        col: u8,
+    },
+}
+
+impl From<KeyboardEvent> for RecordedEvent {
+    fn from(event: KeyboardEvent) -> Self {
+        match event {
+            KeyboardEvent::Connected => RecordedEvent::Connected,
+            KeyboardEvent::Disconnected => RecordedEvent::Disconnected,
+            KeyboardEvent::LayerState {
+                default_layer_state,
+                layer_state,
+            } => RecordedEvent::Layer {
+                default_layer_state,
+                layer_state,
+            },
+            KeyboardEvent::Key {
+                row,
+                col,
+                pressed: true,
+                ..
+            } => RecordedEvent::Press { row, col },
+            KeyboardEvent::Key {
+                row,
+                col,
+                pressed: false,
+                ..
+            } => RecordedEvent::Release { row, col },
+        }
+    }
+}
+
+/// Writes the events of a keyboard to a JSON Lines file as they happen. Every line is flushed
+/// right away, so the recording is complete even if the application is killed.
+pub struct SessionRecorder {
+    path: String,
+    file: LineWriter<File>,
+    start: Instant,
+    last_keymap: Option<RecordedEvent>,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &str) -> Result<Self, String> {
+        let file =
+            File::create(path).map_err(|e| format!("Failed to create recording '{path}': {e}"))?;
+        Ok(SessionRecorder {
+            path: path.to_string(),
+            file: LineWriter::new(file),
+            start: Instant::now(),
+            last_keymap: None,
+        })
+    }
+
+    pub fn record(&mut self, event: RecordedEvent) {
+        let line = RecordedLine {
+            time_ms: self.start.elapsed().as_millis() as u64,
+            event,
+        };
+        let result = serde_json::to_string(&line)
+            .map_err(|e| e.to_string())
+            .and_then(|json| writeln!(self.file, "{json}").map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to write recording '{}': {e}", self.path);
+        }
+    }
+
+    /// Records the keymap unless it is the same as the one recorded last.
+    pub fn record_keymap(&mut self, keymap: &[Vec<Vec<u16>>], encoders: &[Vec<[u16; 2]>]) {
+        let event = RecordedEvent::Keymap {
+            keymap: keymap.to_vec(),
+            encoders: encoders.to_vec(),
+        };
+        if keymap.is_empty() || self.last_keymap.as_ref() == Some(&event) {
+            return;
+        }
+        self.last_keymap = Some(event.clone());
+        self.record(event);
+    }
+}
+
+/// Reads a recorded session.
+pub fn read_recording(path: &str) -> Result<Vec<RecordedLine>, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open recording '{path}': {e}"))?;
+    let mut lines = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Failed to read recording '{path}': {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let recorded = serde_json::from_str(&line).map_err(|e| {
+            format!(
+                "Failed to parse line {} of recording '{path}': {e}",
+                number + 1
+            )
+        })?;
+        lines.push(recorded);
+    }
+    Ok(lines)
+}
@@ -1,6 +1,10 @@
-use ini::Ini;
+use crate::layer_emulation::LAYER_COUNT;
+use crate::transport::DeviceId;
+
+use ini::{Ini, Properties, SectionSetter};
 use std::fmt;
 use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum WindowPosition {
@@ -48,14 +52,287 @@ impl FromStr for WindowPosition {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverlayMode {
+    Combined,
+    Separate,
+}
+
+impl fmt::Display for OverlayMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                OverlayMode::Combined => "Combined",
+                OverlayMode::Separate => "Separate",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseOverlayModeError;
+
+impl FromStr for OverlayMode {
+    type Err = ParseOverlayModeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "Combined" => Ok(OverlayMode::Combined),
+            "Separate" => Ok(OverlayMode::Separate),
+            _ => Err(ParseOverlayModeError),
+        }
+    }
+}
+
+/// How the lighting state of the keyboard is shown in the overlay.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LightingDisplay {
+    Off,
+    Tint,
+    Indicator,
+    KeyBorders,
+}
+
+impl fmt::Display for LightingDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                LightingDisplay::Off => "Off",
+                LightingDisplay::Tint => "Tint",
+                LightingDisplay::Indicator => "Indicator",
+                LightingDisplay::KeyBorders => "Key Borders",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseLightingDisplayError;
+
+impl FromStr for LightingDisplay {
+    type Err = ParseLightingDisplayError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "Off" => Ok(LightingDisplay::Off),
+            "Tint" => Ok(LightingDisplay::Tint),
+            "Indicator" => Ok(LightingDisplay::Indicator),
+            "Key Borders" => Ok(LightingDisplay::KeyBorders),
+            _ => Err(ParseLightingDisplayError),
+        }
+    }
+}
+
+/// How modifiers are written in the labels of modded keys, one-shot modifiers, layer
+/// modifiers and mod-taps.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ModifierLabels {
+    Glyphs,
+    Text,
+}
+
+impl fmt::Display for ModifierLabels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ModifierLabels::Glyphs => "Glyphs",
+                ModifierLabels::Text => "Text",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseModifierLabelsError;
+
+impl FromStr for ModifierLabels {
+    type Err = ParseModifierLabelsError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "Glyphs" => Ok(ModifierLabels::Glyphs),
+            "Text" => Ok(ModifierLabels::Text),
+            _ => Err(ParseModifierLabelsError),
+        }
+    }
+}
+
+/// Keyboard layout of the host, deciding the characters shown on the keys.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HostLayout {
+    Us,
+    Uk,
+    German,
+    French,
+    Spanish,
+    Swedish,
+    Dvorak,
+    Colemak,
+}
+
+impl HostLayout {
+    pub const ALL: [HostLayout; 8] = [
+        HostLayout::Us,
+        HostLayout::Uk,
+        HostLayout::German,
+        HostLayout::French,
+        HostLayout::Spanish,
+        HostLayout::Swedish,
+        HostLayout::Dvorak,
+        HostLayout::Colemak,
+    ];
+}
+
+impl fmt::Display for HostLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                HostLayout::Us => "US",
+                HostLayout::Uk => "UK",
+                HostLayout::German => "German",
+                HostLayout::French => "French",
+                HostLayout::Spanish => "Spanish",
+                HostLayout::Swedish => "Swedish",
+                HostLayout::Dvorak => "Dvorak",
+                HostLayout::Colemak => "Colemak",
+            }
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseHostLayoutError;
+
+impl FromStr for HostLayout {
+    type Err = ParseHostLayoutError;
+
+    /// Ignores the case, so that `us` can be passed on the command line.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        HostLayout::ALL
+            .into_iter()
+            .find(|layout| layout.to_string().eq_ignore_ascii_case(value))
+            .ok_or(ParseHostLayoutError)
+    }
+}
+
+/// Settings of a single keyboard shown in the overlay.
 #[derive(Clone)]
-pub struct Settings {
+pub struct KeyboardSettings {
     pub keyboard_config_path: String,
     pub layout_name: String,
+    pub device: Option<DeviceId>,
+    pub simulation_path: String,
+    /// Recorded session played back instead of connecting to the device
+    pub replay_path: String,
+    /// Factor the recorded session is played back faster with
+    pub replay_speed: f64,
+    /// QMK keymap.json shown offline instead of the keymap of the device
+    pub keymap_path: String,
+}
+
+impl Default for KeyboardSettings {
+    fn default() -> Self {
+        Self {
+            keyboard_config_path: String::new(),
+            layout_name: "LAYOUT".to_string(),
+            device: None,
+            simulation_path: String::new(),
+            replay_path: String::new(),
+            replay_speed: 1.0,
+            keymap_path: String::new(),
+        }
+    }
+}
+
+impl KeyboardSettings {
+    pub fn is_offline(&self) -> bool {
+        !self.keymap_path.is_empty()
+    }
+
+    fn save_to_section(&self, section: &mut SectionSetter) {
+        section.set("keyboard_config_path", &self.keyboard_config_path);
+        section.set("layout_name", &self.layout_name);
+        if let Some(device) = &self.device {
+            section.set("device", device.to_string());
+        }
+        if !self.simulation_path.is_empty() {
+            section.set("simulation_path", &self.simulation_path);
+        }
+        if !self.replay_path.is_empty() {
+            section.set("replay_path", &self.replay_path);
+            section.set("replay_speed", self.replay_speed.to_string());
+        }
+        if !self.keymap_path.is_empty() {
+            section.set("keymap_path", &self.keymap_path);
+        }
+    }
+
+    fn load_from_section(section: &Properties) -> Self {
+        let mut s = KeyboardSettings::default();
+        if let Some(val) = section.get("keyboard_config_path") {
+            s.keyboard_config_path = val.to_string();
+        }
+        if let Some(val) = section.get("layout_name") {
+            s.layout_name = val.to_string();
+        }
+        if let Some(val) = section.get("device") {
+            s.device = val.parse().ok();
+        }
+        if let Some(val) = section.get("simulation_path") {
+            s.simulation_path = val.to_string();
+        }
+        if let Some(val) = section.get("replay_path") {
+            s.replay_path = val.to_string();
+        }
+        if let Some(val) = section.get("replay_speed") {
+            s.replay_speed = val
+                .parse()
+                .ok()
+                .filter(|speed: &f64| *speed > 0.0)
+                .unwrap_or(s.replay_speed);
+        }
+        if let Some(val) = section.get("keymap_path") {
+            s.keymap_path = val.to_string();
+        }
+        s
+    }
+}
+
+#[derive(Clone)]
+pub struct Settings {
+    pub keyboards: Vec<KeyboardSettings>,
+    pub overlay_mode: OverlayMode,
     pub size: i32,
     pub position: WindowPosition,
     pub timeout: u64,
     pub margin: u32,
+    /// Interval in seconds in which the keymap is re-read from the device. 0 disables it.
+    pub keymap_refresh_interval: u64,
+    pub lighting_display: LightingDisplay,
+    pub modifier_labels: ModifierLabels,
+    pub host_layout: HostLayout,
+    /// Track the layer state on the host from key events, for firmware not reporting it.
+    pub layer_emulation: bool,
+    /// Time in milliseconds after which a layer tap key counts as held.
+    pub tapping_term: u64,
+    /// Lower, upper and adjust layer of the tri layer feature.
+    pub tri_layer: Option<[u8; 3]>,
+    /// Interval in milliseconds in which the switch matrix is polled for pressed keys. 0
+    /// disables it.
+    pub matrix_poll_interval: u64,
+    /// Count key presses per key and layer for the heatmap, kept across sessions.
+    pub record_statistics: bool,
+    /// JSON Lines file the events of the keyboards are recorded to. Only set from the command
+    /// line, so a recording is never overwritten by accident.
+    pub record_path: String,
     pub confirmed: bool,
     pub save_settings: bool,
 }
@@ -63,12 +340,22 @@ pub struct Settings {
 impl Default for Settings {
     fn default() -> Self {
         Self {
-            keyboard_config_path: String::new(),
-            layout_name: "LAYOUT".to_string(),
+            keyboards: vec![KeyboardSettings::default()],
+            overlay_mode: OverlayMode::Combined,
             size: 60,
             position: WindowPosition::BottomRight,
             timeout: 2000,
             margin: 10,
+            keymap_refresh_interval: 0,
+            lighting_display: LightingDisplay::Off,
+            modifier_labels: ModifierLabels::Glyphs,
+            host_layout: HostLayout::Us,
+            layer_emulation: false,
+            tapping_term: 200,
+            tri_layer: None,
+            matrix_poll_interval: 0,
+            record_statistics: false,
+            record_path: String::new(),
             confirmed: false,
             save_settings: false,
         }
@@ -76,27 +363,91 @@ impl Default for Settings {
 }
 
 impl Settings {
+    pub fn get_keymap_refresh_interval(&self) -> Option<Duration> {
+        (self.keymap_refresh_interval > 0)
+            .then(|| Duration::from_secs(self.keymap_refresh_interval))
+    }
+
+    pub fn get_matrix_poll_interval(&self) -> Option<Duration> {
+        (self.matrix_poll_interval > 0).then(|| Duration::from_millis(self.matrix_poll_interval))
+    }
+
+    fn format_tri_layer(tri_layer: Option<[u8; 3]>) -> String {
+        match tri_layer {
+            Some([lower, upper, adjust]) => format!("{lower},{upper},{adjust}"),
+            None => String::new(),
+        }
+    }
+
+    /// Parses the lower, upper and adjust layer, which have to fit into the layer state.
+    pub fn parse_tri_layer(value: &str) -> Option<[u8; 3]> {
+        let layers: Vec<u8> = value
+            .split(',')
+            .map(|layer| {
+                layer
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|layer| *layer < LAYER_COUNT)
+            })
+            .collect::<Option<_>>()?;
+        layers.try_into().ok()
+    }
+
+    // The first keyboard is stored in the main section to stay compatible with settings files
+    // from before multiple keyboards were supported. Each further keyboard gets its own section.
+    const KEYBOARD_SECTION_PREFIX: &str = "keyboard.";
+
     pub fn save_to_file(&self, path: &str) -> std::io::Result<()> {
         let mut conf = Ini::new();
         let mut section = conf.with_section(Some("settings"));
-        section.set("keyboard_config_path", &self.keyboard_config_path);
-        section.set("layout_name", &self.layout_name);
+        if let Some(keyboard) = self.keyboards.first() {
+            keyboard.save_to_section(&mut section);
+        }
+        section.set("overlay_mode", self.overlay_mode.to_string());
         section.set("size", self.size.to_string());
         section.set("position", self.position.to_string());
         section.set("timeout", self.timeout.to_string());
         section.set("margin", self.margin.to_string());
+        section.set(
+            "keymap_refresh_interval",
+            self.keymap_refresh_interval.to_string(),
+        );
+        section.set("lighting_display", self.lighting_display.to_string());
+        section.set("modifier_labels", self.modifier_labels.to_string());
+        section.set("host_layout", self.host_layout.to_string());
+        section.set("layer_emulation", self.layer_emulation.to_string());
+        section.set("tapping_term", self.tapping_term.to_string());
+        section.set("tri_layer", Self::format_tri_layer(self.tri_layer));
+        section.set(
+            "matrix_poll_interval",
+            self.matrix_poll_interval.to_string(),
+        );
+        section.set("record_statistics", self.record_statistics.to_string());
+        for (i, keyboard) in self.keyboards.iter().enumerate().skip(1) {
+            let name = format!("{}{}", Self::KEYBOARD_SECTION_PREFIX, i + 1);
+            keyboard.save_to_section(&mut conf.with_section(Some(name)));
+        }
         conf.write_to_file(path)
     }
 
     pub fn load_from_file(path: &str) -> Option<Self> {
         let conf = Ini::load_from_file(path).ok()?;
         let section = conf.section(Some("settings"))?;
-        let mut s = Settings::default();
-        if let Some(val) = section.get("keyboard_config_path") {
-            s.keyboard_config_path = val.to_string();
+        let mut s = Settings {
+            keyboards: vec![KeyboardSettings::load_from_section(section)],
+            ..Default::default()
+        };
+        for (name, section) in conf.iter() {
+            if name.is_some_and(|name| name.starts_with(Self::KEYBOARD_SECTION_PREFIX)) {
+                s.keyboards
+                    .push(KeyboardSettings::load_from_section(section));
+            }
         }
-        if let Some(val) = section.get("layout_name") {
-            s.layout_name = val.to_string();
+        if let Some(val) = section.get("overlay_mode") {
+            if let Ok(parsed) = val.parse() {
+                s.overlay_mode = parsed;
+            }
         }
         if let Some(val) = section.get("size") {
             s.size = val.parse().unwrap_or(s.size);
@@ -112,7 +463,54 @@ impl Settings {
         if let Some(val) = section.get("margin") {
             s.margin = val.parse().unwrap_or(s.margin);
         }
+        if let Some(val) = section.get("keymap_refresh_interval") {
+            s.keymap_refresh_interval = val.parse().unwrap_or(s.keymap_refresh_interval);
+        }
+        if let Some(val) = section.get("lighting_display") {
+            if let Ok(parsed) = val.parse() {
+                s.lighting_display = parsed;
+            }
+        }
+        if let Some(val) = section.get("modifier_labels") {
+            if let Ok(parsed) = val.parse() {
+                s.modifier_labels = parsed;
+            }
+        }
+        if let Some(val) = section.get("host_layout") {
+            if let Ok(parsed) = val.parse() {
+                s.host_layout = parsed;
+            }
+        }
+        if let Some(val) = section.get("layer_emulation") {
+            s.layer_emulation = val.parse().unwrap_or(s.layer_emulation);
+        }
+        if let Some(val) = section.get("tapping_term") {
+            s.tapping_term = val.parse().unwrap_or(s.tapping_term);
+        }
+        if let Some(val) = section.get("tri_layer") {
+            s.tri_layer = Self::parse_tri_layer(val);
+        }
+        if let Some(val) = section.get("matrix_poll_interval") {
+            s.matrix_poll_interval = val.parse().unwrap_or(s.matrix_poll_interval);
+        }
+        if let Some(val) = section.get("record_statistics") {
+            s.record_statistics = val.parse().unwrap_or(s.record_statistics);
+        }
         s.confirmed = true;
         Some(s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tri_layers_within_the_layer_state() {
+        assert_eq!(Settings::parse_tri_layer("1,2,3"), Some([1, 2, 3]));
+        assert_eq!(Settings::parse_tri_layer(" 4, 5 ,31"), Some([4, 5, 31]));
+        assert_eq!(Settings::parse_tri_layer("1,2,32"), None);
+        assert_eq!(Settings::parse_tri_layer("1,2"), None);
+        assert_eq!(Settings::parse_tri_layer("1,2,x"), None);
+    }
+}
@@ -1,33 +1,90 @@
-use crate::keyboard::Keyboard;
+use crate::diagnostics::{CheckStatus, Diagnostics};
 use crate::keyboard_info::KeyboardInfo;
-use crate::settings::Settings;
+use crate::layer_emulation::LAYER_COUNT;
+use crate::qmk_keymap::QmkKeymap;
 use crate::settings::WindowPosition;
+use crate::settings::{
+    HostLayout, KeyboardSettings, LightingDisplay, ModifierLabels, OverlayMode, Settings,
+};
+use crate::transport::{self, DeviceId, ViaDevice, ViaTransport};
+use crate::vial;
 
 use eframe::egui::{self};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct SettingsApp {
     current: Settings,
     shared: Arc<Mutex<Settings>>,
     error: Option<String>,
-    layout_names: Vec<String>,
+    selected_keyboard: usize,
+    layout_names: Vec<Vec<String>>,
+    devices: Vec<ViaDevice>,
+    diagnostics: Option<Diagnostics>,
 }
 
 impl SettingsApp {
     pub fn new(shared: Arc<Mutex<Settings>>) -> Self {
         let current = shared.lock().map(|s| s.clone()).unwrap_or_default();
+        let layout_names = vec![Vec::new(); current.keyboards.len()];
         Self {
             current,
             shared,
             error: None,
-            layout_names: Vec::new(),
+            selected_keyboard: 0,
+            layout_names,
+            devices: transport::list_devices(),
+            diagnostics: None,
+        }
+    }
+
+    fn keyboard(&self) -> &KeyboardSettings {
+        &self.current.keyboards[self.selected_keyboard]
+    }
+
+    fn keyboard_mut(&mut self) -> &mut KeyboardSettings {
+        &mut self.current.keyboards[self.selected_keyboard]
+    }
+
+    fn add_keyboard(&mut self) {
+        self.current.keyboards.push(KeyboardSettings::default());
+        self.layout_names.push(Vec::new());
+        self.selected_keyboard = self.current.keyboards.len() - 1;
+    }
+
+    fn remove_selected_keyboard(&mut self) {
+        if self.current.keyboards.len() > 1 {
+            self.current.keyboards.remove(self.selected_keyboard);
+            self.layout_names.remove(self.selected_keyboard);
+            self.selected_keyboard = self.selected_keyboard.saturating_sub(1);
+        }
+    }
+
+    fn device_combo_label(&self) -> String {
+        match &self.keyboard().device {
+            None => "Auto (from info JSON)".to_string(),
+            Some(id) => self
+                .devices
+                .iter()
+                .find(|device| &device.id == id)
+                .map(|device| device.to_string())
+                .unwrap_or_else(|| id.to_string()),
+        }
+    }
+
+    fn overlay_mode_label(mode: OverlayMode) -> &'static str {
+        match mode {
+            OverlayMode::Combined => "Combined overlay",
+            OverlayMode::Separate => "Separate windows",
         }
     }
 
     fn file_button_label(&self) -> String {
-        let path_str = self.current.keyboard_config_path.trim();
-        if path_str.is_empty() {
+        let path_str = self.keyboard().keyboard_config_path.trim();
+        if path_str.is_empty() && !self.layout_names[self.selected_keyboard].is_empty() {
+            "From device (Vial)".to_string()
+        } else if path_str.is_empty() {
             "Open file…".to_string()
         } else {
             Path::new(path_str)
@@ -38,10 +95,65 @@ impl SettingsApp {
         }
     }
 
+    fn keymap_button_label(&self) -> String {
+        let path_str = self.keyboard().keymap_path.trim();
+        if path_str.is_empty() {
+            "None (live from device)".to_string()
+        } else {
+            Path::new(path_str)
+                .file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or(path_str)
+                .to_string()
+        }
+    }
+
+    /// An offline keymap names the layout macro it was made for, which is preselected if the
+    /// keyboard info has it.
+    fn handle_picked_keymap(&mut self, picked: String) {
+        match QmkKeymap::load(&picked) {
+            Ok(keymap) => {
+                if self.layout_names[self.selected_keyboard].contains(&keymap.layout) {
+                    self.keyboard_mut().layout_name = keymap.layout;
+                }
+                self.keyboard_mut().keymap_path = picked;
+                self.error = None;
+            }
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    /// Vial keyboards don't need an info JSON, the definition is read from the device instead.
+    fn handle_picked_device(&mut self) {
+        if !self.keyboard().keyboard_config_path.is_empty() {
+            return;
+        }
+        let Some(device_id) = self.keyboard().device.clone() else {
+            self.layout_names[self.selected_keyboard].clear();
+            return;
+        };
+        match vial::read_keyboard_info(&device_id) {
+            Ok(keyboard_info) => {
+                let names = keyboard_info.get_layout_names().unwrap_or_default();
+                if let Some(first) = names.first() {
+                    self.keyboard_mut().layout_name = first.clone();
+                }
+                self.layout_names[self.selected_keyboard] = names;
+                self.error = None;
+            }
+            Err(err) => {
+                self.layout_names[self.selected_keyboard].clear();
+                self.error = Some(format!(
+                    "Failed to read the keyboard definition from the device ({err}). Select the keyboard info JSON instead."
+                ));
+            }
+        }
+    }
+
     fn handle_picked_file(&mut self, picked: String) {
-        self.current.keyboard_config_path = picked;
+        self.keyboard_mut().keyboard_config_path = picked;
 
-        let keyboard_info = match KeyboardInfo::new(&self.current.keyboard_config_path) {
+        let keyboard_info = match KeyboardInfo::new(&self.keyboard().keyboard_config_path) {
             Ok(info) => info,
             Err(err) => {
                 self.error = Some(format!(
@@ -51,35 +163,100 @@ impl SettingsApp {
             }
         };
 
-        if let Err(err) = Keyboard::try_get_api(keyboard_info.vid, keyboard_info.pid) {
-            self.error = Some(format!(
-                "Failed to initialize keyboard from the selected JSON: {err}"
-            ));
-            return;
-        }
-
         match keyboard_info.get_layout_names() {
             Ok(names) => {
-                self.layout_names = names;
-                if let Some(first) = self.layout_names.first() {
-                    if !self
-                        .layout_names
-                        .iter()
-                        .any(|n| n == &self.current.layout_name)
-                    {
-                        self.current.layout_name = first.clone();
+                if let Some(first) = names.first() {
+                    if !names.iter().any(|n| n == &self.keyboard().layout_name) {
+                        self.keyboard_mut().layout_name = first.clone();
                     }
                 }
-                self.error = None;
+                self.layout_names[self.selected_keyboard] = names;
+                // The overlay waits for the keyboard, so a missing device is not fatal here
+                let device_id = self
+                    .keyboard()
+                    .device
+                    .clone()
+                    .unwrap_or_else(|| DeviceId::new(keyboard_info.vid, keyboard_info.pid));
+                self.error = ViaTransport::connect(&device_id)
+                    .err()
+                    .map(|err| {
+                        format!("The keyboard is currently not connected ({err}). The overlay will wait for it.")
+                    });
             }
             Err(err) => {
-                self.layout_names.clear();
+                self.layout_names[self.selected_keyboard].clear();
                 self.error = Some(err.to_string());
             }
         }
     }
 }
 
+impl SettingsApp {
+    fn show_diagnostics(&mut self, ctx: &egui::Context) {
+        let Some(diagnostics) = &self.diagnostics else {
+            return;
+        };
+        let report = diagnostics.report();
+        let mut close = false;
+        egui::Window::new("Diagnostics")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.set_width(380.0);
+                for check in &report.checks {
+                    let (icon, color) = match check.status {
+                        CheckStatus::Running => (
+                            egui_phosphor::regular::HOURGLASS,
+                            ui.visuals().weak_text_color(),
+                        ),
+                        CheckStatus::Passed => (
+                            egui_phosphor::regular::CHECK_CIRCLE,
+                            egui::Color32::from_rgb(80, 170, 90),
+                        ),
+                        CheckStatus::Warning => {
+                            (egui_phosphor::regular::WARNING, ui.visuals().warn_fg_color)
+                        }
+                        CheckStatus::Failed => (
+                            egui_phosphor::regular::X_CIRCLE,
+                            ui.visuals().error_fg_color,
+                        ),
+                    };
+                    ui.horizontal_top(|ui| {
+                        ui.label(egui::RichText::new(icon).color(color).size(16.0));
+                        ui.vertical(|ui| {
+                            ui.strong(check.name);
+                            if !check.details.is_empty() {
+                                ui.label(&check.details);
+                            }
+                        });
+                    });
+                    ui.add_space(4.0);
+                }
+                if let (Some(prompt), Some(listen_until)) = (&report.prompt, report.listen_until) {
+                    let seconds = listen_until
+                        .saturating_duration_since(Instant::now())
+                        .as_secs()
+                        + 1;
+                    ui.add_space(6.0);
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.strong(format!("{prompt} ({seconds} s left)"));
+                    });
+                }
+                ui.add_space(10.0);
+                let label = if report.finished { "Close" } else { "Cancel" };
+                close = ui.button(label).clicked();
+            });
+
+        if close {
+            self.diagnostics = None;
+        } else if !report.finished {
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+    }
+}
+
 impl eframe::App for SettingsApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default()
@@ -103,9 +280,55 @@ impl eframe::App for SettingsApp {
                         .striped(true)
                         .spacing([25.0, 14.0])
                         .show(ui, |ui| {
+                            ui.label("Keyboard");
+                            ui.horizontal(|ui| {
+                                let button_width = ui.spacing().interact_size.y;
+                                egui::ComboBox::from_id_salt("keyboard_combo")
+                                    .width(
+                                        ui.available_width()
+                                            - 2.0 * (button_width + ui.spacing().item_spacing.x),
+                                    )
+                                    .selected_text(format!(
+                                        "Keyboard {}",
+                                        self.selected_keyboard + 1
+                                    ))
+                                    .show_ui(ui, |ui| {
+                                        for i in 0..self.current.keyboards.len() {
+                                            ui.selectable_value(
+                                                &mut self.selected_keyboard,
+                                                i,
+                                                format!("Keyboard {}", i + 1),
+                                            );
+                                        }
+                                    });
+                                if ui
+                                    .add_sized(
+                                        [button_width, button_width],
+                                        egui::Button::new(egui_phosphor::regular::PLUS),
+                                    )
+                                    .on_hover_text("Add keyboard")
+                                    .clicked()
+                                {
+                                    self.add_keyboard();
+                                }
+                                ui.add_enabled_ui(self.current.keyboards.len() > 1, |ui| {
+                                    if ui
+                                        .add_sized(
+                                            [button_width, button_width],
+                                            egui::Button::new(egui_phosphor::regular::MINUS),
+                                        )
+                                        .on_hover_text("Remove keyboard")
+                                        .clicked()
+                                    {
+                                        self.remove_selected_keyboard();
+                                    }
+                                });
+                            });
+                            ui.end_row();
+
                             ui.label("Keyboard info JSON");
                             ui.add_enabled_ui(
-                                self.current.keyboard_config_path.trim().is_empty(),
+                                self.keyboard().keyboard_config_path.trim().is_empty(),
                                 |ui| {
                                     if ui
                                         .add_sized(
@@ -122,15 +345,103 @@ impl eframe::App for SettingsApp {
                             );
                             ui.end_row();
 
+                            ui.label("Offline keymap");
+                            ui.horizontal(|ui| {
+                                let clear_width = ui.spacing().interact_size.y;
+                                if ui
+                                    .add_sized(
+                                        [
+                                            ui.available_width()
+                                                - clear_width
+                                                - ui.spacing().item_spacing.x,
+                                            clear_width,
+                                        ],
+                                        egui::Button::new(self.keymap_button_label()),
+                                    )
+                                    .on_hover_text("Show a QMK keymap.json instead of the device")
+                                    .clicked()
+                                {
+                                    if let Some(path) = rfd::FileDialog::new()
+                                        .add_filter("QMK keymap", &["json"])
+                                        .pick_file()
+                                    {
+                                        self.handle_picked_keymap(path.display().to_string());
+                                    }
+                                }
+                                ui.add_enabled_ui(self.keyboard().is_offline(), |ui| {
+                                    if ui
+                                        .add_sized(
+                                            [clear_width, clear_width],
+                                            egui::Button::new(egui_phosphor::regular::X),
+                                        )
+                                        .on_hover_text("Use the device")
+                                        .clicked()
+                                    {
+                                        self.keyboard_mut().keymap_path.clear();
+                                    }
+                                });
+                            });
+                            ui.end_row();
+
+                            ui.label("Device");
+                            ui.horizontal(|ui| {
+                                let refresh_button =
+                                    egui::Button::new(egui_phosphor::regular::ARROWS_CLOCKWISE);
+                                let refresh_width = ui.spacing().interact_size.y;
+                                let device_changed = egui::ComboBox::from_id_salt("device_combo")
+                                    .width(
+                                        ui.available_width()
+                                            - refresh_width
+                                            - ui.spacing().item_spacing.x,
+                                    )
+                                    .selected_text(self.device_combo_label())
+                                    .show_ui(ui, |ui| {
+                                        let keyboard =
+                                            &mut self.current.keyboards[self.selected_keyboard];
+                                        let mut changed = ui
+                                            .selectable_value(
+                                                &mut keyboard.device,
+                                                None,
+                                                "Auto (from info JSON)",
+                                            )
+                                            .changed();
+                                        for device in &self.devices {
+                                            changed |= ui
+                                                .selectable_value(
+                                                    &mut keyboard.device,
+                                                    Some(device.id.clone()),
+                                                    device.to_string(),
+                                                )
+                                                .changed();
+                                        }
+                                        changed
+                                    })
+                                    .inner
+                                    .unwrap_or(false);
+                                if device_changed {
+                                    self.handle_picked_device();
+                                }
+                                if ui
+                                    .add_sized([refresh_width, refresh_width], refresh_button)
+                                    .on_hover_text("Search for connected devices")
+                                    .clicked()
+                                {
+                                    self.devices = transport::list_devices();
+                                }
+                            });
+                            ui.end_row();
+
                             ui.label("Layout");
-                            ui.add_enabled_ui(!self.layout_names.is_empty(), |ui| {
+                            let layout_names = &self.layout_names[self.selected_keyboard];
+                            let keyboard = &mut self.current.keyboards[self.selected_keyboard];
+                            ui.add_enabled_ui(!layout_names.is_empty(), |ui| {
                                 egui::ComboBox::from_id_salt("layout_combo")
                                     .width(ui.available_width())
-                                    .selected_text(self.current.layout_name.as_str())
+                                    .selected_text(keyboard.layout_name.as_str())
                                     .show_ui(ui, |ui| {
-                                        for name in &self.layout_names {
+                                        for name in layout_names {
                                             ui.selectable_value(
-                                                &mut self.current.layout_name,
+                                                &mut keyboard.layout_name,
                                                 name.clone(),
                                                 name,
                                             );
@@ -139,6 +450,34 @@ impl eframe::App for SettingsApp {
                             });
                             ui.end_row();
 
+                            ui.label("Diagnostics");
+                            ui.add_enabled_ui(!layout_names.is_empty(), |ui| {
+                                if ui.button("Check firmware setup…").clicked() {
+                                    self.diagnostics =
+                                        Some(Diagnostics::start(self.keyboard().clone()));
+                                }
+                            });
+                            ui.end_row();
+
+                            ui.label("Multiple keyboards");
+                            ui.add_enabled_ui(self.current.keyboards.len() > 1, |ui| {
+                                egui::ComboBox::from_id_salt("overlay_mode_combo")
+                                    .width(ui.available_width())
+                                    .selected_text(Self::overlay_mode_label(
+                                        self.current.overlay_mode,
+                                    ))
+                                    .show_ui(ui, |ui| {
+                                        for mode in [OverlayMode::Combined, OverlayMode::Separate] {
+                                            ui.selectable_value(
+                                                &mut self.current.overlay_mode,
+                                                mode,
+                                                Self::overlay_mode_label(mode),
+                                            );
+                                        }
+                                    });
+                            });
+                            ui.end_row();
+
                             let position_label = self.current.position.to_string();
                             ui.label("Alignment");
                             ui.horizontal(|ui| {
@@ -192,23 +531,175 @@ impl eframe::App for SettingsApp {
                                     .suffix(" ms"),
                             );
                             ui.end_row();
+
+                            ui.label("Keymap refresh interval");
+                            ui.add_sized(
+                                ui.available_size(),
+                                egui::DragValue::new(&mut self.current.keymap_refresh_interval)
+                                    .speed(1)
+                                    .range(0..=3600)
+                                    .custom_formatter(|value, _| {
+                                        if value == 0.0 {
+                                            "Off".to_string()
+                                        } else {
+                                            format!("{value} s")
+                                        }
+                                    }),
+                            );
+                            ui.end_row();
+
+                            let lighting_label = self.current.lighting_display.to_string();
+                            ui.label("Lighting");
+                            egui::ComboBox::from_id_salt("lighting_combo")
+                                .width(ui.available_width())
+                                .selected_text(lighting_label)
+                                .show_ui(ui, |ui| {
+                                    for display in [
+                                        LightingDisplay::Off,
+                                        LightingDisplay::Tint,
+                                        LightingDisplay::Indicator,
+                                        LightingDisplay::KeyBorders,
+                                    ] {
+                                        ui.selectable_value(
+                                            &mut self.current.lighting_display,
+                                            display,
+                                            display.to_string(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+
+                            let modifier_label = self.current.modifier_labels.to_string();
+                            ui.label("Modifier labels");
+                            egui::ComboBox::from_id_salt("modifier_labels_combo")
+                                .width(ui.available_width())
+                                .selected_text(modifier_label)
+                                .show_ui(ui, |ui| {
+                                    for labels in [ModifierLabels::Glyphs, ModifierLabels::Text] {
+                                        ui.selectable_value(
+                                            &mut self.current.modifier_labels,
+                                            labels,
+                                            labels.to_string(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+
+                            let host_layout_label = self.current.host_layout.to_string();
+                            ui.label("Host layout");
+                            egui::ComboBox::from_id_salt("host_layout_combo")
+                                .width(ui.available_width())
+                                .selected_text(host_layout_label)
+                                .show_ui(ui, |ui| {
+                                    for layout in HostLayout::ALL {
+                                        ui.selectable_value(
+                                            &mut self.current.host_layout,
+                                            layout,
+                                            layout.to_string(),
+                                        );
+                                    }
+                                });
+                            ui.end_row();
+
+                            ui.label("Key press polling");
+                            ui.add_sized(
+                                ui.available_size(),
+                                egui::DragValue::new(&mut self.current.matrix_poll_interval)
+                                    .speed(1)
+                                    .range(0..=1000)
+                                    .custom_formatter(|value, _| {
+                                        if value == 0.0 {
+                                            "Off".to_string()
+                                        } else {
+                                            format!("{value} ms")
+                                        }
+                                    }),
+                            );
+                            ui.end_row();
+
+                            ui.label("Layer emulation");
+                            ui.checkbox(
+                                &mut self.current.layer_emulation,
+                                "Track layers from key presses",
+                            );
+                            ui.end_row();
+
+                            ui.label("Tapping term");
+                            ui.add_enabled(
+                                self.current.layer_emulation,
+                                egui::DragValue::new(&mut self.current.tapping_term)
+                                    .speed(5)
+                                    .range(50..=1000)
+                                    .suffix(" ms"),
+                            );
+                            ui.end_row();
+
+                            ui.label("Tri layer");
+                            ui.add_enabled_ui(self.current.layer_emulation, |ui| {
+                                ui.horizontal(|ui| {
+                                    let mut enabled = self.current.tri_layer.is_some();
+                                    ui.checkbox(&mut enabled, "");
+                                    let mut layers = self.current.tri_layer.unwrap_or([1, 2, 3]);
+                                    for (layer, name) in
+                                        layers.iter_mut().zip(["Lower", "Upper", "Adjust"])
+                                    {
+                                        ui.add_enabled(
+                                            enabled,
+                                            egui::DragValue::new(layer)
+                                                .range(0..=LAYER_COUNT - 1)
+                                                .prefix(format!("{name} ")),
+                                        );
+                                    }
+                                    self.current.tri_layer = enabled.then_some(layers);
+                                });
+                            });
+                            ui.end_row();
+
+                            ui.label("Heatmap");
+                            ui.checkbox(&mut self.current.record_statistics, "Count key presses");
+                            ui.end_row();
                         });
                     ui.add_space(20.0);
                     ui.checkbox(&mut self.current.save_settings, "Remember settings");
                     ui.add_space(5.0);
-                    ui.add_enabled_ui(!self.current.keyboard_config_path.is_empty(), |ui| {
+                    let all_configured = self.current.keyboards.iter().zip(&self.layout_names).all(
+                        |(keyboard, layout_names)| {
+                            !keyboard.keyboard_config_path.is_empty() || !layout_names.is_empty()
+                        },
+                    );
+                    ui.add_enabled_ui(all_configured, |ui| {
                         if ui
                             .add_sized([90.0, 28.0], egui::Button::new("Start"))
                             .clicked()
                         {
                             if let Ok(mut settings) = self.shared.lock() {
-                                settings.keyboard_config_path =
-                                    self.current.keyboard_config_path.trim().to_string();
-                                settings.layout_name = self.current.layout_name.clone();
+                                settings.keyboards = self
+                                    .current
+                                    .keyboards
+                                    .iter()
+                                    .map(|keyboard| KeyboardSettings {
+                                        keyboard_config_path: keyboard
+                                            .keyboard_config_path
+                                            .trim()
+                                            .to_string(),
+                                        ..keyboard.clone()
+                                    })
+                                    .collect();
+                                settings.overlay_mode = self.current.overlay_mode;
                                 settings.size = self.current.size;
                                 settings.position = self.current.position;
                                 settings.timeout = self.current.timeout;
                                 settings.margin = self.current.margin;
+                                settings.keymap_refresh_interval =
+                                    self.current.keymap_refresh_interval;
+                                settings.lighting_display = self.current.lighting_display;
+                                settings.modifier_labels = self.current.modifier_labels;
+                                settings.host_layout = self.current.host_layout;
+                                settings.layer_emulation = self.current.layer_emulation;
+                                settings.tapping_term = self.current.tapping_term;
+                                settings.tri_layer = self.current.tri_layer;
+                                settings.matrix_poll_interval = self.current.matrix_poll_interval;
+                                settings.record_statistics = self.current.record_statistics;
                                 settings.confirmed = true;
                                 settings.save_settings = self.current.save_settings;
                                 ctx.send_viewport_cmd(egui::ViewportCommand::Close);
@@ -218,6 +709,8 @@ impl eframe::App for SettingsApp {
                 });
             });
 
+        self.show_diagnostics(ctx);
+
         if let Some(error_message) = self.error.clone() {
             egui::Window::new("Error")
                 .collapsible(false)
@@ -0,0 +1,126 @@
+use qmk_via_api::keycodes::Keycode;
+
+use crate::keycode_labels::constants::*;
+
+/// Bits of the host keyboard LED state.
+pub mod leds {
+    pub const NUM_LOCK: u8 = 1 << 0;
+    pub const CAPS_LOCK: u8 = 1 << 1;
+    pub const SCROLL_LOCK: u8 = 1 << 2;
+}
+
+const MODIFIER_NAMES: [(u16, &str); 8] = [
+    (MOD_LCTL, "LCtrl"),
+    (MOD_LSFT, "LShift"),
+    (MOD_LALT, "LAlt"),
+    (MOD_LGUI, "LGui"),
+    (MOD_RCTL, "RCtrl"),
+    (MOD_RSFT, "RShift"),
+    (MOD_RALT, "RAlt"),
+    (MOD_RGUI, "RGui"),
+];
+
+/// Modifier, one-shot, Caps Word and lock LED state reported by the firmware. Modifiers use
+/// the 8-bit format of `get_mods()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyboardStatus {
+    pub mods: u8,
+    pub weak_mods: u8,
+    pub oneshot_mods: u8,
+    pub oneshot_locked_mods: u8,
+    /// Pending one-shot layer
+    pub oneshot_layer: Option<u8>,
+    pub caps_word: bool,
+    pub leds: u8,
+}
+
+fn modifier_names(mods: u8) -> Vec<&'static str> {
+    MODIFIER_NAMES
+        .iter()
+        .filter(|(bit, _)| mods as u16 & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Converts the 5-bit modifiers of `OSM()` and similar keycodes to the 8-bit format.
+fn expand_mods(mods: u16) -> u8 {
+    let is_right = mods & 0x10 != 0;
+    let mods = (mods & 0x0F) as u8;
+    if is_right {
+        mods << 4
+    } else {
+        mods
+    }
+}
+
+impl KeyboardStatus {
+    /// Modifiers currently applied to key presses, including pending one-shot modifiers.
+    pub fn get_active_mods(&self) -> u8 {
+        self.mods | self.weak_mods | self.oneshot_mods | self.oneshot_locked_mods
+    }
+
+    pub fn is_shifted(&self) -> bool {
+        self.get_active_mods() as u16 & (MOD_LSFT | MOD_RSFT) != 0
+    }
+
+    /// Whether the right Alt key, which is AltGr on most non-US host layouts, is held.
+    pub fn is_altgr(&self) -> bool {
+        self.get_active_mods() as u16 & MOD_RALT != 0
+    }
+
+    /// Whether a key with the given keycode is in effect, e.g. a held modifier, a pending
+    /// one-shot modifier or layer or an enabled lock.
+    pub fn is_keycode_active(&self, keycode: u16) -> bool {
+        let left_ctrl = Keycode::KC_LEFT_CTRL as u16;
+        match keycode {
+            k if (left_ctrl..=Keycode::KC_RIGHT_GUI as u16).contains(&k) => {
+                self.mods & (1 << (k - left_ctrl)) != 0
+            }
+            k if QK_ONE_SHOT_MOD.contains(&k) => {
+                let mods = expand_mods(k - QK_ONE_SHOT_MOD.start);
+                (self.oneshot_mods | self.oneshot_locked_mods) & mods == mods
+            }
+            k if QK_ONE_SHOT_LAYER.contains(&k) => {
+                self.oneshot_layer == Some((k - QK_ONE_SHOT_LAYER.start) as u8)
+            }
+            k if k == Keycode::KC_CAPS_LOCK as u16 => self.leds & leds::CAPS_LOCK != 0,
+            k if k == Keycode::KC_NUM_LOCK as u16 => self.leds & leds::NUM_LOCK != 0,
+            k if k == Keycode::KC_SCROLL_LOCK as u16 => self.leds & leds::SCROLL_LOCK != 0,
+            k if k == Keycode::QK_CAPS_WORD_TOGGLE as u16 => self.caps_word,
+            _ => false,
+        }
+    }
+
+    /// Returns the parts of the status strip, e.g. `Mods: LCtrl LShift` or `Caps Lock`.
+    pub fn describe(&self) -> Vec<String> {
+        let mut parts = Vec::new();
+        let mods = modifier_names(self.mods | self.weak_mods);
+        if !mods.is_empty() {
+            parts.push(format!("Mods: {}", mods.join(" ")));
+        }
+        let oneshot_mods = modifier_names(self.oneshot_mods & !self.oneshot_locked_mods);
+        if !oneshot_mods.is_empty() {
+            parts.push(format!("One-shot: {}", oneshot_mods.join(" ")));
+        }
+        let locked_mods = modifier_names(self.oneshot_locked_mods);
+        if !locked_mods.is_empty() {
+            parts.push(format!("Locked: {}", locked_mods.join(" ")));
+        }
+        if let Some(layer) = self.oneshot_layer {
+            parts.push(format!("One-shot layer {layer}"));
+        }
+        if self.caps_word {
+            parts.push("Caps Word".to_string());
+        }
+        for (bit, name) in [
+            (leds::CAPS_LOCK, "Caps Lock"),
+            (leds::NUM_LOCK, "Num Lock"),
+            (leds::SCROLL_LOCK, "Scroll Lock"),
+        ] {
+            if self.leds & bit != 0 {
+                parts.push(name.to_string());
+            }
+        }
+        parts
+    }
+}
@@ -0,0 +1,184 @@
+use hidapi::HidApi;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::transport::{Transport, ViaTransport};
+
+pub const VIA_USAGE_PAGE: u16 = 0xff60;
+const VIA_USAGE: u16 = 0x61;
+
+/// Identifies a raw HID interface of a keyboard across reconnects. Unlike the device path, this
+/// stays the same when the keyboard is plugged into another port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vid: u16,
+    pub pid: u16,
+    pub usage_page: u16,
+    pub serial: Option<String>,
+}
+
+impl DeviceId {
+    pub fn new(vid: u16, pid: u16) -> Self {
+        DeviceId {
+            vid,
+            pid,
+            usage_page: VIA_USAGE_PAGE,
+            serial: None,
+        }
+    }
+
+    pub fn matches(&self, info: &hidapi::DeviceInfo) -> bool {
+        info.vendor_id() == self.vid
+            && info.product_id() == self.pid
+            && info.usage_page() == self.usage_page
+            && self
+                .serial
+                .as_ref()
+                .is_none_or(|serial| info.serial_number() == Some(serial.as_str()))
+    }
+
+    fn from_device_info(info: &hidapi::DeviceInfo) -> Self {
+        DeviceId {
+            vid: info.vendor_id(),
+            pid: info.product_id(),
+            usage_page: info.usage_page(),
+            serial: info
+                .serial_number()
+                .filter(|serial| !serial.is_empty())
+                .map(|serial| serial.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:04x}:{:04x}",
+            self.vid, self.pid, self.usage_page
+        )?;
+        if let Some(serial) = &self.serial {
+            write!(f, ":{serial}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDeviceIdError;
+
+impl FromStr for DeviceId {
+    type Err = ParseDeviceIdError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.splitn(4, ':');
+        let mut next_hex = || {
+            parts
+                .next()
+                .and_then(|part| u16::from_str_radix(part, 16).ok())
+                .ok_or(ParseDeviceIdError)
+        };
+        let vid = next_hex()?;
+        let pid = next_hex()?;
+        let usage_page = next_hex()?;
+        let serial = parts
+            .next()
+            .filter(|serial| !serial.is_empty())
+            .map(|serial| serial.to_string());
+        Ok(DeviceId {
+            vid,
+            pid,
+            usage_page,
+            serial,
+        })
+    }
+}
+
+/// A connected raw HID interface that answered the VIA protocol version query.
+#[derive(Clone, Debug)]
+pub struct ViaDevice {
+    pub id: DeviceId,
+    pub product: Option<String>,
+    pub protocol_version: u16,
+}
+
+impl fmt::Display for ViaDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:04x}:{:04x}",
+            self.product.as_deref().unwrap_or("Unknown device"),
+            self.id.vid,
+            self.id.pid
+        )?;
+        if let Some(serial) = &self.id.serial {
+            write!(f, ", {serial}")?;
+        }
+        write!(f, ", VIA v{})", self.protocol_version)
+    }
+}
+
+/// Lists all connected raw HID interfaces speaking the VIA protocol. Besides the default VIA
+/// usage page, vendor defined usage pages with the VIA usage are probed as well, since some
+/// firmware forks move the raw HID interface to another usage page.
+pub fn list_devices() -> Vec<ViaDevice> {
+    let api = match HidApi::new() {
+        Ok(api) => api,
+        Err(_) => return Vec::new(),
+    };
+
+    api.device_list()
+        .filter(|info| {
+            info.usage_page() == VIA_USAGE_PAGE
+                || (info.usage_page() >= 0xff00 && info.usage() == VIA_USAGE)
+        })
+        .filter_map(|info| {
+            let device = info.open_device(&api).ok()?;
+            let protocol_version = ViaTransport::from_device(device)
+                .get_protocol_version()
+                .ok()?;
+            Some(ViaDevice {
+                id: DeviceId::from_device_info(info),
+                product: info.product_string().map(|s| s.to_string()),
+                protocol_version,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_displayed_device_ids() {
+        let device_id = DeviceId::new(0x4653, 0x0001);
+        assert_eq!(device_id.to_string(), "4653:0001:ff60");
+        assert_eq!("4653:0001:ff60".parse::<DeviceId>().unwrap(), device_id);
+
+        // Serial numbers may contain colons themselves
+        let device_id: DeviceId = "FEED:6060:FF60:vial:f64c2b3c".parse().unwrap();
+        assert_eq!(device_id.serial.as_deref(), Some("vial:f64c2b3c"));
+        assert_eq!(device_id.to_string(), "feed:6060:ff60:vial:f64c2b3c");
+        assert_eq!(
+            device_id.to_string().parse::<DeviceId>().unwrap(),
+            device_id
+        );
+
+        assert_eq!("feed:6060:ff60:".parse::<DeviceId>().unwrap().serial, None);
+    }
+
+    #[test]
+    fn rejects_malformed_device_ids() {
+        for value in [
+            "",
+            "feed",
+            "feed:6060",
+            "feed:6060:",
+            "feed:60g0:ff60",
+            "feed:6060:10000",
+        ] {
+            assert!(value.parse::<DeviceId>().is_err(), "{value}");
+        }
+    }
+}
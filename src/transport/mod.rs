@@ -0,0 +1,268 @@
+mod discovery;
+mod simulated;
+mod via;
+
+pub use discovery::{list_devices, DeviceId, ViaDevice};
+pub use simulated::SimulatedTransport;
+pub use via::{check_protocol_version, ViaTransport};
+
+use qmk_via_api::api::DATA_BUFFER_SIZE;
+use qmk_via_api::api_commands::ViaCommandId;
+use qmk_via_api::utils;
+use std::time::Duration;
+
+use crate::keyboard_info::KeyboardInfo;
+use crate::settings::KeyboardSettings;
+
+/// Command id the firmware responds with if it does not handle a command.
+pub const ID_UNHANDLED: u8 = 0xFF;
+
+/// Keyboard value id of the switch matrix state, as used by the VIA matrix tester.
+pub const ID_SWITCH_MATRIX_STATE: u8 = 0x03;
+/// Bytes of a response available for the switch matrix state.
+const SWITCH_MATRIX_STATE_SIZE: usize = 28;
+
+/// Connection to a keyboard speaking the VIA protocol, extended by the custom layer and key
+/// press messages sent from the firmware.
+pub trait Transport: Send {
+    /// Sends a raw HID command and waits for the response echoing the command id and arguments.
+    /// Returns `None` if the firmware does not handle the command.
+    fn try_command(&self, command: u8, args: &[u8]) -> Result<Option<Vec<u8>>, String>;
+
+    /// Like [`Self::try_command`], but commands the firmware does not handle are an error.
+    fn command(&self, command: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        self.try_command(command, args)?
+            .ok_or_else(|| format!("Command 0x{command:02X} is not supported by the firmware"))
+    }
+
+    /// Sends a raw HID command whose response does not echo the command, as used by Vial.
+    /// Messages sent by the firmware in the meantime are kept for [`Self::read_frame`].
+    fn raw_command(&self, command: u8, args: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// Whether the firmware is Vial, which replaces some VIA commands with its own.
+    fn is_vial(&self) -> bool {
+        false
+    }
+
+    /// Whether the keymap changed since the last call without the firmware telling, as during
+    /// a replay. It is read again then.
+    fn keymap_changed(&self) -> bool {
+        false
+    }
+
+    /// Waits up to `timeout` for the next raw HID frame sent by the firmware. Returns `None` if
+    /// no frame arrived in time.
+    fn read_frame(&self, timeout: Duration) -> Result<Option<Vec<u8>>, String>;
+
+    fn get_protocol_version(&self) -> Result<u16, String> {
+        self.command(ViaCommandId::GetProtocolVersion as u8, &[])
+            .map(|response| utils::shift_to_16_bit(response[1], response[2]))
+            .map_err(|e| format!("Failed to get protocol version: {e}"))
+    }
+
+    fn get_layer_count(&self) -> Result<u8, String> {
+        self.command(ViaCommandId::DynamicKeymapGetLayerCount as u8, &[])
+            .map(|response| response[1])
+            .map_err(|e| format!("Failed to get layer count: {e}"))
+    }
+
+    /// Returns the keycodes of the given layer in row-major order.
+    fn read_raw_matrix(&self, layer: u8, rows: usize, cols: usize) -> Result<Vec<u16>, String> {
+        let layer_size = rows * cols * 2;
+        let mut bytes = Vec::with_capacity(layer_size);
+        while bytes.len() < layer_size {
+            let size = (layer_size - bytes.len()).min(DATA_BUFFER_SIZE);
+            let (offset_hi, offset_lo) =
+                utils::shift_from_16_bit((layer as usize * layer_size + bytes.len()) as u16);
+            let response = self
+                .command(
+                    ViaCommandId::DynamicKeymapGetBuffer as u8,
+                    &[offset_hi, offset_lo, size as u8],
+                )
+                .map_err(|e| format!("Failed to read keymap of layer {layer}: {e}"))?;
+            bytes.extend_from_slice(&response[4..4 + size]);
+        }
+        Ok(utils::shift_buffer_to_16_bit(&bytes))
+    }
+
+    /// Returns the keycode at a matrix position. Unlike [`Self::read_raw_matrix`], the firmware
+    /// locates the key using its own matrix size.
+    fn read_keycode(&self, layer: u8, row: u8, col: u8) -> Result<u16, String> {
+        self.command(
+            ViaCommandId::DynamicKeymapGetKeycode as u8,
+            &[layer, row, col],
+        )
+        .map(|response| utils::shift_to_16_bit(response[4], response[5]))
+        .map_err(|e| format!("Failed to read keycode at row {row}, column {col}: {e}"))
+    }
+
+    fn get_macro_count(&self) -> Result<u8, String> {
+        self.command(ViaCommandId::DynamicKeymapMacroGetCount as u8, &[])
+            .map(|response| response[1])
+            .map_err(|e| format!("Failed to get macro count: {e}"))
+    }
+
+    /// Returns the start of the dynamic macro buffer containing the first `macro_count` NUL
+    /// terminated macros. Reading stops early once they are complete.
+    fn read_macro_buffer(&self, macro_count: u8) -> Result<Vec<u8>, String> {
+        let buffer_size = self
+            .command(ViaCommandId::DynamicKeymapMacroGetBufferSize as u8, &[])
+            .map(|response| utils::shift_to_16_bit(response[1], response[2]) as usize)
+            .map_err(|e| format!("Failed to get macro buffer size: {e}"))?;
+        let mut bytes = Vec::with_capacity(buffer_size);
+        let is_complete =
+            |bytes: &[u8]| bytes.iter().filter(|b| **b == 0).count() >= macro_count as usize;
+        while bytes.len() < buffer_size && !is_complete(&bytes) {
+            let size = (buffer_size - bytes.len()).min(DATA_BUFFER_SIZE);
+            let (offset_hi, offset_lo) = utils::shift_from_16_bit(bytes.len() as u16);
+            let response = self
+                .command(
+                    ViaCommandId::DynamicKeymapMacroGetBuffer as u8,
+                    &[offset_hi, offset_lo, size as u8],
+                )
+                .map_err(|e| format!("Failed to read macro buffer: {e}"))?;
+            bytes.extend_from_slice(&response[4..4 + size]);
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the current switch state indexed by `[row][col]`, or `None` if the firmware does
+    /// not support the query. Rows are read in chunks fitting into a response, each row sent as
+    /// a big-endian bitmask of its columns.
+    fn read_switch_matrix(
+        &self,
+        rows: usize,
+        cols: usize,
+    ) -> Result<Option<Vec<Vec<bool>>>, String> {
+        let row_size = cols.div_ceil(8);
+        let rows_per_read = (SWITCH_MATRIX_STATE_SIZE / row_size).max(1);
+        let mut matrix = Vec::with_capacity(rows);
+        while matrix.len() < rows {
+            let offset = matrix.len();
+            let Some(response) = self
+                .try_command(
+                    ViaCommandId::GetKeyboardValue as u8,
+                    &[ID_SWITCH_MATRIX_STATE, offset as u8],
+                )
+                .map_err(|e| format!("Failed to read switch matrix state: {e}"))?
+            else {
+                return Ok(None);
+            };
+            let data = response
+                .get(3..3 + SWITCH_MATRIX_STATE_SIZE)
+                .ok_or("Switch matrix state response is too short")?;
+            for row_bytes in data
+                .chunks_exact(row_size)
+                .take(rows_per_read.min(rows - offset))
+            {
+                let value = row_bytes
+                    .iter()
+                    .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                matrix.push((0..cols).map(|col| value & (1 << col) != 0).collect());
+            }
+        }
+        Ok(Some(matrix))
+    }
+
+    /// Returns the counter-clockwise and clockwise keycodes of an encoder on the given layer.
+    fn read_encoder(&self, layer: u8, index: u8) -> Result<[u16; 2], String> {
+        let mut keycodes = [0; 2];
+        for (clockwise, keycode) in keycodes.iter_mut().enumerate() {
+            let response = self
+                .command(
+                    ViaCommandId::DynamicKeymapGetEncoder as u8,
+                    &[layer, index, clockwise as u8],
+                )
+                .map_err(|e| format!("Failed to read encoder {index} of layer {layer}: {e}"))?;
+            *keycode = utils::shift_to_16_bit(response[4], response[5]);
+        }
+        Ok(keycodes)
+    }
+}
+
+/// Opens a new connection to the keyboard. Called again whenever the connection is lost.
+pub type Connector = Box<dyn Fn() -> Result<Box<dyn Transport>, String> + Send>;
+
+/// Connects to the recorded session or the simulated keyboard if configured, otherwise to the
+/// selected device or the device matching the IDs of the keyboard info.
+pub fn create_connector(settings: &KeyboardSettings, keyboard_info: &KeyboardInfo) -> Connector {
+    let settings = settings.clone();
+    let device_id = device_id(&settings, keyboard_info);
+    Box::new(move || open(&settings, &device_id, true))
+}
+
+/// Returns the selected device or the one matching the IDs of the keyboard info.
+pub fn device_id(settings: &KeyboardSettings, keyboard_info: &KeyboardInfo) -> DeviceId {
+    settings
+        .device
+        .clone()
+        .unwrap_or_else(|| DeviceId::new(keyboard_info.vid, keyboard_info.pid))
+}
+
+/// Connects like the connector of [`create_connector`]. Without `check_version`, a device
+/// speaking an unsupported protocol version is accepted, so the diagnostics can report it.
+pub fn open(
+    settings: &KeyboardSettings,
+    device_id: &DeviceId,
+    check_version: bool,
+) -> Result<Box<dyn Transport>, String> {
+    if !settings.replay_path.is_empty() {
+        return Ok(Box::new(SimulatedTransport::from_recording(
+            &settings.replay_path,
+            settings.replay_speed,
+        )?));
+    }
+    if !settings.simulation_path.is_empty() {
+        return Ok(Box::new(SimulatedTransport::new(
+            &settings.simulation_path,
+        )?));
+    }
+    if check_version {
+        Ok(Box::new(ViaTransport::connect(device_id)?))
+    } else {
+        Ok(Box::new(ViaTransport::open(device_id)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmk_via_api::api::RAW_EPSIZE;
+
+    /// Transport answering every command with the same outcome.
+    struct FixedTransport(Result<Option<Vec<u8>>, String>);
+
+    impl Transport for FixedTransport {
+        fn try_command(&self, _command: u8, _args: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            self.0.clone()
+        }
+
+        fn raw_command(&self, _command: u8, _args: &[u8]) -> Result<Vec<u8>, String> {
+            Err("unsupported".to_string())
+        }
+
+        fn read_frame(&self, _timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn reads_the_switch_matrix_only_if_supported() {
+        let mut response = vec![0u8; RAW_EPSIZE];
+        response[3] = 0b10;
+        let transport = FixedTransport(Ok(Some(response)));
+        assert_eq!(
+            transport.read_switch_matrix(1, 2),
+            Ok(Some(vec![vec![false, true]]))
+        );
+
+        let unsupported = FixedTransport(Ok(None));
+        assert_eq!(unsupported.read_switch_matrix(1, 2), Ok(None));
+        assert!(unsupported
+            .command(0x02, &[ID_SWITCH_MATRIX_STATE])
+            .is_err());
+
+        let timed_out = FixedTransport(Err("Timed out".to_string()));
+        assert!(timed_out.read_switch_matrix(1, 2).is_err());
+    }
+}
@@ -0,0 +1,498 @@
+use qmk_via_api::api::RAW_EPSIZE;
+use qmk_via_api::api_commands::{ViaChannelId, ViaCommandId};
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::BufReader;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, Message};
+use crate::session::{self, RecordedEvent};
+use crate::status::KeyboardStatus;
+use crate::transport::{Transport, ID_SWITCH_MATRIX_STATE};
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ScriptedEvent {
+    Layer {
+        #[serde(default)]
+        delay_ms: u64,
+        layer_state: u32,
+        #[serde(default = "default_layer_state")]
+        default_layer_state: u32,
+    },
+    Press {
+        #[serde(default)]
+        delay_ms: u64,
+        row: u8,
+        col: u8,
+    },
+    Release {
+        #[serde(default)]
+        delay_ms: u64,
+        row: u8,
+        col: u8,
+    },
+    Status {
+        #[serde(default)]
+        delay_ms: u64,
+        #[serde(default)]
+        mods: u8,
+        #[serde(default)]
+        oneshot_mods: u8,
+        #[serde(default)]
+        oneshot_locked_mods: u8,
+        #[serde(default)]
+        oneshot_layer: Option<u8>,
+        #[serde(default)]
+        caps_word: bool,
+        #[serde(default)]
+        leds: u8,
+    },
+    /// Replaces the keymap, as if it was changed by another program.
+    Keymap {
+        #[serde(default)]
+        delay_ms: u64,
+        keymap: Vec<Vec<Vec<u16>>>,
+        #[serde(default)]
+        encoders: Vec<Vec<[u16; 2]>>,
+    },
+}
+
+impl ScriptedEvent {
+    fn delay_ms(&self) -> u64 {
+        match *self {
+            ScriptedEvent::Layer { delay_ms, .. }
+            | ScriptedEvent::Press { delay_ms, .. }
+            | ScriptedEvent::Release { delay_ms, .. }
+            | ScriptedEvent::Status { delay_ms, .. }
+            | ScriptedEvent::Keymap { delay_ms, .. } => delay_ms,
+        }
+    }
+}
+
+fn default_layer_state() -> u32 {
+    1
+}
+
+fn default_protocol_version() -> u16 {
+    12
+}
+
+fn default_key_events() -> bool {
+    true
+}
+
+/// RGB matrix state served through the VIA custom value commands.
+#[derive(Deserialize)]
+struct SimulatedLighting {
+    brightness: u8,
+    #[serde(default)]
+    effect: u8,
+    #[serde(default)]
+    speed: u8,
+    #[serde(default)]
+    hue: u8,
+    #[serde(default)]
+    saturation: u8,
+}
+
+#[derive(Deserialize)]
+struct Simulation {
+    #[serde(default = "default_protocol_version")]
+    protocol_version: u16,
+    /// Keycodes indexed by `[layer][row][col]`, replaced by keymap events.
+    keymap: RefCell<Vec<Vec<Vec<u16>>>>,
+    #[serde(default)]
+    events: Vec<ScriptedEvent>,
+    #[serde(default)]
+    repeat: bool,
+    #[serde(default)]
+    lighting: Option<SimulatedLighting>,
+    /// Counter-clockwise and clockwise keycodes indexed by `[layer][encoder]`.
+    #[serde(default)]
+    encoders: RefCell<Vec<Vec<[u16; 2]>>>,
+    /// Whether key presses are sent as key events. Otherwise they are only visible in the
+    /// switch matrix state, like on firmware without the custom messages.
+    #[serde(default = "default_key_events")]
+    key_events: bool,
+}
+
+/// Keyboard backend without hardware. The keymap is served from a JSON file and the scripted
+/// events are played back as the same raw HID frames the firmware would send.
+pub struct SimulatedTransport {
+    simulation: Simulation,
+    next_event: Cell<usize>,
+    /// Point in time at which the delay of the next event started
+    last_event_time: Cell<Instant>,
+    /// Keys pressed by the script, served as switch matrix state
+    pressed: RefCell<Vec<(u8, u8)>>,
+    /// Whether a keymap event replaced the keymap since it was last asked
+    keymap_changed: Cell<bool>,
+}
+
+impl SimulatedTransport {
+    pub fn new(path: &str) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open simulated keyboard file '{path}': {e}"))?;
+        let simulation: Simulation = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Failed to parse simulated keyboard file '{path}': {e}"))?;
+
+        Ok(SimulatedTransport {
+            simulation,
+            next_event: Cell::new(0),
+            last_event_time: Cell::new(Instant::now()),
+            pressed: RefCell::new(Vec::new()),
+            keymap_changed: Cell::new(false),
+        })
+    }
+
+    /// Serves a fixed keymap without any events, as used to view keymaps offline.
+    pub fn from_keymap(keymap: Vec<Vec<Vec<u16>>>, encoders: Vec<Vec<[u16; 2]>>) -> Self {
+        SimulatedTransport {
+            simulation: Simulation {
+                protocol_version: default_protocol_version(),
+                keymap: RefCell::new(keymap),
+                events: Vec::new(),
+                repeat: false,
+                lighting: None,
+                encoders: RefCell::new(encoders),
+                key_events: default_key_events(),
+            },
+            next_event: Cell::new(0),
+            last_event_time: Cell::new(Instant::now()),
+            pressed: RefCell::new(Vec::new()),
+            keymap_changed: Cell::new(false),
+        }
+    }
+
+    /// Replays a recorded session, with the delays between the events divided by `speed`. The
+    /// replay starts with the first keymap recorded and later keymaps replace it at the time
+    /// they were recorded. Connection changes are left out.
+    pub fn from_recording(path: &str, speed: f64) -> Result<Self, String> {
+        let lines = session::read_recording(path)?;
+        let (first, keymap, encoders) = lines
+            .iter()
+            .enumerate()
+            .find_map(|(index, line)| match &line.event {
+                RecordedEvent::Keymap { keymap, encoders } => {
+                    Some((index, keymap.clone(), encoders.clone()))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| format!("Recording '{path}' contains no keymap"))?;
+        let start = lines[first].time_ms;
+
+        let mut last_time_ms = start;
+        let mut events = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            if line.time_ms < start || index == first {
+                continue;
+            }
+            let delay_ms = (line.time_ms.saturating_sub(last_time_ms) as f64 / speed) as u64;
+            let event = match &line.event {
+                RecordedEvent::Keymap { keymap, encoders } => ScriptedEvent::Keymap {
+                    delay_ms,
+                    keymap: keymap.clone(),
+                    encoders: encoders.clone(),
+                },
+                &RecordedEvent::Layer {
+                    default_layer_state,
+                    layer_state,
+                } => ScriptedEvent::Layer {
+                    delay_ms,
+                    layer_state,
+                    default_layer_state,
+                },
+                &RecordedEvent::Press { row, col } => ScriptedEvent::Press { delay_ms, row, col },
+                &RecordedEvent::Release { row, col } => {
+                    ScriptedEvent::Release { delay_ms, row, col }
+                }
+                RecordedEvent::Connected | RecordedEvent::Disconnected => continue,
+            };
+            events.push(event);
+            last_time_ms = line.time_ms;
+        }
+
+        let mut transport = Self::from_keymap(keymap, encoders);
+        transport.simulation.events = events;
+        Ok(transport)
+    }
+
+    /// Encodes the switch matrix state starting at row `offset` like the VIA keyboard value
+    /// query, with the rows as big-endian bitmasks of their columns.
+    fn encode_switch_matrix_state(&self, offset: u8, response: &mut [u8]) {
+        let keymap = self.simulation.keymap.borrow();
+        let rows = keymap.first().map_or(0, Vec::len);
+        let cols = keymap
+            .first()
+            .and_then(|layer| layer.first())
+            .map_or(0, Vec::len);
+        let row_size = cols.div_ceil(8).max(1);
+        let pressed = self.pressed.borrow();
+        for (i, chunk) in response[3..].chunks_exact_mut(row_size).enumerate() {
+            let row = offset as usize + i;
+            if row >= rows {
+                break;
+            }
+            let value = pressed
+                .iter()
+                .filter(|(pressed_row, _)| *pressed_row as usize == row)
+                .fold(0u64, |value, (_, col)| value | 1 << col);
+            chunk.copy_from_slice(&value.to_be_bytes()[8 - row_size..]);
+        }
+    }
+
+    /// Returns the frame the firmware would send for the event, `None` for keymap changes.
+    fn encode_event(event: &ScriptedEvent) -> Option<Vec<u8>> {
+        let message = match *event {
+            ScriptedEvent::Layer {
+                layer_state,
+                default_layer_state,
+                ..
+            } => Message::LayerState {
+                default_layer_state,
+                layer_state,
+            },
+            ScriptedEvent::Press { row, col, .. } => Message::KeyEvent {
+                row,
+                col,
+                pressed: true,
+            },
+            ScriptedEvent::Release { row, col, .. } => Message::KeyEvent {
+                row,
+                col,
+                pressed: false,
+            },
+            ScriptedEvent::Status {
+                mods,
+                oneshot_mods,
+                oneshot_locked_mods,
+                oneshot_layer,
+                caps_word,
+                leds,
+                ..
+            } => Message::Status(KeyboardStatus {
+                mods,
+                weak_mods: 0,
+                oneshot_mods,
+                oneshot_locked_mods,
+                oneshot_layer,
+                caps_word,
+                leds,
+            }),
+            ScriptedEvent::Keymap { .. } => return None,
+        };
+        Some(protocol::encode(&message))
+    }
+}
+
+impl Transport for SimulatedTransport {
+    fn try_command(&self, command: u8, args: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        if command == protocol::HANDSHAKE_COMMAND_ID {
+            let mut capabilities =
+                protocol::capabilities::LAYER_STATE | protocol::capabilities::STATUS;
+            if self.simulation.key_events {
+                capabilities |= protocol::capabilities::KEY_EVENTS;
+            }
+            let mut response = vec![0u8; RAW_EPSIZE];
+            response[0] = command;
+            response[1..1 + args.len()].copy_from_slice(args);
+            response[3] = protocol::HOST_PROTOCOL_VERSION;
+            response[4..6].copy_from_slice(&capabilities.to_le_bytes());
+            return Ok(Some(response));
+        }
+        if let (true, [ID_SWITCH_MATRIX_STATE, offset]) =
+            (command == ViaCommandId::GetKeyboardValue as u8, args)
+        {
+            let mut response = vec![0u8; RAW_EPSIZE];
+            response[0] = command;
+            response[1..3].copy_from_slice(args);
+            self.encode_switch_matrix_state(*offset, &mut response);
+            return Ok(Some(response));
+        }
+        if let (Some(lighting), [channel, value_id]) = (&self.simulation.lighting, args) {
+            let is_rgb_matrix_value = command == ViaCommandId::CustomMenuGetValue as u8
+                && *channel == ViaChannelId::IdQmkRgbMatrixChannel as u8;
+            let value = match value_id {
+                1 => Some([lighting.brightness, 0]),
+                2 => Some([lighting.effect, 0]),
+                3 => Some([lighting.speed, 0]),
+                4 => Some([lighting.hue, lighting.saturation]),
+                _ => None,
+            };
+            if let (true, Some(value)) = (is_rgb_matrix_value, value) {
+                let mut response = vec![0u8; RAW_EPSIZE];
+                response[0] = command;
+                response[1..3].copy_from_slice(args);
+                response[3..5].copy_from_slice(&value);
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    fn raw_command(&self, command: u8, _args: &[u8]) -> Result<Vec<u8>, String> {
+        Err(format!(
+            "Command 0x{command:02X} is not supported by the simulated keyboard"
+        ))
+    }
+
+    fn get_protocol_version(&self) -> Result<u16, String> {
+        Ok(self.simulation.protocol_version)
+    }
+
+    fn get_layer_count(&self) -> Result<u8, String> {
+        Ok(self.simulation.keymap.borrow().len() as u8)
+    }
+
+    fn read_raw_matrix(&self, layer: u8, rows: usize, cols: usize) -> Result<Vec<u16>, String> {
+        let keymap = self.simulation.keymap.borrow();
+        let keymap_layer = keymap
+            .get(layer as usize)
+            .ok_or_else(|| format!("Simulated keymap has no layer {layer}"))?;
+
+        let mut keycodes = vec![0; rows * cols];
+        for (row, row_keycodes) in keymap_layer.iter().take(rows).enumerate() {
+            for (col, keycode) in row_keycodes.iter().take(cols).enumerate() {
+                keycodes[row * cols + col] = *keycode;
+            }
+        }
+        Ok(keycodes)
+    }
+
+    fn read_keycode(&self, layer: u8, row: u8, col: u8) -> Result<u16, String> {
+        Ok(self
+            .simulation
+            .keymap
+            .borrow()
+            .get(layer as usize)
+            .and_then(|keymap_layer| keymap_layer.get(row as usize))
+            .and_then(|row_keycodes| row_keycodes.get(col as usize))
+            .copied()
+            .unwrap_or(0))
+    }
+
+    fn read_encoder(&self, layer: u8, index: u8) -> Result<[u16; 2], String> {
+        self.simulation
+            .encoders
+            .borrow()
+            .get(layer as usize)
+            .and_then(|encoders| encoders.get(index as usize))
+            .copied()
+            .ok_or_else(|| format!("Simulated keymap has no encoder {index} on layer {layer}"))
+    }
+
+    fn keymap_changed(&self) -> bool {
+        self.keymap_changed.replace(false)
+    }
+
+    fn read_frame(&self, timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        let events = &self.simulation.events;
+        let mut index = self.next_event.get();
+        if index >= events.len() {
+            if !self.simulation.repeat || events.is_empty() {
+                // The script is over, so the simulated keyboard stays silent from now on
+                thread::sleep(timeout);
+                return Ok(None);
+            }
+            index = 0;
+        }
+
+        let event = &events[index];
+        let due = self.last_event_time.get() + Duration::from_millis(event.delay_ms());
+        let now = Instant::now();
+        if due > now + timeout {
+            thread::sleep(timeout);
+            return Ok(None);
+        }
+
+        thread::sleep(due.saturating_duration_since(now));
+        self.next_event.set(index + 1);
+        self.last_event_time.set(due.max(now));
+
+        match event {
+            ScriptedEvent::Keymap {
+                keymap, encoders, ..
+            } => {
+                *self.simulation.keymap.borrow_mut() = keymap.clone();
+                *self.simulation.encoders.borrow_mut() = encoders.clone();
+                self.keymap_changed.set(true);
+                return Ok(None);
+            }
+            &ScriptedEvent::Press { row, col, .. } if !self.simulation.key_events => {
+                self.pressed.borrow_mut().push((row, col));
+                return Ok(None);
+            }
+            &ScriptedEvent::Release { row, col, .. } if !self.simulation.key_events => {
+                self.pressed.borrow_mut().retain(|key| *key != (row, col));
+                return Ok(None);
+            }
+            _ => {}
+        }
+        Ok(Self::encode_event(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const RECORDING: &str = r#"{"time_ms":0,"type":"keymap","keymap":[[[4,5]]],"encoders":[]}
+{"time_ms":1,"type":"connected"}
+{"time_ms":10,"type":"layer","default_layer_state":1,"layer_state":2}
+{"time_ms":20,"type":"keymap","keymap":[[[6,7]],[[8,9]]],"encoders":[[[1,2]],[[3,4]]]}
+{"time_ms":30,"type":"press","row":0,"col":1}
+"#;
+
+    fn replay(recording: &str) -> SimulatedTransport {
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+        fs::write(&path, recording).unwrap();
+        let transport = SimulatedTransport::from_recording(path.to_str().unwrap(), 1000.0);
+        fs::remove_file(&path).unwrap();
+        transport.unwrap()
+    }
+
+    fn next_frame(transport: &SimulatedTransport) -> Option<Vec<u8>> {
+        transport.read_frame(Duration::from_millis(100)).unwrap()
+    }
+
+    #[test]
+    fn replays_every_recorded_keymap_in_order() {
+        let transport = replay(RECORDING);
+        assert_eq!(transport.read_raw_matrix(0, 1, 2).unwrap(), [4, 5]);
+        assert_eq!(transport.get_layer_count().unwrap(), 1);
+
+        let layer_frame = next_frame(&transport).unwrap();
+        assert_eq!(
+            protocol::decode(&layer_frame, &protocol::FirmwareInfo::LEGACY),
+            Ok(Message::LayerState {
+                default_layer_state: 1,
+                layer_state: 2,
+            })
+        );
+        // The layer change was recorded with the first keymap still in place
+        assert!(!transport.keymap_changed());
+        assert_eq!(transport.read_keycode(0, 0, 0).unwrap(), 4);
+
+        assert_eq!(next_frame(&transport), None);
+        assert!(transport.keymap_changed());
+        assert!(!transport.keymap_changed());
+        assert_eq!(transport.get_layer_count().unwrap(), 2);
+        assert_eq!(transport.read_raw_matrix(1, 1, 2).unwrap(), [8, 9]);
+        assert_eq!(transport.read_encoder(1, 0).unwrap(), [3, 4]);
+
+        assert!(next_frame(&transport).is_some());
+    }
+
+    #[test]
+    fn rejects_recordings_without_keymap() {
+        let path = std::env::temp_dir().join(format!("no-keymap-{}.jsonl", std::process::id()));
+        fs::write(&path, "{\"time_ms\":0,\"type\":\"connected\"}\n").unwrap();
+        let result = SimulatedTransport::from_recording(path.to_str().unwrap(), 1.0);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
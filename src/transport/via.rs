@@ -0,0 +1,185 @@
+use hidapi::{HidApi, HidDevice};
+use qmk_via_api::api::RAW_EPSIZE;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::protocol;
+use crate::transport::{DeviceId, Transport, ID_UNHANDLED};
+use crate::vial;
+
+/// Oldest VIA protocol version whose keycodes are understood.
+const MIN_PROTOCOL_VERSION: u16 = 12;
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct ViaTransport {
+    device: HidDevice,
+    /// Frames sent by the firmware while waiting for a command response
+    pending_frames: RefCell<VecDeque<Vec<u8>>>,
+    is_vial: bool,
+}
+
+impl ViaTransport {
+    /// Opens the device and rejects it unless it speaks a supported protocol version.
+    pub fn connect(device_id: &DeviceId) -> Result<Self, String> {
+        let transport = Self::open(device_id)?;
+        let protocol_version = transport.get_protocol_version()?;
+        let vial_version = vial::get_protocol_version(&transport).ok();
+        check_protocol_version(protocol_version, vial_version)?;
+        Ok(transport)
+    }
+
+    /// Opens the device without checking the protocol version.
+    pub fn open(device_id: &DeviceId) -> Result<Self, String> {
+        let api = HidApi::new().map_err(|e| format!("Failed to initialize HID API: {e}"))?;
+        let device = api
+            .device_list()
+            .find(|info| device_id.matches(info))
+            .ok_or_else(|| format!("Failed to find device ({device_id})"))?
+            .open_device(&api)
+            .map_err(|e| format!("Failed to connect to device ({device_id}): {e}"))?;
+        let mut transport = Self::from_device(device);
+        transport.is_vial = vial::get_protocol_version(&transport).is_ok();
+        Ok(transport)
+    }
+
+    pub(crate) fn from_device(device: HidDevice) -> Self {
+        ViaTransport {
+            device,
+            pending_frames: RefCell::new(VecDeque::new()),
+            is_vial: false,
+        }
+    }
+
+    fn send(&self, command: u8, args: &[u8]) -> Result<(), String> {
+        let mut request = vec![0u8; RAW_EPSIZE + 1];
+        request[1] = command;
+        request[2..2 + args.len()].copy_from_slice(args);
+        self.device
+            .write(&request)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send command 0x{command:02X}: {e}"))
+    }
+
+    /// Waits for the next frame until the command timed out.
+    fn receive(&self, command: u8, deadline: Instant) -> Result<Vec<u8>, String> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(format!(
+                    "Timed out waiting for response to command 0x{command:02X}"
+                ));
+            }
+
+            let mut response = vec![0u8; RAW_EPSIZE];
+            let size = self
+                .device
+                .read_timeout(&mut response, remaining.as_millis() as i32)
+                .map_err(|e| e.to_string())?;
+            if size > 0 {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Returns an error unless the VIA protocol version, or the Vial one if the firmware is Vial, is
+/// supported. Vial firmware reports an older VIA protocol version but uses the same keycodes.
+pub fn check_protocol_version(
+    protocol_version: u16,
+    vial_version: Option<u32>,
+) -> Result<(), String> {
+    let is_supported_vial =
+        vial_version.is_some_and(|version| version >= vial::MIN_PROTOCOL_VERSION);
+    if protocol_version < MIN_PROTOCOL_VERSION && !is_supported_vial {
+        return Err(format!(
+            "Unsupported protocol version: {}. Minimum required version is {}.",
+            protocol_version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    Ok(())
+}
+
+impl Transport for ViaTransport {
+    fn try_command(&self, command: u8, args: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.send(command, args)?;
+
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            let response = self.receive(command, deadline)?;
+            if response[1..].starts_with(args) {
+                if response[0] == command {
+                    return Ok(Some(response));
+                }
+                if response[0] == ID_UNHANDLED && !args.is_empty() {
+                    return Ok(None);
+                }
+            }
+            self.pending_frames.borrow_mut().push_back(response);
+        }
+    }
+
+    fn raw_command(&self, command: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+        self.send(command, args)?;
+
+        // The response is raw data and may look like a message by chance. Messages are only
+        // set aside as long as another frame follows, otherwise the first one is the response.
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        let mut messages = VecDeque::new();
+        loop {
+            let response = match self.receive(command, deadline) {
+                Ok(response) => response,
+                Err(e) => {
+                    let response = messages.pop_front().ok_or(e)?;
+                    self.pending_frames.borrow_mut().extend(messages);
+                    return Ok(response);
+                }
+            };
+            let is_unhandled = response[0] == ID_UNHANDLED
+                && response[1..].starts_with(args)
+                && response[1 + args.len()..].iter().all(|byte| *byte == 0);
+            if is_unhandled {
+                self.pending_frames.borrow_mut().extend(messages);
+                return Err(format!(
+                    "Command 0x{command:02X} is not supported by the firmware"
+                ));
+            }
+            if protocol::is_message_frame(&response) {
+                messages.push_back(response);
+                continue;
+            }
+            self.pending_frames.borrow_mut().extend(messages);
+            return Ok(response);
+        }
+    }
+
+    fn is_vial(&self) -> bool {
+        self.is_vial
+    }
+
+    fn read_frame(&self, timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+        if let Some(frame) = self.pending_frames.borrow_mut().pop_front() {
+            return Ok(Some(frame));
+        }
+
+        let mut frame = vec![0u8; RAW_EPSIZE];
+        let size = self
+            .device
+            .read_timeout(&mut frame, timeout.as_millis() as i32)
+            .map_err(|e| e.to_string())?;
+        Ok((size > 0).then_some(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_recent_via_and_vial_firmware() {
+        assert!(check_protocol_version(MIN_PROTOCOL_VERSION, None).is_ok());
+        assert!(check_protocol_version(9, None).is_err());
+        assert!(check_protocol_version(9, Some(vial::MIN_PROTOCOL_VERSION)).is_ok());
+        assert!(check_protocol_version(9, Some(vial::MIN_PROTOCOL_VERSION - 1)).is_err());
+    }
+}
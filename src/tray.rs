@@ -1,7 +1,30 @@
 use image::load_from_memory;
-use std::process;
+use std::sync::mpsc::{self, Receiver};
 use std::thread;
-use tray_icon::{menu::Menu, menu::MenuEvent, menu::MenuItem, Icon, TrayIcon, TrayIconBuilder};
+use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuItem};
+use tray_icon::{Icon, TrayIcon, TrayIconBuilder};
+
+const TOOLTIP: &str = "QMK Layout Helper";
+
+/// Actions requested from the tray menu that are handled by the overlay.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TrayCommand {
+    RefreshKeymap,
+    ExportKeymap,
+    ExportCheatSheet,
+    ToggleDetails,
+    ToggleHeatmap,
+    ExportStatistics,
+    NextLayer,
+    PreviousLayer,
+    /// Closes the overlay, the press statistics are saved before exiting
+    Quit,
+}
+
+pub struct Tray {
+    icon: TrayIcon,
+    commands: Receiver<TrayCommand>,
+}
 
 fn create_icon() -> Icon {
     const ICON_BYTES: &[u8] = include_bytes!("../resources/icon.ico");
@@ -14,9 +37,37 @@ fn create_icon() -> Icon {
     Icon::from_rgba(icon.into_raw(), width, height).expect("Failed to create icon.")
 }
 
-pub fn create_tray_icon() -> TrayIcon {
+/// Creates the tray icon. Offline keymaps additionally get menu items to switch layers, recorded
+/// key press statistics get menu items for the heatmap.
+pub fn create_tray_icon(offline: bool, statistics: bool) -> Tray {
+    let next_layer = MenuItem::new("Next layer", true, None);
+    let previous_layer = MenuItem::new("Previous layer", true, None);
+    let refresh_keymap = MenuItem::new("Refresh keymap", true, None);
+    let export_keymap = MenuItem::new("Export keymap...", true, None);
+    let export_cheat_sheet = MenuItem::new("Export cheat sheet...", true, None);
+    let details = CheckMenuItem::new("Show details", true, false, None);
+    let heatmap = CheckMenuItem::new("Show heatmap", true, false, None);
+    let export_statistics = MenuItem::new("Export statistics...", true, None);
     let quit = MenuItem::new("Quit", true, None);
     let menu = Menu::new();
+    if offline {
+        menu.append(&next_layer)
+            .expect("Failed to append menu item.");
+        menu.append(&previous_layer)
+            .expect("Failed to append menu item.");
+    }
+    menu.append(&refresh_keymap)
+        .expect("Failed to append menu item.");
+    menu.append(&export_keymap)
+        .expect("Failed to append menu item.");
+    menu.append(&export_cheat_sheet)
+        .expect("Failed to append menu item.");
+    menu.append(&details).expect("Failed to append menu item.");
+    if statistics {
+        menu.append(&heatmap).expect("Failed to append menu item.");
+        menu.append(&export_statistics)
+            .expect("Failed to append menu item.");
+    }
     menu.append(&quit).expect("Failed to append menu item.");
 
     let icon = create_icon();
@@ -24,15 +75,61 @@ pub fn create_tray_icon() -> TrayIcon {
     let tray_icon = TrayIconBuilder::new()
         .with_menu(Box::new(menu))
         .with_icon(icon)
-        .with_tooltip("QMK Layout Helper")
+        .with_tooltip(TOOLTIP)
         .build()
         .unwrap();
 
+    let (sender, receiver) = mpsc::channel();
+    let refresh_keymap_id = refresh_keymap.id().clone();
+    let export_keymap_id = export_keymap.id().clone();
+    let export_cheat_sheet_id = export_cheat_sheet.id().clone();
+    let details_id = details.id().clone();
+    let heatmap_id = heatmap.id().clone();
+    let export_statistics_id = export_statistics.id().clone();
+    let next_layer_id = next_layer.id().clone();
+    let previous_layer_id = previous_layer.id().clone();
+    let quit_id = quit.id().clone();
     thread::spawn(move || {
-        if MenuEvent::receiver().recv().is_ok() {
-            process::exit(0);
+        while let Ok(event) = MenuEvent::receiver().recv() {
+            if event.id == quit_id {
+                let _ = sender.send(TrayCommand::Quit);
+            } else if event.id == refresh_keymap_id {
+                let _ = sender.send(TrayCommand::RefreshKeymap);
+            } else if event.id == export_keymap_id {
+                let _ = sender.send(TrayCommand::ExportKeymap);
+            } else if event.id == export_cheat_sheet_id {
+                let _ = sender.send(TrayCommand::ExportCheatSheet);
+            } else if event.id == details_id {
+                let _ = sender.send(TrayCommand::ToggleDetails);
+            } else if event.id == heatmap_id {
+                let _ = sender.send(TrayCommand::ToggleHeatmap);
+            } else if event.id == export_statistics_id {
+                let _ = sender.send(TrayCommand::ExportStatistics);
+            } else if event.id == next_layer_id {
+                let _ = sender.send(TrayCommand::NextLayer);
+            } else if event.id == previous_layer_id {
+                let _ = sender.send(TrayCommand::PreviousLayer);
+            }
         }
     });
 
-    tray_icon
+    Tray {
+        icon: tray_icon,
+        commands: receiver,
+    }
+}
+
+impl Tray {
+    pub fn set_connected(&self, connected: bool) {
+        let tooltip = if connected {
+            TOOLTIP.to_string()
+        } else {
+            format!("{TOOLTIP} (disconnected)")
+        };
+        let _ = self.icon.set_tooltip(Some(tooltip));
+    }
+
+    pub fn try_recv_command(&self) -> Option<TrayCommand> {
+        self.commands.try_recv().ok()
+    }
 }
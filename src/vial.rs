@@ -0,0 +1,386 @@
+use serde_json::Value;
+
+use crate::keyboard_info::KeyboardInfo;
+use crate::keycode_labels::constants::QK_TAP_DANCE;
+use crate::keycode_labels::{self, KeycodeLabel, LabelOptions};
+use crate::transport::{DeviceId, Transport, ViaTransport};
+
+/// VIA command id all Vial commands are sent with.
+const VIAL_PREFIX: u8 = 0xFE;
+
+const GET_KEYBOARD_ID: u8 = 0x00;
+const GET_SIZE: u8 = 0x01;
+const GET_DEFINITION: u8 = 0x02;
+const GET_ENCODER: u8 = 0x03;
+const DYNAMIC_ENTRY_OP: u8 = 0x0D;
+
+const GET_NUMBER_OF_ENTRIES: u8 = 0x00;
+const TAP_DANCE_GET: u8 = 0x01;
+const COMBO_GET: u8 = 0x03;
+const KEY_OVERRIDE_GET: u8 = 0x05;
+
+const DEFINITION_PAGE_SIZE: usize = 32;
+/// Largest definition size accepted from the device. Compressed definitions take a few KiB.
+const MAX_DEFINITION_SIZE: usize = 1 << 20;
+const KEY_OVERRIDE_ENABLED: u8 = 1 << 7;
+
+/// First Vial protocol version using the same keycodes as VIA protocol version 12.
+pub const MIN_PROTOCOL_VERSION: u32 = 6;
+
+fn command(transport: &dyn Transport, command: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+    let mut vial_args = vec![command];
+    vial_args.extend_from_slice(args);
+    transport.raw_command(VIAL_PREFIX, &vial_args)
+}
+
+fn u16_at(response: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([response[offset], response[offset + 1]])
+}
+
+pub fn get_protocol_version(transport: &dyn Transport) -> Result<u32, String> {
+    command(transport, GET_KEYBOARD_ID, &[])
+        .map(|response| u32::from_le_bytes([response[0], response[1], response[2], response[3]]))
+        .map_err(|e| format!("Failed to get Vial protocol version: {e}"))
+}
+
+/// Reads the keyboard definition stored on the device. It has the format of a VIA definition
+/// and is stored XZ compressed.
+pub fn read_definition(transport: &dyn Transport) -> Result<Value, String> {
+    let size = command(transport, GET_SIZE, &[])
+        .map(|response| u32::from_le_bytes([response[0], response[1], response[2], response[3]]))
+        .map_err(|e| format!("Failed to get Vial definition size: {e}"))? as usize;
+    if size > MAX_DEFINITION_SIZE {
+        return Err(format!(
+            "Vial definition size of {size} bytes exceeds the limit of {MAX_DEFINITION_SIZE}"
+        ));
+    }
+
+    let mut compressed = Vec::with_capacity(size);
+    for page in 0..size.div_ceil(DEFINITION_PAGE_SIZE) as u16 {
+        let response = command(transport, GET_DEFINITION, &page.to_le_bytes())
+            .map_err(|e| format!("Failed to read Vial definition: {e}"))?;
+        compressed.extend_from_slice(&response[..DEFINITION_PAGE_SIZE]);
+    }
+    compressed.truncate(size);
+
+    let mut definition = Vec::new();
+    lzma_rs::xz_decompress(&mut compressed.as_slice(), &mut definition)
+        .map_err(|e| format!("Failed to decompress Vial definition: {e}"))?;
+    serde_json::from_slice(&definition).map_err(|e| format!("Failed to parse Vial definition: {e}"))
+}
+
+/// Connects to the device and builds the keyboard info from its Vial definition, so that no
+/// info JSON is needed.
+pub fn read_keyboard_info(device_id: &DeviceId) -> Result<KeyboardInfo, String> {
+    let transport = ViaTransport::connect(device_id)?;
+    let definition = read_definition(&transport)?;
+    let mut keyboard_info =
+        KeyboardInfo::from_via_definition(&definition).map_err(|e| e.to_string())?;
+    keyboard_info.vid = device_id.vid;
+    keyboard_info.pid = device_id.pid;
+    Ok(keyboard_info)
+}
+
+/// Vial replaces the VIA encoder commands with its own. Returns the counter-clockwise and
+/// clockwise keycode.
+pub fn read_encoder(transport: &dyn Transport, layer: u8, index: u8) -> Result<[u16; 2], String> {
+    command(transport, GET_ENCODER, &[layer, index])
+        .map(|response| {
+            [
+                u16::from_be_bytes([response[0], response[1]]),
+                u16::from_be_bytes([response[2], response[3]]),
+            ]
+        })
+        .map_err(|e| format!("Failed to read encoder {index} of layer {layer}: {e}"))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TapDance {
+    pub on_tap: u16,
+    pub on_hold: u16,
+    pub on_double_tap: u16,
+    pub on_tap_hold: u16,
+    pub tapping_term: u16,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Combo {
+    pub keys: [u16; 4],
+    pub output: u16,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KeyOverride {
+    pub trigger: u16,
+    pub replacement: u16,
+    pub layers: u16,
+    pub trigger_mods: u8,
+    pub negative_mod_mask: u8,
+    pub suppressed_mods: u8,
+    pub options: u8,
+}
+
+/// Features configured with Vial that are not part of the keymap.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VialFeatures {
+    pub tap_dances: Vec<TapDance>,
+    pub combos: Vec<Combo>,
+    pub key_overrides: Vec<KeyOverride>,
+}
+
+impl VialFeatures {
+    pub fn read(transport: &dyn Transport) -> Result<Self, String> {
+        let counts = command(transport, DYNAMIC_ENTRY_OP, &[GET_NUMBER_OF_ENTRIES])
+            .map_err(|e| format!("Failed to get number of Vial entries: {e}"))?;
+
+        // Entries start with a status byte, which is zero on success
+        let get_entry = |operation: u8, index: u8| {
+            command(transport, DYNAMIC_ENTRY_OP, &[operation, index]).and_then(|response| {
+                if response[0] == 0 {
+                    Ok(response[1..].to_vec())
+                } else {
+                    Err(format!("Failed to read Vial entry {index}"))
+                }
+            })
+        };
+
+        let tap_dances = (0..counts[0])
+            .map(|i| {
+                get_entry(TAP_DANCE_GET, i).map(|entry| TapDance {
+                    on_tap: u16_at(&entry, 0),
+                    on_hold: u16_at(&entry, 2),
+                    on_double_tap: u16_at(&entry, 4),
+                    on_tap_hold: u16_at(&entry, 6),
+                    tapping_term: u16_at(&entry, 8),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let combos = (0..counts[1])
+            .map(|i| {
+                get_entry(COMBO_GET, i).map(|entry| Combo {
+                    keys: [0, 1, 2, 3].map(|key| u16_at(&entry, key * 2)),
+                    output: u16_at(&entry, 8),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let key_overrides = (0..counts[2])
+            .map(|i| {
+                get_entry(KEY_OVERRIDE_GET, i).map(|entry| KeyOverride {
+                    trigger: u16_at(&entry, 0),
+                    replacement: u16_at(&entry, 2),
+                    layers: u16_at(&entry, 4),
+                    trigger_mods: entry[6],
+                    negative_mod_mask: entry[7],
+                    suppressed_mods: entry[8],
+                    options: entry[9],
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(VialFeatures {
+            tap_dances,
+            combos,
+            key_overrides,
+        })
+    }
+
+    /// Returns the tap dance index of a `TD(n)` keycode.
+    pub fn get_tap_dance_index(keycode: u16) -> Option<usize> {
+        QK_TAP_DANCE
+            .contains(&keycode)
+            .then(|| (keycode - QK_TAP_DANCE.start) as usize)
+    }
+
+    /// Annotates the label of a `TD(n)` keycode with the tap and hold action of the tap dance.
+    pub fn annotate_label(&self, keycode: u16, label: &mut KeycodeLabel, options: LabelOptions) {
+        let Some(tap_dance) =
+            Self::get_tap_dance_index(keycode).and_then(|index| self.tap_dances.get(index))
+        else {
+            return;
+        };
+        let actions: Vec<String> = [tap_dance.on_tap, tap_dance.on_hold]
+            .into_iter()
+            .filter(|keycode| *keycode != 0)
+            .map(|keycode| keycode_labels::get_keycode_name(keycode, options))
+            .collect();
+        if !actions.is_empty() {
+            label.long = Some(actions.join(" / "));
+            label.short = None;
+        }
+    }
+}
+
+impl TapDance {
+    pub fn is_empty(&self) -> bool {
+        self.on_tap == 0 && self.on_hold == 0 && self.on_double_tap == 0 && self.on_tap_hold == 0
+    }
+
+    /// Describes the actions, e.g. `tap A, hold Ctrl (200 ms)`.
+    pub fn describe(&self, options: LabelOptions) -> String {
+        let actions: Vec<String> = [
+            ("tap", self.on_tap),
+            ("hold", self.on_hold),
+            ("double tap", self.on_double_tap),
+            ("tap and hold", self.on_tap_hold),
+        ]
+        .into_iter()
+        .filter(|(_, keycode)| *keycode != 0)
+        .map(|(action, keycode)| {
+            format!(
+                "{action} {}",
+                keycode_labels::get_keycode_name(keycode, options)
+            )
+        })
+        .collect();
+        format!("{} ({} ms)", actions.join(", "), self.tapping_term)
+    }
+}
+
+impl Combo {
+    pub fn is_empty(&self) -> bool {
+        self.output == 0
+    }
+
+    pub fn get_keys(&self) -> impl Iterator<Item = u16> + '_ {
+        self.keys.iter().copied().filter(|keycode| *keycode != 0)
+    }
+
+    /// Describes the keys and their output, e.g. `J + K → Esc`.
+    pub fn describe(&self, options: LabelOptions) -> String {
+        let keys: Vec<String> = self
+            .get_keys()
+            .map(|keycode| keycode_labels::get_keycode_name(keycode, options))
+            .collect();
+        format!(
+            "{} → {}",
+            keys.join(" + "),
+            keycode_labels::get_keycode_name(self.output, options)
+        )
+    }
+}
+
+impl KeyOverride {
+    pub fn is_enabled(&self) -> bool {
+        self.options & KEY_OVERRIDE_ENABLED != 0 && self.trigger != 0
+    }
+
+    pub fn is_active_on_layer(&self, layer: u8) -> bool {
+        layer < 16 && self.layers & (1 << layer) != 0
+    }
+
+    /// Describes the trigger and its replacement, e.g. `Shift+Bspc → Del`.
+    pub fn describe(&self, options: LabelOptions) -> String {
+        let trigger: Vec<String> = mod_mask_names(self.trigger_mods)
+            .into_iter()
+            .map(str::to_string)
+            .chain(std::iter::once(keycode_labels::get_keycode_name(
+                self.trigger,
+                options,
+            )))
+            .collect();
+        format!(
+            "{} → {}",
+            trigger.join("+"),
+            keycode_labels::get_keycode_name(self.replacement, options)
+        )
+    }
+}
+
+/// Names of the modifiers in an 8 bit modifier mask, left modifiers first.
+fn mod_mask_names(mask: u8) -> Vec<&'static str> {
+    const NAMES: [&str; 8] = [
+        "Ctrl", "Shift", "Alt", "GUI", "RCtrl", "RShift", "RAlt", "RGUI",
+    ];
+    NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| mask & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmk_via_api::api::RAW_EPSIZE;
+    use std::time::Duration;
+
+    /// Transport answering Vial commands, given their arguments, with the response payload.
+    struct VialStub(fn(&[u8]) -> Vec<u8>);
+
+    impl Transport for VialStub {
+        fn try_command(&self, _command: u8, _args: &[u8]) -> Result<Option<Vec<u8>>, String> {
+            Ok(None)
+        }
+
+        fn raw_command(&self, command: u8, args: &[u8]) -> Result<Vec<u8>, String> {
+            assert_eq!(command, VIAL_PREFIX);
+            let mut response = self.0(args);
+            response.resize(RAW_EPSIZE, 0);
+            Ok(response)
+        }
+
+        fn read_frame(&self, _timeout: Duration) -> Result<Option<Vec<u8>>, String> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn reads_tap_dances_combos_and_key_overrides() {
+        let transport = VialStub(|args| match args {
+            [DYNAMIC_ENTRY_OP, GET_NUMBER_OF_ENTRIES] => vec![1, 1, 1],
+            [DYNAMIC_ENTRY_OP, TAP_DANCE_GET, 0] => {
+                vec![0, 0x04, 0, 0xE0, 0, 0x05, 0, 0x06, 0, 0xC8, 0]
+            }
+            [DYNAMIC_ENTRY_OP, COMBO_GET, 0] => vec![0, 0x0D, 0, 0x0E, 0, 0, 0, 0, 0, 0x29, 0],
+            [DYNAMIC_ENTRY_OP, KEY_OVERRIDE_GET, 0] => {
+                vec![0, 0x2A, 0, 0x4C, 0, 0x03, 0x00, 0x02, 0x01, 0x02, 0x80]
+            }
+            _ => panic!("unexpected command {args:?}"),
+        });
+
+        assert_eq!(
+            VialFeatures::read(&transport),
+            Ok(VialFeatures {
+                tap_dances: vec![TapDance {
+                    on_tap: 0x04,
+                    on_hold: 0xE0,
+                    on_double_tap: 0x05,
+                    on_tap_hold: 0x06,
+                    tapping_term: 200,
+                }],
+                combos: vec![Combo {
+                    keys: [0x0D, 0x0E, 0, 0],
+                    output: 0x29,
+                }],
+                key_overrides: vec![KeyOverride {
+                    trigger: 0x2A,
+                    replacement: 0x4C,
+                    layers: 0x0003,
+                    trigger_mods: 0x02,
+                    negative_mod_mask: 0x01,
+                    suppressed_mods: 0x02,
+                    options: KEY_OVERRIDE_ENABLED,
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_failed_entries() {
+        let transport = VialStub(|args| match args {
+            [DYNAMIC_ENTRY_OP, GET_NUMBER_OF_ENTRIES] => vec![1, 0, 0],
+            _ => vec![1],
+        });
+        assert!(VialFeatures::read(&transport).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_definitions() {
+        let transport = VialStub(|args| match args {
+            [GET_SIZE] => vec![0xFF, 0xFF, 0xFF, 0xFF],
+            _ => panic!("the definition must not be read"),
+        });
+        assert!(read_definition(&transport).is_err());
+    }
+}